@@ -0,0 +1,260 @@
+// Model architectures and the dispatch between them.
+//
+// Llama runs on our own `llama::Llama`, which exposes activations and can be
+// sharded across devices; so do Qwen2, which only adds attention biases and
+// optional sliding-window layers, and Phi-3, whose projections are fused and
+// whose long-context variants scale RoPE with "longrope". Every other architecture runs on its
+// candle-transformers implementation behind `StatefulModel` (Gemma and Gemma 2
+// differ too much: offset RMSNorm weights, extra norms around each block,
+// logit soft-capping and a head size not tied to the hidden size): those models
+// keep their KV cache inside the model struct, so each sequence decodes on
+// its own clone (the weight tensors are reference-counted and shared).
+// StableLM runs on our adaptation in stablelm.rs, since candle's can't be
+// cloned; Falcon's parallel attention/MLP blocks with a fused query/key/value
+// projection run on candle's `Falcon`.
+
+use anyhow::{bail, Result};
+use candle_core::Tensor;
+use candle_nn::VarBuilder;
+use candle_transformers::models::quantized_llama::ModelWeights as QuantizedLlama;
+use crate::error::Sl5Error;
+use crate::stablelm;
+use candle_transformers::models::{falcon, gemma, gemma2, mistral, mixtral};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Arch {
+    Llama,
+    Mistral,
+    /// Mistral with sparse mixture-of-experts MLPs.
+    Mixtral,
+    /// Qwen2 and Qwen2.5: Llama with attention biases.
+    Qwen2,
+    /// Phi-3 and Phi-3.5 (mini, medium): Llama with fused projections.
+    Phi3,
+    /// Gemma (and CodeGemma).
+    Gemma,
+    /// Gemma 2: Gemma with attention and final logit soft-capping and
+    /// sliding-window layers.
+    Gemma2,
+    /// StableLM-3B-4E1T and StableLM 2 (1.6B, 12B): LayerNorm, partial
+    /// rotary embeddings and, in StableLM 2, query/key/value biases.
+    StableLm,
+    /// Falcon 7B and 1B style models: multi-query attention in parallel with
+    /// the MLP.
+    Falcon,
+}
+
+impl Arch {
+    /// The architecture for a config.json `model_type`.
+    pub fn from_model_type(model_type: &str) -> Option<Self> {
+        match model_type {
+            "llama" => Some(Arch::Llama),
+            "mistral" => Some(Arch::Mistral),
+            "mixtral" => Some(Arch::Mixtral),
+            "qwen2" => Some(Arch::Qwen2),
+            "phi3" => Some(Arch::Phi3),
+            "gemma" => Some(Arch::Gemma),
+            "gemma2" => Some(Arch::Gemma2),
+            "stablelm" | "stablelm_epoch" => Some(Arch::StableLm),
+            "falcon" | "RefinedWebModel" | "RefinedWeb" => Some(Arch::Falcon),
+            _ => None,
+        }
+    }
+
+    /// Pick the architecture from config.json. Configs without a
+    /// `model_type` are treated as Llama.
+    pub fn detect(config_json: &serde_json::Value) -> Result<Self> {
+        match config_json["model_type"].as_str() {
+            None => Ok(Arch::Llama),
+            Some(model_type) => match Self::from_model_type(model_type) {
+                Some(arch) => Ok(arch),
+                None => Err(Sl5Error::UnsupportedArch {
+                    model_type: model_type.to_string(),
+                }
+                .into()),
+            },
+        }
+    }
+
+    /// Whether the model runs on `llama::Llama` (and so supports device maps,
+    /// sessions and activation capture).
+    pub fn is_llama_like(self) -> bool {
+        matches!(self, Arch::Llama | Arch::Qwen2 | Arch::Phi3)
+    }
+
+    /// Build a model that isn't run by `llama::Llama`.
+    pub(crate) fn load_stateful(
+        self,
+        config_json: &serde_json::Value,
+        vb: VarBuilder,
+        use_flash_attn: bool,
+    ) -> Result<Box<dyn StatefulModel>> {
+        // candle's configs expect this field, HF configs don't have it.
+        let mut config_json = config_json.clone();
+        config_json["use_flash_attn"] = use_flash_attn.into();
+        match self {
+            Arch::Llama | Arch::Qwen2 | Arch::Phi3 => bail!("{} models are loaded with llama::Llama", self),
+            Arch::Mistral => {
+                let config: mistral::Config = serde_json::from_value(config_json)?;
+                Ok(Box::new(mistral::Model::new(&config, vb)?))
+            }
+            Arch::Mixtral => {
+                if config_json["sliding_window"].is_null() {
+                    config_json["sliding_window"] = config_json["max_position_embeddings"].clone();
+                }
+                let config: mixtral::Config = serde_json::from_value(config_json)?;
+                Ok(Box::new(mixtral::Model::new(&config, vb)?))
+            }
+            Arch::Gemma => {
+                // Newer Gemma configs carry the legacy `hidden_act` next to
+                // the `hidden_activation` transformers actually uses.
+                if !config_json["hidden_activation"].is_null() {
+                    config_json["hidden_act"] = serde_json::Value::Null;
+                }
+                gemma_defaults(&mut config_json);
+                let config: gemma::Config = serde_json::from_value(config_json)?;
+                Ok(Box::new(gemma::Model::new(use_flash_attn, &config, vb)?))
+            }
+            Arch::Gemma2 => {
+                if config_json["hidden_activation"].is_null() {
+                    config_json["hidden_activation"] = config_json["hidden_act"].clone();
+                }
+                gemma_defaults(&mut config_json);
+                if config_json["query_pre_attn_scalar"].is_null() {
+                    config_json["query_pre_attn_scalar"] = config_json["head_dim"].clone();
+                }
+                let config: gemma2::Config = serde_json::from_value(config_json)?;
+                Ok(Box::new(gemma2::Model::new(use_flash_attn, &config, vb)?))
+            }
+            Arch::StableLm => {
+                let config: stablelm::Config = serde_json::from_value(config_json)?;
+                Ok(Box::new(stablelm::Model::new(&config, vb)?))
+            }
+            Arch::Falcon => {
+                falcon_defaults(&mut config_json);
+                let config: falcon::Config = serde_json::from_value(config_json)?;
+                if config.validate().is_err() {
+                    bail!("Only Falcon 7B/1B style models (multi-query attention, rotary embeddings) are supported, not Falcon 40B/180B (new_decoder_architecture) or ALiBi models");
+                }
+                Ok(Box::new(falcon::Falcon::load(vb, config)?))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Arch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Arch::Llama => "llama",
+            Arch::Mistral => "mistral",
+            Arch::Mixtral => "mixtral",
+            Arch::Qwen2 => "qwen2",
+            Arch::Phi3 => "phi3",
+            Arch::Gemma => "gemma",
+            Arch::Gemma2 => "gemma2",
+            Arch::StableLm => "stablelm",
+            Arch::Falcon => "falcon",
+        })
+    }
+}
+
+/// Fill in the Gemma config fields transformers has defaults for.
+fn gemma_defaults(config_json: &mut serde_json::Value) {
+    if config_json["head_dim"].is_null() {
+        let hidden = config_json["hidden_size"].as_u64().unwrap_or(0);
+        let heads = config_json["num_attention_heads"].as_u64().unwrap_or(1).max(1);
+        config_json["head_dim"] = (hidden / heads).into();
+    }
+    if config_json["hidden_act"].is_null() && config_json["hidden_activation"].is_null() {
+        config_json["hidden_activation"] = "gelu_pytorch_tanh".into();
+    }
+    if config_json["attention_bias"].is_null() {
+        config_json["attention_bias"] = false.into();
+    }
+    if config_json["rope_theta"].is_null() {
+        config_json["rope_theta"] = 10000.0.into();
+    }
+}
+
+/// Fill in the Falcon config fields transformers has defaults for, and the
+/// current names of the ones older RefinedWeb configs call differently.
+fn falcon_defaults(config_json: &mut serde_json::Value) {
+    for (old, new) in [
+        ("n_head", "num_attention_heads"),
+        ("n_layer", "num_hidden_layers"),
+        ("num_kv_heads", "n_head_kv"),
+    ] {
+        if config_json[new].is_null() {
+            config_json[new] = config_json[old].clone();
+        }
+    }
+    let defaults = [
+        ("layer_norm_epsilon", 1e-5.into()),
+        ("initializer_range", 0.02.into()),
+        ("use_cache", true.into()),
+        ("bos_token_id", 11.into()),
+        ("eos_token_id", 11.into()),
+        ("hidden_dropout", 0.0.into()),
+        ("attention_dropout", 0.0.into()),
+        ("alibi", false.into()),
+        ("new_decoder_architecture", false.into()),
+        ("multi_query", true.into()),
+        ("parallel_attn", true.into()),
+        ("bias", false.into()),
+    ];
+    for (key, value) in defaults {
+        if config_json[key].is_null() {
+            config_json[key] = value;
+        }
+    }
+}
+
+/// A model that keeps its own KV cache. A forward pass at position 0 must
+/// only be made on a fresh clone.
+pub trait StatefulModel: Send + Sync {
+    /// Logits for the last position of `input`.
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> candle_core::Result<Tensor>;
+
+    fn clone_box(&self) -> Box<dyn StatefulModel>;
+}
+
+macro_rules! impl_stateful_model {
+    ($($model:ty),*) => {$(
+        impl StatefulModel for $model {
+            fn forward(&mut self, input: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+                <$model>::forward(self, input, index_pos)
+            }
+
+            fn clone_box(&self) -> Box<dyn StatefulModel> {
+                Box::new(self.clone())
+            }
+        }
+    )*};
+}
+
+impl_stateful_model!(
+    QuantizedLlama,
+    mistral::Model,
+    mixtral::Model,
+    gemma::Model,
+    gemma2::Model,
+    stablelm::Model
+);
+
+// Falcon finds its position from the length of its KV cache.
+impl StatefulModel for falcon::Falcon {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        // Its causal mask only covers the new tokens, so anything after the
+        // first pass has to come one token at a time.
+        if index_pos > 0 && input.dim(1)? > 1 {
+            candle_core::bail!("Falcon models can only extend their KV cache one token at a time");
+        }
+        falcon::Falcon::forward(self, input)
+    }
+
+    fn clone_box(&self) -> Box<dyn StatefulModel> {
+        Box::new(self.clone())
+    }
+}
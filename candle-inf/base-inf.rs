@@ -8,31 +8,80 @@ extern crate accelerate_src;
 #[cfg(feature = "mkl")]
 extern crate intel_mkl_src;
 
-use anyhow::{bail, Result};
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgAction, CommandFactory, Parser, ValueEnum};
 
 use candle_core::{DType, Device, Tensor};
-use candle_nn::VarBuilder;
-use candle_transformers::generation::{LogitsProcessor, Sampling};
-use candle_transformers::models::llama as model;
-use hf_hub::{api::sync::Api, Repo, RepoType};
-use model::{Llama, Config};
-use tokenizers::Tokenizer;
+use candle_inference::batch::{read_prompts, BatchCompletion, BatchPrompt};
+use candle_inference::bias::parse_logit_bias;
+use candle_inference::chat::{read_messages, ChatTemplate, Message};
+use candle_inference::check::check_weights;
+use candle_inference::device_map::{format_size, free_cuda_memory, parse_memory_caps};
+use candle_inference::energy::{EnergyReport, PowerMonitor};
+use candle_inference::extract::{extract, Extractor};
+use candle_inference::hub::{self, RetryPolicy};
+use candle_inference::llama::{Capture, KvCacheDtype};
+use candle_inference::logging::{self, LogFormat, LogLevel};
+use candle_inference::loader;
+use candle_inference::lora::LoraSpec;
+use candle_inference::memory;
+use candle_inference::metrics::Metrics;
+use candle_inference::sampling::Truncation;
+use candle_inference::tensors::save_tensors;
+use candle_inference::tokenize::{encode_prompt, tokenize};
+use candle_inference::watermark::Watermark;
+use candle_inference::{
+    Arch, Attention, ContextOverflow, DeviceMap, FinishReason, FlashAttn, GenerationConfig,
+    Generator, KvEviction, Model, ModelLoader, Quantization, RepeatScope, Session, Sl5Error,
+    TokenLogprobs, TokenTrace,
+};
+use serde::{Serialize, Serializer};
 
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-const EOS_TOKEN: &str = "</s>";
 const DEFAULT_PROMPT: &str = "Hello, my name is";
+/// Process exit code when a --max-total-* budget stopped the run early.
+const BUDGET_EXCEEDED_EXIT_CODE: i32 = 3;
+/// Process exit code after Ctrl-C stopped the run, as a shell reports SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
 
-#[derive(Parser, Debug)]
+/// Set with --output json, which keeps stdout for the final JSON object.
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Set by Ctrl-C once generation has started; the generation loops stop at
+/// the next token.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// `println!` for the human-readable banners, progress and results.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !JSON_OUTPUT.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Print streamed output text, unless the output is JSON.
+fn say_inline(text: &str) {
+    if !JSON_OUTPUT.load(Ordering::Relaxed) {
+        print!("{}", text);
+    }
+}
+
+#[derive(Parser, Debug, Serialize)]
 #[command(
     name = "base-inf",
     about = "Basic LLM Inference with Candle",
     long_about = "A simple script to run LLM inference using the Candle framework"
 )]
 struct Args {
-    /// Model ID from HuggingFace Hub (e.g., "meta-llama/Llama-2-7b-hf") or local path
+    /// Model ID from HuggingFace Hub (e.g., "meta-llama/Llama-2-7b-hf"), local
+    /// path, or an s3://, gs:// or https:// URL of a model directory
     #[arg(short = 'm', long)]
     model_id: String,
 
@@ -40,18 +89,123 @@ struct Args {
     #[arg(long)]
     local: bool,
 
+    /// Run a quantized GGUF file (e.g. `llama-2-7b.Q4_K_M.gguf`) instead of
+    /// the safetensors weights: a local path or a file in the model repo. The
+    /// tokenizer still comes from the model; --dtype is ignored
+    #[arg(
+        long,
+        value_name = "GGUF",
+        conflicts_with_all = ["dump_hidden_states", "dump_attention"]
+    )]
+    quantized: Option<String>,
+
+    /// Model architecture (default: from config.json's model_type)
+    #[arg(long, value_enum)]
+    arch: Option<Arch>,
+
+    /// Merge a LoRA adapter into the weights while loading: a local directory
+    /// or Hub repo with adapter_config.json and adapter_model.safetensors.
+    /// Repeatable; `=WEIGHT` scales the adapter's contribution (default 1.0)
+    #[arg(long, value_name = "PATH_OR_REPO[=WEIGHT]", conflicts_with = "quantized")]
+    lora: Vec<String>,
+
+    /// Quantize the linear layers of the safetensors weights while loading
+    /// them (Llama, Qwen2 and Phi-3), for a fraction of the memory
+    #[arg(long, value_enum, conflicts_with = "quantized")]
+    quantize: Option<Quantization>,
+
+    /// Attention kernels: `auto` uses flash attention when the build
+    /// (--features flash-attn), GPUs (Ampere or newer) and dtype (f16/bf16)
+    /// support it, `on` also warns when it can't, `off` never uses it
+    #[arg(long, value_enum, default_value_t = FlashAttn::Auto)]
+    flash_attn: FlashAttn,
+
     /// The initial prompt for text generation
     #[arg(short = 'p', long, default_value = DEFAULT_PROMPT)]
     prompt: String,
 
+    /// System message; the prompt becomes the user's turn and both are
+    /// rendered with the model's chat template
+    #[arg(long, value_name = "TEXT")]
+    system: Option<String>,
+
+    /// A conversation turn as `role:content`, e.g. `user:Hello` (repeatable,
+    /// in order), rendered with the model's chat template instead of -p
+    #[arg(long = "message", value_name = "ROLE:CONTENT", conflicts_with = "prompt")]
+    messages: Vec<String>,
+
+    /// JSON file with the conversation: an array of {"role", "content"}
+    /// messages, or an object with one under "messages"
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["prompt", "messages"])]
+    messages_file: Option<PathBuf>,
+
+    /// Text that starts the model's answer: it is fed to the model after the
+    /// prompt as-is (not sampled) and generation continues from it
+    #[arg(long)]
+    assistant_prefix: Option<String>,
+
+    /// Text to steer away from with classifier-free guidance; an empty
+    /// string contrasts against the unconditioned model
+    #[arg(long)]
+    negative_prompt: Option<String>,
+
+    /// Guidance strength for --negative-prompt (1.0 = no guidance)
+    #[arg(long, default_value_t = 1.5)]
+    cfg_scale: f64,
+
+    /// Stop generating when the output contains this text (repeatable); the
+    /// stop text itself is not printed
+    #[arg(long = "stop", value_name = "TEXT")]
+    stop_sequences: Vec<String>,
+
+    /// Post-process the final text (repeatable, applied in order): `trim`,
+    /// `code` (first fenced code block), `json` (first JSON object or array)
+    /// or `regex:<pattern>` (first match, or its first capture group)
+    #[arg(long, value_name = "trim|code|json|regex:<PATTERN>")]
+    extract: Vec<String>,
+
     /// Number of tokens to generate
     #[arg(short = 'n', long, default_value_t = 128)]
     num_tokens: usize,
 
+    /// Generate this many independent completions of the prompt, sampled
+    /// with seeds --seed, --seed + 1, ...; the prompt is processed once and
+    /// its KV cache shared by every sequence
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        conflicts_with_all = [
+            "prompt_file",
+            "negative_prompt",
+            "dump_hidden_states",
+            "dump_attention",
+            "trace_file",
+            "save_session",
+        ]
+    )]
+    num_return_sequences: usize,
+
     /// Run on CPU instead of GPU
     #[arg(long)]
     cpu: bool,
 
+    /// Split the decoder layers across devices, e.g. `0:40,1:40` puts 40
+    /// layers on each of cuda:0 and cuda:1 (`cpu:N` keeps N layers on the CPU);
+    /// `auto` splits them across all GPUs in proportion to their free memory
+    #[arg(long, value_name = "DEVICE:LAYERS,...|auto")]
+    device_map: Option<String>,
+
+    /// Split the layers across these devices in proportion to how much
+    /// memory each may use for weights, e.g. `0:20GiB,1:6GiB`
+    #[arg(long, value_name = "DEVICE:SIZE,...")]
+    max_mem: Option<String>,
+
+    /// Load the model even when its estimated memory footprint (weights, KV
+    /// cache and activations) is more than the devices have free
+    #[arg(long)]
+    no_memory_check: bool,
+
     /// Temperature for sampling (higher = more random)
     #[arg(long, default_value_t = 0.8)]
     temperature: f64,
@@ -64,6 +218,12 @@ struct Args {
     #[arg(long)]
     top_k: Option<usize>,
 
+    /// Truncate the distribution before top-k/top-p: `typical:P` keeps the
+    /// tokens closest to its entropy covering mass P, `top-n-sigma:N` those
+    /// within N standard deviations of the largest logit
+    #[arg(long, value_name = "typical:P|top-n-sigma:N")]
+    sampling: Option<Truncation>,
+
     /// Random seed for reproducibility
     #[arg(long, default_value_t = 299792458)]
     seed: u64,
@@ -80,25 +240,1243 @@ struct Args {
     #[arg(long, default_value_t = 128)]
     repeat_last_n: usize,
 
+    /// Tokens --repeat-last-n counts back through: the prompt, the generated
+    /// tokens, or both together
+    #[arg(long, value_enum, default_value_t = RepeatScope::All)]
+    repeat_scope: RepeatScope,
+
+    /// DRY penalty on tokens that would continue a sequence already in the
+    /// context (0 = off; 0.8 is a common setting)
+    #[arg(long, default_value_t = 0.)]
+    dry_multiplier: f32,
+
+    /// How fast the DRY penalty grows with each token of the repetition
+    #[arg(long, default_value_t = 1.75)]
+    dry_base: f32,
+
+    /// Repeated sequences up to this many tokens long are not penalized by DRY
+    #[arg(long, default_value_t = 2)]
+    dry_allowed_length: usize,
+
+    /// Text a DRY repetition can't extend across (repeatable; default: newline,
+    /// `:`, `"` and `*`)
+    #[arg(long = "dry-sequence-breaker", value_name = "TEXT")]
+    dry_sequence_breakers: Vec<String>,
+
+    /// Add a bias to the logits of token ids before sampling, as
+    /// `ID:BIAS,ID:BIAS,...` (positive favours a token, `-inf` forbids it)
+    #[arg(long, value_name = "ID:BIAS,...", value_parser = logit_bias_arg)]
+    logit_bias: Option<String>,
+
+    /// Words the output must not contain (comma-separated or repeatable)
+    #[arg(long, value_name = "WORD", value_delimiter = ',')]
+    ban_words: Vec<String>,
+
     /// Disable key-value cache
     #[arg(long)]
     no_kv_cache: bool,
 
+    /// Keep the KV cache in f16 or q8_0 (about half of f16) instead of the
+    /// model's dtype, for long contexts (Llama, Qwen2 and Phi-3)
+    #[arg(long, value_enum, conflicts_with_all = ["no_kv_cache", "quantized"])]
+    kv_cache_dtype: Option<KvCacheDtype>,
+
+    /// Feed the prompt to the model this many tokens at a time, so an 8k+
+    /// token prompt doesn't need the activations of all its tokens at once
+    /// (Llama, Qwen2 and Phi-3)
+    #[arg(long, value_name = "TOKENS", conflicts_with_all = ["no_kv_cache", "dump_attention"])]
+    prefill_chunk: Option<usize>,
+
+    /// What happens when the sequence fills the context window: stop, drop
+    /// the oldest half of the KV cache (window), or do that while keeping
+    /// the first --sink-tokens (sink)
+    #[arg(long, value_enum, default_value_t = KvEviction::None)]
+    kv_eviction: KvEviction,
+
+    /// Leading tokens --kv-eviction sink always keeps
+    #[arg(long, default_value_t = 4)]
+    sink_tokens: usize,
+
+    /// What happens when the prompt and --num-tokens don't fit in the
+    /// context window together: refuse the prompt (error), drop its start
+    /// (truncate-left), drop its middle or, for conversations, the oldest
+    /// turns (truncate-middle), or stop generating once the window is full
+    /// (reduce-max-tokens)
+    #[arg(long, value_enum, default_value_t = ContextOverflow::ReduceMaxTokens)]
+    context_overflow: ContextOverflow,
+
     /// Revision/branch to use from HuggingFace
     #[arg(long)]
     revision: Option<String>,
+
+    /// Hub endpoint or mirror to download from (default: $HF_ENDPOINT or
+    /// https://huggingface.co)
+    #[arg(long)]
+    hub_endpoint: Option<String>,
+
+    /// Hugging Face access token for gated or private models (default:
+    /// $HF_TOKEN, else the token saved by `huggingface-cli login`)
+    #[arg(long)]
+    #[serde(serialize_with = "redact")]
+    hf_token: Option<String>,
+
+    /// Directory to cache downloaded models in (default: $HF_HOME/hub, or
+    /// ~/.cache/huggingface/hub)
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Retries for failed downloads (network errors, 429 and 5xx only)
+    #[arg(long, default_value_t = 5)]
+    download_retries: u32,
+
+    /// Initial delay in seconds between download retries, doubled each time
+    #[arg(long, default_value_t = 1.0)]
+    retry_backoff: f64,
+
+    /// Read the weight files into the page cache before loading and run a
+    /// throwaway forward pass so the first generation isn't slowed by page
+    /// faults and kernel initialisation
+    #[arg(long)]
+    warmup_weights: bool,
+
+    /// Generate from the prompt this many times (up to 16 tokens each)
+    /// before the timed run, so its statistics measure steady-state speed
+    #[arg(long, value_name = "N", default_value_t = 0, conflicts_with = "prompt_file")]
+    warmup: usize,
+
+    /// Before loading, check that every tensor the model needs is present
+    /// with the right shape and free of NaN/Inf, and stop with a report if not
+    #[arg(long, conflicts_with = "quantized")]
+    check_weights: bool,
+
+    /// Lock the loaded model in RAM (mlockall) so it can't be swapped out
+    #[arg(long)]
+    mlock: bool,
+
+    /// Prompts longer than this many bytes are split at line boundaries and
+    /// tokenized in parallel (0 = always tokenize in one pass)
+    #[arg(long, default_value_t = 16384)]
+    tokenize_chunk_size: usize,
+
+    /// Save hidden states of the given decoder layers for the prompt, e.g.
+    /// `--dump-hidden-states layer=16 out.npz` or `layers=0,8-11 out.safetensors`
+    #[arg(long, num_args = 2, value_names = ["LAYERS", "PATH"])]
+    dump_hidden_states: Option<Vec<String>>,
+
+    /// Also record hidden states for generated tokens when dumping
+    #[arg(long, requires = "dump_hidden_states")]
+    dump_generated_hidden_states: bool,
+
+    /// Debug: save the prompt's attention probabilities for the given layers,
+    /// e.g. `--dump-attention layers=0,31 attn.npz`
+    #[arg(long, num_args = 2, value_names = ["LAYERS", "PATH"])]
+    dump_attention: Option<Vec<String>>,
+
+    /// Heads to keep when dumping attention, e.g. `0-3,7` (default: all)
+    #[arg(long, requires = "dump_attention")]
+    attention_heads: Option<String>,
+
+    /// Abort generation once the whole run (including loading) has taken this
+    /// many seconds; partial output is kept and the exit code is 3
+    #[arg(long)]
+    max_total_time: Option<f64>,
+
+    /// Abort generation once this many tokens have been generated across the
+    /// run; partial output is kept and the exit code is 3
+    #[arg(long)]
+    max_total_tokens_per_run: Option<usize>,
+
+    /// Secret key for watermarking generated text (see `sl5 detect-watermark`)
+    #[arg(long)]
+    #[serde(serialize_with = "redact")]
+    watermark_key: Option<String>,
+
+    /// Fraction of the vocabulary favoured at each watermarked step
+    #[arg(long, default_value_t = 0.25)]
+    watermark_gamma: f64,
+
+    /// Logit bias added to favoured tokens; higher is easier to detect but
+    /// distorts the output more
+    #[arg(long, default_value_t = 2.0)]
+    watermark_delta: f32,
+
+    /// Write a JSON line per generated token with its id, text, logprob, the
+    /// entropy of the distribution and how many candidates each sampling
+    /// filter removed
+    #[arg(long, value_name = "PATH")]
+    trace_file: Option<PathBuf>,
+
+    /// Record the log-probability of every generated token under the model
+    /// and of the N most likely alternatives, printed as JSON after the
+    /// output (and added to each --prompt-file result)
+    #[arg(long, value_name = "N")]
+    logprobs: Option<usize>,
+
+    /// Save the prompt's KV cache and tokens to this file once the prompt has
+    /// been processed
+    #[arg(long, value_name = "PATH")]
+    save_session: Option<PathBuf>,
+
+    /// Restore a session saved with --save-session and only process the part
+    /// of the prompt that differs from the saved one
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["dump_hidden_states", "dump_attention"]
+    )]
+    load_session: Option<PathBuf>,
+
+    /// Generate a completion for every prompt in a JSONL file (one JSON
+    /// string or {"prompt": ..., "id": ...} object per line) instead of -p
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "output_file",
+        conflicts_with_all = [
+            "system",
+            "messages",
+            "messages_file",
+            "negative_prompt",
+            "dump_hidden_states",
+            "dump_attention",
+            "trace_file",
+            "save_session",
+            "load_session",
+        ]
+    )]
+    prompt_file: Option<PathBuf>,
+
+    /// JSONL file for the --prompt-file completions and their timings
+    #[arg(long, value_name = "PATH", requires = "prompt_file")]
+    output_file: Option<PathBuf>,
+
+    /// Sample the GPUs' power draw (nvidia-smi) while generating and report
+    /// the energy used and its cost per 1k tokens
+    #[arg(long)]
+    energy: bool,
+
+    /// Electricity price per kWh for the --energy cost estimate
+    #[arg(long, value_name = "PRICE", default_value_t = 0.15)]
+    price_per_kwh: f64,
+
+    /// Write the run's statistics (tokens, time and --energy figures) to this
+    /// file in the Prometheus text format, e.g. for node_exporter's textfile
+    /// collector
+    #[arg(long, value_name = "PATH")]
+    metrics_file: Option<PathBuf>,
+
+    /// TOML file of options by their long names (`top_p = 0.9`), or a JSON
+    /// one from --dump-config; options on the command line take precedence
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Print the fully resolved configuration (as JSON, or TOML for --config)
+    /// before running; JSON results always include it as `config`
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "json"
+    )]
+    #[serde(skip)]
+    print_config: Option<ConfigFormat>,
+
+    /// Print the fully resolved configuration (as JSON, or TOML for --config)
+    /// and exit without loading anything
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "json",
+        conflicts_with = "print_config"
+    )]
+    #[serde(skip)]
+    dump_config: Option<ConfigFormat>,
+
+    /// `text` for the banners, streamed output and statistics; `json` prints
+    /// nothing but one JSON object with the completion, token counts,
+    /// timings and sampling settings once the run is over
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// How generated text is streamed to stdout: `text`, or `ndjson` for one
+    /// {"token", "id", "logprob"} JSON line per token followed by the
+    /// --output json object
+    #[arg(long, value_enum, default_value_t = StreamFormat::Text)]
+    stream_format: StreamFormat,
+
+    /// Log the library's spans and events (model loading, prefill and
+    /// decode steps with their timings, warnings) to stderr up to this level
+    #[arg(long, value_enum, default_value_t = LogLevel::Warn)]
+    log_level: LogLevel,
+
+    /// `text` log lines, or `json` for one object per line with the span
+    /// chain (and span ids) each event happened in
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Record the run's spans as a Chrome trace, for chrome://tracing or
+    /// https://ui.perfetto.dev (--trace-file is the per-token trace)
+    #[arg(long, value_name = "PATH")]
+    timeline_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum StreamFormat {
+    Text,
+    Ndjson,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum ConfigFormat {
+    Json,
+    Toml,
+}
+
+/// Keys of a resolved configuration that aren't options, skipped when it is
+/// read back with --config.
+const RESOLVED_ONLY_KEYS: [&str; 2] = ["config", "hf_cache"];
+
+/// `argv` with the options of its --config file added before the command
+/// line's own, leaving out the ones the command line sets. List options
+/// (`stop = ["\n", "###"]`) repeat their flag for each value.
+fn args_with_config(argv: Vec<OsString>) -> Result<Vec<OsString>> {
+    // Required options may come from the file, so nothing is an error yet.
+    let command = Args::command();
+    let matches = command.clone().ignore_errors(true).get_matches_from(&argv);
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return Ok(argv);
+    };
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let table = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str::<serde_json::Value>(&text)
+            .with_context(|| format!("Invalid JSON in {}", path.display()))?
+    } else {
+        toml::from_str::<serde_json::Value>(&text)
+            .with_context(|| format!("Invalid TOML in {}", path.display()))?
+    };
+    let serde_json::Value::Object(table) = table else {
+        bail!("{} must hold a table of options", path.display());
+    };
+
+    let scalar = |key: &str, value: &serde_json::Value| -> Result<OsString> {
+        match value {
+            serde_json::Value::String(text) => Ok(text.into()),
+            serde_json::Value::Number(_) | serde_json::Value::Bool(_) => Ok(value.to_string().into()),
+            _ => bail!("{} in {} must be a string, number or boolean", key, path.display()),
+        }
+    };
+    let mut config_args = vec![argv[0].clone()];
+    for (key, value) in &table {
+        // Options are named by their flag (`stop`) or by their field in a
+        // resolved configuration (`stop_sequences`).
+        let name = key.replace('-', "_");
+        if RESOLVED_ONLY_KEYS.contains(&name.as_str()) || value.is_null() || value == "<redacted>" {
+            continue;
+        }
+        let Some((arg, long)) = command.get_arguments().find_map(|arg| {
+            let long = arg.get_long()?;
+            (arg.get_id() == name.as_str() || long.replace('-', "_") == name).then_some((arg, long))
+        }) else {
+            bail!("Unknown option '{}' in {}", key, path.display());
+        };
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let flag = OsString::from(format!("--{long}"));
+        let takes_several = arg.get_num_args().is_some_and(|n| n.min_values() > 1);
+        match value {
+            serde_json::Value::Bool(set) if matches!(arg.get_action(), ArgAction::SetTrue) => {
+                if *set {
+                    config_args.push(flag);
+                }
+            }
+            serde_json::Value::Array(values) if takes_several => {
+                config_args.push(flag);
+                for value in values {
+                    config_args.push(scalar(key, value)?);
+                }
+            }
+            serde_json::Value::Array(values) => {
+                for value in values {
+                    config_args.push(flag.clone());
+                    config_args.push(scalar(key, value)?);
+                }
+            }
+            value => {
+                config_args.push(flag);
+                config_args.push(scalar(key, value)?);
+            }
+        }
+    }
+    config_args.extend(argv.into_iter().skip(1));
+    Ok(config_args)
+}
+
+/// Check a --logit-bias value; `generation_config` parses it again.
+fn logit_bias_arg(spec: &str) -> Result<String, String> {
+    parse_logit_bias(spec)
+        .map(|_| spec.to_string())
+        .map_err(|e| format!("{:#}", e))
+}
+
+/// `config` as a TOML table, without the unset options TOML can't express.
+fn config_toml(config: &serde_json::Value) -> Result<String> {
+    let mut config = config.clone();
+    if let Some(table) = config.as_object_mut() {
+        table.retain(|_, value| !value.is_null());
+    }
+    Ok(toml::to_string(&config)?)
+}
+
+impl ConfigFormat {
+    /// A resolved configuration in this format, ending with a newline.
+    fn render(self, config: &serde_json::Value) -> Result<String> {
+        match self {
+            ConfigFormat::Json => Ok(format!("{}\n", serde_json::to_string_pretty(config)?)),
+            ConfigFormat::Toml => config_toml(config),
+        }
+    }
+}
+
+impl Args {
+    /// The sampling settings for `-p`, which --prompt-file lines may override.
+    fn generation_config(&self) -> GenerationConfig {
+        GenerationConfig {
+            max_new_tokens: self.num_tokens,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            truncation: self.sampling,
+            seed: self.seed,
+            repeat_penalty: self.repeat_penalty,
+            repeat_last_n: self.repeat_last_n,
+            repeat_scope: self.repeat_scope,
+            dry_multiplier: self.dry_multiplier,
+            dry_base: self.dry_base,
+            dry_allowed_length: self.dry_allowed_length,
+            dry_sequence_breakers: if self.dry_sequence_breakers.is_empty() {
+                GenerationConfig::default().dry_sequence_breakers
+            } else {
+                self.dry_sequence_breakers.clone()
+            },
+            logit_bias: self
+                .logit_bias
+                .as_deref()
+                .map_or_else(BTreeMap::new, |spec| parse_logit_bias(spec).expect("checked by clap")),
+            ban_words: self.ban_words.clone(),
+            stop_sequences: self.stop_sequences.clone(),
+            use_kv_cache: !self.no_kv_cache,
+            kv_eviction: self.kv_eviction,
+            sink_tokens: self.sink_tokens,
+            context_overflow: self.context_overflow,
+            prefill_chunk: self.prefill_chunk,
+        }
+    }
+
+    /// The settings for one line of a --prompt-file.
+    fn batch_generation_config(&self, prompt: &BatchPrompt) -> GenerationConfig {
+        let mut config = self.generation_config();
+        config.max_new_tokens = prompt.max_tokens.unwrap_or(self.num_tokens);
+        config.stop_sequences.extend(prompt.stop.iter().cloned());
+        config
+    }
+
+    /// Check the settings clap can't, so mistakes fail before anything is
+    /// downloaded or loaded.
+    fn validate(&self) -> Result<()> {
+        self.generation_config()
+            .validate()
+            .map_err(|e| anyhow::anyhow!("Invalid sampling settings: {}", e))?;
+        let seconds = |value: f64, flag: &str| {
+            if !value.is_finite() || value < 0. {
+                bail!("{} must be a number of seconds of 0 or more, got {}", flag, value);
+            }
+            Ok(())
+        };
+        seconds(self.retry_backoff, "--retry-backoff")?;
+        if let Some(max_time) = self.max_total_time {
+            seconds(max_time, "--max-total-time")?;
+        }
+        if self.max_total_tokens_per_run == Some(0) {
+            bail!("--max-total-tokens-per-run 0 would stop before the first token");
+        }
+        if !self.cfg_scale.is_finite() || self.cfg_scale <= 0. {
+            bail!("--cfg-scale must be positive (1.0 = no guidance), got {}", self.cfg_scale);
+        }
+        if self.watermark_gamma <= 0. || self.watermark_gamma >= 1. {
+            bail!("--watermark-gamma must be between 0 and 1");
+        }
+        if !self.price_per_kwh.is_finite() || self.price_per_kwh < 0. {
+            bail!("--price-per-kwh must be 0 or more, got {}", self.price_per_kwh);
+        }
+        if !self.watermark_delta.is_finite() {
+            bail!("--watermark-delta must be a finite number");
+        }
+        for adapter in &self.lora {
+            adapter.parse::<LoraSpec>()?;
+        }
+        if self.stream_format == StreamFormat::Ndjson && self.prompt_file.is_some() {
+            bail!("--stream-format ndjson streams -p's output; --prompt-file writes its completions to --output-file");
+        }
+        if self.num_return_sequences == 0 {
+            bail!("--num-return-sequences must be at least 1");
+        }
+        if self.num_return_sequences > 1 {
+            if self.temperature == 0. {
+                bail!(
+                    "--num-return-sequences with temperature 0 would repeat the same greedy \
+                     completion; set a temperature above 0 to sample different ones"
+                );
+            }
+            if self.stream_format == StreamFormat::Ndjson {
+                bail!("--stream-format ndjson streams a single sequence; use --output json for --num-return-sequences");
+            }
+        }
+        Ok(())
+    }
+
+    /// The conversation given by --system, --message or --messages-file, if
+    /// any; with only --system, -p is the user's turn.
+    fn chat_messages(&self) -> Result<Option<Vec<Message>>> {
+        let mut messages = match &self.messages_file {
+            Some(path) => read_messages(path)?,
+            None => self
+                .messages
+                .iter()
+                .map(|spec| spec.parse())
+                .collect::<Result<Vec<Message>>>()?,
+        };
+        if messages.is_empty() {
+            if self.system.is_none() {
+                return Ok(None);
+            }
+            messages.push(Message::new("user", &self.prompt));
+        }
+        if let Some(system) = &self.system {
+            messages.insert(0, Message::new("system", system));
+        }
+        Ok(Some(messages))
+    }
+
+    /// Whether stdout carries only JSON: the --output json result, after the
+    /// tokens with --stream-format ndjson.
+    fn json_output(&self) -> bool {
+        self.output == OutputFormat::Json || self.stream_format == StreamFormat::Ndjson
+    }
+}
+
+/// Keep secrets out of printed configurations.
+fn redact<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    value.as_ref().map(|_| "<redacted>").serialize(serializer)
+}
+
+/// Every option as it takes effect: defaults and command-line flags, with
+/// settings that fall back to the environment filled in.
+fn resolved_config(args: &Args) -> Result<serde_json::Value> {
+    let mut config = serde_json::to_value(args)?;
+    if args.hub_endpoint.is_none() {
+        config["hub_endpoint"] = hub::default_endpoint().into();
+    }
+    if args.hf_token.is_none() && hub::default_token().is_some() {
+        config["hf_token"] = "<redacted>".into();
+    }
+    config["hf_cache"] = match &args.cache_dir {
+        Some(dir) => dir.display().to_string().into(),
+        None => hf_hub::Cache::default().path().display().to_string().into(),
+    };
+    Ok(config)
+}
+
+/// Run-wide limits, checked before every decoding step.
+struct Budget {
+    start: std::time::Instant,
+    max_time: Option<std::time::Duration>,
+    max_tokens: Option<usize>,
+}
+
+impl Budget {
+    /// Returns the limit that was hit, if any, with a description.
+    fn exceeded(&self, tokens_generated: usize) -> Option<(FinishReason, String)> {
+        if let Some(max_time) = self.max_time {
+            if self.start.elapsed() >= max_time {
+                return Some((
+                    FinishReason::Timeout,
+                    format!("--max-total-time of {:.2?} reached", max_time),
+                ));
+            }
+        }
+        match self.max_tokens {
+            Some(max_tokens) if tokens_generated >= max_tokens => Some((
+                FinishReason::Length,
+                format!("--max-total-tokens-per-run of {} reached", max_tokens),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// One line of --trace-file.
+#[derive(Serialize)]
+struct TraceLine<'a> {
+    /// Position among the generated tokens.
+    index: usize,
+    #[serde(flatten)]
+    trace: &'a TokenTrace,
+}
+
+/// One line of --stream-format ndjson.
+#[derive(Serialize)]
+struct StreamedToken<'a> {
+    /// The text the token adds to the output; empty while it is held back
+    /// (a partial character or a possible stop sequence) and then part of a
+    /// later token's text.
+    token: &'a str,
+    id: u32,
+    logprob: f32,
+}
+
+/// The --energy figures of a run, for --output json.
+#[derive(Serialize)]
+struct EnergyStats {
+    watt_hours: f64,
+    average_watts: f64,
+    gpus: usize,
+    cost_per_1k_tokens: Option<f64>,
+}
+
+/// The --output json result of generating from -p.
+#[derive(Serialize)]
+struct RunResult<'a> {
+    model_id: &'a str,
+    /// The attention implementation the model ran with.
+    attention: Attention,
+    /// The generated text (after --assistant-prefix), after --extract;
+    /// `None` if an extractor found nothing.
+    completion: Option<String>,
+    /// The generated text before --extract, when extractors are set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw_completion: Option<String>,
+    prompt_tokens: usize,
+    generated_tokens: usize,
+    finish_reason: &'static str,
+    /// The stop sequence that ended generation, with `stop_sequence`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequence: Option<String>,
+    /// The --max-total-* limit that cut the run short.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    budget_exceeded: Option<String>,
+    load_ms: f64,
+    tokenization_ms: f64,
+    /// Prompt processing and the first token.
+    time_to_first_token_ms: f64,
+    /// Prompt tokens processed (those not restored from a session) per
+    /// second of `time_to_first_token_ms`.
+    prompt_tokens_per_sec: f64,
+    /// Tokens per second of the steps after the first, and percentiles of
+    /// their latency; none when at most one token was generated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    decode_tokens_per_sec: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_latency_p50_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_latency_p95_ms: Option<f64>,
+    generation_ms: f64,
+    tokens_per_sec: f64,
+    /// Everything from start-up to the end of generation.
+    total_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<Vec<TokenLogprobs>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    perplexity: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    energy: Option<EnergyStats>,
+    sampling: GenerationConfig,
+    /// Every option as it took effect, as --dump-config prints it.
+    config: serde_json::Value,
+}
+
+/// The --output json result of a --prompt-file run; the completions
+/// themselves are in the --output-file.
+#[derive(Serialize)]
+struct BatchResult<'a> {
+    model_id: &'a str,
+    attention: Attention,
+    output_file: &'a Path,
+    prompts: usize,
+    completed: usize,
+    generated_tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    budget_exceeded: Option<String>,
+    /// Ctrl-C stopped the run before every prompt was completed.
+    interrupted: bool,
+    load_ms: f64,
+    generation_ms: f64,
+    tokens_per_sec: f64,
+    total_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    energy: Option<EnergyStats>,
+    /// The settings shared by the prompts, before per-line overrides.
+    sampling: GenerationConfig,
+    /// Every option as it took effect, as --dump-config prints it.
+    config: serde_json::Value,
+}
+
+/// The --output json result of a --num-return-sequences run.
+#[derive(Serialize)]
+struct SequencesResult<'a> {
+    model_id: &'a str,
+    attention: Attention,
+    /// One completion per sequence; sequence `index` was sampled with seed
+    /// `sampling.seed + index`.
+    sequences: Vec<BatchCompletion>,
+    prompt_tokens: usize,
+    generated_tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    budget_exceeded: Option<String>,
+    generation_ms: f64,
+    tokens_per_sec: f64,
+    total_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    energy: Option<EnergyStats>,
+    sampling: GenerationConfig,
+    /// Every option as it took effect, as --dump-config prints it.
+    config: serde_json::Value,
+}
+
+/// Tokens the --warmup generations stop after.
+const WARMUP_TOKENS: usize = 16;
+
+/// Decoding speed, from the time of each step after the first.
+struct DecodeStats {
+    tokens: usize,
+    time: std::time::Duration,
+    p50: std::time::Duration,
+    p95: std::time::Duration,
+}
+
+impl DecodeStats {
+    fn new(mut latencies: Vec<std::time::Duration>) -> Option<Self> {
+        if latencies.is_empty() {
+            return None;
+        }
+        latencies.sort_unstable();
+        // Nearest-rank percentiles.
+        let percentile = |p: usize| latencies[(latencies.len() * p).div_ceil(100) - 1];
+        Some(Self {
+            tokens: latencies.len(),
+            time: latencies.iter().sum(),
+            p50: percentile(50),
+            p95: percentile(95),
+        })
+    }
+
+    fn tokens_per_sec(&self) -> f64 {
+        self.tokens as f64 / self.time.as_secs_f64()
+    }
+}
+
+fn milliseconds(duration: std::time::Duration) -> f64 {
+    duration.as_micros() as f64 / 1e3
+}
+
+/// Print a --output json result.
+fn print_json(result: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string(result)?);
+    Ok(())
+}
+
+/// Parse an index selection such as `layer=16`, `layers=0,4,8-11`, `heads=0-3`
+/// or `all`, checking every index is below `count`.
+fn parse_indices(spec: &str, count: usize, what: &str) -> Result<Vec<usize>> {
+    let list = spec.split_once('=').map_or(spec, |(_, list)| list);
+    if list == "all" {
+        return Ok((0..count).collect());
+    }
+    let mut indices = Vec::new();
+    for part in list.split(',') {
+        let (lo, hi) = match part.split_once('-') {
+            Some((lo, hi)) => (lo.trim().parse::<usize>()?, hi.trim().parse::<usize>()?),
+            None => {
+                let layer = part.trim().parse::<usize>()?;
+                (layer, layer)
+            }
+        };
+        if lo > hi || hi >= count {
+            bail!("Invalid {} range '{}': model has {} {}", what, part, count, what);
+        }
+        indices.extend(lo..=hi);
+    }
+    indices.sort_unstable();
+    indices.dedup();
+    Ok(indices)
+}
+
+#[cfg(unix)]
+fn lock_memory() -> Result<()> {
+    if unsafe { libc::mlockall(libc::MCL_CURRENT) } != 0 {
+        bail!(
+            "mlockall failed: {} (check `ulimit -l` or CAP_IPC_LOCK)",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn lock_memory() -> Result<()> {
+    bail!("--mlock is only supported on Unix platforms")
+}
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+    // A second Ctrl-C ends the process right away.
+    unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+}
+
+/// Make Ctrl-C stop generation at the next token, so the output so far, its
+/// statistics and any --save-session still get written.
+#[cfg(unix)]
+fn catch_interrupt() {
+    let handler: extern "C" fn(libc::c_int) = on_interrupt;
+    unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+}
+
+#[cfg(not(unix))]
+fn catch_interrupt() {}
+
+/// Print the --energy statistics and write the --metrics-file for a run that
+/// generated `tokens` in `elapsed`. Returns the energy figures.
+fn report_run(
+    args: &Args,
+    tokens: usize,
+    elapsed: std::time::Duration,
+    energy: Option<PowerMonitor>,
+) -> Result<Option<EnergyStats>> {
+    let energy = match energy.map(PowerMonitor::stop).transpose() {
+        Ok(energy) => energy,
+        Err(e) => {
+            say!("Energy: not measured ({:#})", e);
+            None
+        }
+    };
+    let cost = energy
+        .as_ref()
+        .and_then(|energy| energy.cost_per_1k_tokens(tokens, args.price_per_kwh));
+    if let Some(energy) = &energy {
+        say!(
+            "Energy: {:.3} Wh (average {:.1} W over {} GPU{})",
+            energy.watt_hours(),
+            energy.average_watts(),
+            energy.gpus,
+            if energy.gpus == 1 { "" } else { "s" }
+        );
+        if let Some(cost) = cost {
+            say!(
+                "Cost: {:.5} per 1k tokens (at {} per kWh)",
+                cost, args.price_per_kwh
+            );
+        }
+    }
+
+    let stats = energy.as_ref().map(|energy: &EnergyReport| EnergyStats {
+        watt_hours: energy.watt_hours(),
+        average_watts: energy.average_watts(),
+        gpus: energy.gpus,
+        cost_per_1k_tokens: cost,
+    });
+
+    let Some(path) = &args.metrics_file else {
+        return Ok(stats);
+    };
+    let mut metrics = Metrics::new().with_label("model", &args.model_id);
+    metrics.counter(
+        "sl5_generated_tokens_total",
+        "Tokens generated in the run.",
+        tokens as f64,
+    );
+    metrics.gauge(
+        "sl5_generation_duration_seconds",
+        "Time spent generating.",
+        elapsed.as_secs_f64(),
+    );
+    metrics.gauge(
+        "sl5_tokens_per_second",
+        "Generation speed.",
+        tokens as f64 / elapsed.as_secs_f64(),
+    );
+    if let Some(energy) = &energy {
+        metrics.gauge(
+            "sl5_energy_watt_hours",
+            "Estimated GPU energy used while generating.",
+            energy.watt_hours(),
+        );
+        metrics.gauge(
+            "sl5_gpu_power_watts",
+            "Average total power draw of the GPUs while generating.",
+            energy.average_watts(),
+        );
+        if let Some(cost) = cost {
+            metrics.gauge(
+                "sl5_energy_cost_per_1k_tokens",
+                "Estimated electricity cost of 1000 generated tokens.",
+                cost,
+            );
+        }
+    }
+    metrics.write(path)?;
+    say!("Metrics written to {}", path.display());
+    Ok(stats)
+}
+
+fn run_prompt_file(
+    args: &Args,
+    model: &Model,
+    prompts: &[BatchPrompt],
+    extractors: &[Extractor],
+    watermark: Option<Watermark>,
+    budget: &Budget,
+    energy_gpus: &[usize],
+) -> Result<()> {
+    let output_path = args.output_file.as_ref().expect("required by --prompt-file");
+    let mut output = std::io::BufWriter::new(std::fs::File::create(output_path)?);
+    say!("Running {} prompts (one at a time)\n", prompts.len());
+
+    let monitor = args
+        .energy
+        .then(|| PowerMonitor::start(energy_gpus))
+        .transpose()?;
+    let start_run = std::time::Instant::now();
+    let (mut total_tokens, mut completed, mut budget_exceeded) = (0, 0, None);
+    catch_interrupt();
+    for (index, prompt) in prompts.iter().enumerate() {
+        let id = prompt
+            .id
+            .as_ref()
+            .map(|id| id.as_str().map_or_else(|| id.to_string(), str::to_string));
+        let _span = tracing::info_span!("request", index, id).entered();
+        let full_prompt = format!("{}{}", prompt.prompt, args.assistant_prefix.as_deref().unwrap_or(""));
+        let prompt_tokens = if prompt.is_chat() {
+            // The template has written the BOS token, if the model uses one.
+            tokenize(&model.tokenizer, &full_prompt, false)?.ids
+        } else {
+            encode_prompt(&model.tokenizer, &full_prompt, args.tokenize_chunk_size)?.0
+        };
+        let config = args.batch_generation_config(prompt);
+        let mut generator = Generator::new(model, config, prompt_tokens)?;
+        let prompt_len = generator.prompt_len();
+        let truncated = generator.truncated_prompt_tokens();
+        if let Some(watermark) = &watermark {
+            generator = generator.with_watermark(watermark.clone());
+        }
+        if let Some(top_n) = args.logprobs {
+            generator = generator.with_logprobs(top_n);
+        }
+
+        let start = std::time::Instant::now();
+        let mut first_token = None;
+        let mut completion = String::new();
+        let mut logprobs = args.logprobs.map(|_| Vec::new());
+        while generator.finish_reason().is_none() {
+            if let Some((reason, description)) =
+                budget.exceeded(total_tokens + generator.generated_tokens())
+            {
+                completion.push_str(&generator.abort(reason));
+                say!("[Generation aborted: {}]", description);
+                budget_exceeded = Some(description);
+                break;
+            }
+            let step = generator.step()?;
+            completion.push_str(&step.text);
+            if let (Some(logprobs), Some(token)) = (logprobs.as_mut(), step.logprobs) {
+                logprobs.push(token);
+            }
+            first_token.get_or_insert_with(|| start.elapsed());
+            if INTERRUPTED.load(Ordering::Relaxed) && generator.finish_reason().is_none() {
+                completion.push_str(&generator.abort(FinishReason::Cancelled));
+                say!("[Generation interrupted]");
+            }
+        }
+        let elapsed = start.elapsed();
+        let generated_tokens = generator.generated_tokens();
+        total_tokens += generated_tokens;
+        let (completion, raw_completion) = if extractors.is_empty() {
+            (Some(completion), None)
+        } else {
+            // The forced prefix is part of the answer (e.g. the `{` of a
+            // JSON object), so the extractors see it too.
+            let prefix = args.assistant_prefix.as_deref().unwrap_or("");
+            (extract(extractors, &format!("{}{}", prefix, completion)), Some(completion))
+        };
+
+        serde_json::to_writer(
+            &mut output,
+            &BatchCompletion {
+                index,
+                id: prompt.id.clone(),
+                completion,
+                raw_completion,
+                prompt_tokens: prompt_len,
+                truncated_prompt_tokens: (truncated > 0).then_some(truncated),
+                generated_tokens,
+                finish_reason: generator.finish_reason().map_or("length", FinishReason::as_str),
+                time_to_first_token_ms: milliseconds(first_token.unwrap_or_default()),
+                prompt_tokens_per_sec: prompt_len as f64
+                    / first_token.unwrap_or_default().as_secs_f64(),
+                total_ms: milliseconds(elapsed),
+                tokens_per_sec: generated_tokens as f64 / elapsed.as_secs_f64(),
+                logprobs,
+            },
+        )?;
+        writeln!(output)?;
+        output.flush()?;
+        completed += 1;
+        say!(
+            "[{}/{}] {} prompt + {} generated tokens in {:.2?}",
+            index + 1,
+            prompts.len(),
+            prompt_len,
+            generated_tokens,
+            elapsed
+        );
+        if budget_exceeded.is_some() || INTERRUPTED.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+
+    let elapsed = start_run.elapsed();
+    say!("\n=== Statistics ===");
+    say!("Completions written to {}", output_path.display());
+    say!("Tokens generated: {}", total_tokens);
+    say!("Time: {:.2?}", elapsed);
+    say!("Speed: {:.2} tokens/s", total_tokens as f64 / elapsed.as_secs_f64());
+    let energy = report_run(args, total_tokens, elapsed, monitor)?;
+    if args.json_output() {
+        print_json(&BatchResult {
+            model_id: &args.model_id,
+            attention: model.attention,
+            output_file: output_path,
+            prompts: prompts.len(),
+            completed,
+            generated_tokens: total_tokens,
+            budget_exceeded: budget_exceeded.clone(),
+            interrupted: INTERRUPTED.load(Ordering::Relaxed),
+            load_ms: milliseconds(start_run.duration_since(budget.start)),
+            generation_ms: milliseconds(elapsed),
+            tokens_per_sec: total_tokens as f64 / elapsed.as_secs_f64(),
+            total_ms: milliseconds(budget.start.elapsed()),
+            energy,
+            sampling: args.generation_config(),
+            config: resolved_config(args)?,
+        })?;
+    }
+    if budget_exceeded.is_some() {
+        say!("\n=== Inference Aborted (budget exceeded) ===\n");
+        std::io::stdout().flush()?;
+        logging::finish();
+        std::process::exit(BUDGET_EXCEEDED_EXIT_CODE);
+    }
+    if INTERRUPTED.load(Ordering::Relaxed) {
+        say!("\n=== Inference Interrupted ===\n");
+        std::io::stdout().flush()?;
+        logging::finish();
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    }
+    say!("\n=== Inference Complete ===\n");
+    Ok(())
+}
+
+/// Generate --num-return-sequences completions of `prompt` one after
+/// another, each from a fork of `first` sharing its prefilled prompt.
+fn run_sequences(
+    args: &Args,
+    model: &Model,
+    mut first: Generator,
+    prompt: &str,
+    extractors: &[Extractor],
+    budget: &Budget,
+    energy_gpus: &[usize],
+) -> Result<()> {
+    let count = args.num_return_sequences;
+    let prefix = args.assistant_prefix.as_deref().unwrap_or("");
+    let monitor = args
+        .energy
+        .then(|| PowerMonitor::start(energy_gpus))
+        .transpose()?;
+    let start_run = std::time::Instant::now();
+    let (mut total_tokens, mut sequences, mut budget_exceeded) = (0, Vec::new(), None);
+    catch_interrupt();
+    for index in 0..count {
+        let start = std::time::Instant::now();
+        // Forked as it's needed, so only one sequence's cache grows at a time.
+        let mut generator = first.fork(args.seed + index as u64)?;
+        say!("=== Sequence {} of {} ===\n{}", index + 1, count, prompt);
+        say_inline(prefix);
+        let mut first_token = None;
+        let mut completion = String::new();
+        let mut logprobs = args.logprobs.map(|_| Vec::new());
+        while generator.finish_reason().is_none() {
+            if let Some((reason, description)) =
+                budget.exceeded(total_tokens + generator.generated_tokens())
+            {
+                let text = generator.abort(reason);
+                say_inline(&text);
+                completion.push_str(&text);
+                say!("\n[Generation aborted: {}]", description);
+                budget_exceeded = Some(description);
+                break;
+            }
+            let step = generator.step()?;
+            first_token.get_or_insert_with(|| start.elapsed());
+            if let (Some(logprobs), Some(token)) = (logprobs.as_mut(), step.logprobs) {
+                logprobs.push(token);
+            }
+            say_inline(&step.text);
+            completion.push_str(&step.text);
+            std::io::stdout().flush()?;
+            if INTERRUPTED.load(Ordering::Relaxed) && generator.finish_reason().is_none() {
+                let text = generator.abort(FinishReason::Cancelled);
+                say_inline(&text);
+                completion.push_str(&text);
+                say!("\n[Generation interrupted]");
+            }
+        }
+        let elapsed = start.elapsed();
+        let generated_tokens = generator.generated_tokens();
+        total_tokens += generated_tokens;
+        let finish_reason = generator.finish_reason().map_or("length", FinishReason::as_str);
+        say!("\n[{} tokens, {}]\n", generated_tokens, finish_reason);
+        let (completion, raw_completion) = if extractors.is_empty() {
+            (Some(completion), None)
+        } else {
+            let extracted = extract(extractors, &format!("{}{}", prefix, completion));
+            match &extracted {
+                Some(extracted) => say!("=== Extracted ===\n{}\n", extracted),
+                None => say!("=== Extracted ===\n[--extract found nothing]\n"),
+            }
+            (extracted, Some(completion))
+        };
+        let prompt_len = generator.prompt_len();
+        let truncated = generator.truncated_prompt_tokens();
+        sequences.push(BatchCompletion {
+            index,
+            id: None,
+            completion,
+            raw_completion,
+            prompt_tokens: prompt_len,
+            truncated_prompt_tokens: (truncated > 0).then_some(truncated),
+            generated_tokens,
+            finish_reason,
+            time_to_first_token_ms: milliseconds(first_token.unwrap_or_default()),
+            prompt_tokens_per_sec: prompt_len as f64 / first_token.unwrap_or_default().as_secs_f64(),
+            total_ms: milliseconds(elapsed),
+            tokens_per_sec: generated_tokens as f64 / elapsed.as_secs_f64(),
+            logprobs,
+        });
+        if budget_exceeded.is_some() || INTERRUPTED.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+
+    let elapsed = start_run.elapsed();
+    say!("=== Statistics ===");
+    say!("Sequences: {} of {}", sequences.len(), count);
+    say!("Tokens generated: {}", total_tokens);
+    say!("Time: {:.2?}", elapsed);
+    say!("Speed: {:.2} tokens/s", total_tokens as f64 / elapsed.as_secs_f64());
+    let energy = report_run(args, total_tokens, elapsed, monitor)?;
+    if args.json_output() {
+        print_json(&SequencesResult {
+            model_id: &args.model_id,
+            attention: model.attention,
+            sequences,
+            prompt_tokens: first.prompt_len(),
+            generated_tokens: total_tokens,
+            budget_exceeded: budget_exceeded.clone(),
+            generation_ms: milliseconds(elapsed),
+            tokens_per_sec: total_tokens as f64 / elapsed.as_secs_f64(),
+            total_ms: milliseconds(budget.start.elapsed()),
+            energy,
+            sampling: args.generation_config(),
+            config: resolved_config(args)?,
+        })?;
+    }
+    if budget_exceeded.is_some() {
+        say!("\n=== Inference Aborted (budget exceeded) ===\n");
+        std::io::stdout().flush()?;
+        logging::finish();
+        std::process::exit(BUDGET_EXCEEDED_EXIT_CODE);
+    }
+    if INTERRUPTED.load(Ordering::Relaxed) {
+        say!("\n=== Inference Interrupted ===\n");
+        std::io::stdout().flush()?;
+        logging::finish();
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    }
+    say!("\n=== Inference Complete ===\n");
+    Ok(())
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let args = Args::parse_from(args_with_config(std::env::args_os().collect())?);
+    args.validate()?;
+    JSON_OUTPUT.store(args.json_output(), Ordering::Relaxed);
+    let _log_guard = logging::init(args.log_level, args.log_format, args.timeline_file.as_deref())?;
+    let budget = Budget {
+        start: std::time::Instant::now(),
+        max_time: args.max_total_time.map(std::time::Duration::from_secs_f64),
+        max_tokens: args.max_total_tokens_per_run,
+    };
+
+    if let Some(format) = args.dump_config {
+        print!("{}", format.render(&resolved_config(&args)?)?);
+        return Ok(());
+    }
+    if let Some(format) = args.print_config {
+        say!("=== Configuration ===");
+        say!("{}", format.render(&resolved_config(&args)?)?.trim_end());
+    }
 
-    println!("\n=== Basic LLM Inference with Candle ===\n");
-    println!("Model ID: {}", args.model_id);
-    println!("Prompt: \"{}\"", args.prompt);
-    println!("Tokens to generate: {}", args.num_tokens);
-    println!("Device: {}", if args.cpu { "CPU" } else { "GPU (CUDA)" });
-    println!("Temperature: {}", args.temperature);
-    println!();
+    say!("\n=== Basic LLM Inference with Candle ===\n");
+    say!("Model ID: {}", args.model_id);
+    let chat_messages = args.chat_messages()?;
+    match (&args.prompt_file, &chat_messages) {
+        (Some(path), _) => say!("Prompt file: {}", path.display()),
+        (None, Some(messages)) => say!("Conversation: {} messages", messages.len()),
+        (None, None) => say!("Prompt: \"{}\"", args.prompt),
+    }
+    if let Some(prefix) = &args.assistant_prefix {
+        say!("Assistant prefix: \"{}\"", prefix);
+    }
+    say!("Tokens to generate: {}", args.num_tokens);
+    say!("Device: {}", if args.cpu { "CPU" } else { "GPU (CUDA)" });
+    say!("Temperature: {}", args.temperature);
+    say!();
+
+    // Read the prompts up front so a malformed file fails before loading.
+    let mut batch_prompts = args.prompt_file.as_deref().map(read_prompts).transpose()?;
+    for (index, prompt) in batch_prompts.iter().flatten().enumerate() {
+        args.batch_generation_config(prompt)
+            .validate()
+            .map_err(|e| anyhow::anyhow!("Prompt {} of the prompt file: {}", index, e))?;
+    }
+    let extractors = args
+        .extract
+        .iter()
+        .map(|spec| spec.parse())
+        .collect::<Result<Vec<Extractor>>>()?;
 
     // Set up device
     let device = if args.cpu {
@@ -106,7 +1484,7 @@ fn main() -> Result<()> {
     } else {
         Device::cuda_if_available(0)?
     };
-    println!("Using device: {:?}\n", device);
+    say!("Using device: {:?}\n", device);
 
     // Parse dtype
     let dtype = match args.dtype.as_str() {
@@ -116,183 +1494,648 @@ fn main() -> Result<()> {
         dtype => bail!("Unsupported dtype: {}", dtype),
     };
 
-    // Load model files (from local directory or HuggingFace Hub)
-    let (tokenizer_filename, config_filename, weights_filename) = if args.local {
-        println!("Loading model from local directory: {}", args.model_id);
-        let model_dir = PathBuf::from(&args.model_id);
-        
-        let tokenizer = model_dir.join("tokenizer.json");
-        let config = model_dir.join("config.json");
-        let weights = if model_dir.join("model.safetensors").exists() {
-            model_dir.join("model.safetensors")
-        } else if model_dir.join("model-00001-of-00002.safetensors").exists() {
-            // Handle sharded models - we'll need to adjust VarBuilder later
-            bail!("Sharded models not yet supported in this script. Please use a single safetensors file.");
-        } else {
-            bail!("No model.safetensors found in {}", args.model_id);
+    // Load model files (from local directory, object storage or HuggingFace Hub)
+    let loader = ModelLoader::new(args.model_id.clone())
+        .local(args.local)
+        .revision(args.revision.clone())
+        .hub_endpoint(args.hub_endpoint.clone())
+        .hf_token(args.hf_token.clone())
+        .cache_dir(args.cache_dir.clone())
+        .quantized(args.quantized.clone())
+        .arch(args.arch)
+        .lora(args.lora.clone())
+        .quantize(args.quantize)
+        .kv_cache_dtype(args.kv_cache_dtype)
+        .flash_attn(args.flash_attn)
+        .retry(RetryPolicy {
+            max_retries: args.download_retries,
+            initial_backoff: std::time::Duration::from_secs_f64(args.retry_backoff),
+            max_backoff: std::time::Duration::from_secs(60),
+        });
+    let files = loader.files()?;
+    for lora in &files.lora {
+        say!("LoRA adapter: {} x {} (merged on load)", lora.weights.display(), lora.weight);
+    }
+    if !files.lora.is_empty() {
+        say!();
+    }
+    if let Some(quantize) = files.quantize {
+        say!("Quantization: {:?} (applied on load)\n", quantize);
+    }
+    if let Some(dtype) = files.kv_cache_dtype {
+        say!("KV cache: {:?}\n", dtype);
+    }
+
+    // Load tokenizer
+    say!("Loading tokenizer...");
+    let tokenizer = files.load_tokenizer()?;
+    say!("Tokenizer loaded!\n");
+
+    // Render the prompt file's conversations now so a template error stops
+    // the run before the weights load.
+    if let Some(prompts) = batch_prompts.as_mut().filter(|p| p.iter().any(BatchPrompt::is_chat)) {
+        let Some(template) = ChatTemplate::for_model(&loader)? else {
+            bail!("{} has no chat template for the prompt file's messages", args.model_id);
         };
-        
-        if !tokenizer.exists() || !config.exists() || !weights.exists() {
-            bail!(
-                "Missing required files in {}. Need: tokenizer.json, config.json, and model.safetensors",
-                args.model_id
-            );
+        for (index, prompt) in prompts.iter_mut().enumerate() {
+            prompt
+                .render_messages(&template)
+                .map_err(|e| anyhow::anyhow!("Prompt {} of the prompt file: {:#}", index, e))?;
         }
-        
-        println!("Found local model files!\n");
-        (tokenizer, config, weights)
-    } else {
-        println!("Downloading model files from HuggingFace Hub...");
-        let api = Api::new()?;
-        let repo = api.repo(Repo::with_revision(
-            args.model_id.clone(),
-            RepoType::Model,
-            args.revision.unwrap_or("main".to_string()),
-        ));
-
-        let tokenizer = repo.get("tokenizer.json")?;
-        let config = repo.get("config.json")?;
-        let weights = repo.get("model.safetensors").or_else(|_| {
-            println!("model.safetensors not found, trying pytorch_model.bin...");
-            repo.get("pytorch_model.bin")
-        })?;
+        say!("Rendered conversations with the model's chat template\n");
+    }
+    let mut prompt = match &chat_messages {
+        Some(messages) => {
+            let Some(template) = ChatTemplate::for_model(&loader)? else {
+                bail!("{} has no chat template for --system/--message", args.model_id);
+            };
+            let prompt = template.render(messages, true)?;
+            say!("Rendered the conversation with the model's chat template\n");
+            prompt
+        }
+        None => args.prompt.clone(),
+    };
+
+    // Load config
+    say!("Loading model config...");
+    let config = files.load_config()?;
+    loader::check_vocab(&tokenizer, &config)?;
+    let arch = files.arch()?;
+    say!("Config loaded!");
+    say!("  - Architecture: {}", arch);
+    say!("  - Hidden size: {}", config.hidden_size);
+    say!("  - Layers: {}", config.num_hidden_layers);
+    say!("  - Vocab size: {}\n", config.vocab_size);
 
-        println!("Model files downloaded successfully!\n");
-        (tokenizer, config, weights)
+    let device_map = match (args.device_map.as_deref(), &args.max_mem) {
+        (None, None) => DeviceMap::single(device.clone(), config.num_hidden_layers),
+        (Some(spec), None) if spec != "auto" => DeviceMap::parse(spec, config.num_hidden_layers)?,
+        (Some(spec), Some(_)) if spec != "auto" => {
+            bail!("--max-mem chooses the split itself; use it with --device-map auto or alone")
+        }
+        (_, Some(caps)) => DeviceMap::proportional(&parse_memory_caps(caps)?, &config, dtype)?,
+        (_, None) => {
+            // Leave a tenth of the free memory for the KV cache and activations.
+            let budgets: Vec<_> = free_cuda_memory()?
+                .into_iter()
+                .map(|(device, free)| (device, free / 10 * 9))
+                .collect();
+            DeviceMap::proportional(&budgets, &config, dtype)?
+        }
     };
+    if args.device_map.is_some() || args.max_mem.is_some() {
+        say!("Device map: {}\n", device_map);
+    }
+    let energy_gpus = device_map.cuda_ordinals();
+    if args.energy && energy_gpus.is_empty() {
+        bail!("--energy measures GPU power draw, but the model runs on the CPU");
+    }
 
-    // Load tokenizer
-    println!("Loading tokenizer...");
-    let tokenizer = Tokenizer::from_file(tokenizer_filename)
-        .map_err(|e| anyhow::anyhow!("Failed to load tokenizer: {}", e))?;
-    println!("Tokenizer loaded!\n");
+    // Limits that depend on the model, checked before its weights load.
+    args.generation_config()
+        .validate_for_context(config.max_position_embeddings)
+        .map_err(|e| anyhow::anyhow!("Invalid sampling settings: {}", e))?;
+    for prompt in batch_prompts.iter().flatten() {
+        args.batch_generation_config(prompt)
+            .validate_for_context(config.max_position_embeddings)?;
+    }
+    // Conversations that don't fit lose their oldest turns whole; the
+    // generator then cuts whatever is still too long token by token.
+    if args.context_overflow == ContextOverflow::TruncateMiddle {
+        if let Some(template) = ChatTemplate::for_model(&loader)? {
+            if let Some(messages) = &chat_messages {
+                let room = args.generation_config().prompt_room(config.max_position_embeddings);
+                let (fitted, dropped) = template.render_within(messages, &tokenizer, room)?;
+                if dropped > 0 {
+                    say!("Dropped the {} oldest messages to fit the context window\n", dropped);
+                    prompt = fitted;
+                }
+            }
+            for prompt in batch_prompts.iter_mut().flatten() {
+                let room = args
+                    .batch_generation_config(prompt)
+                    .prompt_room(config.max_position_embeddings);
+                prompt.render_messages_within(&template, &tokenizer, room)?;
+            }
+        }
+    }
+    let watermark = args
+        .watermark_key
+        .as_deref()
+        .map(|key| Watermark::new(key, args.watermark_gamma, args.watermark_delta));
 
-    // Load config
-    println!("Loading model config...");
-    let config_json: serde_json::Value = serde_json::from_slice(&std::fs::read(config_filename)?)?;
-    
-    // Build Config manually from JSON
-    let config = Config {
-        hidden_size: config_json["hidden_size"].as_u64().unwrap_or(4096) as usize,
-        intermediate_size: config_json["intermediate_size"].as_u64().unwrap_or(11008) as usize,
-        vocab_size: config_json["vocab_size"].as_u64().unwrap_or(32000) as usize,
-        num_hidden_layers: config_json["num_hidden_layers"].as_u64().unwrap_or(32) as usize,
-        num_attention_heads: config_json["num_attention_heads"].as_u64().unwrap_or(32) as usize,
-        num_key_value_heads: config_json["num_key_value_heads"]
-            .as_u64()
-            .or_else(|| config_json["num_attention_heads"].as_u64())
-            .unwrap_or(32) as usize,
-        rms_norm_eps: config_json["rms_norm_eps"].as_f64().unwrap_or(1e-5),
-        rope_theta: config_json["rope_theta"].as_f64().unwrap_or(10000.0) as f32,
-        use_flash_attn: false, // Set to false for compatibility
+    if !arch.is_llama_like() {
+        if args.check_weights {
+            bail!("--check-weights only knows the Llama tensor layout, not {}", arch);
+        }
+        if args.dump_hidden_states.is_some() || args.dump_attention.is_some() {
+            bail!("Activation dumps are only supported for Llama models, not {}", arch);
+        }
+    }
+
+    if args.check_weights {
+        say!("Checking weights...");
+        let start = std::time::Instant::now();
+        let expected = files.loaded_tensors(&config)?;
+        let report = check_weights(&files.weights, &expected)?;
+        say!(
+            "Checked {} tensors ({:.1}M parameters) in {:.2?}",
+            report.checked,
+            report.parameters as f64 / 1e6,
+            start.elapsed()
+        );
+        for issue in &report.issues {
+            say!("  - {}", issue);
+        }
+        if !report.issues.is_empty() {
+            bail!("Weight check failed with {} problem(s)", report.issues.len());
+        }
+        say!("All weights OK\n");
+    }
+
+    if args.warmup_weights {
+        say!("Prefetching weight files...");
+        let start = std::time::Instant::now();
+        let bytes = files.prefetch()?;
+        say!(
+            "Prefetched {:.2} GiB in {:.2?}\n",
+            bytes as f64 / (1u64 << 30) as f64,
+            start.elapsed()
+        );
+    }
+
+    // Flash attention falls back to the standard kernels rather than failing.
+    let attention = files.attention(dtype, &device_map);
+    if args.flash_attn == FlashAttn::On {
+        if let Some(reason) = files.flash_attn_unsupported(dtype, &device_map) {
+            tracing::warn!("Flash attention unavailable ({}); using standard attention", reason);
+        }
+    }
+    say!("Attention: {}\n", attention);
+
+    // The longest sequence the run can reach, and the most tokens one
+    // forward pass feeds.
+    let mut longest = (0, 0);
+    let mut measure = |text: &str, max_new_tokens: usize| -> Result<()> {
+        let tokens = tokenize(&tokenizer, text, false)?.ids.len();
+        longest.0 = longest.0.max(tokens + max_new_tokens);
+        longest.1 = longest.1.max(args.prefill_chunk.map_or(tokens, |n| n.min(tokens)));
+        Ok(())
+    };
+    match &batch_prompts {
+        Some(prompts) => {
+            for prompt in prompts {
+                measure(&prompt.prompt, prompt.max_tokens.unwrap_or(args.num_tokens))?;
+            }
+        }
+        None => measure(
+            &format!("{}{}", prompt, args.assistant_prefix.as_deref().unwrap_or("")),
+            args.num_tokens,
+        )?,
+    }
+    let positions = longest.0.min(config.max_position_embeddings);
+    let footprints = memory::estimate(
+        &files,
+        &config,
+        dtype,
+        &device_map,
+        positions,
+        longest.1.max(1),
+        !args.no_kv_cache,
+    )?;
+    say!(
+        "Estimated memory for {} positions: {}\n",
+        positions,
+        footprints
+            .iter()
+            .map(|f| format!("{} on {:?}", format_size(f.total()), f.device.location()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let fits = match args.no_memory_check {
+        true => Ok(()),
+        false => memory::check(&footprints),
     };
-    
-    println!("Config loaded!");
-    println!("  - Hidden size: {}", config.hidden_size);
-    println!("  - Layers: {}", config.num_hidden_layers);
-    println!("  - Vocab size: {}\n", config.vocab_size);
+    if let Err(err) = fits {
+        // The library's breakdown, then the options that change it.
+        let mut message = err.to_string();
+        message.push_str(
+            "\nTo make it fit:\n  \
+             - quantize the weights as they load (--quantize q8_0 or q4_0) or run a \
+             GGUF model (--quantized)\n  \
+             - generate fewer tokens (-n), or feed a long prompt in pieces \
+             (--prefill-chunk 512)",
+        );
+        if !args.no_kv_cache && files.kv_cache_dtype != Some(KvCacheDtype::Q8_0) {
+            message.push_str("\n  - keep the KV cache in 8 bits (--kv-cache-dtype q8_0)");
+        }
+        if footprints.iter().any(|f| f.device.is_cuda()) {
+            message.push_str(
+                "\n  - spread the layers over more GPUs (--device-map auto) or run on \
+                 the CPU (--cpu)",
+            );
+        }
+        message.push_str("\nPass --no-memory-check to load it anyway.");
+        return Err(match err.downcast_ref::<Sl5Error>() {
+            Some(cause) => anyhow::Error::new(cause.clone()).context(message),
+            None => err.context(message),
+        });
+    }
 
     // Load model weights
-    println!("Loading model weights...");
-    let vb = unsafe {
-        VarBuilder::from_mmaped_safetensors(&[weights_filename], dtype, &device)?
-    };
+    say!("Loading model weights...");
+    let weights = files.load_weights(&config, dtype, &device_map)?;
+    let model = Model::new(weights, tokenizer, config, dtype, device_map.first_device().clone())
+        .with_attention(attention);
+    say!("Model loaded successfully!\n");
 
-    let mut cache = model::Cache::new(!args.no_kv_cache, dtype, &config, &device)?;
-    let llama = Llama::load(vb, &config)?;
-    println!("Model loaded successfully!\n");
+    if args.warmup_weights {
+        // Touch every layer once with a throwaway cache so the timed run
+        // starts from a warm state.
+        say!("Running warmup forward pass...");
+        let start = std::time::Instant::now();
+        let mut warmup_cache = model.new_cache(true)?;
+        model.forward_logits(&[0], 0, &mut warmup_cache)?;
+        say!("Warmup done in {:.2?}\n", start.elapsed());
+    }
+
+    if args.mlock {
+        lock_memory()?;
+        say!("Model memory locked (mlock)\n");
+    }
+
+    if let Some(prompts) = &batch_prompts {
+        return run_prompt_file(
+            &args,
+            &model,
+            prompts,
+            &extractors,
+            watermark,
+            &budget,
+            &energy_gpus,
+        );
+    }
 
     // Tokenize the prompt
-    println!("Tokenizing prompt...");
-    let tokens = tokenizer
-        .encode(args.prompt.clone(), true)
-        .map_err(|e| anyhow::anyhow!("Failed to encode prompt: {}", e))?;
-    let prompt_tokens = tokens.get_ids().to_vec();
-    println!("Tokenized into {} tokens\n", prompt_tokens.len());
-
-    // Convert tokens to tensor
-    let mut tokens_tensor = Tensor::new(prompt_tokens.as_slice(), &device)?.unsqueeze(0)?;
-
-    // Set up the sampler
-    let mut logits_processor = {
-        let sampling = if args.temperature <= 0. {
-            Sampling::ArgMax
-        } else {
-            match (args.top_k, args.top_p) {
-                (None, None) => Sampling::All { temperature: args.temperature },
-                (Some(k), None) => Sampling::TopK { k, temperature: args.temperature },
-                (None, Some(p)) => Sampling::TopP { p, temperature: args.temperature },
-                (Some(k), Some(p)) => Sampling::TopKThenTopP {
-                    k,
-                    p,
-                    temperature: args.temperature,
-                },
-            }
-        };
-        LogitsProcessor::from_sampling(args.seed, sampling)
+    say!("Tokenizing prompt...");
+    let start_encode = std::time::Instant::now();
+    // The forced prefix is tokenized together with the prompt so it merges
+    // the same way it would have if the model had produced it.
+    let full_prompt = match &args.assistant_prefix {
+        Some(prefix) => format!("{}{}", prompt, prefix),
+        None => prompt.clone(),
+    };
+    let (prompt_tokens, num_chunks) = if chat_messages.is_some() {
+        // The template has written the BOS token, if the model uses one.
+        (tokenize(&model.tokenizer, &full_prompt, false)?.ids, 1)
+    } else {
+        encode_prompt(&model.tokenizer, &full_prompt, args.tokenize_chunk_size)?
     };
+    let encode_time = start_encode.elapsed();
+    if num_chunks > 1 {
+        say!(
+            "Tokenized into {} tokens in {:.2?} ({} chunks in parallel)\n",
+            prompt_tokens.len(),
+            encode_time,
+            num_chunks
+        );
+    } else {
+        say!(
+            "Tokenized into {} tokens in {:.2?}\n",
+            prompt_tokens.len(),
+            encode_time
+        );
+    }
 
-    // Generate tokens
-    println!("=== Output ===\n{}", args.prompt);
-    std::io::stdout().flush()?;
+    if args.warmup > 0 {
+        say!("Running {} warmup generation(s)...", args.warmup);
+        let start = std::time::Instant::now();
+        let config = GenerationConfig {
+            max_new_tokens: args.num_tokens.min(WARMUP_TOKENS),
+            ..args.generation_config()
+        };
+        for _ in 0..args.warmup {
+            let mut warmup = Generator::new(&model, config.clone(), prompt_tokens.clone())?;
+            while warmup.finish_reason().is_none() {
+                warmup.step()?;
+            }
+        }
+        say!("Warmup done in {:.2?}\n", start.elapsed());
+    }
 
-    let start_gen = std::time::Instant::now();
-    let mut generated_tokens = 0usize;
-    let mut pos = 0;
+    let mut generator = Generator::new(&model, args.generation_config(), prompt_tokens)?;
+    let prompt_tokens = generator.tokens().to_vec();
+    if generator.truncated_prompt_tokens() > 0 {
+        say!(
+            "Dropped {} prompt tokens to fit the context window ({}), {} left\n",
+            generator.truncated_prompt_tokens(),
+            args.context_overflow.as_str(),
+            prompt_tokens.len()
+        );
+    }
+    let mut restored = 0;
+    if let Some(path) = &args.load_session {
+        let session = Session::load(path)?;
+        (generator, restored) = generator.with_session(&session)?;
+        say!(
+            "Session {}: reusing {} of {} prompt tokens\n",
+            path.display(),
+            restored,
+            prompt_tokens.len()
+        );
+    }
 
-    for index in 0..args.num_tokens {
-        let start_token = std::time::Instant::now();
+    if let Some(negative) = &args.negative_prompt {
+        // The forced answer prefix belongs to the continuation, so both
+        // contexts see it.
+        let prefix = args.assistant_prefix.as_deref().unwrap_or("");
+        let negative = format!("{}{}", negative, prefix);
+        let (mut negative_tokens, _) =
+            encode_prompt(&model.tokenizer, &negative, args.tokenize_chunk_size)?;
+        if negative_tokens.is_empty() {
+            negative_tokens = prompt_tokens[..1].to_vec();
+        }
+        say!(
+            "Negative prompt: {} tokens, CFG scale {}\n",
+            negative_tokens.len(),
+            args.cfg_scale
+        );
+        generator = generator.with_negative_prompt(negative_tokens, args.cfg_scale)?;
+    }
+    if let Some(watermark) = watermark {
+        generator = generator.with_watermark(watermark);
+    }
+    if args.dump_hidden_states.is_some() || args.dump_attention.is_some() {
+        let num_layers = model.config.num_hidden_layers;
+        let mut capture = Capture::default();
+        if let Some(spec) = &args.dump_hidden_states {
+            capture.hidden_layers = parse_indices(&spec[0], num_layers, "layers")?;
+        }
+        if let Some(spec) = &args.dump_attention {
+            capture.attention_layers = parse_indices(&spec[0], num_layers, "layers")?;
+        }
+        if let Some(spec) = &args.attention_heads {
+            capture.attention_heads =
+                Some(parse_indices(spec, model.config.num_attention_heads, "heads")?);
+        }
+        generator = generator.with_capture(capture, args.dump_generated_hidden_states);
+    }
 
-        // Forward pass through the model
-        let logits = llama.forward(&tokens_tensor, pos, &mut cache)?;
-        let logits = logits.squeeze(0)?.to_dtype(DType::F32)?;
+    let mut trace_file = match &args.trace_file {
+        Some(path) => {
+            generator = generator.with_trace();
+            Some(std::io::BufWriter::new(std::fs::File::create(path)?))
+        }
+        None => None,
+    };
+    let streamed_logprobs = args.stream_format == StreamFormat::Ndjson;
+    if let Some(top_n) = args.logprobs.or(streamed_logprobs.then_some(0)) {
+        generator = generator.with_logprobs(top_n);
+    }
+    let mut logprobs = Vec::new();
 
-        // Apply repeat penalty
-        let logits = if args.repeat_penalty == 1. {
-            logits
-        } else {
-            let start_at = prompt_tokens.len().saturating_sub(args.repeat_last_n);
-            candle_transformers::utils::apply_repeat_penalty(
-                &logits,
-                args.repeat_penalty,
-                &prompt_tokens[start_at..],
-            )?
-        };
+    if args.num_return_sequences > 1 {
+        return run_sequences(&args, &model, generator, &prompt, &extractors, &budget, &energy_gpus);
+    }
 
-        // Sample next token
-        let next_token = logits_processor.sample(&logits)?;
-        generated_tokens += 1;
+    // Generate tokens
+    say!("=== Output ===\n{}", prompt);
+    if let Some(prefix) = &args.assistant_prefix {
+        say_inline(prefix);
+    }
+    std::io::stdout().flush()?;
 
-        // Check for EOS token
-        if let Some(text) = tokenizer.decode(&[next_token], true).ok() {
-            if text == EOS_TOKEN || text.contains(EOS_TOKEN) {
-                println!("\n[End of generation]");
-                break;
-            }
-            print!("{}", text);
-            std::io::stdout().flush()?;
+    let monitor = args
+        .energy
+        .then(|| PowerMonitor::start(&energy_gpus))
+        .transpose()?;
+    let start_gen = std::time::Instant::now();
+    let mut first_token = None;
+    // The time of every step after the first, which also runs the prompt.
+    let mut token_latencies = Vec::new();
+    let mut budget_exceeded = None;
+    // Everything shown after the prompt, for --extract.
+    let mut output = args.assistant_prefix.clone().unwrap_or_default();
+    catch_interrupt();
+
+    while generator.finish_reason().is_none() {
+        if let Some((reason, description)) = budget.exceeded(generator.generated_tokens()) {
+            let text = generator.abort(reason);
+            say_inline(&text);
+            output.push_str(&text);
+            say!("\n[Generation aborted: {}]", description);
+            budget_exceeded = Some(description);
+            break;
         }
 
-        // Update for next iteration
-        pos += tokens_tensor.dim(1)?;
-        tokens_tensor = Tensor::new(&[next_token], &device)?.unsqueeze(0)?;
+        let start_token = std::time::Instant::now();
+        let step = generator.step()?;
+        first_token.get_or_insert_with(|| start_gen.elapsed());
+        if generator.generated_tokens() > 1 {
+            token_latencies.push(start_token.elapsed());
+        }
+        if let Some(path) = args.save_session.as_ref().filter(|_| generator.generated_tokens() == 1) {
+            generator.session()?.save(path)?;
+        }
+        if let (Some(file), Some(trace)) = (trace_file.as_mut(), &step.trace) {
+            let index = generator.generated_tokens() - 1;
+            serde_json::to_writer(&mut *file, &TraceLine { index, trace })?;
+            writeln!(file)?;
+        }
+        if let Some(token) = step.logprobs.as_ref().filter(|_| streamed_logprobs) {
+            let line = StreamedToken {
+                token: &step.text,
+                id: step.token,
+                logprob: token.sampled.logprob,
+            };
+            println!("{}", serde_json::to_string(&line)?);
+        }
+        logprobs.extend(step.logprobs.clone());
+        say_inline(&step.text);
+        output.push_str(&step.text);
+        std::io::stdout().flush()?;
+        if generator.finish_reason() == Some(&FinishReason::Eos) {
+            say!("\n[End of generation]");
+            break;
+        }
+        // Checked after the step so that the session holds the whole prompt.
+        if INTERRUPTED.load(Ordering::Relaxed) && generator.finish_reason().is_none() {
+            let text = generator.abort(FinishReason::Cancelled);
+            say_inline(&text);
+            output.push_str(&text);
+            say!("\n[Generation interrupted]");
+            break;
+        }
 
+        let index = generator.generated_tokens() - 1;
         let token_time = start_token.elapsed();
         if index % 10 == 0 && index > 0 {
             let tokens_per_sec = 10.0 / token_time.as_secs_f64();
-            println!(" [{:.2} tok/s]", tokens_per_sec);
+            say!(" [{:.2} tok/s]", tokens_per_sec);
         }
     }
 
     let elapsed = start_gen.elapsed();
-    println!("\n\n=== Statistics ===");
-    println!("Tokens generated: {}", generated_tokens);
-    println!("Time: {:.2?}", elapsed);
-    println!(
+    if let Some(mut file) = trace_file {
+        file.flush()?;
+    }
+    let extracted = (!extractors.is_empty()).then(|| extract(&extractors, &output));
+    match &extracted {
+        Some(Some(extracted)) => say!("\n\n=== Extracted ===\n{}", extracted),
+        Some(None) => say!("\n\n=== Extracted ===\n[--extract found nothing]"),
+        None => {}
+    }
+    if args.logprobs.is_some() {
+        say!("\n\n=== Logprobs ===");
+        say!("{}", serde_json::to_string(&logprobs)?);
+    }
+    let generated_tokens = generator.generated_tokens();
+    say!("\n\n=== Statistics ===");
+    say!("Tokens generated: {}", generated_tokens);
+    if let Some(reason) = generator.finish_reason() {
+        say!("Finish reason: {}", reason);
+    }
+    let mean_logprob = (!logprobs.is_empty()).then(|| {
+        let total: f64 = logprobs.iter().map(|token| token.sampled.logprob as f64).sum();
+        total / logprobs.len() as f64
+    });
+    if let Some(mean_logprob) = mean_logprob {
+        say!(
+            "Output perplexity: {:.4} (mean logprob {:.4})",
+            (-mean_logprob).exp(),
+            mean_logprob
+        );
+    }
+    say!("Tokenization time: {:.2?}", encode_time);
+    // The first step runs the prompt (minus any restored session) and
+    // samples one token; the rest of the time is decoding.
+    let prefilled = prompt_tokens.len() - restored;
+    let prefill_time = first_token.unwrap_or_default();
+    if generated_tokens > 0 {
+        say!(
+            "Prompt processing: {} tokens in {:.2?} ({:.2} tokens/s)",
+            prefilled,
+            prefill_time,
+            prefilled as f64 / prefill_time.as_secs_f64()
+        );
+    }
+    let decode = DecodeStats::new(token_latencies);
+    if let Some(decode) = &decode {
+        say!(
+            "Decoding: {} tokens in {:.2?} ({:.2} tokens/s)",
+            decode.tokens,
+            decode.time,
+            decode.tokens_per_sec()
+        );
+        say!(
+            "Per-token latency: p50 {:.2?}, p95 {:.2?}",
+            decode.p50,
+            decode.p95
+        );
+    }
+    if let Some(path) = args.save_session.as_ref().filter(|_| generated_tokens > 0) {
+        say!("Session saved to {}", path.display());
+    }
+    say!("Time: {:.2?}", elapsed);
+    say!(
         "Speed: {:.2} tokens/s",
         generated_tokens as f64 / elapsed.as_secs_f64()
     );
-    println!("\n=== Inference Complete ===\n");
+    let energy = report_run(&args, generated_tokens, elapsed, monitor)?;
+    if let Some(capture) = generator.take_capture() {
+        if let Some(spec) = &args.dump_hidden_states {
+            let mut per_layer: BTreeMap<usize, Vec<Tensor>> = BTreeMap::new();
+            for (layer, hidden) in capture.hidden_states {
+                per_layer.entry(layer).or_default().push(hidden);
+            }
+            let mut tensors = Vec::new();
+            let mut num_positions = 0;
+            for (layer, states) in per_layer {
+                let states = Tensor::cat(&states, 0)?;
+                num_positions = states.dim(0)?;
+                tensors.push((format!("layer_{layer}"), states));
+            }
+            // Token ids aligned with the rows of each layer tensor.
+            tensors.push((
+                "tokens".to_string(),
+                Tensor::new(&generator.tokens()[..num_positions], &Device::Cpu)?,
+            ));
+            let path = PathBuf::from(&spec[1]);
+            save_tensors(tensors, &path)?;
+            say!("Hidden states saved to {}", path.display());
+        }
+        if let Some(spec) = &args.dump_attention {
+            let mut tensors: Vec<(String, Tensor)> = capture
+                .attentions
+                .into_iter()
+                .map(|(layer, att)| (format!("layer_{layer}"), att))
+                .collect();
+            let heads: Vec<u32> = match capture.attention_heads {
+                Some(heads) => heads.into_iter().map(|h| h as u32).collect(),
+                None => (0..model.config.num_attention_heads as u32).collect(),
+            };
+            tensors.push(("heads".to_string(), Tensor::new(heads, &Device::Cpu)?));
+            tensors.push((
+                "tokens".to_string(),
+                Tensor::new(prompt_tokens.as_slice(), &Device::Cpu)?,
+            ));
+            let path = PathBuf::from(&spec[1]);
+            save_tensors(tensors, &path)?;
+            say!("Attention maps saved to {}", path.display());
+        }
+    }
+
+    if args.json_output() {
+        let raw_completion = output[args.assistant_prefix.as_deref().unwrap_or("").len()..].to_string();
+        let (completion, raw_completion) = match extracted {
+            Some(extracted) => (extracted, Some(raw_completion)),
+            None => (Some(raw_completion), None),
+        };
+        print_json(&RunResult {
+            model_id: &args.model_id,
+            attention: model.attention,
+            completion,
+            raw_completion,
+            prompt_tokens: prompt_tokens.len(),
+            generated_tokens,
+            finish_reason: generator.finish_reason().map_or("length", FinishReason::as_str),
+            stop_sequence: match generator.finish_reason() {
+                Some(FinishReason::StopSequence(stop)) => Some(stop.clone()),
+                _ => None,
+            },
+            budget_exceeded: budget_exceeded.clone(),
+            load_ms: milliseconds(start_encode.duration_since(budget.start)),
+            tokenization_ms: milliseconds(encode_time),
+            time_to_first_token_ms: milliseconds(prefill_time),
+            prompt_tokens_per_sec: prefilled as f64 / prefill_time.as_secs_f64(),
+            decode_tokens_per_sec: decode.as_ref().map(DecodeStats::tokens_per_sec),
+            token_latency_p50_ms: decode.as_ref().map(|decode| milliseconds(decode.p50)),
+            token_latency_p95_ms: decode.as_ref().map(|decode| milliseconds(decode.p95)),
+            generation_ms: milliseconds(elapsed),
+            tokens_per_sec: generated_tokens as f64 / elapsed.as_secs_f64(),
+            total_ms: milliseconds(budget.start.elapsed()),
+            logprobs: args.logprobs.map(|_| logprobs),
+            perplexity: mean_logprob.map(|mean_logprob| (-mean_logprob).exp()),
+            energy,
+            sampling: args.generation_config(),
+            config: resolved_config(&args)?,
+        })?;
+    }
+
+    if budget_exceeded.is_some() {
+        say!("\n=== Inference Aborted (budget exceeded) ===\n");
+        std::io::stdout().flush()?;
+        logging::finish();
+        std::process::exit(BUDGET_EXCEEDED_EXIT_CODE);
+    }
+    if generator.finish_reason() == Some(&FinishReason::Cancelled) {
+        say!("\n=== Inference Interrupted ===\n");
+        std::io::stdout().flush()?;
+        logging::finish();
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    }
+
+    say!("\n=== Inference Complete ===\n");
 
     Ok(())
 }
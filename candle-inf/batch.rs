@@ -0,0 +1,170 @@
+// Prompt files for offline batch generation.
+//
+// Each line of a prompt file is a JSON string, an array of chat messages, or
+// an object with a `prompt` or `messages` and, optionally, an `id` to carry
+// through to the output and per-prompt `max_tokens`/`stop` overrides.
+// Conversations are rendered with the model's chat template, so multi-turn
+// evaluation sets (MT-Bench style) run as they are. Every completion is
+// written as one JSON line with its token counts and timings, in input order.
+
+use crate::chat::{ChatTemplate, Message};
+use crate::generation::TokenLogprobs;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::BufRead;
+use std::path::Path;
+use tokenizers::Tokenizer;
+
+#[derive(Debug, Clone)]
+pub struct BatchPrompt {
+    /// Copied to the completion as-is (any JSON value).
+    pub id: Option<serde_json::Value>,
+    /// The text given to the model; for a conversation, set by
+    /// `render_messages`.
+    pub prompt: String,
+    /// The conversation to answer, if the line has one instead of a prompt.
+    pub messages: Option<Vec<Message>>,
+    /// Overrides the run's token limit for this prompt.
+    pub max_tokens: Option<usize>,
+    /// Added to the run's stop sequences for this prompt.
+    pub stop: Vec<String>,
+}
+
+impl BatchPrompt {
+    /// Whether the prompt is a conversation rendered with a chat template
+    /// (which writes any BOS token itself).
+    pub fn is_chat(&self) -> bool {
+        self.messages.is_some()
+    }
+
+    /// Render the conversation into `prompt`, ending with an opened
+    /// assistant turn for the model to complete.
+    pub fn render_messages(&mut self, template: &ChatTemplate) -> Result<()> {
+        if let Some(messages) = &self.messages {
+            self.prompt = template.render(messages, true)?;
+        }
+        Ok(())
+    }
+
+    /// `render_messages`, dropping the oldest turns of the conversation
+    /// until the prompt is at most `max_tokens` tokens long. Returns the
+    /// number of messages dropped.
+    pub fn render_messages_within(
+        &mut self,
+        template: &ChatTemplate,
+        tokenizer: &Tokenizer,
+        max_tokens: usize,
+    ) -> Result<usize> {
+        let Some(messages) = &self.messages else {
+            return Ok(0);
+        };
+        let (prompt, dropped) = template.render_within(messages, tokenizer, max_tokens)?;
+        self.prompt = prompt;
+        Ok(dropped)
+    }
+}
+
+#[derive(Deserialize)]
+struct PromptObject {
+    #[serde(default)]
+    id: Option<serde_json::Value>,
+    #[serde(default)]
+    prompt: Option<String>,
+    #[serde(default)]
+    messages: Option<Vec<Message>>,
+    #[serde(default)]
+    max_tokens: Option<usize>,
+    #[serde(default)]
+    stop: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PromptLine {
+    Text(String),
+    Messages(Vec<Message>),
+    Prompt(PromptObject),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchCompletion {
+    /// Line of the prompt in the prompt file (0-based, blank lines skipped).
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    /// The generated text, after --extract; `None` if an extractor found
+    /// nothing.
+    pub completion: Option<String>,
+    /// The generated text before --extract, when extractors are set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_completion: Option<String>,
+    pub prompt_tokens: usize,
+    /// Prompt tokens dropped to fit the context window, with
+    /// --context-overflow truncate-left/truncate-middle.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated_prompt_tokens: Option<usize>,
+    pub generated_tokens: usize,
+    pub finish_reason: &'static str,
+    /// Prompt processing and the first token.
+    pub time_to_first_token_ms: f64,
+    pub prompt_tokens_per_sec: f64,
+    pub total_ms: f64,
+    pub tokens_per_sec: f64,
+    /// Per-token log-probabilities, with --logprobs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Vec<TokenLogprobs>>,
+}
+
+/// Read the prompts in a JSONL prompt file, skipping blank lines.
+pub fn read_prompts(path: &Path) -> Result<Vec<BatchPrompt>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open prompt file {}", path.display()))?;
+    let mut prompts = Vec::new();
+    for (number, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let location = || format!("{} line {}", path.display(), number + 1);
+        let parsed = serde_json::from_str(&line).with_context(|| {
+            format!(
+                "{}: expected a JSON string, an array of {{\"role\", \"content\"}} messages \
+                 or an object with a \"prompt\" or \"messages\"",
+                location()
+            )
+        })?;
+        let object = match parsed {
+            PromptLine::Text(prompt) => PromptObject {
+                id: None,
+                prompt: Some(prompt),
+                messages: None,
+                max_tokens: None,
+                stop: Vec::new(),
+            },
+            PromptLine::Messages(messages) => PromptObject {
+                id: None,
+                prompt: None,
+                messages: Some(messages),
+                max_tokens: None,
+                stop: Vec::new(),
+            },
+            PromptLine::Prompt(object) => object,
+        };
+        let (prompt, messages) = match (object.prompt, object.messages) {
+            (Some(_), Some(_)) => bail!("{}: has both a \"prompt\" and \"messages\"", location()),
+            (None, None) => bail!("{}: has neither a \"prompt\" nor \"messages\"", location()),
+            (None, Some(messages)) if messages.is_empty() => {
+                bail!("{}: \"messages\" is empty", location())
+            }
+            (prompt, messages) => (prompt.unwrap_or_default(), messages),
+        };
+        prompts.push(BatchPrompt {
+            id: object.id,
+            prompt,
+            messages,
+            max_tokens: object.max_tokens,
+            stop: object.stop,
+        });
+    }
+    Ok(prompts)
+}
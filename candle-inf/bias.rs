@@ -0,0 +1,191 @@
+// Logit bias: fixed per-token adjustments and banned words.
+//
+// `logit_bias` adds a bias to chosen token ids before sampling, as in the
+// OpenAI API: positive values favour a token, large negative ones all but
+// forbid it and `-inf` forbids it outright. Banned words are turned into the
+// token sequences they are written with, alone and after a space; a word
+// that is a single token is forbidden everywhere, while the last token of a
+// longer one is only forbidden right after the tokens before it (like
+// transformers' `bad_words_ids`), so its pieces stay usable in other words.
+
+use crate::generation::GenerationConfig;
+use anyhow::{bail, Context, Result};
+use candle_core::{DType, Device, Tensor};
+use std::collections::BTreeMap;
+use tokenizers::Tokenizer;
+
+/// Parse `ID:BIAS,ID:BIAS,...`. A bias of `-inf` forbids the token.
+pub fn parse_logit_bias(spec: &str) -> Result<BTreeMap<u32, f32>> {
+    let mut biases = BTreeMap::new();
+    for entry in spec
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let Some((id, bias)) = entry.split_once(':') else {
+            bail!("Invalid logit bias '{}', expected TOKEN_ID:BIAS", entry);
+        };
+        let id: u32 = id
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid token id in logit bias '{}'", entry))?;
+        let bias: f32 = bias
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid bias in logit bias '{}'", entry))?;
+        if biases.insert(id, bias).is_some() {
+            bail!("Token id {} has more than one logit bias", id);
+        }
+    }
+    Ok(biases)
+}
+
+#[derive(Debug, Clone)]
+pub struct LogitBias {
+    /// The bias of every token, single-token banned words included.
+    dense: Tensor,
+    /// Banned words of several tokens.
+    sequences: Vec<Vec<u32>>,
+}
+
+impl LogitBias {
+    /// The bias `config` asks for over a `vocab_size`-token vocabulary, or
+    /// `None` if it has neither biases nor banned words.
+    pub fn new(
+        config: &GenerationConfig,
+        tokenizer: &Tokenizer,
+        vocab_size: usize,
+        device: &Device,
+    ) -> Result<Option<Self>> {
+        if config.logit_bias.is_empty() && config.ban_words.is_empty() {
+            return Ok(None);
+        }
+        let mut dense = vec![0f32; vocab_size];
+        for (&id, &bias) in &config.logit_bias {
+            let Some(value) = dense.get_mut(id as usize) else {
+                bail!(
+                    "Logit bias token id {} is outside the vocabulary ({} entries)",
+                    id,
+                    vocab_size
+                );
+            };
+            *value = bias;
+        }
+        let mut sequences: Vec<Vec<u32>> = Vec::new();
+        for word in &config.ban_words {
+            for ids in word_tokens(tokenizer, word)? {
+                match ids.as_slice() {
+                    [] => {}
+                    [id] => {
+                        if let Some(value) = dense.get_mut(*id as usize) {
+                            *value = f32::NEG_INFINITY;
+                        }
+                    }
+                    _ if !sequences.contains(&ids) => sequences.push(ids),
+                    _ => {}
+                }
+            }
+        }
+        Ok(Some(Self {
+            dense: Tensor::new(dense, device)?,
+            sequences,
+        }))
+    }
+
+    /// Add the bias to `logits` (a 1-d tensor over the vocabulary) for the
+    /// token following `tokens`.
+    pub fn apply(&self, logits: &Tensor, tokens: &[u32]) -> Result<Tensor> {
+        let logits = logits
+            .to_dtype(DType::F32)?
+            .broadcast_add(&self.dense.to_device(logits.device())?)?;
+        let banned: Vec<u32> = self
+            .sequences
+            .iter()
+            .filter_map(|sequence| {
+                let (last, prefix) = sequence.split_last()?;
+                tokens.ends_with(prefix).then_some(*last)
+            })
+            .collect();
+        if banned.is_empty() {
+            return Ok(logits);
+        }
+        let mut values: Vec<f32> = logits.to_vec1()?;
+        for token in banned {
+            if let Some(value) = values.get_mut(token as usize) {
+                *value = f32::NEG_INFINITY;
+            }
+        }
+        Ok(Tensor::new(values, logits.device())?)
+    }
+}
+
+/// The tokens `word` is written with at the start of a text and after a
+/// space.
+fn word_tokens(tokenizer: &Tokenizer, word: &str) -> Result<Vec<Vec<u32>>> {
+    let encode = |text: &str| -> Result<Vec<u32>> {
+        Ok(tokenizer
+            .encode(text, false)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec())
+    };
+    let mut variants = vec![encode(word)?];
+    // Encoded after a letter so that the word gets the tokens it has in
+    // running text.
+    let prefix = encode("a")?;
+    let spaced = encode(&format!("a {word}"))?;
+    if let Some(ids) = spaced.strip_prefix(prefix.as_slice()) {
+        if !variants.iter().any(|variant| variant == ids) {
+            variants.push(ids.to_vec());
+        }
+    }
+    Ok(variants)
+}
+
+/// Serialize a logit bias as a map from token id to bias, with `-inf` as a
+/// string since JSON has no infinity.
+pub(crate) fn serialize_logit_bias<S: serde::Serializer>(
+    logit_bias: &BTreeMap<u32, f32>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_map(logit_bias.iter().map(|(id, &bias)| {
+        let value = match bias.is_finite() {
+            true => serde_json::Value::from(bias),
+            false => bias.to_string().into(),
+        };
+        (id, value)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(spec: &str) -> String {
+        format!("{:#}", parse_logit_bias(spec).unwrap_err())
+    }
+
+    #[test]
+    fn parses_biases() {
+        let biases = parse_logit_bias(" 5:1.5, 7:-inf,12 : -100,").unwrap();
+        assert_eq!(
+            biases,
+            BTreeMap::from([(5, 1.5), (7, f32::NEG_INFINITY), (12, -100.)])
+        );
+        assert!(parse_logit_bias("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_ids() {
+        assert!(parse_error("x:1").starts_with("Invalid token id in logit bias 'x:1'"));
+        assert!(parse_error("-3:1").starts_with("Invalid token id in logit bias '-3:1'"));
+        assert!(parse_error("5:1,5:2").contains("Token id 5 has more than one logit bias"));
+    }
+
+    #[test]
+    fn rejects_bad_biases() {
+        assert!(parse_error("5").contains("expected TOKEN_ID:BIAS"));
+        assert!(parse_error("5:").starts_with("Invalid bias in logit bias '5:'"));
+        assert!(parse_error("5:lots").starts_with("Invalid bias in logit bias '5:lots'"));
+    }
+}
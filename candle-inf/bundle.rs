@@ -0,0 +1,339 @@
+// Run bundles: a base-inf run packaged so that someone else can audit it and
+// run it again.
+//
+// A bundle is a zip archive holding `bundle.json` (the base-inf arguments,
+// the resolved configuration, SHA-256 hashes of the model files and chat
+// template, and a snapshot of the machine that created it) and the run's
+// input files. The model itself isn't included: running a bundle hashes the
+// model files found for its configuration (local directory, Hugging Face
+// cache or object storage) and refuses to run if any differ from the ones
+// the bundle was created with.
+
+use crate::arch::Arch;
+use crate::chat::ChatTemplate;
+use crate::loader::{ModelLoader, Quantization};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The manifest's name inside the archive.
+pub const MANIFEST: &str = "bundle.json";
+
+/// The base-inf options whose values are input files, copied into bundles.
+pub const INPUT_OPTIONS: [&str; 4] = ["config", "prompt_file", "messages_file", "load_session"];
+
+/// Environment variables recorded in the snapshot; anything holding
+/// credentials is left out.
+const RECORDED_ENV: [&str; 5] = [
+    "CUDA_VISIBLE_DEVICES",
+    "HF_ENDPOINT",
+    "HF_HOME",
+    "HF_HUB_OFFLINE",
+    "RAYON_NUM_THREADS",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    /// The base-inf arguments, without --hf-token.
+    pub args: Vec<String>,
+    /// base-inf's resolved configuration for `args` (--dump-config).
+    pub config: Value,
+    pub seed: Option<u64>,
+    /// The model files (tokenizer, config, weights, adapters) and the chat
+    /// template the run used.
+    pub model: Vec<FileHash>,
+    pub inputs: Vec<BundledFile>,
+    pub environment: Environment,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileHash {
+    /// What the file is, e.g. `weights` or `lora 0 weights`.
+    pub name: String,
+    /// Where it was found when the bundle was created.
+    pub path: String,
+    pub sha256: String,
+    pub bytes: u64,
+}
+
+/// An input file stored in the archive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundledFile {
+    /// The base-inf option it was given to, e.g. `prompt_file`.
+    pub option: String,
+    /// The path as given on the command line.
+    pub original: String,
+    /// Its name inside the archive.
+    pub entry: String,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Environment {
+    pub version: String,
+    pub os: String,
+    pub arch: String,
+    pub cpus: usize,
+    /// Cargo features the binaries were built with (cuda, mkl, ...).
+    pub features: Vec<String>,
+    /// `nvidia-smi` GPU names and driver versions, if it is available.
+    pub gpus: Vec<String>,
+    pub env: BTreeMap<String, String>,
+}
+
+impl Environment {
+    pub fn snapshot() -> Self {
+        let features = [
+            ("cuda", cfg!(feature = "cuda")),
+            ("accelerate", cfg!(feature = "accelerate")),
+            ("mkl", cfg!(feature = "mkl")),
+            ("flash-attn", cfg!(feature = "flash-attn")),
+        ];
+        let gpus = std::process::Command::new("nvidia-smi")
+            .args(["--query-gpu=name,driver_version", "--format=csv,noheader"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| {
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .map(|line| line.trim().to_string())
+                    .filter(|line| !line.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            cpus: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            features: features
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| name.to_string())
+                .collect(),
+            gpus,
+            env: RECORDED_ENV
+                .iter()
+                .filter_map(|name| Some((name.to_string(), std::env::var(name).ok()?)))
+                .collect(),
+        }
+    }
+}
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The loader for the model a base-inf configuration runs.
+pub fn model_loader(config: &Value) -> Result<ModelLoader> {
+    let string = |key: &str| config[key].as_str().map(str::to_string);
+    let Some(model_id) = string("model_id") else {
+        bail!("The configuration has no model_id");
+    };
+    let arch = match config["arch"].as_str() {
+        Some(arch) => Some(Arch::from_str(arch, true).map_err(anyhow::Error::msg)?),
+        None => None,
+    };
+    let quantize = match config["quantize"].as_str() {
+        Some(quantize) => Some(Quantization::from_str(quantize, true).map_err(anyhow::Error::msg)?),
+        None => None,
+    };
+    let lora = config["lora"]
+        .as_array()
+        .map(|adapters| {
+            adapters
+                .iter()
+                .filter_map(|a| a.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(ModelLoader::new(model_id)
+        .local(config["local"].as_bool().unwrap_or(false))
+        .revision(string("revision"))
+        .hub_endpoint(string("hub_endpoint"))
+        .cache_dir(string("cache_dir").map(PathBuf::from))
+        .quantized(string("quantized"))
+        .arch(arch)
+        .lora(lora)
+        .quantize(quantize))
+}
+
+/// Hash the model files `loader` finds, downloading them if needed, and its
+/// chat template. Returns the template too, to store in the bundle.
+pub fn model_hashes(loader: &ModelLoader) -> Result<(Vec<FileHash>, Option<ChatTemplate>)> {
+    let files = loader.files()?;
+    let mut named = vec![("tokenizer".to_string(), files.tokenizer.clone())];
+    if let Some(config) = &files.config {
+        named.push(("config".to_string(), config.clone()));
+    }
+    if let Some(config) = &files.quantize_config {
+        named.push(("quantize config".to_string(), config.clone()));
+    }
+    named.push(("weights".to_string(), files.weights.clone()));
+    for (i, lora) in files.lora.iter().enumerate() {
+        named.push((format!("lora {i} config"), lora.config.clone()));
+        named.push((format!("lora {i} weights"), lora.weights.clone()));
+    }
+    let mut hashes = Vec::new();
+    for (name, path) in named {
+        hashes.push(FileHash {
+            sha256: sha256_file(&path)?,
+            bytes: std::fs::metadata(&path)?.len(),
+            path: path.display().to_string(),
+            name,
+        });
+    }
+    let template = ChatTemplate::for_model(loader)?;
+    if let Some(template) = &template {
+        hashes.push(FileHash {
+            name: "chat template".to_string(),
+            path: "chat_template.jinja".to_string(),
+            sha256: hex(&Sha256::digest(template.source.as_bytes())),
+            bytes: template.source.len() as u64,
+        });
+    }
+    Ok((hashes, template))
+}
+
+/// Write the archive: the manifest, the input files and the chat template.
+pub fn write(
+    path: &Path,
+    manifest: &Manifest,
+    inputs: &[(String, PathBuf)],
+    template: Option<&ChatTemplate>,
+) -> Result<()> {
+    let mut zip = zip::ZipWriter::new(
+        std::fs::File::create(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?,
+    );
+    let options = zip::write::FileOptions::<()>::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+    zip.start_file(MANIFEST, options)?;
+    zip.write_all(serde_json::to_string_pretty(manifest)?.as_bytes())?;
+    for (entry, source) in inputs {
+        zip.start_file(entry.as_str(), options)?;
+        std::io::copy(&mut std::fs::File::open(source)?, &mut zip)?;
+    }
+    if let Some(template) = template {
+        zip.start_file("chat_template.jinja", options)?;
+        zip.write_all(template.source.as_bytes())?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Read a bundle's manifest and extract its input files into `dir`, checking
+/// them against their recorded hashes.
+pub fn extract(path: &Path, dir: &Path) -> Result<Manifest> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut archive = zip::ZipArchive::new(file)
+        .with_context(|| format!("{} is not a bundle archive", path.display()))?;
+    let manifest: Manifest = {
+        let mut text = String::new();
+        archive
+            .by_name(MANIFEST)
+            .with_context(|| format!("{} has no {}", path.display(), MANIFEST))?
+            .read_to_string(&mut text)?;
+        serde_json::from_str(&text)
+            .with_context(|| format!("Invalid {} in {}", MANIFEST, path.display()))?
+    };
+    for input in &manifest.inputs {
+        if Path::new(&input.entry).is_absolute() || input.entry.contains("..") {
+            bail!("Bundle entry {} points outside the bundle", input.entry);
+        }
+        let target = dir.join(&input.entry);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::io::copy(
+            &mut archive.by_name(&input.entry)?,
+            &mut std::fs::File::create(&target)?,
+        )?;
+        if sha256_file(&target)? != input.sha256 {
+            bail!(
+                "{} in the bundle doesn't match its recorded hash",
+                input.entry
+            );
+        }
+    }
+    Ok(manifest)
+}
+
+/// How `current` differs from the recorded model files, one line each.
+pub fn compare(recorded: &[FileHash], current: &[FileHash]) -> Vec<String> {
+    let mut differences = Vec::new();
+    for expected in recorded {
+        match current.iter().find(|file| file.name == expected.name) {
+            None => differences.push(format!("{}: missing", expected.name)),
+            Some(file) if file.sha256 != expected.sha256 => differences.push(format!(
+                "{}: {} has sha256 {}, the bundle recorded {}",
+                file.name, file.path, file.sha256, expected.sha256
+            )),
+            Some(_) => {}
+        }
+    }
+    for file in current {
+        if !recorded.iter().any(|expected| expected.name == file.name) {
+            differences.push(format!("{}: not in the bundle", file.name));
+        }
+    }
+    differences
+}
+
+/// `args` with every bundled input's path replaced by its extracted copy in
+/// `dir`, in both `--option PATH` and `--option=PATH` form. Inputs named in
+/// a --config file rather than in `args` are passed as options after them,
+/// which take precedence over the file.
+pub fn rewrite_args(args: &[String], inputs: &[BundledFile], dir: &Path) -> Vec<String> {
+    let mut rewritten: Vec<String> = args
+        .iter()
+        .map(|arg| {
+            for input in inputs {
+                let extracted = dir.join(&input.entry).display().to_string();
+                if *arg == input.original {
+                    return extracted;
+                }
+                if let Some((option, value)) = arg.split_once('=') {
+                    if option.starts_with("--") && value == input.original {
+                        return format!("{option}={extracted}");
+                    }
+                }
+            }
+            arg.clone()
+        })
+        .collect();
+    for input in inputs {
+        let in_args = args.iter().any(|arg| {
+            *arg == input.original || arg.split_once('=').is_some_and(|(_, v)| v == input.original)
+        });
+        if !in_args {
+            rewritten.push(format!("--{}", input.option.replace('_', "-")));
+            rewritten.push(dir.join(&input.entry).display().to_string());
+        }
+    }
+    rewritten
+}
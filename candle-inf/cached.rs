@@ -0,0 +1,125 @@
+// Models already in the local Hugging Face cache.
+//
+// The Hub cache keeps each repo under `models--<org>--<name>`, with file
+// contents in `blobs/` and one directory per downloaded revision in
+// `snapshots/`. Listing them (with their size, weight formats and context
+// length) lets a user pick a model that is ready to run without downloading.
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct CachedModel {
+    /// Repo id, e.g. `TinyLlama/TinyLlama-1.1B-Chat-v1.0`.
+    pub id: String,
+    /// Bytes of all cached files of the repo.
+    pub size: u64,
+    /// GGUF files in the newest snapshot.
+    pub gguf_files: Vec<String>,
+    /// `torch_dtype` from config.json, if there is one.
+    pub dtype: Option<String>,
+    /// `max_position_embeddings` from config.json.
+    pub context: Option<usize>,
+}
+
+impl CachedModel {
+    /// Short description of the weights on disk: the GGUF quantizations, or
+    /// the safetensors dtype.
+    pub fn weights(&self) -> String {
+        if self.gguf_files.is_empty() {
+            return self.dtype.clone().unwrap_or_else(|| "?".to_string());
+        }
+        let quants: Vec<&str> = self
+            .gguf_files
+            .iter()
+            .map(|file| {
+                let stem = file.trim_end_matches(".gguf");
+                stem.rsplit(['.', '-']).next().unwrap_or(stem)
+            })
+            .collect();
+        format!("gguf {}", quants.join(","))
+    }
+}
+
+/// The models in the Hub cache, sorted by id.
+pub fn cached_models() -> Result<Vec<CachedModel>> {
+    let cache = hf_hub::Cache::default().path().clone();
+    let Ok(entries) = std::fs::read_dir(&cache) else {
+        return Ok(Vec::new());
+    };
+    let mut models = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(repo) = name.strip_prefix("models--") else {
+            continue;
+        };
+        let repo_dir = entry.path();
+        let mut model = CachedModel {
+            id: repo.replace("--", "/"),
+            size: dir_size(&repo_dir.join("blobs")),
+            gguf_files: Vec::new(),
+            dtype: None,
+            context: None,
+        };
+        if let Some(snapshot) = newest_snapshot(&repo_dir) {
+            for file in std::fs::read_dir(&snapshot)?.flatten() {
+                let file = file.file_name().to_string_lossy().to_string();
+                if file.ends_with(".gguf") {
+                    model.gguf_files.push(file);
+                }
+            }
+            model.gguf_files.sort();
+            if let Ok(config) = std::fs::read_to_string(snapshot.join("config.json")) {
+                let config: serde_json::Value = serde_json::from_str(&config).unwrap_or_default();
+                model.dtype = config["torch_dtype"].as_str().map(str::to_string);
+                model.context = config["max_position_embeddings"].as_u64().map(|n| n as usize);
+            }
+        }
+        models.push(model);
+    }
+    models.sort_by_key(|model| model.id.to_lowercase());
+    Ok(models)
+}
+
+/// The snapshot `refs/main` points to, else the most recently modified one.
+fn newest_snapshot(repo_dir: &Path) -> Option<PathBuf> {
+    if let Ok(commit) = std::fs::read_to_string(repo_dir.join("refs/main")) {
+        let snapshot = repo_dir.join("snapshots").join(commit.trim());
+        if snapshot.is_dir() {
+            return Some(snapshot);
+        }
+    }
+    std::fs::read_dir(repo_dir.join("snapshots"))
+        .ok()?
+        .flatten()
+        .max_by_key(|entry| entry.metadata().and_then(|m| m.modified()).ok())
+        .map(|entry| entry.path())
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter_map(|entry| entry.metadata().ok())
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len())
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Fuzzy match score of `query` against `text`: `None` unless the query's
+/// characters appear in order (case-insensitively); lower is a tighter match.
+pub fn fuzzy_score(query: &str, text: &str) -> Option<usize> {
+    let text = text.to_lowercase();
+    let mut chars = text.char_indices();
+    let (mut first, mut last) = (None, 0);
+    for q in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let (at, _) = chars.find(|&(_, c)| c == q)?;
+        first.get_or_insert(at);
+        last = at;
+    }
+    Some(first.map_or(0, |first| last - first))
+}
@@ -0,0 +1,293 @@
+// Chat templates: turning a list of messages into the prompt text a chat
+// model was trained on.
+//
+// Models ship the template as Jinja in tokenizer_config.json (or
+// chat_template.jinja). It is rendered the way transformers'
+// `apply_chat_template` does, with `trim_blocks`/`lstrip_blocks` and Python
+// string methods available. `lint` looks for the mistakes that don't fail
+// rendering but quietly degrade output: doubled BOS tokens, dropped messages
+// and role markers that the tokenizer splits into ordinary text.
+
+use crate::loader::ModelLoader;
+use crate::tokenize::{tokenize, Tokenized};
+use anyhow::{anyhow, bail, Context, Result};
+use minijinja::{Environment, Error, ErrorKind, Value};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::str::FromStr;
+use tokenizers::Tokenizer;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub role: String,
+    pub content: String,
+}
+
+impl Message {
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: content.into(),
+        }
+    }
+}
+
+/// A `role:content` message from the command line, e.g. `user:Hello`; the
+/// content may contain further colons.
+impl FromStr for Message {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some((role, content)) if !role.trim().is_empty() => {
+                Ok(Self::new(role.trim(), content))
+            }
+            _ => bail!("Invalid message {:?} (expected role:content, e.g. user:Hello)", s),
+        }
+    }
+}
+
+/// A short conversation exercising every role, for previewing a template.
+pub fn sample_messages() -> Vec<Message> {
+    vec![
+        Message::new("system", "You are a helpful assistant."),
+        Message::new("user", "What is the capital of France?"),
+        Message::new("assistant", "The capital of France is Paris."),
+        Message::new("user", "And of Italy?"),
+    ]
+}
+
+/// Read messages from a JSON file: an array of `{"role", "content"}` objects,
+/// or an object with such an array under `"messages"`.
+pub fn read_messages(path: &Path) -> Result<Vec<Message>> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum MessagesFile {
+        List(Vec<Message>),
+        Object { messages: Vec<Message> },
+    }
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let file: MessagesFile = serde_json::from_str(&text).with_context(|| {
+        format!(
+            "{}: expected a JSON array of {{\"role\", \"content\"}} messages",
+            path.display()
+        )
+    })?;
+    Ok(match file {
+        MessagesFile::List(messages) | MessagesFile::Object { messages } => messages,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatTemplate {
+    pub source: String,
+    pub bos_token: String,
+    pub eos_token: String,
+}
+
+impl ChatTemplate {
+    /// The template in a tokenizer_config.json, with its BOS and EOS tokens.
+    /// Returns `None` if the config has no template.
+    pub fn from_tokenizer_config(path: &Path) -> Result<Option<Self>> {
+        let config: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?,
+        )?;
+        let source = match &config["chat_template"] {
+            serde_json::Value::String(source) => source.clone(),
+            // Several named templates (e.g. "default", "tool_use").
+            serde_json::Value::Array(templates) => {
+                let default = templates
+                    .iter()
+                    .find(|template| template["name"] == "default")
+                    .or(templates.first());
+                match default.and_then(|template| template["template"].as_str()) {
+                    Some(source) => source.to_string(),
+                    None => bail!("{} has no default chat template", path.display()),
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(Self {
+            source,
+            bos_token: special_token(&config["bos_token"]),
+            eos_token: special_token(&config["eos_token"]),
+        }))
+    }
+
+    /// The model's template: from tokenizer_config.json, or from
+    /// chat_template.jinja where newer repos keep it. Returns `None` if the
+    /// model has neither.
+    pub fn for_model(loader: &ModelLoader) -> Result<Option<Self>> {
+        if let Ok(path) = loader.file("tokenizer_config.json") {
+            if let Some(template) = Self::from_tokenizer_config(&path)? {
+                return Ok(Some(template));
+            }
+        }
+        match loader.file("chat_template.jinja") {
+            Ok(path) => Ok(Some(Self {
+                source: std::fs::read_to_string(path)?,
+                bos_token: String::new(),
+                eos_token: String::new(),
+            })),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// The prompt text for `messages`. With `add_generation_prompt` it ends
+    /// with the opening of an assistant turn for the model to complete.
+    pub fn render(&self, messages: &[Message], add_generation_prompt: bool) -> Result<String> {
+        let mut env = Environment::new();
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function(
+            "raise_exception",
+            |message: String| -> Result<Value, Error> {
+                Err(Error::new(ErrorKind::InvalidOperation, message))
+            },
+        );
+        // Like Python's json.dumps, which templates use to embed tool
+        // definitions; minijinja's own version escapes HTML characters.
+        env.add_filter("tojson", |value: Value, indent: Option<usize>| {
+            let json = match indent {
+                Some(_) => serde_json::to_string_pretty(&value),
+                None => serde_json::to_string(&value),
+            };
+            json.map_err(|e| Error::new(ErrorKind::InvalidOperation, e.to_string()))
+        });
+        env.render_str(
+            &self.source,
+            minijinja::context! {
+                messages => messages,
+                add_generation_prompt => add_generation_prompt,
+                bos_token => self.bos_token,
+                eos_token => self.eos_token,
+            },
+        )
+        .map_err(|e| anyhow!("Failed to render the chat template: {:#}", e))
+    }
+
+    /// `render` with a generation prompt, dropping the oldest turns until
+    /// the prompt is at most `max_tokens` tokens long. System messages and
+    /// the last message are always kept, so the result may still be too
+    /// long. A turn is a message and the non-user messages after it, so what
+    /// is left still starts with a user message. Returns the prompt and the
+    /// number of messages dropped.
+    pub fn render_within(
+        &self,
+        messages: &[Message],
+        tokenizer: &Tokenizer,
+        max_tokens: usize,
+    ) -> Result<(String, usize)> {
+        let mut kept = messages.to_vec();
+        loop {
+            let prompt = self.render(&kept, true)?;
+            // The template writes the BOS token itself.
+            if tokenize(tokenizer, &prompt, false)?.ids.len() <= max_tokens {
+                return Ok((prompt, messages.len() - kept.len()));
+            }
+            let droppable = |message: &Message| message.role != "system";
+            let Some(first) = kept[..kept.len().saturating_sub(1)].iter().position(droppable) else {
+                return Ok((prompt, messages.len() - kept.len()));
+            };
+            let mut end = first + 1;
+            while end < kept.len() - 1 && !matches!(kept[end].role.as_str(), "user" | "system") {
+                end += 1;
+            }
+            kept.drain(first..end);
+        }
+    }
+}
+
+/// A token given as a string or an `AddedToken` object (`{"content": ...}`).
+fn special_token(value: &serde_json::Value) -> String {
+    value
+        .as_str()
+        .or_else(|| value["content"].as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Whether `id` is one of the tokenizer's special tokens (BOS, role markers,
+/// ...) rather than ordinary text.
+pub fn is_special(tokenizer: &Tokenizer, id: u32) -> bool {
+    tokenizer
+        .get_added_tokens_decoder()
+        .get(&id)
+        .is_some_and(|token| token.special)
+}
+
+/// Problems with how `template` renders `messages` and how `tokenizer` then
+/// splits the result (`tokens`, tokenized without added special tokens).
+pub fn lint(
+    template: &ChatTemplate,
+    messages: &[Message],
+    rendered: &str,
+    tokens: &Tokenized,
+    tokenizer: &Tokenizer,
+) -> Result<Vec<String>> {
+    let mut warnings = Vec::new();
+
+    for (index, message) in messages.iter().enumerate() {
+        if !message.content.trim().is_empty() && !rendered.contains(message.content.trim()) {
+            warnings.push(format!(
+                "message {} ({}) does not appear in the rendered prompt",
+                index, message.role
+            ));
+        }
+    }
+
+    if !template.bos_token.is_empty() && rendered.starts_with(&template.bos_token) {
+        let with_special = tokenize(tokenizer, rendered, true)?;
+        if with_special.ids.len() > 1 && with_special.ids[0] == with_special.ids[1] {
+            warnings.push(format!(
+                "the template starts with {} and the tokenizer adds it again when encoding \
+                 with special tokens; encode the rendered prompt without them",
+                template.bos_token
+            ));
+        }
+    }
+
+    if template.render(messages, true)? == template.render(messages, false)? {
+        warnings.push(
+            "add_generation_prompt makes no difference: the template doesn't open an \
+             assistant turn for the model to complete"
+                .to_string(),
+        );
+    }
+    if rendered.ends_with([' ', '\t']) {
+        warnings.push(
+            "the prompt ends with a space, which the model would normally generate as part \
+             of its first token"
+                .to_string(),
+        );
+    }
+
+    // Role and turn markers should each be a single special token; one the
+    // vocabulary doesn't know is fed to the model as ordinary text.
+    let marker = Regex::new(r"<\|?/?[A-Za-z][A-Za-z0-9_./-]*\|?>|\[/?[A-Z][A-Z_]*\]")?;
+    let mut reported = Vec::new();
+    for found in marker.find_iter(rendered) {
+        let text = found.as_str();
+        let in_content = messages.iter().any(|m| m.content.contains(text));
+        if in_content || reported.contains(&text) {
+            continue;
+        }
+        let pieces = tokens
+            .offsets
+            .iter()
+            .filter(|&&(start, end)| start < found.end() && end > found.start())
+            .count();
+        if pieces > 1 {
+            warnings.push(format!(
+                "{} is split into {} tokens; it isn't a special token of this tokenizer",
+                text, pieces
+            ));
+            reported.push(text);
+        }
+    }
+    Ok(warnings)
+}
@@ -0,0 +1,216 @@
+// Weight integrity checks.
+//
+// A truncated download, a checkpoint for a different config or a
+// half-converted export normally shows up as a shape error deep inside a
+// forward pass, or as garbage output. Checking every expected tensor up front
+// names the exact tensor that is missing, mis-shaped or holds NaN/Inf values.
+//
+// `audit_tensors` is the cheap part of that check, run on every load: it only
+// reads the safetensors header, so a checkpoint whose layout doesn't match
+// the model fails with the list of missing tensors (and hints such as
+// quantization scales that suggest why) instead of candle's error for the
+// first one, and tensors the model never reads are reported.
+
+use anyhow::Result;
+use candle_core::safetensors::MmapedSafetensors;
+use candle_core::{DType, Device};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// Name suffixes of the tensors GPTQ, AWQ and FP8 checkpoints store instead
+/// of (or next to) plain weights.
+const QUANTIZATION_SUFFIXES: [&str; 6] =
+    [".qweight", ".qzeros", ".scales", ".g_idx", ".weight_scale", ".input_scale"];
+
+pub enum WeightIssue {
+    Missing(String),
+    Shape {
+        name: String,
+        expected: Vec<usize>,
+        found: Vec<usize>,
+    },
+    NonFinite {
+        name: String,
+        nan: usize,
+        inf: usize,
+        total: usize,
+    },
+}
+
+impl std::fmt::Display for WeightIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WeightIssue::Missing(name) => write!(f, "missing tensor {}", name),
+            WeightIssue::Shape {
+                name,
+                expected,
+                found,
+            } => write!(f, "{}: expected shape {:?}, found {:?}", name, expected, found),
+            WeightIssue::NonFinite {
+                name,
+                nan,
+                inf,
+                total,
+            } => write!(f, "{}: {} NaN and {} Inf of {} values", name, nan, inf, total),
+        }
+    }
+}
+
+pub struct WeightReport {
+    /// Tensors that were present with the right shape and scanned.
+    pub checked: usize,
+    pub parameters: usize,
+    pub issues: Vec<WeightIssue>,
+}
+
+/// Check that the safetensors file at `path` holds every `(name, shape)` in
+/// `expected` and that their values are all finite. Tensors are scanned one
+/// at a time on the CPU.
+pub fn check_weights(path: &Path, expected: &[(String, Vec<usize>)]) -> Result<WeightReport> {
+    let tensors = unsafe { MmapedSafetensors::new(path)? };
+    let names = tensor_names(path)?;
+    let mut report = WeightReport {
+        checked: 0,
+        parameters: 0,
+        issues: Vec::new(),
+    };
+    for (name, shape) in expected {
+        if !names.contains(name) {
+            report.issues.push(WeightIssue::Missing(name.clone()));
+            continue;
+        }
+        let found = tensors.get(name)?.shape().to_vec();
+        if &found != shape {
+            report.issues.push(WeightIssue::Shape {
+                name: name.clone(),
+                expected: shape.clone(),
+                found,
+            });
+            continue;
+        }
+
+        let tensor = tensors.load(name, &Device::Cpu)?.to_dtype(DType::F32)?;
+        // x - x is 0 for finite values and NaN for both NaN and Inf.
+        let count = |t: candle_core::Tensor| -> Result<usize> {
+            Ok(t.to_dtype(DType::U32)?.sum_all()?.to_scalar::<u32>()? as usize)
+        };
+        let non_finite = count(tensor.sub(&tensor)?.ne(0f32)?)?;
+        if non_finite > 0 {
+            let nan = count(tensor.ne(&tensor)?)?;
+            report.issues.push(WeightIssue::NonFinite {
+                name: name.clone(),
+                nan,
+                inf: non_finite - nan,
+                total: tensor.elem_count(),
+            });
+        }
+        report.checked += 1;
+        report.parameters += tensor.elem_count();
+    }
+    Ok(report)
+}
+
+/// The names of the tensors in the safetensors file at `path`.
+pub fn tensor_names(path: &Path) -> Result<HashSet<String>> {
+    let tensors = unsafe { MmapedSafetensors::new(path)? };
+    Ok(tensors.tensors().into_iter().map(|(name, _)| name).collect())
+}
+
+/// How the tensors of a safetensors file compare with what a model reads.
+pub struct TensorAudit {
+    /// Tensors the model reads that are missing or have the wrong shape.
+    pub issues: Vec<WeightIssue>,
+    /// Tensors in the file that the model never reads.
+    pub unexpected: Vec<String>,
+}
+
+impl TensorAudit {
+    /// Likely causes of the mismatch, for the error or warning.
+    pub fn hints(&self, tie_word_embeddings: bool) -> Vec<String> {
+        let mut hints = Vec::new();
+        if self
+            .unexpected
+            .iter()
+            .any(|name| QUANTIZATION_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)))
+        {
+            hints.push(
+                "the file holds quantization scales (GPTQ, AWQ or FP8) but neither config.json \
+                 nor a quantize_config.json describes them; only fp16/bf16/f32 weights and \
+                 GPTQ/AWQ int4 checkpoints can be loaded from safetensors"
+                    .to_string(),
+            );
+        }
+        let missing = |name: &str| {
+            self.issues
+                .iter()
+                .any(|issue| matches!(issue, WeightIssue::Missing(missing) if missing == name))
+        };
+        if missing("lm_head.weight") {
+            hints.push(
+                "there is no lm_head.weight; if the model shares it with the embeddings, set \
+                 \"tie_word_embeddings\": true in config.json"
+                    .to_string(),
+            );
+        }
+        if tie_word_embeddings && self.unexpected.iter().any(|name| name == "lm_head.weight") {
+            hints.push(
+                "lm_head.weight is ignored because config.json sets tie_word_embeddings".to_string(),
+            );
+        }
+        hints
+    }
+}
+
+/// Compare the tensor names and shapes in the header of the safetensors file
+/// at `path` with the `(name, shape)`s in `expected`, without reading any
+/// values.
+pub fn audit_tensors(path: &Path, expected: &[(String, Vec<usize>)]) -> Result<TensorAudit> {
+    let tensors = unsafe { MmapedSafetensors::new(path)? };
+    let shapes: BTreeMap<String, Vec<usize>> = tensors
+        .tensors()
+        .into_iter()
+        .map(|(name, view)| (name, view.shape().to_vec()))
+        .collect();
+    let mut issues = Vec::new();
+    for (name, shape) in expected {
+        match shapes.get(name) {
+            None => issues.push(WeightIssue::Missing(name.clone())),
+            Some(found) if found != shape => issues.push(WeightIssue::Shape {
+                name: name.clone(),
+                expected: shape.clone(),
+                found: found.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    let expected: HashSet<&str> = expected.iter().map(|(name, _)| name.as_str()).collect();
+    let unexpected = shapes
+        .into_keys()
+        .filter(|name| !expected.contains(name.as_str()))
+        .collect();
+    Ok(TensorAudit { issues, unexpected })
+}
+
+/// `names` with the layer numbers replaced by `*` and each pattern counted,
+/// e.g. `model.layers.*.self_attn.rotary_emb.inv_freq (32)`.
+pub fn summarize_names(names: &[String]) -> Vec<String> {
+    let mut patterns: BTreeMap<String, usize> = BTreeMap::new();
+    for name in names {
+        let pattern = name
+            .split('.')
+            .map(|part| match part.parse::<usize>() {
+                Ok(_) => "*",
+                Err(_) => part,
+            })
+            .collect::<Vec<_>>()
+            .join(".");
+        *patterns.entry(pattern).or_default() += 1;
+    }
+    patterns
+        .into_iter()
+        .map(|(pattern, count)| match count {
+            1 => pattern,
+            _ => format!("{} ({})", pattern, count),
+        })
+        .collect()
+}
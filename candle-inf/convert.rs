@@ -0,0 +1,388 @@
+// Safetensors to GGUF conversion.
+//
+// `sl5 convert` writes a Llama safetensors checkpoint as a GGUF file that
+// `--quantized` loads, so quantized artifacts can be made without
+// llama.cpp's Python scripts. The file follows llama.cpp's layout: its
+// tensor names, the hyperparameters as `llama.*` metadata, and the query and
+// key projections permuted from the half-split RoPE layout of Hugging Face
+// checkpoints to the interleaved one GGUF models use. Matrices are quantized
+// to the chosen type; norms stay f32, and matrices whose rows don't divide
+// into the type's blocks are stored as f16 instead. The tokenizer isn't
+// embedded: base-inf reads tokenizer.json next to the GGUF file.
+
+use crate::arch::Arch;
+use crate::check::tensor_names;
+use crate::llama::expected_tensors;
+use crate::loader::{config_from_json, ModelFiles, WeightFormat};
+use anyhow::{bail, Context, Result};
+use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+use candle_core::{DType, Device, Tensor};
+use candle_transformers::models::llama::LlamaEosToks;
+use std::path::Path;
+
+/// Tensor type of the converted matrices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, clap::ValueEnum)]
+pub enum GgufType {
+    #[value(name = "f16")]
+    #[serde(rename = "f16")]
+    F16,
+    /// 8-bit, close to f16 quality.
+    #[value(name = "q8_0")]
+    #[serde(rename = "q8_0")]
+    Q8_0,
+    #[value(name = "q5_0")]
+    #[serde(rename = "q5_0")]
+    Q5_0,
+    #[value(name = "q4_0")]
+    #[serde(rename = "q4_0")]
+    Q4_0,
+    /// k-quants: blocks of 256, better quality than the `_0` types of the
+    /// same size.
+    #[value(name = "q6_k")]
+    #[serde(rename = "q6_k")]
+    Q6K,
+    #[value(name = "q5_k")]
+    #[serde(rename = "q5_k")]
+    Q5K,
+    #[value(name = "q4_k")]
+    #[serde(rename = "q4_k")]
+    Q4K,
+}
+
+impl GgufType {
+    /// Name used on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            GgufType::F16 => "f16",
+            GgufType::Q8_0 => "q8_0",
+            GgufType::Q5_0 => "q5_0",
+            GgufType::Q4_0 => "q4_0",
+            GgufType::Q6K => "q6_k",
+            GgufType::Q5K => "q5_k",
+            GgufType::Q4K => "q4_k",
+        }
+    }
+
+    pub fn ggml_dtype(self) -> GgmlDType {
+        match self {
+            GgufType::F16 => GgmlDType::F16,
+            GgufType::Q8_0 => GgmlDType::Q8_0,
+            GgufType::Q5_0 => GgmlDType::Q5_0,
+            GgufType::Q4_0 => GgmlDType::Q4_0,
+            GgufType::Q6K => GgmlDType::Q6K,
+            GgufType::Q5K => GgmlDType::Q5K,
+            GgufType::Q4K => GgmlDType::Q4K,
+        }
+    }
+}
+
+/// What a conversion wrote.
+#[derive(Debug)]
+pub struct Conversion {
+    pub tensors: usize,
+    /// Matrices stored as f16 because their rows don't divide into blocks.
+    pub f16_fallback: Vec<String>,
+    /// Size of the GGUF file in bytes.
+    pub size: u64,
+    /// The config's `rope_scaling`, which GGUF models run without.
+    pub dropped_rope_scaling: bool,
+}
+
+/// The GGUF name of a Llama safetensors tensor.
+fn gguf_name(name: &str) -> Option<String> {
+    let name = name.strip_suffix(".weight")?;
+    let mapped = match name {
+        "model.embed_tokens" => "token_embd".to_string(),
+        "model.norm" => "output_norm".to_string(),
+        "lm_head" => "output".to_string(),
+        _ => {
+            let (layer, tensor) = name.strip_prefix("model.layers.")?.split_once('.')?;
+            let tensor = match tensor {
+                "input_layernorm" => "attn_norm",
+                "self_attn.q_proj" => "attn_q",
+                "self_attn.k_proj" => "attn_k",
+                "self_attn.v_proj" => "attn_v",
+                "self_attn.o_proj" => "attn_output",
+                "post_attention_layernorm" => "ffn_norm",
+                "mlp.gate_proj" => "ffn_gate",
+                "mlp.up_proj" => "ffn_up",
+                "mlp.down_proj" => "ffn_down",
+                _ => return None,
+            };
+            format!("blk.{layer}.{tensor}")
+        }
+    };
+    Some(format!("{mapped}.weight"))
+}
+
+/// Reorder the rows of a query or key projection from the half-split RoPE
+/// layout (first half of each head's dimensions rotated with the second) to
+/// the interleaved one (pairs of adjacent dimensions), like llama.cpp's
+/// converter.
+fn permute_for_rope(weight: &Tensor, heads: usize) -> Result<Tensor> {
+    let (rows, cols) = weight.dims2()?;
+    Ok(weight
+        .reshape((heads, 2, rows / heads / 2, cols))?
+        .transpose(1, 2)?
+        .contiguous()?
+        .reshape((rows, cols))?)
+}
+
+/// Convert the Llama safetensors weights of `files` to a GGUF file at
+/// `output` named `name`, quantizing the matrices to `dtype`. `on_tensor` is
+/// called with each tensor's name before it is converted.
+pub fn convert(
+    files: &ModelFiles,
+    name: &str,
+    output: &Path,
+    dtype: GgufType,
+    mut on_tensor: impl FnMut(&str),
+) -> Result<Conversion> {
+    if files.format != WeightFormat::Safetensors {
+        bail!("{} is already a GGUF file", files.weights.display());
+    }
+    let arch = files.arch()?;
+    if arch != Arch::Llama {
+        bail!(
+            "Only Llama checkpoints can be converted to GGUF (candle runs GGUF files as Llama), \
+             not {}",
+            arch
+        );
+    }
+    if let Some(int4) = files.int4_config()? {
+        bail!("{} checkpoints can't be converted; convert the unquantized model", int4.method);
+    }
+    let Some(config_path) = &files.config else {
+        bail!("The model has no config.json");
+    };
+    let config_json: serde_json::Value = serde_json::from_slice(&std::fs::read(config_path)?)
+        .with_context(|| format!("Invalid JSON in {}", config_path.display()))?;
+    // The unscaled config: GGUF models run without RoPE scaling.
+    let config = config_from_json(&config_json);
+    let names = tensor_names(&files.weights)?;
+    if names.contains("model.layers.0.self_attn.q_proj.bias")
+        || names.contains("model.layers.0.self_attn.qkv_proj.weight")
+    {
+        bail!("Only the plain Llama layout can be converted, without attention biases or fused projections");
+    }
+
+    let head_dim = config.hidden_size / config.num_attention_heads;
+    let eos_token_id = match &config.eos_token_id {
+        Some(LlamaEosToks::Single(id)) => Some(*id),
+        Some(LlamaEosToks::Multiple(ids)) => ids.first().copied(),
+        None => None,
+    };
+    let mut metadata = vec![
+        ("general.architecture", gguf_file::Value::String("llama".to_string())),
+        ("general.name", gguf_file::Value::String(name.to_string())),
+        ("general.quantization_version", gguf_file::Value::U32(2)),
+        ("llama.context_length", gguf_file::Value::U32(config.max_position_embeddings as u32)),
+        ("llama.embedding_length", gguf_file::Value::U32(config.hidden_size as u32)),
+        ("llama.block_count", gguf_file::Value::U32(config.num_hidden_layers as u32)),
+        ("llama.feed_forward_length", gguf_file::Value::U32(config.intermediate_size as u32)),
+        ("llama.attention.head_count", gguf_file::Value::U32(config.num_attention_heads as u32)),
+        ("llama.attention.head_count_kv", gguf_file::Value::U32(config.num_key_value_heads as u32)),
+        ("llama.rope.dimension_count", gguf_file::Value::U32(head_dim as u32)),
+        ("llama.rope.freq_base", gguf_file::Value::F32(config.rope_theta)),
+        (
+            "llama.attention.layer_norm_rms_epsilon",
+            gguf_file::Value::F32(config.rms_norm_eps as f32),
+        ),
+    ];
+    if let Some(id) = config.bos_token_id {
+        metadata.push(("tokenizer.ggml.bos_token_id", gguf_file::Value::U32(id)));
+    }
+    if let Some(id) = eos_token_id {
+        metadata.push(("tokenizer.ggml.eos_token_id", gguf_file::Value::U32(id)));
+    }
+
+    // LoRA adapters given to the loader are merged here as usual.
+    let vb = files.var_builder(DType::F32, &Device::Cpu)?;
+    let ggml_dtype = dtype.ggml_dtype();
+    let mut tensors = Vec::new();
+    let mut f16_fallback = Vec::new();
+    for (name, shape) in expected_tensors(&config) {
+        on_tensor(&name);
+        let Some(gguf) = gguf_name(&name) else {
+            bail!("No GGUF name for tensor {}", name);
+        };
+        let mut weight = vb
+            .get(shape.as_slice(), &name)
+            .with_context(|| format!("Failed to read {}", name))?;
+        if name.ends_with("self_attn.q_proj.weight") {
+            weight = permute_for_rope(&weight, config.num_attention_heads)?;
+        } else if name.ends_with("self_attn.k_proj.weight") {
+            weight = permute_for_rope(&weight, config.num_key_value_heads)?;
+        }
+        let tensor_dtype = match shape.as_slice() {
+            [_] => GgmlDType::F32,
+            [_, cols] if cols % ggml_dtype.block_size() != 0 => {
+                f16_fallback.push(name.clone());
+                GgmlDType::F16
+            }
+            _ => ggml_dtype,
+        };
+        let quantized = QTensor::quantize(&weight, tensor_dtype)
+            .with_context(|| format!("Failed to quantize {} to {:?}", name, tensor_dtype))?;
+        tensors.push((gguf, quantized));
+    }
+
+    let mut file = std::io::BufWriter::new(
+        std::fs::File::create(output)
+            .with_context(|| format!("Failed to create {}", output.display()))?,
+    );
+    let metadata: Vec<(&str, &gguf_file::Value)> =
+        metadata.iter().map(|(key, value)| (*key, value)).collect();
+    let tensor_refs: Vec<(&str, &QTensor)> =
+        tensors.iter().map(|(name, tensor)| (name.as_str(), tensor)).collect();
+    gguf_file::write(&mut file, &metadata, &tensor_refs)
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+    std::io::Write::flush(&mut file)?;
+    drop(file);
+    Ok(Conversion {
+        tensors: tensors.len(),
+        f16_fallback,
+        size: std::fs::metadata(output)?.len(),
+        dropped_rope_scaling: !config_json["rope_scaling"].is_null(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::ModelLoader;
+    use std::collections::HashMap;
+
+    #[test]
+    fn permutes_like_llama_cpp() {
+        // llama.cpp's converter:
+        // w.reshape(n_head, 2, dim // n_head // 2, *rest).swapaxes(1, 2).reshape(w.shape)
+        // Two heads of four rows: each head's halves [a0 a1 | b0 b1] become
+        // [a0 b0 a1 b1].
+        let weight = Tensor::arange(0f32, 16., &Device::Cpu)
+            .unwrap()
+            .reshape((8, 2))
+            .unwrap();
+        let permuted = permute_for_rope(&weight, 2).unwrap();
+        let rows: Vec<Vec<f32>> = permuted.to_vec2().unwrap();
+        let expected: Vec<Vec<f32>> = [0, 2, 1, 3, 4, 6, 5, 7]
+            .iter()
+            .map(|&row| vec![2. * row as f32, 2. * row as f32 + 1.])
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn maps_tensor_names() {
+        assert_eq!(gguf_name("model.embed_tokens.weight").as_deref(), Some("token_embd.weight"));
+        assert_eq!(
+            gguf_name("model.layers.3.mlp.down_proj.weight").as_deref(),
+            Some("blk.3.ffn_down.weight")
+        );
+        assert_eq!(gguf_name("model.layers.0.self_attn.rotary_emb.inv_freq"), None);
+    }
+
+    #[test]
+    fn round_trips_through_gguf() {
+        let dir = std::env::temp_dir().join(format!("sl5-convert-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_json = serde_json::json!({
+            "architectures": ["LlamaForCausalLM"],
+            "model_type": "llama",
+            "hidden_size": 32,
+            "intermediate_size": 64,
+            "vocab_size": 16,
+            "num_hidden_layers": 1,
+            "num_attention_heads": 4,
+            "num_key_value_heads": 2,
+            "rms_norm_eps": 1e-5,
+            "rope_theta": 10000.0,
+            "max_position_embeddings": 128,
+            "bos_token_id": 1,
+            "eos_token_id": 2,
+        });
+        std::fs::write(dir.join("config.json"), config_json.to_string()).unwrap();
+        // Only its path is needed for the conversion.
+        std::fs::write(dir.join("tokenizer.json"), "{}").unwrap();
+        let config = config_from_json(&config_json);
+        let weights: HashMap<String, Tensor> = expected_tensors(&config)
+            .into_iter()
+            .map(|(name, shape)| {
+                let weight = Tensor::randn(0f32, 1., shape, &Device::Cpu).unwrap();
+                (name, weight)
+            })
+            .collect();
+        candle_core::safetensors::save(&weights, dir.join("model.safetensors")).unwrap();
+
+        let files = ModelLoader::new(dir.to_string_lossy()).local(true).files().unwrap();
+        let output = dir.join("model.gguf");
+        let mut seen = Vec::new();
+        let conversion =
+            convert(&files, "tiny", &output, GgufType::F16, |name| seen.push(name.to_string()))
+                .unwrap();
+        assert_eq!(conversion.tensors, weights.len());
+        assert_eq!(seen.len(), weights.len());
+        assert!(conversion.f16_fallback.is_empty());
+        assert!(!conversion.dropped_rope_scaling);
+
+        let mut reader = std::fs::File::open(&output).unwrap();
+        let content = gguf_file::Content::read(&mut reader).unwrap();
+        let metadata = |key: &str| content.metadata[key].to_u32().unwrap();
+        assert_eq!(
+            content.metadata["general.architecture"].to_string().unwrap(),
+            "llama"
+        );
+        assert_eq!(content.metadata["general.name"].to_string().unwrap(), "tiny");
+        assert_eq!(metadata("llama.embedding_length"), 32);
+        assert_eq!(metadata("llama.feed_forward_length"), 64);
+        assert_eq!(metadata("llama.block_count"), 1);
+        assert_eq!(metadata("llama.attention.head_count"), 4);
+        assert_eq!(metadata("llama.attention.head_count_kv"), 2);
+        assert_eq!(metadata("llama.rope.dimension_count"), 8);
+        assert_eq!(metadata("llama.context_length"), 128);
+        assert_eq!(metadata("tokenizer.ggml.bos_token_id"), 1);
+        assert_eq!(metadata("tokenizer.ggml.eos_token_id"), 2);
+
+        let mut names: Vec<&str> = content.tensor_infos.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "blk.0.attn_k.weight",
+                "blk.0.attn_norm.weight",
+                "blk.0.attn_output.weight",
+                "blk.0.attn_q.weight",
+                "blk.0.attn_v.weight",
+                "blk.0.ffn_down.weight",
+                "blk.0.ffn_gate.weight",
+                "blk.0.ffn_norm.weight",
+                "blk.0.ffn_up.weight",
+                "output.weight",
+                "output_norm.weight",
+                "token_embd.weight",
+            ]
+        );
+        assert_eq!(content.tensor_infos["blk.0.attn_norm.weight"].ggml_dtype, GgmlDType::F32);
+        assert_eq!(content.tensor_infos["blk.0.attn_q.weight"].ggml_dtype, GgmlDType::F16);
+
+        // The key projection is stored permuted over the key/value heads.
+        let k_proj = &weights["model.layers.0.self_attn.k_proj.weight"];
+        let expected = permute_for_rope(k_proj, 2).unwrap();
+        let stored = content
+            .tensor(&mut reader, "blk.0.attn_k.weight", &Device::Cpu)
+            .unwrap()
+            .dequantize(&Device::Cpu)
+            .unwrap();
+        let error: f32 = (stored - expected)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .max_all()
+            .unwrap()
+            .to_scalar()
+            .unwrap();
+        assert!(error < 1e-2, "k_proj differs by {error}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+// Resident model daemon: a model loaded once and served over a Unix socket.
+//
+// Loading a 7B model takes tens of seconds, which every base-inf run pays
+// again. `sl5 daemon` loads the model once and answers requests on a Unix
+// socket; `sl5 run` sends a prompt and streams the reply, so a run only pays
+// for tokenization and generation. The protocol is line-delimited JSON: the
+// client writes one `Request`, the daemon answers with a `token` event per
+// piece of output text and a final `done` (or `error`) event. Requests are
+// served one at a time, in the order they arrive; a client that disconnects
+// stops its generation at the next token, and a connection that fails (or
+// sends nothing within `REQUEST_TIMEOUT`) is logged and dropped.
+
+use crate::generation::{ContextOverflow, FinishReason, GenerationConfig, Generator};
+use crate::loader::Model;
+use crate::tokenize::tokenize;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// How long a client has to send its request line once connected, so one
+/// that connects and stays silent doesn't hold up the queue.
+#[cfg(unix)]
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// One generation. Unset settings take the `GenerationConfig` defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Request {
+    pub prompt: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_new_tokens: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    /// What to do with a prompt too long for the context window.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_overflow: Option<ContextOverflow>,
+}
+
+impl Request {
+    pub fn generation_config(&self) -> GenerationConfig {
+        let defaults = GenerationConfig::default();
+        GenerationConfig {
+            max_new_tokens: self.max_new_tokens.unwrap_or(defaults.max_new_tokens),
+            temperature: self.temperature.unwrap_or(defaults.temperature),
+            top_p: self.top_p,
+            top_k: self.top_k,
+            seed: self.seed.unwrap_or(defaults.seed),
+            repeat_penalty: self.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            stop_sequences: self.stop.clone(),
+            context_overflow: self.context_overflow.unwrap_or(defaults.context_overflow),
+            ..defaults
+        }
+    }
+}
+
+/// What the daemon sends back, one per line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Output text that became visible with the latest token.
+    Token { text: String },
+    Done {
+        finish_reason: String,
+        prompt_tokens: usize,
+        generated_tokens: usize,
+        time_to_first_token_ms: f64,
+        generation_ms: f64,
+    },
+    /// The request failed; nothing more follows.
+    Error { message: String },
+}
+
+/// `$XDG_RUNTIME_DIR/sl5.sock`, or `sl5-$USER.sock` in the temporary
+/// directory.
+pub fn default_socket() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("sl5.sock"),
+        None => {
+            let user = std::env::var("USER").unwrap_or_else(|_| "sl5".to_string());
+            std::env::temp_dir().join(format!("sl5-{user}.sock"))
+        }
+    }
+}
+
+/// Run one request on `model`, sending its events to `send`. Stops early if
+/// `send` fails, i.e. the client went away.
+pub fn generate(
+    model: &Model,
+    request: &Request,
+    mut send: impl FnMut(&Event) -> Result<()>,
+) -> Result<()> {
+    let prompt_tokens = tokenize(&model.tokenizer, &request.prompt, true)?.ids;
+    let prompt_len = prompt_tokens.len();
+    let mut generator = Generator::new(model, request.generation_config(), prompt_tokens)?;
+    let start = std::time::Instant::now();
+    let mut first_token = None;
+    while generator.finish_reason().is_none() {
+        let step = generator.step()?;
+        first_token.get_or_insert_with(|| start.elapsed());
+        if !step.text.is_empty() {
+            send(&Event::Token { text: step.text })?;
+        }
+    }
+    send(&Event::Done {
+        finish_reason: generator
+            .finish_reason()
+            .map_or("length", FinishReason::as_str)
+            .to_string(),
+        prompt_tokens: prompt_len,
+        generated_tokens: generator.generated_tokens(),
+        time_to_first_token_ms: first_token.unwrap_or_default().as_secs_f64() * 1000.,
+        generation_ms: start.elapsed().as_secs_f64() * 1000.,
+    })
+}
+
+/// Listen on `path`, replacing a stale socket file left by a daemon that
+/// didn't exit cleanly. Only the current user can connect.
+#[cfg(unix)]
+pub fn bind(path: &Path) -> Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            bail!("A daemon is already listening on {}", path.display());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Answer requests on `listener` until the process is stopped. Failed
+/// requests are reported to their client and on stderr.
+#[cfg(unix)]
+pub fn serve(model: &Model, listener: &std::os::unix::net::UnixListener) -> Result<()> {
+    for (id, stream) in listener.incoming().enumerate() {
+        let _span = tracing::info_span!("request", id).entered();
+        // A bad connection fails its own request, never the daemon.
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::error!("Accepting a connection failed: {}", e);
+                continue;
+            }
+        };
+        let mut line = String::new();
+        let read = stream
+            .set_read_timeout(Some(REQUEST_TIMEOUT))
+            .and_then(|()| BufReader::new(&stream).read_line(&mut line));
+        if let Err(e) = read {
+            tracing::error!("Reading the request failed: {}", e);
+            continue;
+        }
+        let mut send = |event: &Event| -> Result<()> {
+            writeln!(stream, "{}", serde_json::to_string(event)?)?;
+            Ok(())
+        };
+        let result = serde_json::from_str::<Request>(&line)
+            .context("Invalid request")
+            .and_then(|request| generate(model, &request, &mut send));
+        if let Err(e) = result {
+            tracing::error!("Request failed: {:#}", e);
+            // The client may be gone already.
+            let _ = send(&Event::Error {
+                message: format!("{:#}", e),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Send `request` to the daemon on `socket` and pass each event to
+/// `on_event` as it arrives. An `error` event becomes the returned error.
+#[cfg(unix)]
+pub fn request(
+    socket: &Path,
+    request: &Request,
+    mut on_event: impl FnMut(&Event) -> Result<()>,
+) -> Result<()> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket).with_context(|| {
+        format!(
+            "No daemon is listening on {}; start one with `sl5 daemon`",
+            socket.display()
+        )
+    })?;
+    writeln!(stream, "{}", serde_json::to_string(request)?)?;
+    for line in BufReader::new(stream).lines() {
+        let event: Event = serde_json::from_str(&line?).context("Invalid reply from the daemon")?;
+        if let Event::Error { message } = &event {
+            bail!("{}", message);
+        }
+        on_event(&event)?;
+        if matches!(event, Event::Done { .. }) {
+            return Ok(());
+        }
+    }
+    bail!("The daemon closed the connection before finishing")
+}
@@ -0,0 +1,99 @@
+// Evaluation text from files and Hugging Face datasets.
+//
+// Plain text files are read as they are. Dataset files on the Hub are usually
+// parquet or JSON lines with one record per row; their text column is joined
+// with a blank line between rows, the way the Hugging Face perplexity guide
+// concatenates wikitext.
+
+use crate::hub::{HubClient, RetryPolicy};
+use anyhow::{bail, Context, Result};
+use hf_hub::{Repo, RepoType};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::Field;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+/// Download `file` from the `dataset` repo on the Hub (or find it in the
+/// cache).
+pub fn download(dataset: &str, file: &str, revision: Option<&str>) -> Result<PathBuf> {
+    let repo = Repo::with_revision(
+        dataset.to_string(),
+        RepoType::Dataset,
+        revision.unwrap_or("main").to_string(),
+    );
+    HubClient::new(RetryPolicy::default()).get(&repo, file)
+}
+
+/// The text of `path`: the `column` of every row for .parquet, .jsonl and
+/// .json files (rows may also be plain strings in JSON), the whole file
+/// otherwise.
+pub fn read_text(path: &Path, column: &str) -> Result<String> {
+    let rows = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("parquet") => parquet_rows(path, column)?,
+        Some("jsonl") => std::fs::read_to_string(path)?
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                let row = serde_json::from_str(line)
+                    .with_context(|| format!("Invalid JSON on line {} of {}", i + 1, path.display()))?;
+                json_row(&row, column)
+            })
+            .collect::<Result<_>>()?,
+        Some("json") => {
+            let rows: Vec<Value> = serde_json::from_str(&std::fs::read_to_string(path)?)
+                .with_context(|| format!("{} is not a JSON array of rows", path.display()))?;
+            rows.iter()
+                .map(|row| json_row(row, column))
+                .collect::<Result<_>>()?
+        }
+        _ => return Ok(std::fs::read_to_string(path)?),
+    };
+    Ok(rows.join("\n\n"))
+}
+
+fn json_row(row: &Value, column: &str) -> Result<String> {
+    match row {
+        Value::String(text) => Ok(text.clone()),
+        Value::Object(fields) => match fields.get(column) {
+            Some(Value::String(text)) => Ok(text.clone()),
+            Some(_) => bail!("Column '{}' is not a string", column),
+            None => bail!(
+                "Rows have no '{}' column (found {})",
+                column,
+                fields.keys().cloned().collect::<Vec<_>>().join(", ")
+            ),
+        },
+        _ => bail!("Rows must be strings or objects"),
+    }
+}
+
+fn parquet_rows(path: &Path, column: &str) -> Result<Vec<String>> {
+    let reader = SerializedFileReader::new(
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    )
+    .with_context(|| format!("{} is not a parquet file", path.display()))?;
+    let fields = reader.metadata().file_metadata().schema().get_fields();
+    if !fields.iter().any(|field| field.name() == column) {
+        bail!(
+            "{} has no '{}' column (found {})",
+            path.display(),
+            column,
+            fields
+                .iter()
+                .map(|field| field.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    let mut rows = Vec::new();
+    for row in reader.get_row_iter(None)? {
+        let row = row?;
+        match row.get_column_iter().find(|(name, _)| *name == column) {
+            Some((_, Field::Str(text))) => rows.push(text.clone()),
+            Some((_, Field::Null)) => {}
+            _ => bail!("Column '{}' of {} is not a string", column, path.display()),
+        }
+    }
+    Ok(rows)
+}
@@ -0,0 +1,320 @@
+// Splitting a model's decoder layers across several devices.
+//
+// A device map such as `0:40,1:40` puts the first 40 layers on cuda:0 and the
+// next 40 on cuda:1. The embedding lives with the first stage and the final
+// norm and lm_head with the last; activations are copied to the next device
+// at stage boundaries, so only one hidden-state tensor crosses per step.
+//
+// With GPUs of different sizes an even split runs the small one out of memory
+// first, so `proportional` sizes each stage by the memory available on its
+// device: explicit caps (`--max-mem 0:20GiB,1:6GiB`) or what nvidia-smi
+// reports as free.
+
+use crate::llama::expected_tensors;
+use anyhow::{bail, Context, Result};
+use candle_core::{DType, Device, DeviceLocation};
+use candle_transformers::models::llama::Config;
+
+#[derive(Debug, Clone)]
+pub struct DeviceMap {
+    /// Consecutive runs of layers and the device that holds them.
+    stages: Vec<(Device, usize)>,
+}
+
+impl DeviceMap {
+    /// Every layer on one device.
+    pub fn single(device: Device, num_layers: usize) -> Self {
+        Self {
+            stages: vec![(device, num_layers)],
+        }
+    }
+
+    /// Parse `DEVICE:LAYERS,...` where DEVICE is a CUDA ordinal or `cpu`. The
+    /// layer counts must add up to `num_layers`.
+    pub fn parse(spec: &str, num_layers: usize) -> Result<Self> {
+        let mut stages = Vec::new();
+        for part in spec.split(',') {
+            let Some((device, layers)) = part.trim().split_once(':') else {
+                bail!("Invalid device map entry '{}', expected DEVICE:LAYERS", part);
+            };
+            let layers: usize = layers
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid layer count in device map entry '{}'", part))?;
+            if layers == 0 {
+                bail!("Device map entry '{}' assigns no layers", part);
+            }
+            stages.push((parse_device(device)?, layers));
+        }
+        let mapped: usize = stages.iter().map(|(_, layers)| layers).sum();
+        if mapped != num_layers {
+            bail!(
+                "Device map assigns {} layers but the model has {}",
+                mapped,
+                num_layers
+            );
+        }
+        Ok(Self { stages })
+    }
+
+    /// Split the layers of a Llama `config` in `dtype` across `budgets`, each
+    /// a device and the bytes of weights it may hold, in proportion to the
+    /// room left on each once the embedding (first device) and lm_head (last
+    /// device) are placed. Devices that end up with no layers are dropped.
+    pub fn proportional(budgets: &[(Device, u64)], config: &Config, dtype: DType) -> Result<Self> {
+        if budgets.is_empty() {
+            bail!("No devices to split the model across");
+        }
+        let (mut layer_bytes, mut first_bytes, mut last_bytes) = (0u64, 0u64, 0u64);
+        for (name, shape) in expected_tensors(config) {
+            let bytes = (shape.iter().product::<usize>() * dtype.size_in_bytes()) as u64;
+            if name.starts_with("model.layers.0.") {
+                layer_bytes += bytes;
+            } else if name.starts_with("model.embed_tokens.") {
+                first_bytes += bytes;
+                // Tied embeddings are copied to the last device for lm_head.
+                if config.tie_word_embeddings {
+                    last_bytes += bytes;
+                }
+            } else if !name.starts_with("model.layers.") {
+                last_bytes += bytes;
+            }
+        }
+
+        let last = budgets.len() - 1;
+        let room: Vec<u64> = budgets
+            .iter()
+            .enumerate()
+            .map(|(i, (_, budget))| {
+                let fixed = if i == 0 { first_bytes } else { 0 } + if i == last { last_bytes } else { 0 };
+                budget.saturating_sub(fixed)
+            })
+            .collect();
+        let num_layers = config.num_hidden_layers;
+        let total_room: u64 = room.iter().sum();
+        let needed = num_layers as u64 * layer_bytes;
+        if total_room < needed {
+            bail!(
+                "The layers need {} but only {} is available after the embedding and lm_head",
+                format_size(needed),
+                format_size(total_room)
+            );
+        }
+
+        // Whole layers in proportion to the room on each device, then the
+        // leftovers to whichever device has the most room remaining.
+        let mut layers: Vec<usize> = room
+            .iter()
+            .map(|&r| (num_layers as u128 * r as u128 / total_room as u128) as usize)
+            .collect();
+        while layers.iter().sum::<usize>() < num_layers {
+            let i = (0..layers.len())
+                .max_by_key(|&i| room[i] as i128 - (layers[i] as u64 * layer_bytes) as i128)
+                .unwrap_or(0);
+            layers[i] += 1;
+        }
+        for (i, (device, _)) in budgets.iter().enumerate() {
+            if layers[i] as u64 * layer_bytes > room[i] {
+                bail!(
+                    "{} layers ({}) don't fit in the memory allowed on {:?}",
+                    layers[i],
+                    format_size(layers[i] as u64 * layer_bytes),
+                    device.location()
+                );
+            }
+        }
+
+        let stages = budgets
+            .iter()
+            .zip(layers)
+            .filter(|(_, layers)| *layers > 0)
+            .map(|((device, _), layers)| (device.clone(), layers))
+            .collect();
+        Ok(Self { stages })
+    }
+
+    pub fn stages(&self) -> &[(Device, usize)] {
+        &self.stages
+    }
+
+    /// Device of the embedding and the first layers, where inputs go.
+    pub fn first_device(&self) -> &Device {
+        &self.stages[0].0
+    }
+
+    /// CUDA ordinals of the GPUs holding layers.
+    pub fn cuda_ordinals(&self) -> Vec<usize> {
+        let mut ordinals: Vec<usize> = self
+            .stages
+            .iter()
+            .filter_map(|(device, _)| match device.location() {
+                DeviceLocation::Cuda { gpu_id } => Some(gpu_id),
+                _ => None,
+            })
+            .collect();
+        ordinals.sort_unstable();
+        ordinals.dedup();
+        ordinals
+    }
+
+    pub fn is_sharded(&self) -> bool {
+        self.stages.len() > 1
+    }
+}
+
+impl std::fmt::Display for DeviceMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut first_layer = 0;
+        for (i, (device, layers)) in self.stages.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(
+                f,
+                "layers {}-{} on {:?}",
+                first_layer,
+                first_layer + layers - 1,
+                device.location()
+            )?;
+            first_layer += layers;
+        }
+        Ok(())
+    }
+}
+
+/// `cpu` or a CUDA ordinal.
+fn parse_device(device: &str) -> Result<Device> {
+    Ok(match device.trim() {
+        "cpu" => Device::Cpu,
+        ordinal => Device::new_cuda(ordinal.parse()?)?,
+    })
+}
+
+/// Parse per-device memory caps such as `0:20GiB,1:6GiB`.
+pub fn parse_memory_caps(spec: &str) -> Result<Vec<(Device, u64)>> {
+    spec.split(',')
+        .map(|part| {
+            let Some((device, size)) = part.trim().split_once(':') else {
+                bail!("Invalid memory cap '{}', expected DEVICE:SIZE", part);
+            };
+            Ok((parse_device(device)?, parse_size(size)?))
+        })
+        .collect()
+}
+
+/// A byte count with an optional unit: `20GiB`, `512MB`, `1.5G`, `1048576`.
+/// Decimal (GB) and binary (GiB, G) units are both accepted.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("Invalid size '{}'", size))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        unit => bail!("Unknown size unit '{}' in '{}'", unit, size),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+pub fn format_size(bytes: u64) -> String {
+    if bytes >= 1 << 30 {
+        format!("{:.2} GiB", bytes as f64 / (1u64 << 30) as f64)
+    } else {
+        format!("{:.1} MiB", bytes as f64 / (1u64 << 20) as f64)
+    }
+}
+
+/// Free memory of every CUDA device as reported by `nvidia-smi`, which lists
+/// GPUs in PCI bus order (set `CUDA_DEVICE_ORDER=PCI_BUS_ID` so CUDA ordinals
+/// agree).
+pub fn free_cuda_memory() -> Result<Vec<(Device, u64)>> {
+    let output = std::process::Command::new("nvidia-smi")
+        .args(["--query-gpu=index,memory.free", "--format=csv,noheader,nounits"])
+        .output()
+        .context("Failed to run nvidia-smi to query free GPU memory (use --max-mem instead)")?;
+    if !output.status.success() {
+        bail!(
+            "nvidia-smi failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let Some((index, free_mib)) = line.split_once(',') else {
+                bail!("Unexpected nvidia-smi output: {}", line);
+            };
+            let device = Device::new_cuda(index.trim().parse()?)?;
+            Ok((device, free_mib.trim().parse::<u64>()? << 20))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layers(map: &DeviceMap) -> Vec<usize> {
+        map.stages().iter().map(|(_, layers)| *layers).collect()
+    }
+
+    fn parse_error(spec: &str, num_layers: usize) -> String {
+        format!("{:#}", DeviceMap::parse(spec, num_layers).unwrap_err())
+    }
+
+    #[test]
+    fn parses_stages_in_order() {
+        let map = DeviceMap::parse("cpu:3, cpu : 5", 8).unwrap();
+        assert_eq!(layers(&map), [3, 5]);
+        assert!(map.is_sharded());
+        assert!(map.cuda_ordinals().is_empty());
+        assert_eq!(map.to_string(), "layers 0-2 on Cpu, layers 3-7 on Cpu");
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        assert!(parse_error("cpu", 4).contains("expected DEVICE:LAYERS"));
+        assert!(parse_error("cpu:4,", 4).contains("expected DEVICE:LAYERS"));
+        assert!(parse_error("cpu:four", 4).contains("Invalid layer count in device map entry 'cpu:four'"));
+        assert!(parse_error("cpu:-1", 4).contains("Invalid layer count"));
+        assert!(parse_error("cpu:0,cpu:4", 4).contains("assigns no layers"));
+        assert!(DeviceMap::parse("gpu:4", 4).is_err());
+    }
+
+    #[test]
+    fn layer_counts_must_add_up() {
+        assert_eq!(
+            parse_error("cpu:2,cpu:1", 4),
+            "Device map assigns 3 layers but the model has 4"
+        );
+        assert_eq!(
+            parse_error("cpu:3,cpu:3", 4),
+            "Device map assigns 6 layers but the model has 4"
+        );
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("1048576").unwrap(), 1 << 20);
+        assert_eq!(parse_size("20GiB").unwrap(), 20 << 30);
+        assert_eq!(parse_size("1.5G").unwrap(), 3 << 29);
+        assert_eq!(parse_size("512MB").unwrap(), 512_000_000);
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("4XB").is_err());
+        assert_eq!(parse_memory_caps("cpu:1GiB").unwrap()[0].1, 1 << 30);
+        assert!(parse_memory_caps("cpu").is_err());
+    }
+}
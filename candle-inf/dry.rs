@@ -0,0 +1,187 @@
+// DRY ("Don't Repeat Yourself") repetition penalty.
+//
+// The classic repeat penalty lowers every token seen recently, which also
+// punishes the words a text has to repeat: articles, names, code keywords.
+// DRY only penalizes a token that would continue a sequence already in the
+// context: when the last n tokens occurred before, followed by token t, t's
+// logit is lowered by `multiplier * base^(n - allowed_length)` once n reaches
+// `allowed_length`, so the penalty grows exponentially with the length of
+// the repetition. Matches don't extend across sequence breakers (newlines,
+// colons, quotes, asterisks by default), so repeated structure such as list
+// markers or dialogue names is left alone.
+
+use crate::generation::GenerationConfig;
+use anyhow::Result;
+use candle_core::{DType, Tensor};
+use std::collections::{HashMap, HashSet};
+use tokenizers::Tokenizer;
+
+/// Matches are followed back at most this far; longer repetitions are
+/// penalized out of reach anyway.
+const MAX_MATCH: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct Dry {
+    multiplier: f32,
+    base: f32,
+    allowed_length: usize,
+    /// Tokens a match can't extend across.
+    breakers: HashSet<u32>,
+}
+
+impl Dry {
+    /// The DRY penalty `config` asks for, or `None` when its multiplier is 0.
+    pub fn new(config: &GenerationConfig, tokenizer: &Tokenizer) -> Result<Option<Self>> {
+        if config.dry_multiplier == 0. {
+            return Ok(None);
+        }
+        let mut breakers = HashSet::new();
+        for breaker in &config.dry_sequence_breakers {
+            // Encoded after a letter so that the breaker gets the token it
+            // has in running text, not at the start of one.
+            let encoding = tokenizer
+                .encode(format!("a{breaker}"), false)
+                .map_err(anyhow::Error::msg)?;
+            breakers.extend(encoding.get_ids().last());
+        }
+        Ok(Some(Self {
+            multiplier: config.dry_multiplier,
+            base: config.dry_base,
+            allowed_length: config.dry_allowed_length,
+            breakers,
+        }))
+    }
+
+    /// The penalty of every token that would extend a repetition of the end
+    /// of `tokens`.
+    pub fn penalties(&self, tokens: &[u32]) -> HashMap<u32, f32> {
+        let Some((&last, earlier)) = tokens.split_last() else {
+            return HashMap::new();
+        };
+        if self.breakers.contains(&last) {
+            return HashMap::new();
+        }
+        // The longest match found for each token that followed it.
+        let mut longest: HashMap<u32, usize> = HashMap::new();
+        for (i, _) in earlier
+            .iter()
+            .enumerate()
+            .filter(|(_, &token)| token == last)
+        {
+            let next = tokens[i + 1];
+            if self.breakers.contains(&next) {
+                continue;
+            }
+            let mut length = 1;
+            while length <= i && length < MAX_MATCH {
+                let token = tokens[i - length];
+                if token != tokens[tokens.len() - 1 - length] || self.breakers.contains(&token) {
+                    break;
+                }
+                length += 1;
+            }
+            let entry = longest.entry(next).or_default();
+            *entry = (*entry).max(length);
+        }
+        longest
+            .into_iter()
+            .filter(|&(_, length)| length >= self.allowed_length)
+            .map(|(token, length)| {
+                let excess = (length - self.allowed_length) as i32;
+                (token, self.multiplier * self.base.powi(excess))
+            })
+            .collect()
+    }
+
+    /// Subtract the penalties for the token following `tokens` from `logits`
+    /// (a 1-d tensor over the vocabulary).
+    pub fn apply(&self, logits: &Tensor, tokens: &[u32]) -> Result<Tensor> {
+        let penalties = self.penalties(tokens);
+        if penalties.is_empty() {
+            return Ok(logits.clone());
+        }
+        let mut values: Vec<f32> = logits.to_dtype(DType::F32)?.to_vec1()?;
+        for (token, penalty) in penalties {
+            if let Some(value) = values.get_mut(token as usize) {
+                *value -= penalty;
+            }
+        }
+        Ok(Tensor::new(values, logits.device())?.to_dtype(logits.dtype())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dry(allowed_length: usize, breakers: &[u32]) -> Dry {
+        Dry {
+            multiplier: 0.5,
+            base: 2.,
+            allowed_length,
+            breakers: breakers.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn penalizes_the_token_that_continues_a_repeat() {
+        // `1 2` repeats `1 2 3`: 3 would make it a match of length 3.
+        let penalties = dry(2, &[]).penalties(&[1, 2, 3, 1, 2]);
+        assert_eq!(penalties, HashMap::from([(3, 0.5)]));
+    }
+
+    #[test]
+    fn penalty_grows_with_the_match_length() {
+        // A match of 3 against `allowed_length` 2: 0.5 * 2^1.
+        let penalties = dry(2, &[]).penalties(&[1, 2, 3, 4, 1, 2, 3]);
+        assert_eq!(penalties, HashMap::from([(4, 1.)]));
+    }
+
+    #[test]
+    fn keeps_the_longest_match_per_token() {
+        // `2` is followed by 7 after a match of 1 and by 8 after `1 2`.
+        let penalties = dry(1, &[]).penalties(&[4, 2, 7, 1, 2, 8, 1, 2]);
+        assert_eq!(penalties, HashMap::from([(7, 0.5), (8, 1.)]));
+    }
+
+    #[test]
+    fn repeats_shorter_than_allowed_length_go_unpenalized() {
+        // `1` alone repeats, a match of 1.
+        assert!(dry(2, &[]).penalties(&[5, 1, 2, 6, 1]).is_empty());
+        assert_eq!(dry(1, &[]).penalties(&[5, 1, 2, 6, 1]), HashMap::from([(2, 0.5)]));
+        // `1 2` is a match of 2.
+        assert!(dry(3, &[]).penalties(&[1, 2, 3, 1, 2]).is_empty());
+    }
+
+    #[test]
+    fn matches_stop_at_a_breaker() {
+        let tokens = [1, 9, 2, 3, 1, 9, 2];
+        assert_eq!(dry(2, &[]).penalties(&tokens), HashMap::from([(3, 1.)]));
+        // With 9 a breaker the match is only `2`, too short to penalize.
+        assert!(dry(2, &[9]).penalties(&tokens).is_empty());
+        assert_eq!(dry(1, &[9]).penalties(&tokens), HashMap::from([(3, 0.5)]));
+    }
+
+    #[test]
+    fn breakers_are_never_penalized_or_matched_from() {
+        // The repeat would continue with the breaker 9.
+        assert!(dry(1, &[9]).penalties(&[1, 2, 9, 1, 2]).is_empty());
+        // The context ends on a breaker.
+        assert!(dry(1, &[9]).penalties(&[9, 1, 9]).is_empty());
+    }
+
+    #[test]
+    fn short_contexts_have_no_penalties() {
+        assert!(dry(1, &[]).penalties(&[]).is_empty());
+        assert!(dry(1, &[]).penalties(&[1]).is_empty());
+        assert!(dry(1, &[]).penalties(&[1, 2, 3]).is_empty());
+    }
+
+    #[test]
+    fn apply_subtracts_the_penalties_from_the_logits() -> Result<()> {
+        let logits = Tensor::new(&[1f32, 1., 1., 1., 1.], &candle_core::Device::Cpu)?;
+        let penalized = dry(2, &[]).apply(&logits, &[1, 2, 3, 4, 1, 2, 3])?;
+        assert_eq!(penalized.to_vec1::<f32>()?, [1., 1., 1., 1., 0.]);
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+// Energy use of a run, estimated from sampled GPU power draw.
+//
+// nvidia-smi reports each GPU's current board power. `PowerMonitor` keeps it
+// running in loop mode for the length of the measured work and integrates the
+// samples over time, assuming the draw changes linearly between samples. The
+// estimate covers the GPUs the model runs on only, not the CPU, RAM or the
+// rest of the machine.
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::process::{Child, Command, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Time between power samples.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Power readings (time, watts) by GPU index.
+type Samples = BTreeMap<usize, Vec<(Instant, f64)>>;
+
+pub struct PowerMonitor {
+    gpus: Vec<usize>,
+    start: Instant,
+    child: Child,
+    reader: JoinHandle<Result<Samples>>,
+}
+
+impl PowerMonitor {
+    /// Start sampling the power draw of the CUDA devices `gpus` (nvidia-smi
+    /// indices; set `CUDA_DEVICE_ORDER=PCI_BUS_ID` so they match the CUDA
+    /// ordinals).
+    pub fn start(gpus: &[usize]) -> Result<Self> {
+        if gpus.is_empty() {
+            bail!("No GPUs to measure the power draw of");
+        }
+        let ids: Vec<String> = gpus.iter().map(usize::to_string).collect();
+        let mut child = Command::new("nvidia-smi")
+            .arg(format!("--id={}", ids.join(",")))
+            .args([
+                "--query-gpu=index,power.draw",
+                "--format=csv,noheader,nounits",
+            ])
+            .arg(format!("--loop-ms={}", SAMPLE_INTERVAL.as_millis()))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .context("Failed to run nvidia-smi to sample GPU power draw")?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let reader = std::thread::spawn(move || {
+            let mut samples = Samples::new();
+            for line in std::io::BufReader::new(stdout).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let at = Instant::now();
+                let Some((index, watts)) = line.split_once(',') else {
+                    bail!("Unexpected nvidia-smi output: {}", line);
+                };
+                let index: usize = index.trim().parse()?;
+                let Ok(watts) = watts.trim().parse::<f64>() else {
+                    bail!(
+                        "GPU {} doesn't report its power draw ({})",
+                        index,
+                        watts.trim()
+                    );
+                };
+                samples.entry(index).or_default().push((at, watts));
+            }
+            Ok(samples)
+        });
+        Ok(Self {
+            gpus: gpus.to_vec(),
+            start: Instant::now(),
+            child,
+            reader,
+        })
+    }
+
+    /// Stop sampling and return the energy used since `start`.
+    pub fn stop(mut self) -> Result<EnergyReport> {
+        let end = Instant::now();
+        // Killing nvidia-smi closes its output, which ends the reader.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let samples = self
+            .reader
+            .join()
+            .map_err(|_| anyhow::anyhow!("The power sampling thread panicked"))??;
+
+        let mut joules = 0.;
+        for gpu in &self.gpus {
+            let Some(readings) = samples.get(gpu) else {
+                bail!("nvidia-smi reported no power draw for GPU {}", gpu);
+            };
+            joules += integrate(readings, self.start, end);
+        }
+        Ok(EnergyReport {
+            joules,
+            duration: end - self.start,
+            gpus: self.gpus.len(),
+            samples: samples.values().map(Vec::len).sum(),
+        })
+    }
+}
+
+/// Joules drawn between `start` and `end` by one GPU: the trapezoid rule
+/// between readings, holding the first and last readings at the edges.
+fn integrate(readings: &[(Instant, f64)], start: Instant, end: Instant) -> f64 {
+    let (first_at, first) = readings[0];
+    let (last_at, last) = readings[readings.len() - 1];
+    let mut joules = first * first_at.saturating_duration_since(start).as_secs_f64();
+    for pair in readings.windows(2) {
+        let ((t0, w0), (t1, w1)) = (pair[0], pair[1]);
+        joules += (w0 + w1) / 2. * (t1 - t0).as_secs_f64();
+    }
+    joules + last * end.saturating_duration_since(last_at).as_secs_f64()
+}
+
+#[derive(Debug, Clone)]
+pub struct EnergyReport {
+    pub joules: f64,
+    pub duration: Duration,
+    /// Number of GPUs measured.
+    pub gpus: usize,
+    /// Power readings taken, across all GPUs.
+    pub samples: usize,
+}
+
+impl EnergyReport {
+    pub fn watt_hours(&self) -> f64 {
+        self.joules / 3600.
+    }
+
+    /// Mean total draw of the measured GPUs.
+    pub fn average_watts(&self) -> f64 {
+        self.joules / self.duration.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Cost of generating 1000 tokens at this rate, with electricity at
+    /// `price_per_kwh`. `None` if no tokens were generated.
+    pub fn cost_per_1k_tokens(&self, tokens: usize, price_per_kwh: f64) -> Option<f64> {
+        (tokens > 0).then(|| self.watt_hours() / 1000. * price_per_kwh / tokens as f64 * 1000.)
+    }
+}
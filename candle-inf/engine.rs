@@ -0,0 +1,162 @@
+// Process-wide registry of loaded models.
+//
+// An application embedding the library may have several components that each
+// need "the 13B model on cuda:0". Loading it once per component would hold
+// several copies of the weights; the registry loads each model spec once and
+// hands out `Arc` handles to it. Concurrent requests for a spec that is still
+// loading wait for that load instead of starting another. Evicting an entry
+// only drops the registry's handle: the weights are freed once every
+// component has dropped its own.
+
+use crate::arch::Arch;
+use crate::flash_attn::FlashAttn;
+use crate::llama::KvCacheDtype;
+use crate::loader::{Model, ModelLoader, Quantization};
+use anyhow::Result;
+use candle_core::{DType, Device, DeviceLocation};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// What makes two loads produce the same model: the source, weights, adapter
+/// and placement, but not download settings such as retries.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct EngineKey {
+    model_id: String,
+    local: bool,
+    revision: Option<String>,
+    hub_endpoint: Option<String>,
+    quantized: Option<String>,
+    arch: Option<Arch>,
+    lora: Vec<String>,
+    quantize: Option<Quantization>,
+    kv_cache_dtype: Option<KvCacheDtype>,
+    flash_attn: FlashAttn,
+    dtype: DType,
+    device: DeviceLocation,
+}
+
+impl EngineKey {
+    fn new(loader: &ModelLoader, dtype: DType, device: &Device) -> Self {
+        // `./model` and `model` are the same local directory.
+        let model_id = match std::fs::canonicalize(&loader.model_id) {
+            Ok(path) if loader.local => path.display().to_string(),
+            _ => loader.model_id.clone(),
+        };
+        Self {
+            model_id,
+            local: loader.local,
+            revision: loader.revision.clone(),
+            hub_endpoint: loader.hub_endpoint.clone(),
+            quantized: loader.quantized.clone(),
+            arch: loader.arch,
+            lora: loader.lora.clone(),
+            quantize: loader.quantize,
+            kv_cache_dtype: loader.kv_cache_dtype,
+            flash_attn: loader.flash_attn,
+            dtype,
+            device: device.location(),
+        }
+    }
+}
+
+/// One registered model; its lock is held while it loads.
+type Slot = Arc<Mutex<Option<Arc<Model>>>>;
+
+#[derive(Default)]
+pub struct EngineRegistry {
+    slots: Mutex<HashMap<EngineKey, Slot>>,
+}
+
+impl EngineRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The registry shared by the whole process.
+    pub fn global() -> &'static EngineRegistry {
+        static GLOBAL: OnceLock<EngineRegistry> = OnceLock::new();
+        GLOBAL.get_or_init(EngineRegistry::new)
+    }
+
+    /// A handle to the model `loader` resolves to, loading it on first use.
+    /// A failed load is not remembered, so the next call tries again.
+    pub fn get(&self, loader: &ModelLoader, dtype: DType, device: &Device) -> Result<Arc<Model>> {
+        let slot = self
+            .slots
+            .lock()
+            .unwrap()
+            .entry(EngineKey::new(loader, dtype, device))
+            .or_default()
+            .clone();
+        // Only this model's slot stays locked during the load, so other
+        // models can be fetched or loaded meanwhile.
+        let mut model = slot.lock().unwrap();
+        if let Some(model) = model.as_ref() {
+            return Ok(model.clone());
+        }
+        match loader.load(dtype, device) {
+            Ok(loaded) => {
+                let loaded = Arc::new(loaded);
+                *model = Some(loaded.clone());
+                Ok(loaded)
+            }
+            Err(err) => {
+                drop(model);
+                // Unregister the empty slot unless another caller is waiting
+                // to retry the load in it.
+                let mut slots = self.slots.lock().unwrap();
+                if Arc::strong_count(&slot) == 2 {
+                    slots.retain(|_, other| !Arc::ptr_eq(other, &slot));
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Whether the model is loaded (or loading).
+    pub fn contains(&self, loader: &ModelLoader, dtype: DType, device: &Device) -> bool {
+        let key = EngineKey::new(loader, dtype, device);
+        self.slots.lock().unwrap().contains_key(&key)
+    }
+
+    /// Remove a model from the registry, so the next `get` loads it again.
+    /// Returns whether it was registered.
+    pub fn evict(&self, loader: &ModelLoader, dtype: DType, device: &Device) -> bool {
+        let key = EngineKey::new(loader, dtype, device);
+        self.slots.lock().unwrap().remove(&key).is_some()
+    }
+
+    /// Remove the models no handle outside the registry refers to, freeing
+    /// their weights. Returns how many were removed.
+    pub fn evict_unused(&self) -> usize {
+        let mut slots = self.slots.lock().unwrap();
+        let before = slots.len();
+        slots.retain(|_, slot| {
+            // Someone is loading or waiting for this model.
+            if Arc::strong_count(slot) > 1 {
+                return true;
+            }
+            let Ok(model) = slot.try_lock() else {
+                return true;
+            };
+            model
+                .as_ref()
+                .is_some_and(|model| Arc::strong_count(model) > 1)
+        });
+        before - slots.len()
+    }
+
+    /// Remove every model.
+    pub fn clear(&self) {
+        self.slots.lock().unwrap().clear();
+    }
+
+    /// Number of registered models.
+    pub fn len(&self) -> usize {
+        self.slots.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
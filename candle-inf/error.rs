@@ -0,0 +1,97 @@
+// Errors a program embedding the library can act on.
+//
+// Most failures are reported as `anyhow` errors with a message for the user.
+// The ones below are also worth telling apart in code (asking for a token,
+// offering a smaller model, falling back to another architecture), so
+// `ModelLoader`, the Hub client and the memory pre-flight return them as an
+// `Sl5Error` inside the `anyhow::Error`. Match on them with
+// `err.downcast_ref::<Sl5Error>()`, which also finds one under added context.
+
+use crate::device_map::format_size;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sl5Error {
+    /// No model under this Hub id, revision or local directory.
+    ModelNotFound { model: String },
+    /// A 401 or 403 from the Hub: the repo is gated or private and the
+    /// request had no token, or one without access.
+    GatedRepo {
+        repo: String,
+        url: String,
+        status: u16,
+        had_token: bool,
+    },
+    /// The tokenizer's vocabulary is larger than the model's embeddings, so
+    /// it belongs to another model.
+    TokenizerMismatch {
+        tokenizer_vocab: usize,
+        model_vocab: usize,
+    },
+    /// config.json names a `model_type` the library can't run.
+    UnsupportedArch { model_type: String },
+    /// A device lacks room for its share of the run, in bytes.
+    OutOfMemory { needed: u64, available: u64 },
+}
+
+impl std::fmt::Display for Sl5Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sl5Error::ModelNotFound { model } => write!(
+                f,
+                "Model {} not found: check the model ID and revision (or the path, with --local)",
+                model
+            ),
+            Sl5Error::GatedRepo {
+                repo,
+                url,
+                status,
+                had_token: false,
+            } => write!(
+                f,
+                "{} is gated or private and needs an access token (HTTP {}): accept its terms at {} \
+                 if it is gated, then pass --hf-token, set HF_TOKEN or run `huggingface-cli login`",
+                repo, status, url
+            ),
+            Sl5Error::GatedRepo {
+                repo, status: 401, ..
+            } => write!(
+                f,
+                "The access token was rejected for {} (HTTP 401): check that it is complete and \
+                 hasn't been revoked",
+                repo
+            ),
+            Sl5Error::GatedRepo {
+                repo, url, status, ..
+            } => write!(
+                f,
+                "The access token has no access to {} (HTTP {}): accept its terms at {} with the \
+                 token's account (access to gated models can take a while to be granted) and check \
+                 that the token has read access",
+                repo, status, url
+            ),
+            Sl5Error::TokenizerMismatch {
+                tokenizer_vocab,
+                model_vocab,
+            } => write!(
+                f,
+                "tokenizer.json has {} tokens but the model only has embeddings for {}: the \
+                 tokenizer belongs to another model",
+                tokenizer_vocab, model_vocab
+            ),
+            Sl5Error::UnsupportedArch { model_type } => write!(
+                f,
+                "Unsupported model_type '{}' in config.json (supported: llama, mistral, mixtral, \
+                 qwen2, phi3, gemma, gemma2, stablelm, falcon; use --arch to override)",
+                model_type
+            ),
+            Sl5Error::OutOfMemory { needed, available } => write!(
+                f,
+                "Out of memory: the model needs about {} but only {} is free",
+                format_size(*needed),
+                format_size(*available)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Sl5Error {}
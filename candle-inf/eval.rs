@@ -0,0 +1,156 @@
+// Model quality evaluation.
+//
+// `perplexity` scores a model on a text with a sliding window, for comparing
+// dtypes, quantization levels and architectures on the same data.
+//
+// `quant_diff` runs a full-precision model and a quantized copy over the same
+// text and compares their next-token distributions: the KL divergence shows
+// how far the quantized distribution drifts, the perplexity delta what that
+// costs on real text, and top-1 agreement how often greedy decoding would
+// pick a different token.
+
+use crate::loader::Model;
+use anyhow::{bail, Result};
+use candle_core::D;
+
+/// A model's perplexity on a text.
+#[derive(Debug, Clone)]
+pub struct Perplexity {
+    /// Next-token predictions scored.
+    pub tokens: usize,
+    pub windows: usize,
+    /// Mean negative log-likelihood per token, in nats.
+    pub mean_nll: f64,
+    pub perplexity: f64,
+}
+
+/// Score `model` on `tokens` with a sliding window of `context` tokens that
+/// advances by `stride`. Each window starts from an empty cache and only its
+/// tokens past the previous window's end are scored, so every token after
+/// the first is scored once with up to `context - stride` tokens of context
+/// before it. `progress` is called after each window with the tokens scored
+/// so far and the running perplexity.
+pub fn perplexity(
+    model: &Model,
+    tokens: &[u32],
+    context: usize,
+    stride: usize,
+    mut progress: impl FnMut(usize, f64),
+) -> Result<Perplexity> {
+    if context < 2 {
+        bail!("The context must hold at least 2 tokens");
+    }
+    if stride == 0 || stride > context {
+        bail!("The stride must be between 1 and the context ({})", context);
+    }
+    if tokens.len() < 2 {
+        bail!("The text must contain at least 2 tokens");
+    }
+    let (mut nll, mut scored, mut windows) = (0f64, 0usize, 0usize);
+    let mut begin = 0;
+    loop {
+        let end = (begin + context).min(tokens.len());
+        // The first target of the window; the tokens before it are context.
+        let first = scored.max(begin) + 1;
+        let mut cache = model.new_cache(true)?;
+        for target in first..end {
+            let logits = if target == first {
+                model.forward_logits(&tokens[begin..first], 0, &mut cache)?
+            } else {
+                let pos = target - 1 - begin;
+                model.forward_logits(&tokens[target - 1..target], pos, &mut cache)?
+            };
+            nll -= log_probs(logits)?[tokens[target] as usize] as f64;
+            scored += 1;
+        }
+        windows += 1;
+        progress(scored, (nll / scored as f64).exp());
+        if end == tokens.len() {
+            break;
+        }
+        begin += stride;
+    }
+    let mean_nll = nll / scored as f64;
+    Ok(Perplexity {
+        tokens: scored,
+        windows,
+        mean_nll,
+        perplexity: mean_nll.exp(),
+    })
+}
+
+/// How much a quantized model deviates from its base model.
+#[derive(Debug, Clone)]
+pub struct QuantDiff {
+    /// Next-token predictions compared.
+    pub tokens: usize,
+    pub base_perplexity: f64,
+    pub quant_perplexity: f64,
+    /// KL(base || quant) per token, in nats.
+    pub mean_kl: f64,
+    pub p99_kl: f64,
+    pub max_kl: f64,
+    /// Fraction of positions where both models rank the same token first.
+    pub top1_agreement: f64,
+}
+
+/// Compare `quant` against `base` on `tokens`, in independent windows of
+/// `window` tokens (each starting from an empty cache).
+pub fn quant_diff(base: &Model, quant: &Model, tokens: &[u32], window: usize) -> Result<QuantDiff> {
+    if window < 2 {
+        bail!("The evaluation window must hold at least 2 tokens");
+    }
+    let mut kls = Vec::new();
+    let (mut base_nll, mut quant_nll, mut agree) = (0f64, 0f64, 0usize);
+    for chunk in tokens.chunks(window).filter(|chunk| chunk.len() > 1) {
+        let mut base_cache = base.new_cache(true)?;
+        let mut quant_cache = quant.new_cache(true)?;
+        for (pos, pair) in chunk.windows(2).enumerate() {
+            let p = log_probs(base.forward_logits(&pair[..1], pos, &mut base_cache)?)?;
+            let q = log_probs(quant.forward_logits(&pair[..1], pos, &mut quant_cache)?)?;
+            if p.len() != q.len() {
+                bail!("Vocabulary sizes differ: {} vs {}", p.len(), q.len());
+            }
+            let target = pair[1] as usize;
+            base_nll -= p[target] as f64;
+            quant_nll -= q[target] as f64;
+            kls.push(
+                p.iter()
+                    .zip(&q)
+                    .map(|(&lp, &lq)| (lp.exp() * (lp - lq)) as f64)
+                    .sum::<f64>(),
+            );
+            if argmax(&p) == argmax(&q) {
+                agree += 1;
+            }
+        }
+    }
+    if kls.is_empty() {
+        bail!("The text must contain at least 2 tokens");
+    }
+
+    let n = kls.len();
+    let mean_kl = kls.iter().sum::<f64>() / n as f64;
+    kls.sort_by(f64::total_cmp);
+    Ok(QuantDiff {
+        tokens: n,
+        base_perplexity: (base_nll / n as f64).exp(),
+        quant_perplexity: (quant_nll / n as f64).exp(),
+        mean_kl,
+        p99_kl: kls[(n * 99 / 100).min(n - 1)],
+        max_kl: kls[n - 1],
+        top1_agreement: agree as f64 / n as f64,
+    })
+}
+
+fn log_probs(logits: candle_core::Tensor) -> Result<Vec<f32>> {
+    Ok(candle_nn::ops::log_softmax(&logits, D::Minus1)?.to_vec1()?)
+}
+
+fn argmax(values: &[f32]) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map_or(0, |(i, _)| i)
+}
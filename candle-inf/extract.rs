@@ -0,0 +1,90 @@
+// Post-processing of the final generated text (--extract).
+//
+// Pipelines that feed completions to another program usually only want part
+// of them: the code in a fenced block, a JSON object, or a field matched by a
+// pattern. Stop sequences are already removed during generation; extractors
+// run on the finished text, in the order given, each on the previous result.
+
+use anyhow::{bail, Result};
+use regex::Regex;
+use std::str::FromStr;
+
+#[derive(Debug, Clone)]
+pub enum Extractor {
+    /// Strip leading and trailing whitespace.
+    Trim,
+    /// Contents of the first fenced (```) code block; an unterminated block
+    /// (generation stopped inside it) runs to the end of the text.
+    Code,
+    /// The first complete JSON object or array.
+    Json,
+    /// The first match of a pattern, or of its first capture group if it has
+    /// one.
+    Regex(Regex),
+}
+
+impl FromStr for Extractor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "trim" => Self::Trim,
+            "code" => Self::Code,
+            "json" => Self::Json,
+            _ => match s.strip_prefix("regex:") {
+                Some(pattern) => Self::Regex(Regex::new(pattern)?),
+                None => bail!(
+                    "Unknown extractor {:?} (expected trim, code, json or regex:<pattern>)",
+                    s
+                ),
+            },
+        })
+    }
+}
+
+impl Extractor {
+    /// The extracted part of `text`, or `None` if it has none.
+    pub fn apply(&self, text: &str) -> Option<String> {
+        match self {
+            Self::Trim => Some(text.trim().to_string()),
+            Self::Code => extract_code(text).map(str::to_string),
+            Self::Json => extract_json(text).map(str::to_string),
+            Self::Regex(regex) => {
+                let captures = regex.captures(text)?;
+                let matched = captures.get(1).or_else(|| captures.get(0))?;
+                Some(matched.as_str().to_string())
+            }
+        }
+    }
+}
+
+/// Run `extractors` in order; `None` as soon as one finds nothing.
+pub fn extract(extractors: &[Extractor], text: &str) -> Option<String> {
+    extractors
+        .iter()
+        .try_fold(text.to_string(), |text, extractor| extractor.apply(&text))
+}
+
+fn extract_code(text: &str) -> Option<&str> {
+    let start = text.find("```")?;
+    // Skip the language tag on the opening fence.
+    let body = &text[start + 3..];
+    let body = &body[body.find('\n')? + 1..];
+    let end = body.find("```").unwrap_or(body.len());
+    Some(
+        body[..end]
+            .trim_end_matches([' ', '\t'])
+            .trim_end_matches('\n'),
+    )
+}
+
+fn extract_json(text: &str) -> Option<&str> {
+    for (start, _) in text.match_indices(['{', '[']) {
+        let mut values =
+            serde_json::Deserializer::from_str(&text[start..]).into_iter::<serde_json::Value>();
+        if let Some(Ok(_)) = values.next() {
+            return Some(&text[start..start + values.byte_offset()]);
+        }
+    }
+    None
+}
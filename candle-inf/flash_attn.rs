@@ -0,0 +1,108 @@
+// Flash attention: whether a run can use candle's flash-attn kernels.
+//
+// The kernels are only built with the `flash-attn` feature (which needs CUDA)
+// and only run on Ampere or newer GPUs (compute capability 8.0+) in f16 or
+// bf16. `--flash-attn auto` uses them whenever that holds, `on` asks for them
+// and falls back to the standard attention with a warning when it doesn't,
+// and `off` never uses them.
+
+use crate::device_map::DeviceMap;
+use candle_core::DType;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+/// Oldest compute capability the kernels support (Ampere).
+const MIN_COMPUTE_CAPABILITY: (u32, u32) = (8, 0);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FlashAttn {
+    /// Use flash attention when the build, GPUs and dtype support it.
+    #[default]
+    Auto,
+    /// Use flash attention, warning and falling back when it can't run.
+    On,
+    /// Always use the standard attention.
+    Off,
+}
+
+/// The attention implementation a model runs with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Attention {
+    Standard,
+    Flash,
+}
+
+impl Attention {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Attention::Standard => "standard",
+            Attention::Flash => "flash",
+        }
+    }
+}
+
+impl std::fmt::Display for Attention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why the flash-attn kernels can't run the layers of `device_map` in
+/// `dtype`, or `None` if they can.
+pub fn unsupported(dtype: DType, device_map: &DeviceMap) -> Option<String> {
+    if !cfg!(feature = "flash-attn") {
+        return Some("this build doesn't include the flash-attn feature".to_string());
+    }
+    if device_map.stages().iter().any(|(device, _)| !device.is_cuda()) {
+        return Some("flash attention only runs on CUDA GPUs".to_string());
+    }
+    if !matches!(dtype, DType::F16 | DType::BF16) {
+        return Some(format!("flash attention needs f16 or bf16, not {:?}", dtype));
+    }
+    // Without nvidia-smi the capability is unknown; the kernels themselves
+    // fail loudly on a GPU they don't support.
+    let capabilities = compute_capabilities();
+    for ordinal in device_map.cuda_ordinals() {
+        match capabilities.get(&ordinal) {
+            Some(&(major, minor)) if (major, minor) < MIN_COMPUTE_CAPABILITY => {
+                return Some(format!(
+                    "GPU {} has compute capability {}.{}, flash attention needs {}.{} (Ampere) or newer",
+                    ordinal, major, minor, MIN_COMPUTE_CAPABILITY.0, MIN_COMPUTE_CAPABILITY.1
+                ));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Compute capability of each GPU by nvidia-smi index (set
+/// `CUDA_DEVICE_ORDER=PCI_BUS_ID` so they match the CUDA ordinals); empty if
+/// nvidia-smi isn't available. Queried once per process.
+fn compute_capabilities() -> &'static HashMap<usize, (u32, u32)> {
+    static CAPABILITIES: OnceLock<HashMap<usize, (u32, u32)>> = OnceLock::new();
+    CAPABILITIES.get_or_init(|| {
+        let Ok(output) = Command::new("nvidia-smi")
+            .args(["--query-gpu=index,compute_cap", "--format=csv,noheader"])
+            .stderr(Stdio::null())
+            .output()
+        else {
+            return HashMap::new();
+        };
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let (index, capability) = line.split_once(',')?;
+                let (major, minor) = capability.trim().split_once('.')?;
+                Some((
+                    index.trim().parse().ok()?,
+                    (major.parse().ok()?, minor.parse().ok()?),
+                ))
+            })
+            .collect()
+    })
+}
@@ -0,0 +1,1121 @@
+// The token-by-token decoding loop.
+//
+// `Generator` owns the KV cache and sampler for one sequence and advances it
+// one token per `step`, so callers decide how output is shown and when to stop
+// early (budgets, Ctrl-C) without reimplementing the loop.
+
+use crate::bias::LogitBias;
+use crate::chat::is_special;
+use crate::dry::Dry;
+use crate::llama::Capture;
+use crate::loader::{Model, ModelCache};
+use crate::sampling::Truncation;
+use crate::session::Session;
+use crate::stop::StopMatcher;
+use crate::tokenize::DecodeStream;
+use crate::watermark::Watermark;
+use anyhow::{bail, Result};
+use candle_core::{Tensor, D};
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Sampling and stopping settings for a generation.
+#[derive(Debug, Clone, Serialize)]
+pub struct GenerationConfig {
+    /// Maximum number of tokens to generate.
+    pub max_new_tokens: usize,
+    /// Sampling temperature; 0 samples greedily.
+    pub temperature: f64,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    /// Typical or top-n-sigma truncation, applied before top-k/top-p.
+    pub truncation: Option<Truncation>,
+    pub seed: u64,
+    /// Penalty for repeating tokens (1.0 = no penalty).
+    pub repeat_penalty: f32,
+    /// Number of trailing tokens the repeat penalty looks at.
+    pub repeat_last_n: usize,
+    /// Which tokens `repeat_last_n` counts back through.
+    pub repeat_scope: RepeatScope,
+    /// Scale of the DRY penalty on tokens that would continue a repeated
+    /// sequence (0 = off).
+    pub dry_multiplier: f32,
+    /// How fast the DRY penalty grows with the length of the repetition.
+    pub dry_base: f32,
+    /// Repetitions shorter than this many tokens aren't penalized by DRY.
+    pub dry_allowed_length: usize,
+    /// Text that DRY's repeated sequences can't extend across.
+    pub dry_sequence_breakers: Vec<String>,
+    /// Bias added to the logits of these token ids before sampling
+    /// (`-inf` forbids the token).
+    #[serde(serialize_with = "crate::bias::serialize_logit_bias")]
+    pub logit_bias: BTreeMap<u32, f32>,
+    /// Words that must not appear in the output.
+    pub ban_words: Vec<String>,
+    /// Generation stops once the output contains any of these.
+    pub stop_sequences: Vec<String>,
+    pub use_kv_cache: bool,
+    /// What to do once the sequence fills the context window.
+    pub kv_eviction: KvEviction,
+    /// Leading tokens `KvEviction::Sink` never evicts.
+    pub sink_tokens: usize,
+    /// What to do when the prompt and `max_new_tokens` don't fit in the
+    /// context window together.
+    pub context_overflow: ContextOverflow,
+    /// Feed the prompt to the model this many tokens per forward pass,
+    /// bounding activation memory for long prompts (all at once if `None`).
+    pub prefill_chunk: Option<usize>,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        Self {
+            max_new_tokens: 128,
+            temperature: 0.8,
+            top_p: None,
+            top_k: None,
+            truncation: None,
+            seed: 299792458,
+            repeat_penalty: 1.1,
+            repeat_last_n: 128,
+            repeat_scope: RepeatScope::All,
+            dry_multiplier: 0.,
+            dry_base: 1.75,
+            dry_allowed_length: 2,
+            dry_sequence_breakers: ["\n", ":", "\"", "*"].map(String::from).to_vec(),
+            logit_bias: BTreeMap::new(),
+            ban_words: Vec::new(),
+            stop_sequences: Vec::new(),
+            use_kv_cache: true,
+            kv_eviction: KvEviction::None,
+            sink_tokens: 4,
+            context_overflow: ContextOverflow::ReduceMaxTokens,
+            prefill_chunk: None,
+        }
+    }
+}
+
+/// The tokens the repeat penalty is taken over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatScope {
+    /// The last `repeat_last_n` tokens of the prompt.
+    Prompt,
+    /// The last `repeat_last_n` generated tokens.
+    Generated,
+    /// The last `repeat_last_n` tokens of prompt and output together, as
+    /// llama.cpp and mistral.rs do.
+    #[default]
+    All,
+}
+
+/// How generation continues once the KV cache holds as many positions as
+/// the model's context window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum KvEviction {
+    /// It doesn't: generation stops with `FinishReason::ContextFull`.
+    #[default]
+    None,
+    /// Drop the oldest half of the cache and carry on with the rest, like
+    /// llama.cpp's context shift.
+    Window,
+    /// As `Window`, but always keep the first `sink_tokens`, which attention
+    /// leans on heavily (StreamingLLM's attention sinks).
+    Sink,
+}
+
+/// What happens when the prompt and the tokens to generate don't fit in the
+/// context window together (with KV eviction, when the prompt alone doesn't).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContextOverflow {
+    /// Refuse the prompt before generating anything.
+    Error,
+    /// Drop the start of the prompt, keeping its leading special tokens
+    /// (BOS).
+    TruncateLeft,
+    /// Drop the middle of the prompt, keeping its start (the system prompt)
+    /// and its end (the latest turns). See `ChatTemplate::render_within` for
+    /// dropping whole turns of a conversation.
+    TruncateMiddle,
+    /// Generate fewer tokens: generation stops with
+    /// `FinishReason::ContextFull` once the window is full. A prompt longer
+    /// than the window is still refused.
+    #[default]
+    ReduceMaxTokens,
+}
+
+impl ContextOverflow {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContextOverflow::Error => "error",
+            ContextOverflow::TruncateLeft => "truncate-left",
+            ContextOverflow::TruncateMiddle => "truncate-middle",
+            ContextOverflow::ReduceMaxTokens => "reduce-max-tokens",
+        }
+    }
+}
+
+impl GenerationConfig {
+    /// Reject settings that are out of range or contradict each other, which
+    /// would otherwise be silently ignored or fail halfway through a run.
+    pub fn validate(&self) -> Result<()> {
+        if !self.temperature.is_finite() || self.temperature < 0. {
+            bail!(
+                "temperature must be 0 (greedy) or positive, got {}",
+                self.temperature
+            );
+        }
+        if let Some(p) = self.top_p {
+            if !(p > 0. && p <= 1.) {
+                bail!("top_p must be in (0, 1], got {} (1 disables nucleus sampling)", p);
+            }
+        }
+        if self.top_k == Some(0) {
+            bail!("top_k must be at least 1 (leave it unset to sample from the whole vocabulary)");
+        }
+        if self.temperature == 0. && (self.top_p.is_some() || self.top_k.is_some()) {
+            bail!(
+                "top_p/top_k have no effect with temperature 0, which always picks the most \
+                 likely token; set a temperature above 0 to sample, or drop top_p/top_k"
+            );
+        }
+        if let Some(truncation) = &self.truncation {
+            truncation.validate()?;
+            if self.temperature == 0. {
+                bail!(
+                    "{} sampling has no effect with temperature 0, which always picks the most \
+                     likely token",
+                    truncation.name()
+                );
+            }
+        }
+        if !self.repeat_penalty.is_finite() || self.repeat_penalty <= 0. {
+            bail!(
+                "repeat_penalty must be positive (1.0 disables it), got {}",
+                self.repeat_penalty
+            );
+        }
+        if self.repeat_penalty != 1. && self.repeat_last_n == 0 {
+            bail!("repeat_penalty {} has no effect with repeat_last_n 0", self.repeat_penalty);
+        }
+        if !self.dry_multiplier.is_finite() || self.dry_multiplier < 0. {
+            bail!(
+                "dry_multiplier must be 0 (off) or positive, got {}",
+                self.dry_multiplier
+            );
+        }
+        if self.dry_multiplier > 0. {
+            if !self.dry_base.is_finite() || self.dry_base <= 1. {
+                bail!("dry_base must be greater than 1, got {}", self.dry_base);
+            }
+            if self.dry_allowed_length == 0 {
+                bail!("dry_allowed_length must be at least 1");
+            }
+            if let Some(index) = self.dry_sequence_breakers.iter().position(String::is_empty) {
+                bail!("DRY sequence breaker {} is empty", index + 1);
+            }
+        }
+        if let Some((id, bias)) = self
+            .logit_bias
+            .iter()
+            .find(|(_, &bias)| bias.is_nan() || bias == f32::INFINITY)
+        {
+            bail!("Logit bias of token {} must be a number or -inf, got {}", id, bias);
+        }
+        if let Some(index) = self.ban_words.iter().position(|word| word.trim().is_empty()) {
+            bail!("Banned word {} is empty", index + 1);
+        }
+        if let Some(index) = self.stop_sequences.iter().position(String::is_empty) {
+            bail!("Stop sequence {} is empty and would match immediately", index + 1);
+        }
+        if self.kv_eviction != KvEviction::None && !self.use_kv_cache {
+            bail!("KV eviction needs the KV cache");
+        }
+        if self.prefill_chunk == Some(0) {
+            bail!("prefill_chunk must be at least 1 token");
+        }
+        if self.prefill_chunk.is_some() && !self.use_kv_cache {
+            bail!("Chunked prefill needs the KV cache");
+        }
+        Ok(())
+    }
+
+    /// `validate`, plus the limits of a model with a `context_len`-token
+    /// context window.
+    pub fn validate_for_context(&self, context_len: usize) -> Result<()> {
+        self.validate()?;
+        if self.kv_eviction == KvEviction::None {
+            match self.context_overflow {
+                ContextOverflow::Error if self.max_new_tokens > context_len => bail!(
+                    "Can't generate {} tokens with a {}-token context window",
+                    self.max_new_tokens,
+                    context_len
+                ),
+                ContextOverflow::TruncateLeft | ContextOverflow::TruncateMiddle
+                    if self.max_new_tokens >= context_len =>
+                {
+                    bail!(
+                        "Generating {} tokens leaves no room for the prompt in a {}-token \
+                         context window",
+                        self.max_new_tokens,
+                        context_len
+                    )
+                }
+                _ => {}
+            }
+        }
+        if self.repeat_last_n > context_len {
+            bail!(
+                "repeat_last_n {} is larger than the {}-token context window",
+                self.repeat_last_n,
+                context_len
+            );
+        }
+        if self.kv_eviction == KvEviction::Sink && self.sink_tokens >= context_len / 2 {
+            bail!(
+                "{} sink tokens leave too little of the {}-token context window to evict",
+                self.sink_tokens,
+                context_len
+            );
+        }
+        Ok(())
+    }
+
+    /// How many prompt tokens fit in a `context_len`-token window next to
+    /// the tokens to generate (all of it with KV eviction, which makes room
+    /// for them as they come).
+    pub fn prompt_room(&self, context_len: usize) -> usize {
+        match self.kv_eviction {
+            KvEviction::None => context_len.saturating_sub(self.max_new_tokens),
+            _ => context_len,
+        }
+    }
+
+    /// Make `prompt_tokens` fit in a `context_len`-token window as
+    /// `context_overflow` says. Returns the number of prompt tokens dropped.
+    fn fit_prompt(
+        &self,
+        prompt_tokens: &mut Vec<u32>,
+        context_len: usize,
+        tokenizer: &tokenizers::Tokenizer,
+    ) -> Result<usize> {
+        let room = self.prompt_room(context_len);
+        let excess = prompt_tokens.len().saturating_sub(room);
+        if excess == 0 {
+            return Ok(0);
+        }
+        match self.context_overflow {
+            ContextOverflow::Error => bail!(
+                "The prompt has {} tokens, but only {} fit in the model's {}-token context \
+                 window next to the {} to generate (context_overflow truncate-left or \
+                 truncate-middle shortens the prompt, reduce-max-tokens generates fewer)",
+                prompt_tokens.len(),
+                room,
+                context_len,
+                self.max_new_tokens
+            ),
+            ContextOverflow::ReduceMaxTokens if prompt_tokens.len() > context_len => bail!(
+                "The prompt has {} tokens, more than the model's {}-token context window",
+                prompt_tokens.len(),
+                context_len
+            ),
+            ContextOverflow::ReduceMaxTokens => return Ok(0),
+            ContextOverflow::TruncateLeft => {
+                // The BOS token (and any other leading special tokens) stays:
+                // models behave erratically without it.
+                let head = prompt_tokens
+                    .iter()
+                    .take_while(|&&id| is_special(tokenizer, id))
+                    .count()
+                    .min(room / 2);
+                prompt_tokens.drain(head..head + excess);
+            }
+            ContextOverflow::TruncateMiddle => {
+                let head = room / 2;
+                prompt_tokens.drain(head..head + excess);
+            }
+        }
+        Ok(excess)
+    }
+
+    pub fn sampling(&self) -> Sampling {
+        if self.temperature <= 0. {
+            return Sampling::ArgMax;
+        }
+        let temperature = self.temperature;
+        match (self.top_k, self.top_p) {
+            (None, None) => Sampling::All { temperature },
+            (Some(k), None) => Sampling::TopK { k, temperature },
+            (None, Some(p)) => Sampling::TopP { p, temperature },
+            (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+        }
+    }
+}
+
+/// Why generation stopped.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FinishReason {
+    /// The model produced an end-of-sequence token.
+    Eos,
+    /// The output reached one of the stop sequences.
+    StopSequence(String),
+    /// `max_new_tokens` (or a caller's token budget) was reached.
+    Length,
+    /// The caller's time budget ran out.
+    Timeout,
+    /// The sequence filled the model's context window.
+    ContextFull,
+    /// The user interrupted generation (Ctrl-C).
+    Cancelled,
+}
+
+impl FinishReason {
+    /// The reason without its details, e.g. for machine-readable output.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FinishReason::Eos => "eos",
+            FinishReason::StopSequence(_) => "stop_sequence",
+            FinishReason::Length => "length",
+            FinishReason::Timeout => "timeout",
+            FinishReason::ContextFull => "context_full",
+            FinishReason::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl std::fmt::Display for FinishReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FinishReason::StopSequence(stop) => write!(f, "stop_sequence ({:?})", stop),
+            reason => f.write_str(reason.as_str()),
+        }
+    }
+}
+
+/// One generated token.
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub token: u32,
+    /// Output that became visible with this token. Empty while text that may
+    /// start a stop sequence is held back, longer when it is released.
+    pub text: String,
+    /// Set when the generator was built `with_trace`.
+    pub trace: Option<TokenTrace>,
+    /// Set when the generator was built `with_logprobs`.
+    pub logprobs: Option<TokenLogprobs>,
+}
+
+/// A token and its log-probability under the model's own distribution:
+/// the raw logits, before penalties, guidance, temperature and filters.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenLogprob {
+    pub token: u32,
+    /// The token's own text, special tokens included.
+    pub text: String,
+    pub logprob: f32,
+}
+
+/// The sampled token with the most likely alternatives at its position.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenLogprobs {
+    #[serde(flatten)]
+    pub sampled: TokenLogprob,
+    /// Most likely first; may include the sampled token itself.
+    pub top_logprobs: Vec<TokenLogprob>,
+}
+
+/// How a token was picked, for debugging "why did it say that".
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenTrace {
+    pub token: u32,
+    /// The token's own text, special tokens included.
+    pub text: String,
+    /// Log-probability of the token under the logits it was sampled from
+    /// (after penalties, guidance, logit bias, watermark, hooks and typical
+    /// or top-n-sigma truncation), at temperature 1.
+    pub logprob: f32,
+    /// Entropy of that distribution, in nats.
+    pub entropy: f32,
+    /// Candidates removed by each sampling filter (`argmax`, `typical`,
+    /// `top_n_sigma`, `top_k`, `top_p`).
+    pub removed: BTreeMap<&'static str, usize>,
+}
+
+/// Called with the context so far and the logits for the next token, just
+/// before sampling; returns the logits to sample from.
+pub type LogitsHook<'a> = Box<dyn FnMut(&[u32], Tensor) -> Result<Tensor> + 'a>;
+
+/// Second decoding context for classifier-free guidance, kept in lockstep
+/// with the main one: it starts from the negative prompt and is fed the same
+/// sampled tokens.
+struct GuidanceContext {
+    cache: ModelCache,
+    /// Negative prompt followed by the generated tokens.
+    tokens: Vec<u32>,
+    pos: usize,
+    scale: f64,
+}
+
+impl GuidanceContext {
+    /// Run the negative context for this step and push `logits` away from it:
+    /// `neg + scale * (cond - neg)`, computed on log-probabilities.
+    fn guide(&mut self, model: &Model, config: &GenerationConfig, logits: &Tensor) -> Result<Tensor> {
+        let start = next_input(model, config.use_kv_cache, &mut self.cache, self.pos)?;
+        let negative = forward_chunked(
+            model,
+            &self.tokens[start..],
+            start,
+            &mut self.cache,
+            config.prefill_chunk,
+            None,
+        )?;
+        self.pos = self.tokens.len();
+        let cond = candle_nn::ops::log_softmax(logits, D::Minus1)?;
+        let negative = candle_nn::ops::log_softmax(&negative, D::Minus1)?;
+        Ok(((cond - &negative)?.affine(self.scale, 0.)? + negative)?)
+    }
+}
+
+pub struct Generator<'a> {
+    model: &'a Model,
+    config: GenerationConfig,
+    cache: ModelCache,
+    logits_processor: LogitsProcessor,
+    dry: Option<Dry>,
+    logit_bias: Option<LogitBias>,
+    stop_matcher: StopMatcher,
+    decoder: DecodeStream,
+    /// Prompt followed by everything generated so far.
+    tokens: Vec<u32>,
+    prompt_len: usize,
+    /// Number of prompt tokens dropped to fit the context window.
+    prompt_truncated: usize,
+    /// Number of tokens already fed to the model.
+    pos: usize,
+    /// Number of them evicted from the cache since.
+    evicted: usize,
+    generated: usize,
+    finish_reason: Option<FinishReason>,
+    guidance: Option<GuidanceContext>,
+    watermark: Option<Watermark>,
+    capture: Option<Capture>,
+    capture_generated: bool,
+    logits_hook: Option<LogitsHook<'a>>,
+    trace: bool,
+    /// Number of alternatives to report with each token's log-probability.
+    logprobs: Option<usize>,
+}
+
+impl<'a> Generator<'a> {
+    /// A prompt that doesn't fit in the context window next to
+    /// `config.max_new_tokens` is handled as `config.context_overflow` says.
+    pub fn new(
+        model: &'a Model,
+        config: GenerationConfig,
+        mut prompt_tokens: Vec<u32>,
+    ) -> Result<Self> {
+        if prompt_tokens.is_empty() {
+            bail!("The prompt must contain at least one token");
+        }
+        let context_len = model.config.max_position_embeddings;
+        config.validate_for_context(context_len)?;
+        let prompt_truncated = config.fit_prompt(&mut prompt_tokens, context_len, &model.tokenizer)?;
+        let cache = model.new_cache(config.use_kv_cache)?;
+        if config.kv_eviction != KvEviction::None && !matches!(cache, ModelCache::Llama(_)) {
+            bail!("KV eviction is only supported for Llama-family safetensors models");
+        }
+        if config.prefill_chunk.is_some() && !matches!(cache, ModelCache::Llama(_)) {
+            bail!("Chunked prefill is only supported for Llama-family safetensors models");
+        }
+        let logits_processor = LogitsProcessor::from_sampling(config.seed, config.sampling());
+        let dry = Dry::new(&config, &model.tokenizer)?;
+        let logit_bias =
+            LogitBias::new(&config, &model.tokenizer, model.config.vocab_size, &model.device)?;
+        let stop_matcher = StopMatcher::new(config.stop_sequences.clone());
+        let finish_reason = (config.max_new_tokens == 0).then_some(FinishReason::Length);
+        Ok(Self {
+            model,
+            cache,
+            logits_processor,
+            dry,
+            logit_bias,
+            stop_matcher,
+            decoder: DecodeStream::new(&prompt_tokens),
+            prompt_len: prompt_tokens.len(),
+            prompt_truncated,
+            tokens: prompt_tokens,
+            pos: 0,
+            evicted: 0,
+            generated: 0,
+            finish_reason,
+            guidance: None,
+            watermark: None,
+            capture: None,
+            capture_generated: false,
+            logits_hook: None,
+            trace: false,
+            logprobs: None,
+            config,
+        })
+    }
+
+    /// Steer away from `negative_tokens` with classifier-free guidance. This
+    /// runs a second forward pass (and KV cache) per step.
+    pub fn with_negative_prompt(mut self, negative_tokens: Vec<u32>, scale: f64) -> Result<Self> {
+        if negative_tokens.is_empty() {
+            bail!("The negative prompt must contain at least one token");
+        }
+        if self.config.kv_eviction != KvEviction::None {
+            bail!("A negative prompt can't be combined with KV eviction");
+        }
+        if negative_tokens.len() > self.model.config.max_position_embeddings {
+            bail!(
+                "The negative prompt has {} tokens, more than the model's {}-token context window",
+                negative_tokens.len(),
+                self.model.config.max_position_embeddings
+            );
+        }
+        self.guidance = Some(GuidanceContext {
+            cache: self.model.new_cache(self.config.use_kv_cache)?,
+            tokens: negative_tokens,
+            pos: 0,
+            scale,
+        });
+        Ok(self)
+    }
+
+    pub fn with_watermark(mut self, watermark: Watermark) -> Self {
+        self.watermark = Some(watermark);
+        self
+    }
+
+    /// Record the activations requested in `capture` for the prompt, and for
+    /// every generated token too when `generated` is set. Attention maps are
+    /// only recorded for the prompt.
+    pub fn with_capture(mut self, capture: Capture, generated: bool) -> Self {
+        self.capture = Some(capture);
+        self.capture_generated = generated;
+        self
+    }
+
+    /// Inspect or modify the logits of every step before sampling, e.g. for
+    /// custom decoding or collecting distillation data.
+    pub fn with_logits_hook(
+        mut self,
+        hook: impl FnMut(&[u32], Tensor) -> Result<Tensor> + 'a,
+    ) -> Self {
+        self.logits_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Attach a `TokenTrace` to every step. This costs a pass over the
+    /// vocabulary (and a sort with top-p) per token.
+    pub fn with_trace(mut self) -> Self {
+        self.trace = true;
+        self
+    }
+
+    /// Attach `TokenLogprobs` with the `top_n` most likely alternatives to
+    /// every step.
+    pub fn with_logprobs(mut self, top_n: usize) -> Self {
+        self.logprobs = Some(top_n);
+        self
+    }
+
+    /// Start from the KV cache saved in `session` for the part of the prompt
+    /// it shares, instead of prefilling it. Returns the number of prompt
+    /// tokens that were restored (0 if the prompts differ from the start).
+    pub fn with_session(mut self, session: &Session) -> Result<(Self, usize)> {
+        if self.pos > 0 {
+            bail!("A session must be restored before the first step");
+        }
+        if !self.config.use_kv_cache {
+            bail!("Restoring a session needs the KV cache");
+        }
+        let restored = session.restore(self.model, &mut self.cache, &self.tokens[..self.prompt_len])?;
+        self.pos = restored;
+        Ok((self, restored))
+    }
+
+    /// The KV cache of the tokens processed so far (the prompt, after the
+    /// first step), for restoring with `with_session` in a later run.
+    pub fn session(&self) -> Result<Session> {
+        if self.pos == 0 {
+            bail!("No tokens have been processed yet");
+        }
+        if self.evicted > 0 {
+            bail!("Can't save a session after tokens were evicted from the KV cache");
+        }
+        Session::capture(&self.tokens[..self.pos], &self.cache)
+    }
+
+    /// A generator that continues from the same prompt independently,
+    /// sampling with `seed`, for drawing several completions of one prompt.
+    /// All but the last prompt token are run here once and the forks share
+    /// their KV cache, so each fork only runs that token before sampling;
+    /// stateful models can't share a cache and prefill the prompt again.
+    pub fn fork(&mut self, seed: u64) -> Result<Self> {
+        if self.generated > 0 {
+            bail!("A generator can only be forked before its first step");
+        }
+        if self.guidance.is_some() || self.capture.is_some() || self.logits_hook.is_some() {
+            bail!("A generator with a negative prompt, capture or logits hook can't be forked");
+        }
+        let shared = self.config.use_kv_cache && matches!(self.cache, ModelCache::Llama(_));
+        if shared && self.pos + 1 < self.prompt_len {
+            forward_chunked(
+                self.model,
+                &self.tokens[self.pos..self.prompt_len - 1],
+                self.pos,
+                &mut self.cache,
+                self.config.prefill_chunk,
+                None,
+            )?;
+            self.pos = self.prompt_len - 1;
+        }
+        let (cache, pos) = match &self.cache {
+            // Cached tensors are never modified in place, so the copies
+            // share their memory until they grow.
+            ModelCache::Llama(cache) if shared => (ModelCache::Llama(cache.clone()), self.pos),
+            _ => (self.model.new_cache(self.config.use_kv_cache)?, 0),
+        };
+        let config = GenerationConfig {
+            seed,
+            ..self.config.clone()
+        };
+        Ok(Self {
+            model: self.model,
+            cache,
+            logits_processor: LogitsProcessor::from_sampling(seed, config.sampling()),
+            dry: self.dry.clone(),
+            logit_bias: self.logit_bias.clone(),
+            stop_matcher: StopMatcher::new(config.stop_sequences.clone()),
+            decoder: DecodeStream::new(&self.tokens),
+            tokens: self.tokens.clone(),
+            prompt_len: self.prompt_len,
+            prompt_truncated: self.prompt_truncated,
+            pos,
+            evicted: 0,
+            generated: 0,
+            finish_reason: self.finish_reason.clone(),
+            guidance: None,
+            watermark: self.watermark.clone(),
+            capture: None,
+            capture_generated: false,
+            logits_hook: None,
+            trace: self.trace,
+            logprobs: self.logprobs,
+            config,
+        })
+    }
+
+    /// Prompt followed by the generated tokens.
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    pub fn prompt_len(&self) -> usize {
+        self.prompt_len
+    }
+
+    /// Number of prompt tokens dropped to fit the context window
+    /// (`ContextOverflow::TruncateLeft`/`TruncateMiddle`).
+    pub fn truncated_prompt_tokens(&self) -> usize {
+        self.prompt_truncated
+    }
+
+    pub fn generated_tokens(&self) -> usize {
+        self.generated
+    }
+
+    /// Set once generation is over; `step` must not be called after that.
+    pub fn finish_reason(&self) -> Option<&FinishReason> {
+        self.finish_reason.as_ref()
+    }
+
+    /// The activations recorded so far.
+    pub fn take_capture(&mut self) -> Option<Capture> {
+        self.capture.take()
+    }
+
+    /// End generation early, e.g. because a budget ran out. Returns any
+    /// output that was being held back.
+    pub fn abort(&mut self, reason: FinishReason) -> String {
+        self.finish_reason = Some(reason);
+        let mut text = self.stop_matcher.flush();
+        text.push_str(&self.decoder.flush(&self.model.tokenizer).unwrap_or_default());
+        text
+    }
+
+    /// Run the model on the pending tokens (the whole prompt on the first
+    /// call) and sample the next token.
+    pub fn step(&mut self) -> Result<Step> {
+        if let Some(reason) = &self.finish_reason {
+            bail!("Generation has already finished ({})", reason);
+        }
+        let _span = match self.pos {
+            0 => tracing::info_span!("prefill", tokens = self.tokens.len()),
+            pos => tracing::debug_span!("decode", position = pos),
+        }
+        .entered();
+
+        if self.tokens.len() - self.evicted > self.model.config.max_position_embeddings {
+            self.evict()?;
+        }
+        let capture_step = self.pos == 0 || self.capture_generated;
+        let start = next_input(self.model, self.config.use_kv_cache, &mut self.cache, self.pos)?;
+        let logits = forward_chunked(
+            self.model,
+            &self.tokens[start..],
+            start - self.evicted,
+            &mut self.cache,
+            self.config.prefill_chunk,
+            self.capture.as_mut().filter(|_| capture_step),
+        )?;
+        if let Some(capture) = self.capture.as_mut() {
+            // Attention maps are only recorded for the prompt.
+            capture.attention_layers.clear();
+        }
+        self.pos = self.tokens.len();
+
+        let raw_logits = self.logprobs.map(|_| logits.clone());
+        let logits = match self.guidance.as_mut() {
+            Some(guidance) => guidance.guide(self.model, &self.config, &logits)?,
+            None => logits,
+        };
+
+        // Apply repeat penalty
+        let logits = if self.config.repeat_penalty == 1. {
+            logits
+        } else {
+            let context = match self.config.repeat_scope {
+                RepeatScope::Prompt => &self.tokens[..self.prompt_len],
+                RepeatScope::Generated => &self.tokens[self.prompt_len..],
+                RepeatScope::All => &self.tokens[..],
+            };
+            let start_at = context.len().saturating_sub(self.config.repeat_last_n);
+            candle_transformers::utils::apply_repeat_penalty(
+                &logits,
+                self.config.repeat_penalty,
+                &context[start_at..],
+            )?
+        };
+
+        let logits = match &self.dry {
+            Some(dry) => dry.apply(&logits, &self.tokens)?,
+            None => logits,
+        };
+        let logits = match &self.logit_bias {
+            Some(bias) => bias.apply(&logits, &self.tokens)?,
+            None => logits,
+        };
+
+        let logits = match &self.watermark {
+            Some(watermark) => watermark.apply(&logits, self.tokens[self.tokens.len() - 1])?,
+            None => logits,
+        };
+        let logits = match self.logits_hook.as_mut() {
+            Some(hook) => hook(&self.tokens, logits)?,
+            None => logits,
+        };
+
+        let (logits, truncated) = match &self.config.truncation {
+            Some(truncation) => truncation.apply(&logits, self.config.temperature)?,
+            None => (logits, 0),
+        };
+
+        let token = self.logits_processor.sample(&logits)?;
+        let trace = match self.trace {
+            true => Some(self.trace_token(&logits, token, truncated)?),
+            false => None,
+        };
+        let logprobs = match (self.logprobs, raw_logits) {
+            (Some(top_n), Some(logits)) => Some(self.token_logprobs(&logits, token, top_n)?),
+            _ => None,
+        };
+        self.tokens.push(token);
+        self.generated += 1;
+        if let Some(guidance) = self.guidance.as_mut() {
+            guidance.tokens.push(token);
+        }
+
+        if self.model.eos_token_ids.contains(&token) {
+            let text = self.abort(FinishReason::Eos);
+            return Ok(Step {
+                token,
+                text,
+                trace,
+                logprobs,
+            });
+        }
+
+        let text = self.decoder.step(&self.model.tokenizer, token)?;
+        let (mut text, stop) = self.stop_matcher.push(&text);
+        if let Some(stop) = stop {
+            self.finish_reason = Some(FinishReason::StopSequence(stop.to_string()));
+        } else if self.generated >= self.config.max_new_tokens {
+            text.push_str(&self.abort(FinishReason::Length));
+        } else if self.context_full() {
+            text.push_str(&self.abort(FinishReason::ContextFull));
+        }
+        Ok(Step {
+            token,
+            text,
+            trace,
+            logprobs,
+        })
+    }
+
+    /// Whether the next token has no position left to go in.
+    fn context_full(&self) -> bool {
+        if self.config.kv_eviction != KvEviction::None {
+            return false;
+        }
+        let negative_len = self.guidance.as_ref().map_or(0, |g| g.tokens.len());
+        self.tokens.len().max(negative_len) > self.model.config.max_position_embeddings
+    }
+
+    /// Make room in a full KV cache: keep the sink tokens and drop half of
+    /// the rest, oldest first.
+    fn evict(&mut self) -> Result<()> {
+        let ModelCache::Llama(cache) = &mut self.cache else {
+            bail!("KV eviction is only supported for Llama-family safetensors models");
+        };
+        let keep = match self.config.kv_eviction {
+            KvEviction::None => bail!("The context window is full"),
+            KvEviction::Window => 0,
+            KvEviction::Sink => self.config.sink_tokens,
+        };
+        let cached = cache.seq_len()?;
+        let count = (cached - keep) / 2;
+        cache.evict(keep, count)?;
+        self.evicted += count;
+        Ok(())
+    }
+
+    fn token_logprobs(&self, logits: &Tensor, token: u32, top_n: usize) -> Result<TokenLogprobs> {
+        let logits: Vec<f32> = logits.to_vec1()?;
+        let log_probs = log_softmax(&logits, 1.);
+        let entry = |token: usize| TokenLogprob {
+            token: token as u32,
+            text: self.model.tokenizer.decode(&[token as u32], false).unwrap_or_default(),
+            logprob: log_probs[token] as f32,
+        };
+        let mut order: Vec<usize> = (0..log_probs.len()).collect();
+        let top_n = top_n.min(order.len());
+        if top_n > 0 {
+            order.select_nth_unstable_by(top_n - 1, |&a, &b| log_probs[b].total_cmp(&log_probs[a]));
+        }
+        let mut top: Vec<usize> = order[..top_n].to_vec();
+        top.sort_by(|&a, &b| log_probs[b].total_cmp(&log_probs[a]));
+        Ok(TokenLogprobs {
+            sampled: entry(token as usize),
+            top_logprobs: top.into_iter().map(entry).collect(),
+        })
+    }
+
+    /// `truncated` tokens were removed from `logits` by typical or
+    /// top-n-sigma sampling already.
+    fn trace_token(&self, logits: &Tensor, token: u32, truncated: usize) -> Result<TokenTrace> {
+        let logits: Vec<f32> = logits.to_vec1()?;
+        let log_probs = log_softmax(&logits, 1.);
+        let entropy = log_probs
+            .iter()
+            .filter(|lp| lp.is_finite())
+            .map(|&lp| -lp.exp() * lp)
+            .sum::<f64>();
+
+        // Mirror what candle's LogitsProcessor does for each sampling mode.
+        // Tokens already at -inf (banned or truncated) aren't counted again.
+        let vocab_size = logits.iter().filter(|l| l.is_finite()).count();
+        let mut removed = BTreeMap::new();
+        if let Some(truncation) = &self.config.truncation {
+            removed.insert(truncation.name(), truncated);
+        }
+        match self.config.sampling() {
+            Sampling::ArgMax => {
+                removed.insert("argmax", vocab_size - 1);
+            }
+            Sampling::All { .. } | Sampling::GumbelSoftmax { .. } => {}
+            Sampling::TopK { k, .. } => {
+                removed.insert("top_k", vocab_size - k.min(vocab_size));
+            }
+            Sampling::TopP { p, temperature } => {
+                let kept = top_p_kept(&logits, temperature, vocab_size, p);
+                removed.insert("top_p", vocab_size - kept);
+            }
+            Sampling::TopKThenTopP { k, p, temperature } => {
+                let k = k.min(vocab_size);
+                removed.insert("top_k", vocab_size - k);
+                removed.insert("top_p", k - top_p_kept(&logits, temperature, k, p));
+            }
+        }
+
+        Ok(TokenTrace {
+            token,
+            text: self.model.tokenizer.decode(&[token], false).unwrap_or_default(),
+            logprob: log_probs[token as usize] as f32,
+            entropy: entropy as f32,
+            removed,
+        })
+    }
+}
+
+/// Index of the first token to feed once `pos` have been: without the KV
+/// cache, the whole sequence again. Stateful models cache regardless, so
+/// they start over from a fresh cache.
+fn next_input(model: &Model, use_kv_cache: bool, cache: &mut ModelCache, pos: usize) -> Result<usize> {
+    if pos == 0 || use_kv_cache {
+        return Ok(pos);
+    }
+    if let ModelCache::Stateful(_) = cache {
+        *cache = model.new_cache(false)?;
+    }
+    Ok(0)
+}
+
+/// Run `tokens`, starting at position `index_pos`, through the model `chunk`
+/// tokens per forward pass (all in one if `None`) and return the logits after
+/// the last one. Each pass extends the KV cache the next one attends to.
+fn forward_chunked(
+    model: &Model,
+    tokens: &[u32],
+    index_pos: usize,
+    cache: &mut ModelCache,
+    chunk: Option<usize>,
+    mut capture: Option<&mut Capture>,
+) -> Result<Tensor> {
+    let chunk = chunk.unwrap_or(tokens.len()).max(1);
+    let mut logits = None;
+    for (i, tokens) in tokens.chunks(chunk).enumerate() {
+        let pos = index_pos + i * chunk;
+        logits = Some(model.forward_with_capture(tokens, pos, cache, capture.as_deref_mut())?);
+    }
+    logits.ok_or_else(|| anyhow::anyhow!("No tokens to run the model on"))
+}
+
+pub(crate) fn log_softmax(logits: &[f32], temperature: f64) -> Vec<f64> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max) as f64 / temperature;
+    let log_sum = logits
+        .iter()
+        .map(|&l| (l as f64 / temperature - max).exp())
+        .sum::<f64>()
+        .ln();
+    logits
+        .iter()
+        .map(|&l| l as f64 / temperature - max - log_sum)
+        .collect()
+}
+
+/// How many of the `candidates` most likely tokens top-p sampling keeps:
+/// tokens are taken in order of probability until they cover `p`.
+fn top_p_kept(logits: &[f32], temperature: f64, candidates: usize, p: f64) -> usize {
+    let mut probs: Vec<f64> = log_softmax(logits, temperature)
+        .into_iter()
+        .map(f64::exp)
+        .collect();
+    probs.sort_by(|a, b| b.total_cmp(a));
+    let mut cumsum = 0.;
+    probs
+        .iter()
+        .take(candidates)
+        .take_while(|&&prob| {
+            let keep = cumsum < p;
+            cumsum += prob;
+            keep
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    /// A tokenizer whose only special token is `<s>`, id 1.
+    fn tokenizer() -> tokenizers::Tokenizer {
+        tokenizers::Tokenizer::from_str(
+            r#"{
+                "version": "1.0",
+                "added_tokens": [{"id": 1, "content": "<s>", "single_word": false, "lstrip": false,
+                                  "rstrip": false, "normalized": false, "special": true}],
+                "model": {"type": "WordLevel", "vocab": {"<unk>": 0, "<s>": 1}, "unk_token": "<unk>"}
+            }"#,
+        )
+        .unwrap()
+    }
+
+    /// Room for 6 prompt tokens in a 10-token window.
+    fn config(context_overflow: ContextOverflow) -> GenerationConfig {
+        GenerationConfig {
+            max_new_tokens: 4,
+            context_overflow,
+            ..Default::default()
+        }
+    }
+
+    fn fit(context_overflow: ContextOverflow, prompt: &[u32]) -> Result<(Vec<u32>, usize)> {
+        let mut tokens = prompt.to_vec();
+        let dropped = config(context_overflow).fit_prompt(&mut tokens, 10, &tokenizer())?;
+        Ok((tokens, dropped))
+    }
+
+    #[test]
+    fn truncate_left_keeps_the_newest_tokens_and_bos() {
+        let prompt = [1, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19];
+        let (tokens, dropped) = fit(ContextOverflow::TruncateLeft, &prompt).unwrap();
+        assert_eq!(tokens, [1, 15, 16, 17, 18, 19]);
+        assert_eq!(dropped, 5);
+
+        let prompt: Vec<u32> = (10..20).collect();
+        let (tokens, dropped) = fit(ContextOverflow::TruncateLeft, &prompt).unwrap();
+        assert_eq!(tokens, [14, 15, 16, 17, 18, 19]);
+        assert_eq!(dropped, 4);
+    }
+
+    #[test]
+    fn truncate_middle_keeps_the_system_prefix_and_recent_turns() {
+        // A 3-token system prompt, old turns, then the latest turn.
+        let prompt = [2, 3, 4, 20, 21, 22, 23, 24, 25, 26, 27, 30, 31, 32];
+        let (tokens, dropped) = fit(ContextOverflow::TruncateMiddle, &prompt).unwrap();
+        assert_eq!(tokens, [2, 3, 4, 30, 31, 32]);
+        assert_eq!(dropped, 8);
+    }
+
+    #[test]
+    fn reduce_max_tokens_keeps_the_prompt() {
+        // Generation then stops at the window with `ContextFull`, after 2
+        // of the 4 tokens.
+        let prompt: Vec<u32> = (10..18).collect();
+        let (tokens, dropped) = fit(ContextOverflow::ReduceMaxTokens, &prompt).unwrap();
+        assert_eq!(tokens, prompt);
+        assert_eq!(dropped, 0);
+
+        let prompt: Vec<u32> = (10..21).collect();
+        let err = fit(ContextOverflow::ReduceMaxTokens, &prompt).unwrap_err();
+        assert!(err.to_string().contains("more than the model's 10-token context window"));
+    }
+
+    #[test]
+    fn error_refuses_prompts_that_dont_fit() {
+        let prompt: Vec<u32> = (10..17).collect();
+        let err = fit(ContextOverflow::Error, &prompt).unwrap_err();
+        assert!(err.to_string().starts_with("The prompt has 7 tokens, but only 6 fit"));
+
+        let prompt: Vec<u32> = (10..16).collect();
+        assert_eq!(fit(ContextOverflow::Error, &prompt).unwrap(), (prompt, 0));
+    }
+
+    #[test]
+    fn kv_eviction_leaves_the_whole_window_to_the_prompt() {
+        let config = GenerationConfig {
+            kv_eviction: KvEviction::Window,
+            ..config(ContextOverflow::Error)
+        };
+        let mut tokens: Vec<u32> = (10..20).collect();
+        assert_eq!(config.fit_prompt(&mut tokens, 10, &tokenizer()).unwrap(), 0);
+    }
+}
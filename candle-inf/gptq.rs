@@ -0,0 +1,499 @@
+// GPTQ and AWQ int4 checkpoints.
+//
+// Both formats store each linear layer as 4-bit codes packed eight to an
+// int32 (`qweight`), with an f16 scale and a 4-bit zero point (`qzeros`,
+// packed the same way) per group of input features: a weight is
+// `scale * (code - zero)`. GPTQ packs the codes along the input dimension
+// and, with act-order (`desc_act`), maps inputs to groups through `g_idx`;
+// AWQ packs them along the output dimension in an interleaved order.
+//
+// A group of 32 consecutive inputs with one scale and zero point is exactly a
+// GGML q4_1 block (`d * code + m`, with `d = scale` and `m = -scale * zero`),
+// so the codes are repacked into q4_1 blocks as the layer loads and candle's
+// `QMatMul` dequantizes them on the fly, at the same 4.5 bits per weight.
+// Layers whose groups don't line up with blocks (act-order, or group sizes
+// that aren't a multiple of 32) are dequantized into dense weights instead.
+
+use anyhow::{bail, Result};
+use candle_core::quantized::{ggml_file::qtensor_from_ggml, GgmlDType, QTensor};
+use candle_core::{DType, Tensor};
+use candle_nn::VarBuilder;
+use half::f16;
+use std::collections::HashSet;
+
+/// Order of the eight output columns in an AWQ int32, by nibble.
+const AWQ_ORDER: [usize; 8] = [0, 2, 4, 6, 1, 3, 5, 7];
+
+/// Inputs per q4_1 block.
+const BLOCK: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Int4Method {
+    Gptq,
+    Awq,
+}
+
+impl std::fmt::Display for Int4Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Int4Method::Gptq => "GPTQ",
+            Int4Method::Awq => "AWQ",
+        })
+    }
+}
+
+/// How the linear layers of an int4 checkpoint are packed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Int4Config {
+    pub method: Int4Method,
+    /// Input features sharing a scale and zero point; `None` for one group
+    /// per output row.
+    pub group_size: Option<usize>,
+    /// GPTQ act-order: inputs map to groups through `g_idx`.
+    pub desc_act: bool,
+    /// Added to the stored zero points: GPTQ's original checkpoint format
+    /// stores them minus one.
+    pub zero_offset: i64,
+}
+
+impl Int4Config {
+    /// Read the `quantization_config` of config.json, GPTQ's
+    /// quantize_config.json or AutoAWQ's quant_config.json.
+    pub fn from_json(json: &serde_json::Value) -> Result<Self> {
+        let method = match json["quant_method"].as_str() {
+            Some("gptq") => Int4Method::Gptq,
+            Some("awq") => Int4Method::Awq,
+            Some(method) => bail!("{} quantization isn't supported (only GPTQ and AWQ)", method),
+            // AutoAWQ names its settings after its own arguments.
+            None if json.get("w_bit").is_some() => Int4Method::Awq,
+            None => Int4Method::Gptq,
+        };
+        let bits = json["bits"].as_u64().or(json["w_bit"].as_u64());
+        if bits != Some(4) {
+            bail!(
+                "Only 4-bit {} checkpoints are supported, not {}",
+                method,
+                bits.map_or("unknown".to_string(), |bits| format!("{}-bit", bits))
+            );
+        }
+        let group_size = match json["group_size"].as_i64().or(json["q_group_size"].as_i64()) {
+            Some(size) if size > 0 => Some(size as usize),
+            Some(-1) | None => None,
+            Some(size) => bail!("Invalid {} group size {}", method, size),
+        };
+        if method == Int4Method::Awq {
+            let version = json["version"].as_str().unwrap_or("gemm");
+            if !version.eq_ignore_ascii_case("gemm") {
+                bail!("Only AWQ's GEMM packing is supported, not {}", version);
+            }
+            if json["zero_point"].as_bool() == Some(false) {
+                bail!("AWQ checkpoints without zero points aren't supported");
+            }
+        }
+        let zero_offset = match (method, json["checkpoint_format"].as_str()) {
+            (Int4Method::Gptq, None | Some("gptq")) => 1,
+            (Int4Method::Gptq, Some("gptq_v2")) | (Int4Method::Awq, _) => 0,
+            (Int4Method::Gptq, Some(format)) => bail!("Unsupported GPTQ checkpoint format {}", format),
+        };
+        Ok(Self {
+            method,
+            group_size,
+            desc_act: json["desc_act"].as_bool().unwrap_or(false),
+            zero_offset,
+        })
+    }
+
+    fn groups(&self, in_dim: usize) -> usize {
+        self.group_size.map_or(1, |size| in_dim.div_ceil(size))
+    }
+
+    /// `tensors` with the weights of the decoder layers' projections, and of
+    /// any other matrix with a `qweight` in `names`, replaced by their
+    /// packed tensors.
+    pub fn packed(
+        &self,
+        tensors: Vec<(String, Vec<usize>)>,
+        names: &HashSet<String>,
+    ) -> Vec<(String, Vec<usize>)> {
+        tensors
+            .into_iter()
+            .flat_map(|(name, shape)| match (name.strip_suffix(".weight"), shape.as_slice()) {
+                (Some(prefix), &[out_dim, in_dim])
+                    if prefix.starts_with("model.layers.")
+                        || names.contains(&format!("{prefix}.qweight")) =>
+                {
+                    self.packed_tensors(prefix, out_dim, in_dim)
+                }
+                _ => vec![(name, shape)],
+            })
+            .collect()
+    }
+
+    /// The packed tensors that replace `{prefix}.weight` of shape
+    /// `[out_dim, in_dim]`.
+    fn packed_tensors(
+        &self,
+        prefix: &str,
+        out_dim: usize,
+        in_dim: usize,
+    ) -> Vec<(String, Vec<usize>)> {
+        let groups = self.groups(in_dim);
+        let mut tensors = match self.method {
+            Int4Method::Gptq => vec![
+                (format!("{prefix}.qweight"), vec![in_dim / 8, out_dim]),
+                (format!("{prefix}.g_idx"), vec![in_dim]),
+            ],
+            Int4Method::Awq => vec![(format!("{prefix}.qweight"), vec![in_dim, out_dim / 8])],
+        };
+        tensors.extend([
+            (format!("{prefix}.qzeros"), vec![groups, out_dim / 8]),
+            (format!("{prefix}.scales"), vec![groups, out_dim]),
+        ]);
+        tensors
+    }
+}
+
+/// A packed linear layer once loaded.
+pub enum Int4Weight {
+    /// q4_1 blocks for `QMatMul`, shape `[out_dim, in_dim]`.
+    Blocks(QTensor),
+    /// Dequantized weights in the var builder's dtype.
+    Dense(Tensor),
+}
+
+/// The codes of a packed layer, unpacked.
+struct Unpacked {
+    /// `[out_dim][in_dim]`.
+    codes: Vec<u8>,
+    /// `[groups][out_dim]`, with the zero offset applied.
+    zeros: Vec<i64>,
+    /// `[groups][out_dim]`.
+    scales: Vec<f32>,
+    /// Group of each input.
+    group_of: Vec<usize>,
+}
+
+/// Read the packed layer under `vb` (its `qweight`, `qzeros`, `scales` and,
+/// for GPTQ, `g_idx`) with `in_dim` inputs and `out_dim` outputs.
+pub fn load_linear(
+    vb: &VarBuilder,
+    config: &Int4Config,
+    in_dim: usize,
+    out_dim: usize,
+) -> candle_core::Result<Int4Weight> {
+    if !in_dim.is_multiple_of(8) || !out_dim.is_multiple_of(8) {
+        candle_core::bail!("{}x{} can't be packed eight to an int32", out_dim, in_dim);
+    }
+    let unpacked = unpack(vb, config, in_dim, out_dim)?;
+    let sequential = unpacked
+        .group_of
+        .chunks(BLOCK)
+        .all(|block| block.len() == BLOCK && block.iter().all(|&g| g == block[0]));
+    if sequential {
+        let bytes = q4_1_blocks(&unpacked, in_dim, out_dim);
+        let qtensor = qtensor_from_ggml(GgmlDType::Q4_1, &bytes, vec![out_dim, in_dim], vb.device())?;
+        return Ok(Int4Weight::Blocks(qtensor));
+    }
+    let mut weight = vec![0f32; out_dim * in_dim];
+    for row in 0..out_dim {
+        for (col, &group) in unpacked.group_of.iter().enumerate() {
+            let at = group * out_dim + row;
+            let code = unpacked.codes[row * in_dim + col] as i64;
+            weight[row * in_dim + col] = unpacked.scales[at] * (code - unpacked.zeros[at]) as f32;
+        }
+    }
+    let weight = Tensor::from_vec(weight, (out_dim, in_dim), vb.device())?.to_dtype(vb.dtype())?;
+    Ok(Int4Weight::Dense(weight))
+}
+
+fn unpack(
+    vb: &VarBuilder,
+    config: &Int4Config,
+    in_dim: usize,
+    out_dim: usize,
+) -> candle_core::Result<Unpacked> {
+    let groups = config.groups(in_dim);
+    let packed = |name: &str, shape: (usize, usize)| -> candle_core::Result<Vec<Vec<i64>>> {
+        // int32 tensors load as i64, sign-extended; the nibbles are intact.
+        vb.get_with_hints_dtype(shape, name, Default::default(), DType::I64)?
+            .to_vec2::<i64>()
+    };
+    let nibble = |word: i64, index: usize| ((word >> (4 * index)) & 0xF) as u8;
+
+    let mut codes = vec![0u8; out_dim * in_dim];
+    let mut zeros = vec![0i64; groups * out_dim];
+    let qzeros = packed("qzeros", (groups, out_dim / 8))?;
+    match config.method {
+        Int4Method::Gptq => {
+            // Eight consecutive inputs per int32, and zero points packed
+            // along the outputs.
+            for (word_row, words) in packed("qweight", (in_dim / 8, out_dim))?.iter().enumerate() {
+                for (row, &word) in words.iter().enumerate() {
+                    for index in 0..8 {
+                        codes[row * in_dim + word_row * 8 + index] = nibble(word, index);
+                    }
+                }
+            }
+            for (group, words) in qzeros.iter().enumerate() {
+                for (word_col, &word) in words.iter().enumerate() {
+                    for index in 0..8 {
+                        zeros[group * out_dim + word_col * 8 + index] = nibble(word, index) as i64;
+                    }
+                }
+            }
+        }
+        Int4Method::Awq => {
+            // Eight outputs per int32, interleaved, for codes and zero points.
+            for (col, words) in packed("qweight", (in_dim, out_dim / 8))?.iter().enumerate() {
+                for (word_col, &word) in words.iter().enumerate() {
+                    for (index, order) in AWQ_ORDER.iter().enumerate() {
+                        codes[(word_col * 8 + order) * in_dim + col] = nibble(word, index);
+                    }
+                }
+            }
+            for (group, words) in qzeros.iter().enumerate() {
+                for (word_col, &word) in words.iter().enumerate() {
+                    for (index, order) in AWQ_ORDER.iter().enumerate() {
+                        zeros[group * out_dim + word_col * 8 + order] = nibble(word, index) as i64;
+                    }
+                }
+            }
+        }
+    }
+    for zero in &mut zeros {
+        *zero += config.zero_offset;
+    }
+    let scales = vb
+        .get_with_hints_dtype((groups, out_dim), "scales", Default::default(), DType::F32)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+
+    let group_size = config.group_size.unwrap_or(in_dim);
+    let group_of: Vec<usize> = match config.method {
+        Int4Method::Gptq if config.desc_act => vb
+            .get_with_hints_dtype(in_dim, "g_idx", Default::default(), DType::I64)?
+            .to_vec1::<i64>()?
+            .into_iter()
+            .map(|group| group as usize)
+            .collect(),
+        _ => (0..in_dim).map(|col| col / group_size).collect(),
+    };
+    if let Some(&group) = group_of.iter().find(|&&group| group >= groups) {
+        candle_core::bail!("g_idx names group {} of {}", group, groups);
+    }
+    Ok(Unpacked {
+        codes,
+        zeros,
+        scales,
+        group_of,
+    })
+}
+
+/// The codes as q4_1 blocks of 32 inputs, row by row: an f16 scale, an f16
+/// minimum, then 16 bytes holding inputs `i` and `i + 16` in their low and
+/// high nibbles.
+fn q4_1_blocks(unpacked: &Unpacked, in_dim: usize, out_dim: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(out_dim * in_dim / BLOCK * 20);
+    for row in 0..out_dim {
+        let codes = &unpacked.codes[row * in_dim..(row + 1) * in_dim];
+        for (block, codes) in codes.chunks(BLOCK).enumerate() {
+            let at = unpacked.group_of[block * BLOCK] * out_dim + row;
+            let scale = unpacked.scales[at];
+            let min = -scale * unpacked.zeros[at] as f32;
+            bytes.extend_from_slice(&f16::from_f32(scale).to_le_bytes());
+            bytes.extend_from_slice(&f16::from_f32(min).to_le_bytes());
+            bytes.extend((0..BLOCK / 2).map(|i| codes[i] | (codes[i + BLOCK / 2] << 4)));
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+    use std::collections::HashMap;
+
+    const IN: usize = 64;
+    const OUT: usize = 16;
+    const GROUP: usize = 32;
+
+    /// A layer of known codes, zero points and scales, `[out][in]` and
+    /// `[group][out]`. The scales are powers of two so q4_1's f16 scale and
+    /// minimum hold them exactly.
+    struct Layer {
+        codes: Vec<Vec<u32>>,
+        zeros: Vec<Vec<u32>>,
+        scales: Vec<Vec<f32>>,
+    }
+
+    impl Layer {
+        fn new() -> Self {
+            let groups = IN / GROUP;
+            Self {
+                codes: (0..OUT)
+                    .map(|row| (0..IN).map(|col| ((row * 7 + col * 3) % 16) as u32).collect())
+                    .collect(),
+                // 1..=15, so GPTQ's stored `zero - 1` stays a nibble.
+                zeros: (0..groups)
+                    .map(|group| (0..OUT).map(|row| (1 + (group * 5 + row) % 15) as u32).collect())
+                    .collect(),
+                scales: (0..groups)
+                    .map(|group| (0..OUT).map(|row| 0.5f32.powi((group + row % 3) as i32)).collect())
+                    .collect(),
+            }
+        }
+
+        /// `scale * (code - zero)` with input `col` in group `group_of[col]`.
+        fn weights(&self, group_of: &[usize]) -> Vec<Vec<f32>> {
+            (0..OUT)
+                .map(|row| {
+                    (0..IN)
+                        .map(|col| {
+                            let group = group_of[col];
+                            let code = self.codes[row][col] as f32;
+                            self.scales[group][row] * (code - self.zeros[group][row] as f32)
+                        })
+                        .collect()
+                })
+                .collect()
+        }
+
+        fn scales(&self) -> Tensor {
+            Tensor::new(self.scales.clone(), &Device::Cpu)
+                .unwrap()
+                .to_dtype(DType::F16)
+                .unwrap()
+        }
+    }
+
+    /// Eight nibbles as the int32 a checkpoint stores, loaded sign-extended.
+    fn word(nibbles: impl Iterator<Item = u32>) -> i64 {
+        let word = nibbles.enumerate().fold(0u32, |word, (i, nibble)| word | nibble << (4 * i));
+        word as i32 as i64
+    }
+
+    fn words(rows: Vec<Vec<i64>>) -> Tensor {
+        Tensor::new(rows, &Device::Cpu).unwrap()
+    }
+
+    /// GPTQ: codes packed along the inputs, zero points (minus one) along
+    /// the outputs, both in nibble order.
+    fn gptq(layer: &Layer, g_idx: &[usize]) -> HashMap<String, Tensor> {
+        let qweight = (0..IN / 8)
+            .map(|word_row| {
+                (0..OUT)
+                    .map(|row| word((0..8).map(|i| layer.codes[row][word_row * 8 + i])))
+                    .collect()
+            })
+            .collect();
+        let qzeros = layer
+            .zeros
+            .iter()
+            .map(|zeros| {
+                (0..OUT / 8)
+                    .map(|word_col| word((0..8).map(|i| zeros[word_col * 8 + i] - 1)))
+                    .collect()
+            })
+            .collect();
+        let g_idx: Vec<i64> = g_idx.iter().map(|&group| group as i64).collect();
+        HashMap::from([
+            ("qweight".to_string(), words(qweight)),
+            ("qzeros".to_string(), words(qzeros)),
+            ("scales".to_string(), layer.scales()),
+            ("g_idx".to_string(), Tensor::new(g_idx, &Device::Cpu).unwrap()),
+        ])
+    }
+
+    /// AWQ: codes and zero points packed along the outputs, nibble `i`
+    /// holding output `AWQ_ORDER[i]` of its eight.
+    fn awq(layer: &Layer) -> HashMap<String, Tensor> {
+        let order = [0, 2, 4, 6, 1, 3, 5, 7];
+        let qweight = (0..IN)
+            .map(|col| {
+                (0..OUT / 8)
+                    .map(|word_col| word(order.iter().map(|o| layer.codes[word_col * 8 + o][col])))
+                    .collect()
+            })
+            .collect();
+        let qzeros = layer
+            .zeros
+            .iter()
+            .map(|zeros| {
+                (0..OUT / 8)
+                    .map(|word_col| word(order.iter().map(|o| zeros[word_col * 8 + o])))
+                    .collect()
+            })
+            .collect();
+        HashMap::from([
+            ("qweight".to_string(), words(qweight)),
+            ("qzeros".to_string(), words(qzeros)),
+            ("scales".to_string(), layer.scales()),
+        ])
+    }
+
+    fn config(method: Int4Method, desc_act: bool) -> Int4Config {
+        Int4Config {
+            method,
+            group_size: Some(GROUP),
+            desc_act,
+            zero_offset: match method {
+                Int4Method::Gptq => 1,
+                Int4Method::Awq => 0,
+            },
+        }
+    }
+
+    fn load(tensors: HashMap<String, Tensor>, config: &Int4Config) -> Int4Weight {
+        let vb = VarBuilder::from_tensors(tensors, DType::F32, &Device::Cpu);
+        load_linear(&vb, config, IN, OUT).unwrap()
+    }
+
+    fn blocks(weight: Int4Weight) -> Vec<Vec<f32>> {
+        match weight {
+            Int4Weight::Blocks(qtensor) => {
+                assert_eq!(qtensor.dtype(), GgmlDType::Q4_1);
+                qtensor.dequantize(&Device::Cpu).unwrap().to_vec2().unwrap()
+            }
+            Int4Weight::Dense(_) => panic!("expected q4_1 blocks"),
+        }
+    }
+
+    fn sequential() -> Vec<usize> {
+        (0..IN).map(|col| col / GROUP).collect()
+    }
+
+    #[test]
+    fn repacks_gptq_into_q4_1() {
+        let layer = Layer::new();
+        let weight = load(gptq(&layer, &sequential()), &config(Int4Method::Gptq, false));
+        assert_eq!(blocks(weight), layer.weights(&sequential()));
+    }
+
+    #[test]
+    fn repacks_awq_into_q4_1() {
+        let layer = Layer::new();
+        let weight = load(awq(&layer), &config(Int4Method::Awq, false));
+        assert_eq!(blocks(weight), layer.weights(&sequential()));
+    }
+
+    #[test]
+    fn gptq_act_order_loads_dense() {
+        // Inputs alternate between the groups, which no q4_1 block can hold.
+        let layer = Layer::new();
+        let g_idx: Vec<usize> = (0..IN).map(|col| col % 2).collect();
+        match load(gptq(&layer, &g_idx), &config(Int4Method::Gptq, true)) {
+            Int4Weight::Dense(weight) => {
+                assert_eq!(weight.to_vec2::<f32>().unwrap(), layer.weights(&g_idx));
+            }
+            Int4Weight::Blocks(_) => panic!("expected dense weights"),
+        }
+    }
+
+    #[test]
+    fn gptq_sequential_act_order_still_uses_blocks() {
+        let layer = Layer::new();
+        let weight = load(gptq(&layer, &sequential()), &config(Int4Method::Gptq, true));
+        assert_eq!(blocks(weight), layer.weights(&sequential()));
+    }
+}
@@ -0,0 +1,493 @@
+// Hugging Face Hub downloads for base-inf.
+//
+// hf-hub's sync API restarts a download from scratch on any error, which makes
+// multi-GB weight files fragile behind flaky proxies. This client keeps
+// hf-hub's cache layout (so downloads are shared with other tools) but retries
+// transient failures with exponential backoff and resumes partial downloads
+// with HTTP range requests. Downloads show a progress bar on a terminal.
+//
+// Gated and private repos need an access token: --hf-token, else HF_TOKEN,
+// else the one `huggingface-cli login` saved. Without any, a gated repo's 401
+// asks for one on the terminal.
+
+use crate::error::Sl5Error;
+use anyhow::anyhow;
+use hf_hub::{Cache, Repo};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::fs::OpenOptions;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const DEFAULT_ENDPOINT: &str = "https://huggingface.co";
+/// A connection that delivers no data for this long is treated as dropped.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How failed downloads are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts made after the first failure.
+    pub max_retries: u32,
+    /// Delay before the first retry; doubled after every further failure.
+    pub initial_backoff: Duration,
+    /// Upper bound for the delay between attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Run `attempt` until it succeeds, fails permanently or runs out of
+    /// retries, sleeping with exponential backoff in between.
+    pub(crate) fn run<T>(
+        &self,
+        filename: &str,
+        mut attempt: impl FnMut() -> Result<T, FetchError>,
+    ) -> anyhow::Result<T> {
+        let mut backoff = self.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match attempt() {
+                Ok(value) => return Ok(value),
+                Err(FetchError::Transient(err)) if retries < self.max_retries => {
+                    retries += 1;
+                    tracing::warn!(
+                        "Download of {} failed ({:#}), retrying in {:.1?} [{}/{}]",
+                        filename, err, backoff, retries, self.max_retries
+                    );
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                }
+                Err(FetchError::Transient(err) | FetchError::Permanent(err)) => {
+                    return Err(err.context(format!("Failed to download {}", filename)))
+                }
+            }
+        }
+    }
+}
+
+/// A download failure, classified by whether trying again can help.
+#[derive(Debug)]
+pub(crate) enum FetchError {
+    /// Connection problems, timeouts, 408/429 and 5xx responses.
+    Transient(anyhow::Error),
+    /// Authentication failures, missing files and other client errors.
+    Permanent(anyhow::Error),
+}
+
+impl From<ureq::Error> for FetchError {
+    fn from(err: ureq::Error) -> Self {
+        match err {
+            ureq::Error::Status(code, response) => {
+                let err = anyhow!("{} returned HTTP {}", response.get_url(), code);
+                if code == 408 || code == 429 || code >= 500 {
+                    FetchError::Transient(err)
+                } else {
+                    FetchError::Permanent(err)
+                }
+            }
+            ureq::Error::Transport(transport) => FetchError::Transient(transport.into()),
+        }
+    }
+}
+
+impl From<std::io::Error> for FetchError {
+    fn from(err: std::io::Error) -> Self {
+        FetchError::Transient(err.into())
+    }
+}
+
+struct Metadata {
+    commit_hash: String,
+    etag: String,
+    size: u64,
+}
+
+pub struct HubClient {
+    cache: Cache,
+    endpoint: String,
+    /// Set from the terminal when a gated repo turns out to need one.
+    token: Mutex<Option<String>>,
+    retry: RetryPolicy,
+    agent: ureq::Agent,
+    no_redirect_agent: ureq::Agent,
+}
+
+/// `HF_ENDPOINT` when set, so mirrors such as hf-mirror.com work without
+/// extra flags, otherwise huggingface.co.
+pub fn default_endpoint() -> String {
+    let endpoint = std::env::var("HF_ENDPOINT")
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty())
+        .unwrap_or_else(|| DEFAULT_ENDPOINT.to_string());
+    endpoint.trim_end_matches('/').to_string()
+}
+
+/// The access token to send: `HF_TOKEN` (or the older
+/// `HUGGING_FACE_HUB_TOKEN`), else the one saved by `huggingface-cli login`.
+pub fn default_token() -> Option<String> {
+    ["HF_TOKEN", "HUGGING_FACE_HUB_TOKEN"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .map(|token| token.trim().to_string())
+        .find(|token| !token.is_empty())
+        .or_else(|| Cache::default().token())
+}
+
+/// A token typed in at the prompt, kept for the rest of the process so the
+/// other files of the model (and any other client) don't ask again.
+static PROMPTED_TOKEN: Mutex<Option<String>> = Mutex::new(None);
+
+/// Ask for an access token on the terminal, without echoing it. Returns
+/// `None` when there is no terminal or nothing was entered.
+fn prompt_token(repo: &str) -> Option<String> {
+    let mut prompted = PROMPTED_TOKEN.lock().unwrap();
+    if prompted.is_some() {
+        return prompted.clone();
+    }
+    if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        return None;
+    }
+    eprint!(
+        "{} is gated or private. Paste a Hugging Face access token (input hidden, empty to cancel): ",
+        repo
+    );
+    std::io::stderr().flush().ok()?;
+    let echo = set_echo(false);
+    let mut line = String::new();
+    let read = std::io::stdin().lock().read_line(&mut line);
+    if echo {
+        set_echo(true);
+    }
+    eprintln!();
+    read.ok()?;
+    let token = line.trim().to_string();
+    (!token.is_empty()).then(|| {
+        *prompted = Some(token.clone());
+        token
+    })
+}
+
+/// Turn the terminal's echo on or off; returns whether it could.
+#[cfg(unix)]
+fn set_echo(on: bool) -> bool {
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+            return false;
+        }
+        if on {
+            termios.c_lflag |= libc::ECHO;
+        } else {
+            termios.c_lflag &= !libc::ECHO;
+        }
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) == 0
+    }
+}
+
+#[cfg(not(unix))]
+fn set_echo(_on: bool) -> bool {
+    false
+}
+
+/// A directory for our own caches next to the Hub cache, e.g.
+/// `$HF_HOME/remote`.
+pub(crate) fn cache_dir(name: &str) -> PathBuf {
+    let hub_cache = Cache::default().path().clone();
+    hub_cache
+        .parent()
+        .map(|root| root.join(name))
+        .unwrap_or_else(|| hub_cache.join(name))
+}
+
+impl HubClient {
+    /// Downloads from [`default_endpoint`] unless changed with `with_endpoint`.
+    pub fn new(retry: RetryPolicy) -> Self {
+        Self {
+            cache: Cache::default(),
+            endpoint: default_endpoint(),
+            token: Mutex::new(PROMPTED_TOKEN.lock().unwrap().clone().or_else(default_token)),
+            retry,
+            agent: agent_builder().build(),
+            no_redirect_agent: agent_builder().redirects(0).build(),
+        }
+    }
+
+    /// Resolve files through `endpoint` (e.g. an internal mirror) instead.
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Cache downloads in `dir` (same layout as `$HF_HOME/hub`) instead of
+    /// the default Hub cache.
+    pub fn with_cache_dir(mut self, dir: &Path) -> Self {
+        self.cache = Cache::new(dir.to_path_buf());
+        self
+    }
+
+    /// Authenticate with `token` instead of [`default_token`].
+    pub fn with_token(self, token: String) -> Self {
+        *self.token.lock().unwrap() = Some(token);
+        self
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Returns the local path of `filename` in `repo`, downloading it first
+    /// if it isn't in the cache yet.
+    pub fn get(&self, repo: &Repo, filename: &str) -> anyhow::Result<PathBuf> {
+        if let Some(path) = self.cache.repo(repo.clone()).get(filename) {
+            return Ok(path);
+        }
+        let result = self.retry.run(filename, || self.download(repo, filename));
+        let unauthorized = result
+            .as_ref()
+            .err()
+            .and_then(|err| err.downcast_ref::<Sl5Error>())
+            .is_some_and(|err| matches!(err, Sl5Error::GatedRepo { had_token: false, .. }));
+        if unauthorized {
+            if let Some(token) = prompt_token(&repo.url()) {
+                *self.token.lock().unwrap() = Some(token);
+                return self.retry.run(filename, || self.download(repo, filename));
+            }
+        }
+        result
+    }
+
+    fn request(&self, agent: &ureq::Agent, url: &str) -> ureq::Request {
+        let request = agent.get(url);
+        match &*self.token.lock().unwrap() {
+            Some(token) => request.set("Authorization", &format!("Bearer {token}")),
+            None => request,
+        }
+    }
+
+    fn download(&self, repo: &Repo, filename: &str) -> Result<PathBuf, FetchError> {
+        let url = format!(
+            "{}/{}/resolve/{}/{}",
+            self.endpoint,
+            repo.url(),
+            repo.url_revision(),
+            filename
+        );
+        let metadata = self.metadata(repo, &url)?;
+
+        let repo_dir = self.cache.path().join(repo.folder_name());
+        let blob_path = repo_dir.join("blobs").join(&metadata.etag);
+        if !blob_path.exists() {
+            std::fs::create_dir_all(repo_dir.join("blobs"))?;
+            let partial = repo_dir
+                .join("blobs")
+                .join(format!("{}.part", metadata.etag));
+            let request = self.request(&self.agent, &url);
+            download_to(request, &partial, Some(metadata.size), filename)?;
+            std::fs::rename(&partial, &blob_path)?;
+        }
+
+        let pointer_path = repo_dir
+            .join("snapshots")
+            .join(&metadata.commit_hash)
+            .join(filename);
+        if let Some(parent) = pointer_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        link_blob(&blob_path, &pointer_path, filename)?;
+        self.cache
+            .repo(repo.clone())
+            .create_ref(&metadata.commit_hash)?;
+        Ok(pointer_path)
+    }
+
+    fn metadata(&self, repo: &Repo, url: &str) -> Result<Metadata, FetchError> {
+        let response = match self
+            .request(&self.no_redirect_agent, url)
+            .set("Range", "bytes=0-0")
+            .call()
+        {
+            Err(ureq::Error::Status(status @ (401 | 403), _)) => {
+                return Err(FetchError::Permanent(
+                    Sl5Error::GatedRepo {
+                        repo: repo.url(),
+                        url: format!("{}/{}", self.endpoint, repo.url()),
+                        status,
+                        had_token: self.token.lock().unwrap().is_some(),
+                    }
+                    .into(),
+                ))
+            }
+            // A missing file is a plain 404; a missing repo or revision says
+            // so in X-Error-Code.
+            Err(ureq::Error::Status(404, response))
+                if matches!(
+                    response.header("x-error-code"),
+                    Some("RepoNotFound" | "RevisionNotFound")
+                ) =>
+            {
+                return Err(FetchError::Permanent(
+                    Sl5Error::ModelNotFound {
+                        model: format!("{}@{}", repo.url(), repo.revision()),
+                    }
+                    .into(),
+                ))
+            }
+            response => response?,
+        };
+        let missing = |name: &str| FetchError::Permanent(anyhow!("{} has no {} header", url, name));
+        let etag = response
+            .header("x-linked-etag")
+            .or_else(|| response.header("etag"))
+            .ok_or_else(|| missing("ETag"))?
+            .replace('"', "");
+        let commit_hash = response
+            .header("x-repo-commit")
+            .ok_or_else(|| missing("X-Repo-Commit"))?
+            .to_string();
+
+        // Large files redirect to a CDN, which is the one that knows the size.
+        let response = if (300..400).contains(&response.status()) {
+            let location = response.header("location").ok_or_else(|| missing("Location"))?;
+            let location = if location.starts_with('/') {
+                format!("{}{}", self.endpoint, location)
+            } else {
+                location.to_string()
+            };
+            self.request(&self.agent, &location)
+                .set("Range", "bytes=0-0")
+                .call()?
+        } else {
+            response
+        };
+        let size = response
+            .header("content-range")
+            .and_then(|range| range.rsplit('/').next())
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(|| missing("Content-Range"))?;
+        Ok(Metadata {
+            commit_hash,
+            etag,
+            size,
+        })
+    }
+}
+
+/// Download into `partial` with `request`, continuing from whatever an earlier
+/// attempt left behind when the total `size` is known.
+pub(crate) fn download_to(
+    request: ureq::Request,
+    partial: &Path,
+    size: Option<u64>,
+    filename: &str,
+) -> Result<(), FetchError> {
+    let mut offset = std::fs::metadata(partial).map(|m| m.len()).unwrap_or(0);
+    match size {
+        Some(size) if offset == size && size > 0 => return Ok(()),
+        Some(size) if offset < size => {}
+        // Without a known size a partial file can't be trusted.
+        _ => offset = 0,
+    }
+
+    let request = if offset > 0 {
+        request.set("Range", &format!("bytes={offset}-"))
+    } else {
+        request
+    };
+    let response = request.call()?;
+    // A server that ignores the range sends the whole file again.
+    let append = offset > 0 && response.status() == 206;
+    let mib = |bytes: u64| bytes as f64 / (1u64 << 20) as f64;
+    match size {
+        Some(size) if append => tracing::info!(
+            "Resuming {} at {:.1}/{:.1} MiB...",
+            filename,
+            mib(offset),
+            mib(size)
+        ),
+        Some(size) => tracing::info!("Downloading {} ({:.1} MiB)...", filename, mib(size)),
+        None => tracing::info!("Downloading {}...", filename),
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(partial)?;
+    let progress = download_progress(size, if append { offset } else { 0 });
+    let copied = std::io::copy(&mut progress.wrap_read(response.into_reader()), &mut file);
+    progress.finish_and_clear();
+    copied?;
+    let written = file.metadata()?.len();
+    match size {
+        Some(size) if written != size => Err(FetchError::Transient(anyhow!(
+            "connection closed after {} of {} bytes",
+            written,
+            size
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// A progress bar for a download of `size` bytes (a byte counter when the
+/// size is unknown) starting at `position`. It is hidden when stderr isn't a
+/// terminal, so logs only get the "Downloading" lines.
+fn download_progress(size: Option<u64>, position: u64) -> ProgressBar {
+    let (progress, template) = match size {
+        Some(size) => (
+            ProgressBar::new(size),
+            "  [{bar:40}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta} left)",
+        ),
+        None => (
+            ProgressBar::new_spinner(),
+            "  {spinner} {bytes} ({bytes_per_sec})",
+        ),
+    };
+    if let Ok(style) = ProgressStyle::with_template(template) {
+        progress.set_style(style.progress_chars("=> "));
+    }
+    progress.set_position(position);
+    progress.reset_eta();
+    progress
+}
+
+pub(crate) fn agent_builder() -> ureq::AgentBuilder {
+    ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+}
+
+/// Point `snapshots/<commit>/<filename>` at the blob, like hf-hub does.
+fn link_blob(blob: &Path, pointer: &Path, filename: &str) -> std::io::Result<()> {
+    if pointer.exists() {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    {
+        // From snapshots/<commit>/<filename> back up to the repo directory.
+        let depth = 2 + filename.matches('/').count();
+        let mut target = PathBuf::new();
+        for _ in 0..depth {
+            target.push("..");
+        }
+        target.push("blobs");
+        target.push(blob.file_name().unwrap_or_default());
+        std::os::unix::fs::symlink(target, pointer)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = filename;
+        std::fs::copy(blob, pointer).map(|_| ())
+    }
+}
@@ -0,0 +1,61 @@
+// Llama inference with Candle, as a library.
+//
+// `ModelLoader` finds and loads a model (Hugging Face Hub, a local directory
+// or object storage), `GenerationConfig` holds the sampling settings and
+// `Generator` runs the decoding loop one token at a time. The `base-inf`
+// binary is a thin command-line front-end over these, and `sl5` collects the
+// tooling subcommands (evaluation, structured extraction, text-to-speech,
+// ...).
+
+pub mod arch;
+pub mod batch;
+pub mod bias;
+pub mod bundle;
+pub mod cached;
+pub mod chat;
+pub mod check;
+pub mod convert;
+pub mod daemon;
+pub mod dataset;
+pub mod device_map;
+pub mod dry;
+pub mod energy;
+pub mod engine;
+pub mod error;
+pub mod eval;
+pub mod extract;
+pub mod flash_attn;
+pub mod generation;
+pub mod gptq;
+pub mod hub;
+pub mod llama;
+pub mod loader;
+pub mod logging;
+pub mod lora;
+pub mod memory;
+pub mod metrics;
+pub mod pth;
+pub mod remote;
+pub mod sampling;
+pub mod schema;
+pub mod session;
+pub mod stablelm;
+pub mod stop;
+pub mod tensors;
+pub mod token_cache;
+pub mod tokenize;
+pub mod tts;
+pub mod watermark;
+
+pub use arch::Arch;
+pub use device_map::DeviceMap;
+pub use engine::EngineRegistry;
+pub use error::Sl5Error;
+pub use flash_attn::{Attention, FlashAttn};
+pub use generation::{
+    ContextOverflow, FinishReason, GenerationConfig, Generator, KvEviction, LogitsHook, RepeatScope,
+    Step, TokenLogprob, TokenLogprobs, TokenTrace,
+};
+pub use loader::{Model, ModelCache, ModelFiles, ModelLoader, Quantization, WeightFormat, Weights};
+pub use session::Session;
+pub use tokenize::{detokenize, tokenize, Tokenized};
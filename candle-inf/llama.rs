@@ -0,0 +1,1260 @@
+// Llama model used by base-inf.
+//
+// Adapted from candle_transformers::models::llama. candle's version keeps its
+// decoder blocks private, so intermediate activations (per-layer hidden
+// states, attention probabilities) can't be observed from outside; this copy
+// exposes them through `Capture` while keeping the same weights layout and
+// `Config`. Its layers can also be spread over several devices
+// (`load_sharded`).
+//
+// Qwen2 and Phi-3 differ from Llama only in details handled here: attention
+// biases, sliding-window layers, fused projection matrices and Phi-3's
+// "longrope" rotary scaling. The other `rope_scaling` types of long-context
+// fine-tunes (linear, dynamic NTK and YaRN) are implemented here too.
+//
+// The projection matrices can be quantized as each layer loads
+// (`load_sharded` with a `GgmlDType`), so fp16 checkpoints run in the memory
+// of a q8_0/q4_0 GGUF without converting them first. GPTQ and AWQ int4
+// checkpoints load their packed projections through `gptq` instead.
+//
+// At long contexts the KV cache outgrows the weights, so it can be kept in f16
+// or q8_0 instead of the model's dtype (`with_kv_cache_dtype`). q8_0 stores
+// each block of 32 values as bytes with one f16 scale, like GGML's q8_0, in
+// plain tensors so that appending stays a `cat` on any device; a layer's keys
+// and values are dequantized when its attention runs.
+
+use candle_core::quantized::{GgmlDType, QMatMul, QTensor};
+use candle_core::{DType, Device, DeviceLocation, IndexOp, Result, Tensor, D};
+use candle_nn::{
+    embedding, linear_no_bias as linear, rms_norm, Embedding, Linear, Module, RmsNorm, VarBuilder,
+};
+use candle_transformers::models::llama::{Config, Llama3RopeConfig, Llama3RopeType};
+
+use crate::gptq::{self, Int4Config, Int4Weight};
+use std::collections::{HashMap, HashSet};
+use std::f32::consts::PI;
+
+/// cos/sin tables on each device holding layers.
+type RopeTables = HashMap<DeviceLocation, (Tensor, Tensor)>;
+
+/// How the KV cache stores keys and values when not in the model's dtype.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, clap::ValueEnum)]
+pub enum KvCacheDtype {
+    /// Half the cache of an f32 model.
+    #[value(name = "f16")]
+    #[serde(rename = "f16")]
+    F16,
+    /// 8-bit values with an f16 scale per block of 32, about half the cache
+    /// of f16.
+    #[value(name = "q8_0")]
+    #[serde(rename = "q8_0")]
+    Q8_0,
+}
+
+/// Values per q8_0 block (fewer when the head dimension isn't a multiple).
+const Q8_BLOCK: usize = 32;
+
+/// One layer's keys or values, `(batch, kv_heads, positions, head_dim)`, as
+/// the cache holds them.
+#[derive(Debug, Clone)]
+enum Cached {
+    Dense(Tensor),
+    /// Values offset by 128 as u8, and a scale per block along `head_dim`.
+    Q8 { codes: Tensor, scales: Tensor },
+}
+
+impl Cached {
+    fn store(x: &Tensor, dtype: Option<KvCacheDtype>) -> Result<Self> {
+        match dtype {
+            None => Ok(Self::Dense(x.clone())),
+            Some(KvCacheDtype::F16) => Ok(Self::Dense(x.to_dtype(DType::F16)?)),
+            Some(KvCacheDtype::Q8_0) => {
+                let (codes, scales) = quantize_q8(x)?;
+                Ok(Self::Q8 { codes, scales })
+            }
+        }
+    }
+
+    /// These positions followed by those of `x`.
+    fn append(&self, x: &Tensor) -> Result<Self> {
+        match self {
+            Self::Dense(t) => Ok(Self::Dense(Tensor::cat(&[t, &x.to_dtype(t.dtype())?], 2)?)),
+            Self::Q8 { codes, scales } => {
+                let (new_codes, new_scales) = quantize_q8(x)?;
+                Ok(Self::Q8 {
+                    codes: Tensor::cat(&[codes, &new_codes], 2)?,
+                    scales: Tensor::cat(&[scales, &new_scales], 2)?,
+                })
+            }
+        }
+    }
+
+    /// The keys or values in `dtype`.
+    fn load(&self, dtype: DType) -> Result<Tensor> {
+        match self {
+            Self::Dense(t) => t.to_dtype(dtype),
+            Self::Q8 { codes, scales } => {
+                let (b, h, t, d) = codes.dims4()?;
+                let blocks = scales.dim(D::Minus2)?;
+                codes
+                    .to_dtype(DType::F32)?
+                    .affine(1., -128.)?
+                    .reshape((b, h, t, blocks, d / blocks))?
+                    .broadcast_mul(&scales.to_dtype(DType::F32)?)?
+                    .reshape((b, h, t, d))?
+                    .to_dtype(dtype)
+            }
+        }
+    }
+
+    fn positions(&self) -> Result<usize> {
+        match self {
+            Self::Dense(t) => t.dim(2),
+            Self::Q8 { codes, .. } => codes.dim(2),
+        }
+    }
+}
+
+/// q8_0 codes and f16 block scales of `x`.
+fn quantize_q8(x: &Tensor) -> Result<(Tensor, Tensor)> {
+    let (b, h, t, d) = x.dims4()?;
+    let block = if d % Q8_BLOCK == 0 { Q8_BLOCK } else { d };
+    let blocks = x.to_dtype(DType::F32)?.reshape((b, h, t, d / block, block))?;
+    let scales = (blocks.abs()?.max_keepdim(D::Minus1)? / 127.)?;
+    let codes = blocks
+        .broadcast_div(&scales.clamp(f32::MIN_POSITIVE, f32::MAX)?)?
+        .round()?
+        .affine(1., 128.)?
+        .clamp(1f32, 255f32)?
+        .to_dtype(DType::U8)?
+        .reshape((b, h, t, d))?;
+    Ok((codes, scales.to_dtype(DType::F16)?))
+}
+
+#[derive(Debug, Clone)]
+pub struct Cache {
+    /// Causal masks by `(query length, cached length, sliding window,
+    /// device)`.
+    masks: HashMap<(usize, usize, Option<usize>, DeviceLocation), Tensor>,
+    pub use_kv_cache: bool,
+    kvs: Vec<Option<(Cached, Cached)>>,
+    /// The model's dtype, which attention runs in.
+    dtype: DType,
+    /// Storage of the keys and values when not `dtype`.
+    kv_dtype: Option<KvCacheDtype>,
+    rope: RopeTables,
+    /// With longrope scaling, the tables used once the sequence is longer
+    /// than the original context length, and that length.
+    long_rope: Option<(usize, RopeTables)>,
+    /// With dynamic NTK scaling, the tables for positions past the original
+    /// context, recomputed as the sequence grows.
+    dynamic_ntk: Option<Box<DynamicTables>>,
+    /// Rotary frequencies, for moving cached keys to other positions.
+    inv_freq: Vec<f32>,
+}
+
+/// Phi-3's "longrope" rotary scaling: every frequency is divided by its own
+/// factor, from one set while the sequence fits the context the model was
+/// pretrained with and from another beyond it, and cos/sin are scaled by
+/// `attention_factor`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LongRope {
+    pub short_factor: Vec<f32>,
+    pub long_factor: Vec<f32>,
+    pub original_max_position_embeddings: usize,
+    pub attention_factor: f32,
+}
+
+impl LongRope {
+    /// The scaling for a model extended from `original_max_position_embeddings`
+    /// to `cfg.max_position_embeddings`, with the default attention factor
+    /// unless one is given.
+    pub fn new(
+        short_factor: Vec<f32>,
+        long_factor: Vec<f32>,
+        original_max_position_embeddings: usize,
+        attention_factor: Option<f32>,
+        cfg: &Config,
+    ) -> Result<Self> {
+        let rotary_dims = cfg.hidden_size / cfg.num_attention_heads / 2;
+        for (name, factors) in [("short_factor", &short_factor), ("long_factor", &long_factor)] {
+            if factors.len() != rotary_dims {
+                candle_core::bail!(
+                    "longrope {} has {} values, expected {} (half the head size)",
+                    name,
+                    factors.len(),
+                    rotary_dims
+                );
+            }
+        }
+        let scale = cfg.max_position_embeddings as f32 / original_max_position_embeddings as f32;
+        let attention_factor = attention_factor.unwrap_or(if scale <= 1. {
+            1.
+        } else {
+            (1. + scale.ln() / (original_max_position_embeddings as f32).ln()).sqrt()
+        });
+        Ok(Self {
+            short_factor,
+            long_factor,
+            original_max_position_embeddings,
+            attention_factor,
+        })
+    }
+}
+
+/// YaRN rotary scaling: frequencies that complete fewer than `beta_slow`
+/// rotations over the original context are interpolated by `factor`, those
+/// completing more than `beta_fast` are left alone, with a linear ramp in
+/// between, and cos/sin are scaled by `attention_factor`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Yarn {
+    pub factor: f32,
+    pub original_max_position_embeddings: usize,
+    pub beta_fast: f32,
+    pub beta_slow: f32,
+    pub attention_factor: f32,
+    /// Round the ramp's ends to whole dimensions, as transformers does
+    /// unless `truncate` is false.
+    pub truncate: bool,
+}
+
+impl Yarn {
+    /// The scaling with transformers' defaults for what isn't given.
+    pub fn new(
+        factor: f32,
+        original_max_position_embeddings: usize,
+        beta_fast: Option<f32>,
+        beta_slow: Option<f32>,
+        attention_factor: Option<f32>,
+        truncate: Option<bool>,
+    ) -> Self {
+        Self {
+            factor,
+            original_max_position_embeddings,
+            beta_fast: beta_fast.unwrap_or(32.),
+            beta_slow: beta_slow.unwrap_or(1.),
+            attention_factor: attention_factor.unwrap_or(if factor <= 1. {
+                1.
+            } else {
+                0.1 * factor.ln() + 1.
+            }),
+            truncate: truncate.unwrap_or(true),
+        }
+    }
+
+    fn inv_freq(&self, inv_freq: &[f32], head_dim: usize, base: f32) -> Vec<f32> {
+        // The dimension whose frequency completes `rotations` turns over the
+        // original context.
+        let dim = |rotations: f32| {
+            head_dim as f32
+                * (self.original_max_position_embeddings as f32 / (rotations * 2. * PI)).ln()
+                / (2. * base.ln())
+        };
+        let (mut low, mut high) = (dim(self.beta_fast), dim(self.beta_slow));
+        if self.truncate {
+            (low, high) = (low.floor(), high.ceil());
+        }
+        let (low, mut high) = (low.max(0.), high.min(head_dim as f32 - 1.));
+        if low == high {
+            high += 0.001;
+        }
+        inv_freq
+            .iter()
+            .enumerate()
+            .map(|(i, freq)| {
+                let interpolated = ((i as f32 - low) / (high - low)).clamp(0., 1.);
+                freq / self.factor * interpolated + freq * (1. - interpolated)
+            })
+            .collect()
+    }
+}
+
+/// How the rotary frequencies are stretched past the context a model was
+/// pretrained with, from the `rope_scaling` of its config.json. Llama 3
+/// scaling comes in `Config::rope_scaling` instead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RopeScaling {
+    /// Every frequency divided by `factor`, i.e. positions interpolated.
+    Linear { factor: f32 },
+    /// Once the sequence outgrows `original_max_position_embeddings`, the
+    /// base is raised with its length (transformers' "dynamic"). Keys already
+    /// in the cache keep the rotation they were stored with.
+    DynamicNtk {
+        factor: f32,
+        original_max_position_embeddings: usize,
+    },
+    Yarn(Yarn),
+    LongRope(LongRope),
+}
+
+impl RopeScaling {
+    /// Positions the scaled model covers, given config.json's
+    /// `max_position_embeddings` and the original context named in
+    /// `rope_scaling`, if any. Configs that scale an unchanged
+    /// `max_position_embeddings` (Qwen2.5 with YaRN, dynamic NTK) reach
+    /// `factor` times the original context.
+    pub fn max_positions(&self, max_position_embeddings: usize, original: Option<usize>) -> usize {
+        let (factor, original) = match self {
+            RopeScaling::Linear { factor } => (*factor, original),
+            RopeScaling::DynamicNtk {
+                factor,
+                original_max_position_embeddings,
+            } => (*factor, Some(*original_max_position_embeddings)),
+            RopeScaling::Yarn(yarn) => (yarn.factor, Some(yarn.original_max_position_embeddings)),
+            RopeScaling::LongRope(_) => return max_position_embeddings,
+        };
+        original.map_or(max_position_embeddings, |original| {
+            max_position_embeddings.max((original as f32 * factor) as usize)
+        })
+    }
+}
+
+/// Dynamic NTK tables for the positions of the last forward pass on each
+/// device.
+#[derive(Debug, Clone)]
+struct DynamicTables {
+    factor: f32,
+    original: usize,
+    base: f32,
+    head_dim: usize,
+    dtype: DType,
+    /// `(index_pos, seq_len, cos, sin)` by device.
+    last: HashMap<DeviceLocation, (usize, usize, Tensor, Tensor)>,
+}
+
+impl DynamicTables {
+    fn tables(&mut self, index_pos: usize, seq_len: usize, device: &Device) -> Result<(Tensor, Tensor)> {
+        if let Some((pos, len, cos, sin)) = self.last.get(&device.location()) {
+            if (*pos, *len) == (index_pos, seq_len) {
+                return Ok((cos.clone(), sin.clone()));
+            }
+        }
+        let len = (index_pos + seq_len) as f32;
+        let dim = self.head_dim as f32;
+        let base = self.base
+            * (self.factor * len / self.original as f32 - (self.factor - 1.)).powf(dim / (dim - 2.));
+        let theta: Vec<f32> = (0..self.head_dim)
+            .step_by(2)
+            .map(|i| 1f32 / base.powf(i as f32 / dim))
+            .collect();
+        let theta = Tensor::new(theta.as_slice(), &Device::Cpu)?;
+        let idx_theta = Tensor::arange(index_pos as u32, (index_pos + seq_len) as u32, &Device::Cpu)?
+            .to_dtype(DType::F32)?
+            .reshape((seq_len, 1))?
+            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+        let cos = idx_theta.cos()?.to_dtype(self.dtype)?.to_device(device)?;
+        let sin = idx_theta.sin()?.to_dtype(self.dtype)?.to_device(device)?;
+        self.last
+            .insert(device.location(), (index_pos, seq_len, cos.clone(), sin.clone()));
+        Ok((cos, sin))
+    }
+}
+
+fn calculate_default_inv_freq(cfg: &Config) -> Vec<f32> {
+    let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+    (0..head_dim)
+        .step_by(2)
+        .map(|i| 1f32 / cfg.rope_theta.powf(i as f32 / head_dim as f32))
+        .collect()
+}
+
+impl Cache {
+    pub fn new(use_kv_cache: bool, dtype: DType, config: &Config, device: &Device) -> Result<Self> {
+        Self::with_devices(use_kv_cache, dtype, config, std::slice::from_ref(device))
+    }
+
+    /// A cache for a model whose layers are spread over `devices`.
+    pub fn with_devices(
+        use_kv_cache: bool,
+        dtype: DType,
+        config: &Config,
+        devices: &[Device],
+    ) -> Result<Self> {
+        Self::with_rope_scaling(use_kv_cache, dtype, config, devices, None)
+    }
+
+    fn with_rope_scaling(
+        use_kv_cache: bool,
+        dtype: DType,
+        config: &Config,
+        devices: &[Device],
+        scaling: Option<&RopeScaling>,
+    ) -> Result<Self> {
+        // precompute freqs_cis
+        let theta = match &config.rope_scaling {
+            None
+            | Some(Llama3RopeConfig {
+                rope_type: Llama3RopeType::Default,
+                ..
+            }) => calculate_default_inv_freq(config),
+            Some(rope_scaling) => {
+                let low_freq_wavelen = rope_scaling.original_max_position_embeddings as f32
+                    / rope_scaling.low_freq_factor;
+                let high_freq_wavelen = rope_scaling.original_max_position_embeddings as f32
+                    / rope_scaling.high_freq_factor;
+
+                calculate_default_inv_freq(config)
+                    .into_iter()
+                    .map(|freq| {
+                        let wavelen = 2. * PI / freq;
+                        if wavelen < high_freq_wavelen {
+                            freq
+                        } else if wavelen > low_freq_wavelen {
+                            freq / rope_scaling.factor
+                        } else {
+                            let smooth = (rope_scaling.original_max_position_embeddings as f32
+                                / wavelen
+                                - rope_scaling.low_freq_factor)
+                                / (rope_scaling.high_freq_factor - rope_scaling.low_freq_factor);
+                            (1. - smooth) * freq / rope_scaling.factor + smooth * freq
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let max_positions = config.max_position_embeddings;
+        let head_dim = config.hidden_size / config.num_attention_heads;
+        let (rope, long_rope, inv_freq) = match scaling {
+            None | Some(RopeScaling::DynamicNtk { .. }) => {
+                (rope_tables(&theta, 1., max_positions, dtype, devices)?, None, theta)
+            }
+            Some(RopeScaling::Linear { factor }) => {
+                let theta: Vec<f32> = theta.iter().map(|freq| freq / factor).collect();
+                (rope_tables(&theta, 1., max_positions, dtype, devices)?, None, theta)
+            }
+            Some(RopeScaling::Yarn(yarn)) => {
+                let theta = yarn.inv_freq(&theta, head_dim, config.rope_theta);
+                let tables = rope_tables(&theta, yarn.attention_factor, max_positions, dtype, devices)?;
+                (tables, None, theta)
+            }
+            Some(RopeScaling::LongRope(long_rope)) => {
+                let scaled = |factors: &[f32]| -> Vec<f32> {
+                    theta.iter().zip(factors).map(|(freq, f)| freq / f).collect()
+                };
+                let tables = |theta: &[f32]| {
+                    rope_tables(theta, long_rope.attention_factor, max_positions, dtype, devices)
+                };
+                // A full cache is past the original context, where the long
+                // factors apply.
+                let long_theta = scaled(&long_rope.long_factor);
+                (
+                    tables(&scaled(&long_rope.short_factor))?,
+                    Some((long_rope.original_max_position_embeddings, tables(&long_theta)?)),
+                    long_theta,
+                )
+            }
+        };
+        let dynamic_ntk = match scaling {
+            Some(&RopeScaling::DynamicNtk {
+                factor,
+                original_max_position_embeddings,
+            }) => Some(Box::new(DynamicTables {
+                factor,
+                original: original_max_position_embeddings,
+                base: config.rope_theta,
+                head_dim,
+                dtype,
+                last: HashMap::new(),
+            })),
+            _ => None,
+        };
+        Ok(Self {
+            masks: HashMap::new(),
+            use_kv_cache,
+            kvs: vec![None; config.num_hidden_layers],
+            dtype,
+            kv_dtype: None,
+            rope,
+            long_rope,
+            dynamic_ntk,
+            inv_freq,
+        })
+    }
+
+    /// cos/sin for `seq_len` positions from `index_pos`, on `device`.
+    fn rotary(&mut self, index_pos: usize, seq_len: usize, device: &Device) -> Result<(Tensor, Tensor)> {
+        if let Some(dynamic) = &mut self.dynamic_ntk {
+            if index_pos + seq_len > dynamic.original {
+                return dynamic.tables(index_pos, seq_len, device);
+            }
+        }
+        // Like transformers, longrope switches tables once the sequence
+        // outgrows the original context, leaving earlier keys as they were.
+        let tables = match &self.long_rope {
+            Some((original, long)) if index_pos + seq_len > *original => long,
+            _ => &self.rope,
+        };
+        let Some((cos, sin)) = tables.get(&device.location()) else {
+            candle_core::bail!("The cache has no rotary tables for {:?}", device.location());
+        };
+        Ok((cos.narrow(0, index_pos, seq_len)?, sin.narrow(0, index_pos, seq_len)?))
+    }
+
+    /// Mask for `t` new positions attending to `offset` cached ones and to
+    /// each other, and with a sliding window only to the last `window`
+    /// positions (their own included).
+    fn mask(
+        &mut self,
+        t: usize,
+        offset: usize,
+        window: Option<usize>,
+        device: &Device,
+    ) -> Result<Tensor> {
+        let key = (t, offset, window, device.location());
+        if let Some(mask) = self.masks.get(&key) {
+            Ok(mask.clone())
+        } else {
+            let mask: Vec<_> = (0..t)
+                .flat_map(|i| {
+                    (0..offset + t).map(move |j| {
+                        let outside_window = window.is_some_and(|w| i + offset >= j + w);
+                        u8::from(j > i + offset || outside_window)
+                    })
+                })
+                .collect();
+            let mask = Tensor::from_slice(&mask, (t, offset + t), device)?;
+            self.masks.insert(key, mask.clone());
+            Ok(mask)
+        }
+    }
+
+    /// Cached keys and values of each layer, `(batch, kv_heads, positions,
+    /// head_dim)`, in the model's dtype.
+    pub fn kvs(&self) -> Result<Vec<Option<(Tensor, Tensor)>>> {
+        self.kvs
+            .iter()
+            .map(|kv| match kv {
+                Some((k, v)) => Ok(Some((k.load(self.dtype)?, v.load(self.dtype)?))),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    /// Replace the cached keys and values, e.g. with ones restored from disk.
+    /// Each layer's tensors must be on that layer's device.
+    pub fn set_kvs(&mut self, kvs: Vec<Option<(Tensor, Tensor)>>) -> Result<()> {
+        if kvs.len() != self.kvs.len() {
+            candle_core::bail!("Expected KV entries for {} layers, got {}", self.kvs.len(), kvs.len());
+        }
+        self.kvs = kvs
+            .iter()
+            .map(|kv| match kv {
+                Some((k, v)) => Ok(Some((
+                    Cached::store(k, self.kv_dtype)?,
+                    Cached::store(v, self.kv_dtype)?,
+                ))),
+                None => Ok(None),
+            })
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    /// Number of positions in the cache.
+    pub fn seq_len(&self) -> Result<usize> {
+        match self.kvs.iter().flatten().next() {
+            Some((k, _)) => k.positions(),
+            None => Ok(0),
+        }
+    }
+
+    /// Drop `count` cached positions after the first `keep`, moving the
+    /// later ones down to close the gap. Their keys are rotated back by
+    /// `count` positions so they match where the next tokens are placed
+    /// (approximately with longrope and dynamic NTK scaling, whose earlier
+    /// keys used other frequencies).
+    pub fn evict(&mut self, keep: usize, count: usize) -> Result<()> {
+        let len = self.seq_len()?;
+        if keep + count > len {
+            candle_core::bail!(
+                "Can't evict positions {}..{} from a cache of {}",
+                keep,
+                keep + count,
+                len
+            );
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        let rest = len - keep - count;
+        let angles: Vec<f32> = self.inv_freq.iter().map(|freq| freq * count as f32).collect();
+        let cos: Vec<f32> = angles.iter().map(|a| a.cos()).collect();
+        let sin: Vec<f32> = angles.iter().map(|a| -a.sin()).collect();
+        for kv in self.kvs.iter_mut() {
+            let Some((k, v)) = kv.as_ref() else {
+                continue;
+            };
+            let (k, v) = (k.load(self.dtype)?, v.load(self.dtype)?);
+            let shift = |table: &[f32]| {
+                Tensor::new(table, k.device())?
+                    .to_dtype(k.dtype())?
+                    .reshape((1, table.len()))?
+                    .broadcast_as((rest, table.len()))?
+                    .contiguous()
+            };
+            let moved = k.narrow(2, keep + count, rest)?.contiguous()?;
+            let moved = candle_nn::rotary_emb::rope(&moved, &shift(&cos)?, &shift(&sin)?)?;
+            let k = Tensor::cat(&[&k.narrow(2, 0, keep)?, &moved], 2)?.contiguous()?;
+            let v = Tensor::cat(&[&v.narrow(2, 0, keep)?, &v.narrow(2, keep + count, rest)?], 2)?
+                .contiguous()?;
+            *kv = Some((Cached::store(&k, self.kv_dtype)?, Cached::store(&v, self.kv_dtype)?));
+        }
+        Ok(())
+    }
+}
+
+/// cos/sin (multiplied by `scale`) of every position up to `max_positions`
+/// for the frequencies `theta`, computed in f32 and copied to each device.
+fn rope_tables(
+    theta: &[f32],
+    scale: f32,
+    max_positions: usize,
+    dtype: DType,
+    devices: &[Device],
+) -> Result<RopeTables> {
+    let theta = Tensor::new(theta, &Device::Cpu)?;
+    let idx_theta = Tensor::arange(0, max_positions as u32, &Device::Cpu)?
+        .to_dtype(DType::F32)?
+        .reshape((max_positions, 1))?
+        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+    let cos = (idx_theta.cos()? * scale as f64)?;
+    let sin = (idx_theta.sin()? * scale as f64)?;
+    let mut tables = HashMap::new();
+    for device in devices {
+        let cos = cos.to_dtype(dtype)?.to_device(device)?;
+        let sin = sin.to_dtype(dtype)?.to_device(device)?;
+        tables.insert(device.location(), (cos, sin));
+    }
+    Ok(tables)
+}
+
+/// Intermediate activations to record during a forward pass.
+#[derive(Debug, Default)]
+pub struct Capture {
+    /// Decoder layers (0-based) whose output hidden states are recorded.
+    pub hidden_layers: Vec<usize>,
+    /// Recorded `(layer, hidden_states)` pairs, each `(seq_len, hidden_size)`
+    /// in f32 on the CPU.
+    pub hidden_states: Vec<(usize, Tensor)>,
+    /// Decoder layers whose attention probabilities are recorded.
+    pub attention_layers: Vec<usize>,
+    /// Heads to keep from each recorded attention map (all when `None`).
+    pub attention_heads: Option<Vec<usize>>,
+    /// Recorded `(layer, attention)` pairs, each `(heads, q_len, kv_len)` in
+    /// f32 on the CPU.
+    pub attentions: Vec<(usize, Tensor)>,
+}
+
+impl Capture {
+    fn record_attention(&mut self, block_idx: usize, att: &Tensor) -> Result<()> {
+        if !self.attention_layers.contains(&block_idx) {
+            return Ok(());
+        }
+        let att = att.i(0)?;
+        let att = match &self.attention_heads {
+            Some(heads) => {
+                let heads = Tensor::from_iter(heads.iter().map(|&h| h as u32), att.device())?;
+                att.index_select(&heads, 0)?
+            }
+            None => att,
+        };
+        self.attentions.push((block_idx, att.to_device(&Device::Cpu)?));
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CausalSelfAttention {
+    q_proj: Proj,
+    k_proj: Proj,
+    v_proj: Proj,
+    o_proj: Proj,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    head_dim: usize,
+    /// Positions attended to, for sliding-window layers.
+    sliding_window: Option<usize>,
+    use_flash_attn: bool,
+}
+
+impl CausalSelfAttention {
+    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        let (_b_sz, _, seq_len, _hidden_size) = x.dims4()?;
+        let (cos, sin) = cache.rotary(index_pos, seq_len, x.device())?;
+        candle_nn::rotary_emb::rope(x, &cos, &sin)
+    }
+
+    fn forward(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        cache: &mut Cache,
+        capture: Option<&mut Capture>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, hidden_size) = x.dims3()?;
+        let q = self.q_proj.forward(x)?;
+        let k = self.k_proj.forward(x)?;
+        let v = self.v_proj.forward(x)?;
+
+        let q = q
+            .reshape((b_sz, seq_len, self.num_attention_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let k = k
+            .reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let mut v = v
+            .reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let q = self.apply_rotary_emb(&q, index_pos, cache)?;
+        let mut k = self.apply_rotary_emb(&k, index_pos, cache)?;
+
+        if cache.use_kv_cache {
+            let (cache_k, cache_v) = match &cache.kvs[block_idx] {
+                Some((cache_k, cache_v)) => (cache_k.append(&k)?, cache_v.append(&v)?),
+                None => (Cached::store(&k, cache.kv_dtype)?, Cached::store(&v, cache.kv_dtype)?),
+            };
+            k = cache_k.load(k.dtype())?.contiguous()?;
+            v = cache_v.load(v.dtype())?.contiguous()?;
+            cache.kvs[block_idx] = Some((cache_k, cache_v))
+        }
+
+        let kv_len = k.dim(2)?;
+        let window = self.sliding_window.filter(|&window| window < kv_len);
+        if let (1, Some(window)) = (seq_len, window) {
+            // A single new position only sees the end of the cache.
+            k = k.narrow(2, kv_len - window, window)?;
+            v = v.narrow(2, kv_len - window, window)?;
+        }
+
+        // The kernels never materialize the attention probabilities, so
+        // layers whose probabilities are recorded take the standard path.
+        let record = capture.as_ref().is_some_and(|c| c.attention_layers.contains(&block_idx));
+        if self.use_flash_attn && !record {
+            // flash-attn takes (batch, seq_len, heads, head_dim) and shares
+            // key/value heads between query heads itself. Its causal mask is
+            // aligned to the end of the keys, like `Cache::mask`.
+            let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
+            let y = flash_attn(
+                &q.transpose(1, 2)?,
+                &k.transpose(1, 2)?,
+                &v.transpose(1, 2)?,
+                softmax_scale,
+                if seq_len == 1 { None } else { window.map(|window| window - 1) },
+                seq_len > 1,
+            )?;
+            let y = y.reshape(&[b_sz, seq_len, hidden_size])?;
+            return self.o_proj.forward(&y);
+        }
+
+        let k = self.repeat_kv(k)?;
+        let v = self.repeat_kv(v)?;
+        let in_dtype = q.dtype();
+        let q = q.to_dtype(DType::F32)?;
+        let k = k.to_dtype(DType::F32)?;
+        let v = v.to_dtype(DType::F32)?;
+        let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+        let att = if seq_len == 1 {
+            att
+        } else {
+            let offset = kv_len - seq_len;
+            let mask = cache
+                .mask(seq_len, offset, window, att.device())?
+                .broadcast_as(att.shape())?;
+            masked_fill(&att, &mask, f32::NEG_INFINITY)?
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        if let Some(capture) = capture {
+            capture.record_attention(block_idx, &att)?;
+        }
+        // Convert to contiguous as matmul doesn't support strided vs for now.
+        let y = att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?;
+
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, hidden_size])?;
+        self.o_proj.forward(&y)
+    }
+
+    fn repeat_kv(&self, x: Tensor) -> Result<Tensor> {
+        candle_transformers::utils::repeat_kv(x, self.num_attention_heads / self.num_key_value_heads)
+    }
+
+    fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        quantize: Option<GgmlDType>,
+        int4: Option<&Int4Config>,
+    ) -> Result<Self> {
+        let size_in = cfg.hidden_size;
+        let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
+        let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
+        if let Some(int4) = int4 {
+            return Ok(Self {
+                q_proj: Proj::int4(vb.pp("q_proj"), size_in, size_q, int4)?,
+                k_proj: Proj::int4(vb.pp("k_proj"), size_in, size_kv, int4)?,
+                v_proj: Proj::int4(vb.pp("v_proj"), size_in, size_kv, int4)?,
+                o_proj: Proj::int4(vb.pp("o_proj"), size_q, size_in, int4)?,
+                num_attention_heads: cfg.num_attention_heads,
+                num_key_value_heads: cfg.num_key_value_heads,
+                head_dim: cfg.hidden_size / cfg.num_attention_heads,
+                sliding_window: None,
+                use_flash_attn: cfg.use_flash_attn,
+            });
+        }
+        let (q_proj, k_proj, v_proj) = if vb.contains_tensor("qkv_proj.weight") {
+            // Phi-3 stores the three projections as one matrix.
+            let qkv = vb.get((size_q + 2 * size_kv, size_in), "qkv_proj.weight")?;
+            (
+                Linear::new(qkv.narrow(0, 0, size_q)?, None),
+                Linear::new(qkv.narrow(0, size_q, size_kv)?, None),
+                Linear::new(qkv.narrow(0, size_q + size_kv, size_kv)?, None),
+            )
+        } else {
+            // Qwen2 adds biases to the query, key and value projections.
+            let qkv = if vb.contains_tensor("q_proj.bias") {
+                candle_nn::linear
+            } else {
+                linear
+            };
+            (
+                qkv(size_in, size_q, vb.pp("q_proj"))?,
+                qkv(size_in, size_kv, vb.pp("k_proj"))?,
+                qkv(size_in, size_kv, vb.pp("v_proj"))?,
+            )
+        };
+        let o_proj = linear(size_q, size_in, vb.pp("o_proj"))?;
+        Ok(Self {
+            q_proj: Proj::new(q_proj, quantize)?,
+            k_proj: Proj::new(k_proj, quantize)?,
+            v_proj: Proj::new(v_proj, quantize)?,
+            o_proj: Proj::new(o_proj, quantize)?,
+            num_attention_heads: cfg.num_attention_heads,
+            num_key_value_heads: cfg.num_key_value_heads,
+            head_dim: cfg.hidden_size / cfg.num_attention_heads,
+            sliding_window: None,
+            use_flash_attn: cfg.use_flash_attn,
+        })
+    }
+}
+
+/// Attention with the flash-attn kernels: `window` earlier positions (all
+/// when `None`) and, when `causal`, none after each query's own.
+#[cfg(feature = "flash-attn")]
+pub(crate) fn flash_attn(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    softmax_scale: f32,
+    window: Option<usize>,
+    causal: bool,
+) -> Result<Tensor> {
+    let right = if causal { Some(0) } else { None };
+    candle_flash_attn::flash_attn_windowed(q, k, v, softmax_scale, window, right)
+}
+
+#[cfg(not(feature = "flash-attn"))]
+pub(crate) fn flash_attn(
+    _: &Tensor,
+    _: &Tensor,
+    _: &Tensor,
+    _: f32,
+    _: Option<usize>,
+    _: bool,
+) -> Result<Tensor> {
+    candle_core::bail!("This build doesn't include the flash-attn feature")
+}
+
+/// A projection matrix: as loaded, or quantized for `QMatMul`.
+#[derive(Debug, Clone)]
+enum Proj {
+    Dense(Linear),
+    Quantized { weight: QMatMul, bias: Option<Tensor> },
+}
+
+impl Proj {
+    /// Quantize `linear`'s weight to `quantize`, or keep it as it is.
+    fn new(linear: Linear, quantize: Option<GgmlDType>) -> Result<Self> {
+        let Some(dtype) = quantize else {
+            return Ok(Proj::Dense(linear));
+        };
+        // Quantizing reads the whole storage, ignoring the start offset of the
+        // views that split Phi-3's fused projections, so give it its own copy.
+        let weight = linear.weight().to_dtype(DType::F32)?.force_contiguous()?;
+        let weight = QTensor::quantize(&weight, dtype)?;
+        Ok(Proj::Quantized {
+            weight: QMatMul::from_qtensor(weight)?,
+            bias: linear.bias().cloned(),
+        })
+    }
+
+    /// The packed int4 projection under `vb`, with its bias if it has one.
+    fn int4(vb: VarBuilder, in_dim: usize, out_dim: usize, config: &Int4Config) -> Result<Self> {
+        let bias = match vb.contains_tensor("bias") {
+            true => Some(vb.get(out_dim, "bias")?),
+            false => None,
+        };
+        Ok(match gptq::load_linear(&vb, config, in_dim, out_dim)? {
+            Int4Weight::Blocks(weight) => Proj::Quantized {
+                weight: QMatMul::from_qtensor(weight)?,
+                bias,
+            },
+            Int4Weight::Dense(weight) => Proj::Dense(Linear::new(weight, bias)),
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        match self {
+            Proj::Dense(linear) => linear.forward(x),
+            Proj::Quantized { weight, bias } => {
+                // Quantized matmuls take and return f32.
+                let y = weight.forward(&x.to_dtype(DType::F32)?.contiguous()?)?;
+                let y = match bias {
+                    Some(bias) => y.broadcast_add(&bias.to_dtype(DType::F32)?)?,
+                    None => y,
+                };
+                y.to_dtype(x.dtype())
+            }
+        }
+    }
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
+    let shape = mask.shape();
+    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
+    mask.where_cond(&on_true, on_false)
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    c_fc1: Proj,
+    c_fc2: Proj,
+    c_proj: Proj,
+}
+
+impl Mlp {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let x = (candle_nn::ops::silu(&self.c_fc1.forward(x)?)? * self.c_fc2.forward(x)?)?;
+        self.c_proj.forward(&x)
+    }
+
+    fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        quantize: Option<GgmlDType>,
+        int4: Option<&Int4Config>,
+    ) -> Result<Self> {
+        let h_size = cfg.hidden_size;
+        let i_size = cfg.intermediate_size;
+        if let Some(int4) = int4 {
+            return Ok(Self {
+                c_fc1: Proj::int4(vb.pp("gate_proj"), h_size, i_size, int4)?,
+                c_fc2: Proj::int4(vb.pp("up_proj"), h_size, i_size, int4)?,
+                c_proj: Proj::int4(vb.pp("down_proj"), i_size, h_size, int4)?,
+            });
+        }
+        let (c_fc1, c_fc2) = if vb.contains_tensor("gate_up_proj.weight") {
+            // Phi-3's fused gate and up projections.
+            let gate_up = vb.get((2 * i_size, h_size), "gate_up_proj.weight")?;
+            (
+                Linear::new(gate_up.narrow(0, 0, i_size)?, None),
+                Linear::new(gate_up.narrow(0, i_size, i_size)?, None),
+            )
+        } else {
+            (
+                linear(h_size, i_size, vb.pp("gate_proj"))?,
+                linear(h_size, i_size, vb.pp("up_proj"))?,
+            )
+        };
+        let c_proj = linear(i_size, h_size, vb.pp("down_proj"))?;
+        Ok(Self {
+            c_fc1: Proj::new(c_fc1, quantize)?,
+            c_fc2: Proj::new(c_fc2, quantize)?,
+            c_proj: Proj::new(c_proj, quantize)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    rms_1: RmsNorm,
+    attn: CausalSelfAttention,
+    rms_2: RmsNorm,
+    mlp: Mlp,
+    device: Device,
+}
+
+impl Block {
+    fn forward(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        cache: &mut Cache,
+        capture: Option<&mut Capture>,
+    ) -> Result<Tensor> {
+        let residual = x;
+        let x = self.rms_1.forward(x)?;
+        let x = (self.attn.forward(&x, index_pos, block_idx, cache, capture)? + residual)?;
+        let residual = &x;
+        let x = (self.mlp.forward(&self.rms_2.forward(&x)?)? + residual)?;
+        Ok(x)
+    }
+
+    fn load(
+        vb: VarBuilder,
+        cfg: &Config,
+        quantize: Option<GgmlDType>,
+        int4: Option<&Int4Config>,
+    ) -> Result<Self> {
+        let attn = CausalSelfAttention::load(vb.pp("self_attn"), cfg, quantize, int4)?;
+        let mlp = Mlp::load(vb.pp("mlp"), cfg, quantize, int4)?;
+        let rms_1 = rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
+        let rms_2 = rms_norm(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("post_attention_layernorm"),
+        )?;
+        Ok(Self {
+            rms_1,
+            attn,
+            rms_2,
+            mlp,
+            device: vb.device().clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Llama {
+    wte: Embedding,
+    blocks: Vec<Block>,
+    ln_f: RmsNorm,
+    lm_head: Proj,
+    /// Devices holding the layers, in order; the last one also holds ln_f
+    /// and lm_head.
+    devices: Vec<Device>,
+    rope_scaling: Option<RopeScaling>,
+    kv_cache_dtype: Option<KvCacheDtype>,
+}
+
+impl Llama {
+    /// Returns the logits for the last position of `x`.
+    pub fn forward(&self, x: &Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        self.forward_with_capture(x, index_pos, cache, None)
+    }
+
+    /// Same as `forward`, additionally recording the activations requested in
+    /// `capture`. Only batch size 1 is supported when capturing.
+    pub fn forward_with_capture(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+        mut capture: Option<&mut Capture>,
+    ) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let mut x = self.wte.forward(x)?;
+        for (block_idx, block) in self.blocks.iter().enumerate() {
+            // A no-op unless the previous layer lives on another device.
+            x = x.to_device(&block.device)?;
+            x = block.forward(&x, index_pos, block_idx, cache, capture.as_deref_mut())?;
+            if let Some(capture) = capture.as_deref_mut() {
+                if capture.hidden_layers.contains(&block_idx) {
+                    let hidden = x.i(0)?.to_dtype(DType::F32)?.to_device(&Device::Cpu)?;
+                    capture.hidden_states.push((block_idx, hidden));
+                }
+            }
+        }
+        let x = self.ln_f.forward(&x.to_device(&self.devices[self.devices.len() - 1])?)?;
+        let x = x.i((.., seq_len - 1, ..))?.contiguous()?;
+        let logits = self.lm_head.forward(&x)?;
+        logits.to_dtype(DType::F32)
+    }
+
+    /// Devices the layers live on, in order.
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+
+    /// Device holding decoder layer `layer`.
+    pub fn layer_device(&self, layer: usize) -> &Device {
+        &self.blocks[layer].device
+    }
+
+    pub fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+        Self::load_sharded(&[(vb, cfg.num_hidden_layers)], cfg, None, None)
+    }
+
+    /// A cache with this model's rotary tables on each of its devices.
+    pub fn new_cache(&self, use_kv_cache: bool, dtype: DType, cfg: &Config) -> Result<Cache> {
+        let mut cache =
+            Cache::with_rope_scaling(use_kv_cache, dtype, cfg, &self.devices, self.rope_scaling.as_ref())?;
+        cache.kv_dtype = self.kv_cache_dtype;
+        Ok(cache)
+    }
+
+    /// Keep the keys and values of new caches in `dtype` (default: the
+    /// model's dtype).
+    pub fn with_kv_cache_dtype(mut self, dtype: Option<KvCacheDtype>) -> Self {
+        self.kv_cache_dtype = dtype;
+        self
+    }
+
+    /// Scale the rotary embeddings as the config's `rope_scaling` asks.
+    pub fn with_rope_scaling(mut self, rope_scaling: RopeScaling) -> Self {
+        self.rope_scaling = Some(rope_scaling);
+        self
+    }
+
+    /// Restrict attention in the layers from `first_layer` on to the last
+    /// `window` positions (Qwen2 with `use_sliding_window`, Phi-3).
+    pub fn with_sliding_window(mut self, window: usize, first_layer: usize) -> Self {
+        for block in self.blocks.iter_mut().skip(first_layer) {
+            block.attn.sliding_window = Some(window);
+        }
+        self
+    }
+
+    /// Load consecutive runs of layers from each `(var_builder, num_layers)`
+    /// stage onto that var builder's device. The embedding goes on the first
+    /// stage, the final norm and lm_head on the last. With `quantize`, the
+    /// projections and lm_head are quantized layer by layer as they load;
+    /// with `int4`, the projections (and lm_head, if it is packed too) are
+    /// read from a GPTQ or AWQ checkpoint.
+    pub fn load_sharded(
+        stages: &[(VarBuilder, usize)],
+        cfg: &Config,
+        quantize: Option<GgmlDType>,
+        int4: Option<&Int4Config>,
+    ) -> Result<Self> {
+        let (Some((first, _)), Some((last, _))) = (stages.first(), stages.last()) else {
+            candle_core::bail!("No devices to load the model on");
+        };
+        let mapped: usize = stages.iter().map(|(_, layers)| layers).sum();
+        if mapped != cfg.num_hidden_layers {
+            candle_core::bail!(
+                "{} layers mapped to devices but the model has {}",
+                mapped,
+                cfg.num_hidden_layers
+            );
+        }
+
+        let wte = embedding(cfg.vocab_size, cfg.hidden_size, first.pp("model.embed_tokens"))?;
+        let lm_head = match int4 {
+            Some(int4) if last.contains_tensor("lm_head.qweight") => {
+                Proj::int4(last.pp("lm_head"), cfg.hidden_size, cfg.vocab_size, int4)?
+            }
+            _ => {
+                let lm_head = if cfg.tie_word_embeddings {
+                    Linear::new(wte.embeddings().to_device(last.device())?, None)
+                } else {
+                    linear(cfg.hidden_size, cfg.vocab_size, last.pp("lm_head"))?
+                };
+                Proj::new(lm_head, quantize)?
+            }
+        };
+        let ln_f = rms_norm(cfg.hidden_size, cfg.rms_norm_eps, last.pp("model.norm"))?;
+        let mut blocks = Vec::with_capacity(cfg.num_hidden_layers);
+        for (vb, layers) in stages {
+            for i in blocks.len()..blocks.len() + layers {
+                blocks.push(Block::load(vb.pp(format!("model.layers.{i}")), cfg, quantize, int4)?);
+            }
+        }
+
+        Ok(Self {
+            wte,
+            blocks,
+            ln_f,
+            lm_head,
+            devices: stages.iter().map(|(vb, _)| vb.device().clone()).collect(),
+            rope_scaling: None,
+            kv_cache_dtype: None,
+        })
+    }
+}
+
+/// Names and shapes of the tensors `Llama::load` reads for `cfg`.
+pub fn expected_tensors(cfg: &Config) -> Vec<(String, Vec<usize>)> {
+    let hidden = cfg.hidden_size;
+    let head_dim = hidden / cfg.num_attention_heads;
+    let size_q = head_dim * cfg.num_attention_heads;
+    let size_kv = head_dim * cfg.num_key_value_heads;
+    let mut tensors = vec![(
+        "model.embed_tokens.weight".to_string(),
+        vec![cfg.vocab_size, hidden],
+    )];
+    for i in 0..cfg.num_hidden_layers {
+        let layer = |name: &str, shape: Vec<usize>| (format!("model.layers.{i}.{name}.weight"), shape);
+        tensors.extend([
+            layer("input_layernorm", vec![hidden]),
+            layer("self_attn.q_proj", vec![size_q, hidden]),
+            layer("self_attn.k_proj", vec![size_kv, hidden]),
+            layer("self_attn.v_proj", vec![size_kv, hidden]),
+            layer("self_attn.o_proj", vec![hidden, size_q]),
+            layer("post_attention_layernorm", vec![hidden]),
+            layer("mlp.gate_proj", vec![cfg.intermediate_size, hidden]),
+            layer("mlp.up_proj", vec![cfg.intermediate_size, hidden]),
+            layer("mlp.down_proj", vec![hidden, cfg.intermediate_size]),
+        ]);
+    }
+    tensors.push(("model.norm.weight".to_string(), vec![hidden]));
+    if !cfg.tie_word_embeddings {
+        tensors.push(("lm_head.weight".to_string(), vec![cfg.vocab_size, hidden]));
+    }
+    tensors
+}
+
+/// `expected_tensors` with the query, key and value projections fused into
+/// `qkv_proj` and the gate and up projections into `gate_up_proj`, as Phi-3
+/// stores them.
+pub fn fused_tensors(cfg: &Config) -> Vec<(String, Vec<usize>)> {
+    let hidden = cfg.hidden_size;
+    let head_dim = hidden / cfg.num_attention_heads;
+    let size_qkv = head_dim * (cfg.num_attention_heads + 2 * cfg.num_key_value_heads);
+    let fused = ["q_proj", "k_proj", "v_proj", "gate_proj", "up_proj"];
+    let mut tensors: Vec<_> = expected_tensors(cfg)
+        .into_iter()
+        .filter(|(name, _)| !fused.iter().any(|f| name.ends_with(&format!(".{f}.weight"))))
+        .collect();
+    for i in 0..cfg.num_hidden_layers {
+        tensors.extend([
+            (format!("model.layers.{i}.self_attn.qkv_proj.weight"), vec![size_qkv, hidden]),
+            (
+                format!("model.layers.{i}.mlp.gate_up_proj.weight"),
+                vec![2 * cfg.intermediate_size, hidden],
+            ),
+        ]);
+    }
+    tensors
+}
+
+/// Names and shapes of the query, key and value biases that Qwen2 adds to
+/// the `expected_tensors` layout.
+pub fn attention_bias_tensors(cfg: &Config) -> Vec<(String, Vec<usize>)> {
+    let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+    let size_q = head_dim * cfg.num_attention_heads;
+    let size_kv = head_dim * cfg.num_key_value_heads;
+    (0..cfg.num_hidden_layers)
+        .flat_map(|i| {
+            [("q_proj", size_q), ("k_proj", size_kv), ("v_proj", size_kv)]
+                .map(|(name, size)| (format!("model.layers.{i}.self_attn.{name}.bias"), vec![size]))
+        })
+        .collect()
+}
+
+/// The tensors `Llama::load` reads from a file whose tensors are `names`:
+/// like the loader, it takes fused projections (Phi-3) and attention biases
+/// (Qwen2) when the file has them, judging by the first layer.
+pub fn loaded_tensors(cfg: &Config, names: &HashSet<String>) -> Vec<(String, Vec<usize>)> {
+    let mut tensors = if names.contains("model.layers.0.self_attn.qkv_proj.weight") {
+        fused_tensors(cfg)
+    } else {
+        expected_tensors(cfg)
+    };
+    if names.contains("model.layers.0.self_attn.q_proj.bias") {
+        tensors.extend(attention_bias_tensors(cfg));
+    }
+    tensors
+}
@@ -0,0 +1,979 @@
+// Locating, downloading and loading model files.
+
+use crate::arch::{Arch, StatefulModel};
+use crate::check::{audit_tensors, summarize_names, tensor_names, WeightIssue};
+use crate::device_map::DeviceMap;
+use crate::error::Sl5Error;
+use crate::flash_attn::{self, Attention, FlashAttn};
+use crate::gptq::Int4Config;
+use crate::hub::{HubClient, RetryPolicy};
+use crate::llama::{
+    loaded_tensors, Cache, Capture, KvCacheDtype, Llama, LongRope, RopeScaling, Yarn,
+};
+use crate::lora::{LoraAdapter, LoraBackend, LoraFiles, LoraSpec};
+use crate::pth;
+use crate::remote::RemoteSource;
+use crate::tokenize::{detokenize, tokenize, Tokenized};
+use anyhow::{anyhow, bail, Context, Result};
+use candle_core::quantized::{gguf_file, GgmlDType};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::llama::{
+    Config, Llama3RopeConfig, Llama3RopeType, LlamaEosToks, DEFAULT_MAX_SEQ_LEN,
+};
+use candle_transformers::models::quantized_llama::ModelWeights as QuantizedLlama;
+use hf_hub::{Repo, RepoType};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tokenizers::Tokenizer;
+
+/// Token that ends generation when config.json doesn't name one.
+const EOS_TOKEN: &str = "</s>";
+
+/// Where to find a model: a Hub repo id, a local directory, or an `s3://`,
+/// `gs://` or `https://` directory URL.
+#[derive(Debug, Clone)]
+pub struct ModelLoader {
+    pub(crate) model_id: String,
+    pub(crate) local: bool,
+    pub(crate) revision: Option<String>,
+    pub(crate) hub_endpoint: Option<String>,
+    hf_token: Option<String>,
+    retry: RetryPolicy,
+    pub(crate) quantized: Option<String>,
+    pub(crate) arch: Option<Arch>,
+    pub(crate) lora: Vec<String>,
+    pub(crate) quantize: Option<Quantization>,
+    pub(crate) kv_cache_dtype: Option<KvCacheDtype>,
+    pub(crate) flash_attn: FlashAttn,
+    pub(crate) cache_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeightFormat {
+    /// fp16/bf16/f32 safetensors, loaded into a `Llama`.
+    Safetensors,
+    /// llama.cpp quantized weights (Q4_K_M, Q5_K_M, ...), run with candle's
+    /// quantized llama.
+    Gguf,
+}
+
+/// Quantization applied to safetensors weights while they load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, clap::ValueEnum)]
+pub enum Quantization {
+    /// 8-bit, about half the memory of f16 at close to no quality cost.
+    #[value(name = "q8_0")]
+    #[serde(rename = "q8_0")]
+    Q8_0,
+    /// 4-bit, about a quarter of the memory of f16, with a visible quality
+    /// cost on small models.
+    #[value(name = "q4_0")]
+    #[serde(rename = "q4_0")]
+    Q4_0,
+}
+
+impl Quantization {
+    pub fn ggml_dtype(self) -> GgmlDType {
+        match self {
+            Quantization::Q8_0 => GgmlDType::Q8_0,
+            Quantization::Q4_0 => GgmlDType::Q4_0,
+        }
+    }
+}
+
+/// The files making up a model, once they are on local disk.
+#[derive(Debug, Clone)]
+pub struct ModelFiles {
+    pub tokenizer: PathBuf,
+    /// config.json; GGUF files carry their own configuration instead.
+    pub config: Option<PathBuf>,
+    /// quantize_config.json (GPTQ) or quant_config.json (AutoAWQ), for int4
+    /// checkpoints whose config.json has no `quantization_config`.
+    pub quantize_config: Option<PathBuf>,
+    pub weights: PathBuf,
+    pub format: WeightFormat,
+    /// Architecture override; detected from config.json when `None`.
+    pub arch: Option<Arch>,
+    /// LoRA adapters merged into the safetensors weights as they load.
+    pub lora: Vec<LoraFiles>,
+    /// Quantization of the safetensors weights as they load.
+    pub quantize: Option<Quantization>,
+    /// Storage of the KV cache when not the model's dtype.
+    pub kv_cache_dtype: Option<KvCacheDtype>,
+    /// Whether to run attention with the flash-attn kernels.
+    pub flash_attn: FlashAttn,
+}
+
+/// Loaded model weights.
+pub enum Weights {
+    Llama(Llama),
+    /// Quantized Llama and the other architectures.
+    Stateful(Box<dyn StatefulModel>),
+}
+
+/// Decoding state of one sequence. Stateful models keep their KV cache inside
+/// the model, so each sequence gets its own (cheap) clone of it.
+pub enum ModelCache {
+    Llama(Cache),
+    Stateful(Box<dyn StatefulModel>),
+}
+
+/// A loaded model together with everything needed to run it.
+pub struct Model {
+    pub weights: Weights,
+    pub tokenizer: Tokenizer,
+    pub config: Config,
+    /// Token ids that end generation.
+    pub eos_token_ids: Vec<u32>,
+    pub dtype: DType,
+    /// Where inputs go: the device of the embedding and first layers.
+    pub device: Device,
+    /// The attention implementation the layers run with.
+    pub attention: Attention,
+}
+
+/// Where `ModelLoader::files` fetches from.
+enum Source {
+    Local(PathBuf),
+    Remote(RemoteSource),
+    Hub(HubClient, Repo),
+}
+
+impl Source {
+    fn get(&self, filename: &str) -> Result<PathBuf> {
+        match self {
+            Source::Local(dir) => {
+                let path = dir.join(filename);
+                if !path.exists() {
+                    bail!("Missing {} in {}", filename, dir.display());
+                }
+                Ok(path)
+            }
+            Source::Remote(remote) => remote.get(filename),
+            Source::Hub(hub, repo) => hub.get(repo, filename),
+        }
+    }
+
+    /// The safetensors weights; a PyTorch checkpoint (pytorch_model.bin,
+    /// possibly sharded) is converted to one first, cached under
+    /// `cache_root`.
+    fn safetensors(&self, cache_root: Option<&Path>) -> Result<PathBuf> {
+        if let Source::Local(dir) = self {
+            if dir.join("model.safetensors").exists() {
+                return Ok(dir.join("model.safetensors"));
+            } else if dir.join("model-00001-of-00002.safetensors").exists() {
+                // Handle sharded models - we'll need to adjust VarBuilder later
+                bail!("Sharded models not yet supported in this script. Please use a single safetensors file.");
+            } else if !dir.join("pytorch_model.bin").exists() && !dir.join(pth::INDEX).exists() {
+                bail!("No model.safetensors found in {}", dir.display());
+            }
+        } else if let Ok(path) = self.get("model.safetensors") {
+            return Ok(path);
+        }
+        tracing::info!("model.safetensors not found, trying pytorch_model.bin...");
+        let shards = match self.get("pytorch_model.bin") {
+            Ok(path) => vec![path],
+            Err(_) => {
+                let index = self.get(pth::INDEX).map_err(|_| {
+                    anyhow!("Found neither model.safetensors nor pytorch_model.bin weights")
+                })?;
+                pth::shard_names(&index)?
+                    .iter()
+                    .map(|shard| self.get(shard))
+                    .collect::<Result<Vec<_>>>()?
+            }
+        };
+        pth::to_safetensors(&shards, cache_root)
+    }
+}
+
+impl ModelLoader {
+    pub fn new(model_id: impl Into<String>) -> Self {
+        Self {
+            model_id: model_id.into(),
+            local: false,
+            revision: None,
+            hub_endpoint: None,
+            hf_token: None,
+            retry: RetryPolicy::default(),
+            quantized: None,
+            arch: None,
+            lora: Vec::new(),
+            quantize: None,
+            kv_cache_dtype: None,
+            flash_attn: FlashAttn::default(),
+            cache_dir: None,
+        }
+    }
+
+    /// Treat the model id as a local directory.
+    pub fn local(mut self, local: bool) -> Self {
+        self.local = local;
+        self
+    }
+
+    /// Hub revision or branch (default: `main`).
+    pub fn revision(mut self, revision: Option<String>) -> Self {
+        self.revision = revision;
+        self
+    }
+
+    /// Hub endpoint or mirror (default: `$HF_ENDPOINT` or huggingface.co).
+    pub fn hub_endpoint(mut self, endpoint: Option<String>) -> Self {
+        self.hub_endpoint = endpoint;
+        self
+    }
+
+    /// Hub access token for gated and private repos (default: `$HF_TOKEN` or
+    /// the `huggingface-cli login` token).
+    pub fn hf_token(mut self, token: Option<String>) -> Self {
+        self.hf_token = token;
+        self
+    }
+
+    /// Cache downloaded files in `dir` instead of `$HF_HOME/hub`: Hub repos
+    /// in the same layout, object storage and HTTPS sources under
+    /// `dir/remote`.
+    pub fn cache_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.cache_dir = dir;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Load this GGUF file instead of the safetensors weights: a local path,
+    /// or a file name within the model repo or directory. The tokenizer still
+    /// comes from the model.
+    pub fn quantized(mut self, gguf: Option<String>) -> Self {
+        self.quantized = gguf;
+        self
+    }
+
+    /// Use this architecture instead of the one config.json names.
+    pub fn arch(mut self, arch: Option<Arch>) -> Self {
+        self.arch = arch;
+        self
+    }
+
+    /// Merge these LoRA adapters (each a local directory or Hub repo holding
+    /// adapter_config.json and adapter_model.safetensors, optionally followed
+    /// by `=WEIGHT`) into the weights.
+    pub fn lora(mut self, adapters: Vec<String>) -> Self {
+        self.lora = adapters;
+        self
+    }
+
+    /// Quantize the projection matrices of fp16/bf16/f32 safetensors weights
+    /// while loading them, for a slower load and a fraction of the memory.
+    pub fn quantize(mut self, quantize: Option<Quantization>) -> Self {
+        self.quantize = quantize;
+        self
+    }
+
+    /// Keep the KV cache in f16 or q8_0 instead of the model's dtype, for
+    /// less memory at long contexts (Llama, Qwen2 and Phi-3 safetensors
+    /// weights).
+    pub fn kv_cache_dtype(mut self, dtype: Option<KvCacheDtype>) -> Self {
+        self.kv_cache_dtype = dtype;
+        self
+    }
+
+    /// Whether to use the flash-attn kernels (default: when the build, GPUs
+    /// and dtype support them).
+    pub fn flash_attn(mut self, flash_attn: FlashAttn) -> Self {
+        self.flash_attn = flash_attn;
+        self
+    }
+
+    /// Find the tokenizer, config and weights, downloading them first when
+    /// the model isn't local.
+    pub fn files(&self) -> Result<ModelFiles> {
+        let source = self.source()?;
+        let tokenizer = source.get("tokenizer.json")?;
+        if !self.lora.is_empty() && self.quantized.is_some() {
+            bail!("LoRA adapters can't be merged into quantized GGUF weights");
+        }
+        let mut lora = Vec::new();
+        for adapter in &self.lora {
+            let spec: LoraSpec = adapter.parse()?;
+            let adapter_source = ModelLoader {
+                model_id: spec.source.clone(),
+                local: Path::new(&spec.source).is_dir(),
+                revision: None,
+                lora: Vec::new(),
+                ..self.clone()
+            }
+            .source()?;
+            lora.push(LoraFiles {
+                config: adapter_source.get("adapter_config.json")?,
+                weights: adapter_source.get("adapter_model.safetensors")?,
+                weight: spec.weight,
+            });
+        }
+        let files = match &self.quantized {
+            Some(_) if self.quantize.is_some() => {
+                bail!("GGUF weights are already quantized; --quantize is for safetensors weights")
+            }
+            Some(_) if self.kv_cache_dtype.is_some() => {
+                bail!("GGUF models keep their KV cache in f32; --kv-cache-dtype is for safetensors weights")
+            }
+            Some(gguf) => ModelFiles {
+                tokenizer,
+                config: None,
+                quantize_config: None,
+                weights: match Path::new(gguf) {
+                    path if path.is_file() => path.to_path_buf(),
+                    _ => source.get(gguf)?,
+                },
+                format: WeightFormat::Gguf,
+                arch: self.arch,
+                lora,
+                quantize: None,
+                kv_cache_dtype: None,
+                flash_attn: self.flash_attn,
+            },
+            None => {
+                let config = source.get("config.json")?;
+                let weights = source.safetensors(self.cache_dir.as_deref())?;
+                // Older GPTQ and AWQ repos describe the packing in a file of
+                // its own rather than in config.json.
+                let config_json: serde_json::Value = serde_json::from_slice(&std::fs::read(&config)?)?;
+                let packed = config_json["quantization_config"].is_null()
+                    && tensor_names(&weights)?.iter().any(|name| name.ends_with(".qweight"));
+                let quantize_config = match packed {
+                    true => ["quantize_config.json", "quant_config.json"]
+                        .iter()
+                        .find_map(|name| source.get(name).ok()),
+                    false => None,
+                };
+                ModelFiles {
+                    tokenizer,
+                    config: Some(config),
+                    quantize_config,
+                    weights,
+                    format: WeightFormat::Safetensors,
+                    arch: self.arch,
+                    lora,
+                    quantize: self.quantize,
+                    kv_cache_dtype: self.kv_cache_dtype,
+                    flash_attn: self.flash_attn,
+                }
+            }
+        };
+
+        match source {
+            Source::Local(_) => tracing::info!("Found local model files"),
+            _ => tracing::info!("Model files downloaded successfully"),
+        }
+        Ok(files)
+    }
+
+    /// Fetch one more file of the model, e.g. tokenizer_config.json.
+    pub fn file(&self, filename: &str) -> Result<PathBuf> {
+        self.source()?.get(filename)
+    }
+
+    fn source(&self) -> Result<Source> {
+        if self.local {
+            if !Path::new(&self.model_id).is_dir() {
+                bail!(Sl5Error::ModelNotFound {
+                    model: self.model_id.clone(),
+                });
+            }
+            tracing::info!("Loading model from local directory: {}", self.model_id);
+            return Ok(Source::Local(PathBuf::from(&self.model_id)));
+        }
+
+        let cache_dir = self.cache_dir.as_deref();
+        if let Some(remote) = RemoteSource::parse(&self.model_id, self.retry.clone(), cache_dir)? {
+            tracing::info!("Downloading model files from {}...", remote.url());
+            return Ok(Source::Remote(remote));
+        }
+
+        let mut hub = HubClient::new(self.retry.clone());
+        if let Some(endpoint) = &self.hub_endpoint {
+            hub = hub.with_endpoint(endpoint);
+        }
+        if let Some(token) = &self.hf_token {
+            hub = hub.with_token(token.clone());
+        }
+        if let Some(dir) = cache_dir {
+            hub = hub.with_cache_dir(dir);
+        }
+        tracing::info!("Downloading model files from {}...", hub.endpoint());
+        let repo = Repo::with_revision(
+            self.model_id.clone(),
+            RepoType::Model,
+            self.revision.clone().unwrap_or("main".to_string()),
+        );
+        Ok(Source::Hub(hub, repo))
+    }
+
+    /// Resolve the files and load everything in one go.
+    pub fn load(&self, dtype: DType, device: &Device) -> Result<Model> {
+        self.files()?.load(dtype, device)
+    }
+}
+
+impl ModelFiles {
+    pub fn load_tokenizer(&self) -> Result<Tokenizer> {
+        Tokenizer::from_file(&self.tokenizer)
+            .map_err(|e| anyhow::anyhow!("Failed to load tokenizer: {}", e))
+    }
+
+    /// The model configuration, as far as it is shared by all architectures.
+    /// With RoPE scaling, `max_position_embeddings` is the scaled context.
+    pub fn load_config(&self) -> Result<Config> {
+        let Some(config_json) = self.config_json()? else {
+            return config_from_gguf(&self.read_gguf()?.0);
+        };
+        let mut config = config_from_json(&config_json);
+        if let Some(scaling) = self.rope_scaling(&config)? {
+            let original = config_json["rope_scaling"]["original_max_position_embeddings"].as_u64();
+            config.max_position_embeddings = scaling
+                .max_positions(config.max_position_embeddings, original.map(|n| n as usize));
+        }
+        Ok(config)
+    }
+
+    fn config_json(&self) -> Result<Option<serde_json::Value>> {
+        match &self.config {
+            Some(path) => Ok(Some(serde_json::from_slice(&std::fs::read(path)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// How the linear layers of a GPTQ or AWQ checkpoint are packed: from
+    /// the `quantization_config` of config.json or from `quantize_config`.
+    /// `None` for unquantized weights.
+    pub fn int4_config(&self) -> Result<Option<Int4Config>> {
+        if let Some(path) = &self.quantize_config {
+            let json = serde_json::from_slice(&std::fs::read(path)?)
+                .with_context(|| format!("Invalid JSON in {}", path.display()))?;
+            return Ok(Some(Int4Config::from_json(&json)?));
+        }
+        match self.config_json()? {
+            Some(json) if !json["quantization_config"].is_null() => {
+                Ok(Some(Int4Config::from_json(&json["quantization_config"])?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The tensors `Llama::load_sharded` reads for `config`, with the packed
+    /// ones of an int4 checkpoint in place of the projection weights.
+    pub fn loaded_tensors(&self, config: &Config) -> Result<Vec<(String, Vec<usize>)>> {
+        let names = tensor_names(&self.weights)?;
+        let tensors = loaded_tensors(config, &names);
+        Ok(match self.int4_config()? {
+            Some(int4) => int4.packed(tensors, &names),
+            None => tensors,
+        })
+    }
+
+    /// The override if there is one, otherwise the architecture config.json
+    /// names. GGUF files are always run as Llama.
+    pub fn arch(&self) -> Result<Arch> {
+        match (self.arch, self.config_json()?) {
+            (Some(arch), _) => Ok(arch),
+            (None, Some(config_json)) => Arch::detect(&config_json),
+            (None, None) => Ok(Arch::Llama),
+        }
+    }
+
+    /// The sliding window and the first layer using it. Qwen2 only uses one
+    /// when config.json enables it (Qwen2.5 configs name a window but leave
+    /// it disabled); Phi-3 applies its window to every layer.
+    fn sliding_window(&self) -> Result<Option<(usize, usize)>> {
+        let Some(config_json) = self.config_json()? else {
+            return Ok(None);
+        };
+        let Some(window) = config_json["sliding_window"].as_u64() else {
+            return Ok(None);
+        };
+        match self.arch()? {
+            Arch::Qwen2 if config_json["use_sliding_window"].as_bool() == Some(true) => {
+                let first_layer = config_json["max_window_layers"].as_u64().unwrap_or(0);
+                Ok(Some((window as usize, first_layer as usize)))
+            }
+            Arch::Phi3 => Ok(Some((window as usize, 0))),
+            _ => Ok(None),
+        }
+    }
+
+    /// The RoPE scaling of Llama, Qwen2 and Phi-3 models from the
+    /// `rope_scaling` of their config.json: linear, dynamic NTK, YaRN or
+    /// Phi-3's longrope (`"su"` in older configs). Llama 3 scaling is read
+    /// into `Config` by `config_from_json`. `config` must be the unscaled one.
+    fn rope_scaling(&self, config: &Config) -> Result<Option<RopeScaling>> {
+        let Some(config_json) = self.config_json()? else {
+            return Ok(None);
+        };
+        let scaling = &config_json["rope_scaling"];
+        if scaling.is_null() || !matches!(self.arch()?, Arch::Llama | Arch::Qwen2 | Arch::Phi3) {
+            return Ok(None);
+        }
+        let rope_type = scaling["rope_type"].as_str().or(scaling["type"].as_str());
+        let factor = || match scaling["factor"].as_f64() {
+            Some(factor) if factor > 0. => Ok(factor as f32),
+            _ => bail!("{} rope_scaling needs a positive factor", rope_type.unwrap_or("")),
+        };
+        let float = |name: &str| scaling[name].as_f64().map(|f| f as f32);
+        // Without an original length, max_position_embeddings is the one the
+        // model was pretrained with.
+        let original = scaling["original_max_position_embeddings"]
+            .as_u64()
+            .map_or(config.max_position_embeddings, |n| n as usize);
+        match rope_type {
+            None | Some("default" | "llama3") => return Ok(None),
+            Some("linear") => return Ok(Some(RopeScaling::Linear { factor: factor()? })),
+            Some("dynamic") => {
+                return Ok(Some(RopeScaling::DynamicNtk {
+                    factor: factor()?,
+                    original_max_position_embeddings: original,
+                }))
+            }
+            Some("yarn") => {
+                return Ok(Some(RopeScaling::Yarn(Yarn::new(
+                    factor()?,
+                    original,
+                    float("beta_fast"),
+                    float("beta_slow"),
+                    float("attention_factor"),
+                    scaling["truncate"].as_bool(),
+                ))))
+            }
+            Some("longrope" | "su") => {}
+            Some(rope_type) => bail!("Unsupported rope_scaling type {:?}", rope_type),
+        }
+        let factors = |name: &str| -> Result<Vec<f32>> {
+            serde_json::from_value(scaling[name].clone())
+                .map_err(|e| anyhow::anyhow!("Invalid rope_scaling.{}: {}", name, e))
+        };
+        // Phi-3 configs keep the original length at the top level, newer
+        // transformers versions inside rope_scaling.
+        let Some(original) = config_json["original_max_position_embeddings"]
+            .as_u64()
+            .or(scaling["original_max_position_embeddings"].as_u64())
+        else {
+            bail!("longrope scaling needs original_max_position_embeddings in config.json");
+        };
+        let long_rope = LongRope::new(
+            factors("short_factor")?,
+            factors("long_factor")?,
+            original as usize,
+            float("attention_factor"),
+            config,
+        )?;
+        Ok(Some(RopeScaling::LongRope(long_rope)))
+    }
+
+    fn read_gguf(&self) -> Result<(gguf_file::Content, std::fs::File)> {
+        let mut file = std::fs::File::open(&self.weights)?;
+        let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(&self.weights))?;
+        Ok((content, file))
+    }
+
+    /// Memory-map the weights.
+    pub fn var_builder(&self, dtype: DType, device: &Device) -> Result<VarBuilder<'static>> {
+        if self.format == WeightFormat::Gguf {
+            bail!("{} is a GGUF file, which needs the quantized model", self.weights.display());
+        }
+        if self.lora.is_empty() {
+            return Ok(unsafe {
+                VarBuilder::from_mmaped_safetensors(&[self.weights.as_path()], dtype, device)?
+            });
+        }
+        let adapters = self.lora.iter().map(LoraAdapter::load).collect::<Result<Vec<_>>>()?;
+        let backend = LoraBackend::new(&self.weights, adapters)?;
+        Ok(VarBuilder::from_backend(Box::new(backend), dtype, device.clone()))
+    }
+
+    /// Why flash attention can't run this model in `dtype` on `device_map`,
+    /// or `None` if it can.
+    pub fn flash_attn_unsupported(&self, dtype: DType, device_map: &DeviceMap) -> Option<String> {
+        if self.format == WeightFormat::Gguf {
+            return Some(
+                "GGUF models run on candle's quantized Llama, which has no flash attention"
+                    .to_string(),
+            );
+        }
+        if matches!(self.arch(), Ok(Arch::Falcon)) {
+            return Some("candle's Falcon has no flash attention".to_string());
+        }
+        flash_attn::unsupported(dtype, device_map)
+    }
+
+    /// The attention `load_weights` builds the model with: flash attention
+    /// when it is asked for (or `auto`) and can run, the standard one otherwise.
+    pub fn attention(&self, dtype: DType, device_map: &DeviceMap) -> Attention {
+        match self.flash_attn {
+            FlashAttn::Off => Attention::Standard,
+            FlashAttn::Auto | FlashAttn::On => match self.flash_attn_unsupported(dtype, device_map) {
+                Some(_) => Attention::Standard,
+                None => Attention::Flash,
+            },
+        }
+    }
+
+    /// Load the weights in the right backend for their format, with the
+    /// layers placed according to `device_map`. GGUF weights stay quantized,
+    /// ignore `dtype` and can't be sharded.
+    pub fn load_weights(
+        &self,
+        config: &Config,
+        dtype: DType,
+        device_map: &DeviceMap,
+    ) -> Result<Weights> {
+        let arch = self.arch()?;
+        let _span = tracing::info_span!("load", arch = %arch, format = ?self.format).entered();
+        let use_flash_attn = self.attention(dtype, device_map) == Attention::Flash;
+        let config = &Config {
+            use_flash_attn,
+            ..config.clone()
+        };
+        match (self.format, arch) {
+            (WeightFormat::Safetensors, Arch::Llama | Arch::Qwen2 | Arch::Phi3) => {
+                let stages = device_map
+                    .stages()
+                    .iter()
+                    .map(|(device, layers)| Ok((self.var_builder(dtype, device)?, *layers)))
+                    .collect::<Result<Vec<_>>>()?;
+                let int4 = self.int4_config()?;
+                if let Some(int4) = &int4 {
+                    if self.quantize.is_some() {
+                        bail!("{} weights are already quantized; --quantize is for fp16/bf16/f32 weights", int4.method);
+                    }
+                    if !self.lora.is_empty() {
+                        bail!("LoRA adapters can't be merged into {} weights", int4.method);
+                    }
+                    if tensor_names(&self.weights)?.contains("model.layers.0.self_attn.qkv_proj.qweight") {
+                        bail!("{} checkpoints with fused projections aren't supported", int4.method);
+                    }
+                }
+                self.audit_tensors(config)?;
+                let quantize = self.quantize.map(Quantization::ggml_dtype);
+                let mut llama = Llama::load_sharded(&stages, config, quantize, int4.as_ref()).map_err(|e| {
+                    match self.quantize {
+                        Some(quantize) => anyhow!("Failed to quantize to {:?}: {}", quantize, e),
+                        None => e.into(),
+                    }
+                })?;
+                if let Some((window, first_layer)) = self.sliding_window()? {
+                    llama = llama.with_sliding_window(window, first_layer);
+                }
+                let unscaled = match self.config_json()? {
+                    Some(config_json) => config_from_json(&config_json),
+                    None => config.clone(),
+                };
+                if let Some(rope_scaling) = self.rope_scaling(&unscaled)? {
+                    llama = llama.with_rope_scaling(rope_scaling);
+                }
+                Ok(Weights::Llama(llama.with_kv_cache_dtype(self.kv_cache_dtype)))
+            }
+            (WeightFormat::Safetensors, _) => {
+                if let Some(int4) = self.int4_config()? {
+                    bail!("{} checkpoints are only supported for Llama, Qwen2 and Phi-3 models, not {}", int4.method, arch);
+                }
+                if self.quantize.is_some() {
+                    bail!("--quantize only supports Llama, Qwen2 and Phi-3 models, not {}", arch);
+                }
+                if self.kv_cache_dtype.is_some() {
+                    bail!("--kv-cache-dtype only supports Llama, Qwen2 and Phi-3 models, not {}", arch);
+                }
+                if device_map.is_sharded() {
+                    bail!("{} models can't be split across devices", arch);
+                }
+                let Some(config_json) = self.config_json()? else {
+                    bail!("{} models need a config.json", arch);
+                };
+                if !config_json["rope_scaling"].is_null() {
+                    tracing::warn!("{} models ignore the rope_scaling in config.json", arch);
+                }
+                let vb = self.var_builder(dtype, device_map.first_device())?;
+                Ok(Weights::Stateful(arch.load_stateful(&config_json, vb, use_flash_attn)?))
+            }
+            (WeightFormat::Gguf, Arch::Llama) => {
+                if device_map.is_sharded() {
+                    bail!("Quantized models can't be split across devices");
+                }
+                let (content, mut file) = self.read_gguf()?;
+                let device = device_map.first_device();
+                let model = QuantizedLlama::from_gguf(content, &mut file, device)?;
+                Ok(Weights::Stateful(Box::new(model)))
+            }
+            (WeightFormat::Gguf, _) => bail!("Only Llama GGUF files are supported, not {}", arch),
+        }
+    }
+
+    /// Compare the tensors in the weights file with those `Llama::load` reads
+    /// for `config`: fail with every missing or mis-shaped one (rather than
+    /// candle's error for the first), and warn about the ones it ignores.
+    fn audit_tensors(&self, config: &Config) -> Result<()> {
+        let expected = self.loaded_tensors(config)?;
+        let audit = audit_tensors(&self.weights, &expected)?;
+        let hints = audit.hints(config.tie_word_embeddings);
+        if !audit.issues.is_empty() {
+            let mut message = format!(
+                "{} doesn't have the tensors config.json describes:",
+                self.weights.display()
+            );
+            let missing: Vec<String> = audit
+                .issues
+                .iter()
+                .filter_map(|issue| match issue {
+                    WeightIssue::Missing(name) => Some(name.clone()),
+                    _ => None,
+                })
+                .collect();
+            if !missing.is_empty() {
+                message.push_str(&format!("\n  - missing {}", summarize_names(&missing).join(", ")));
+            }
+            let shapes: Vec<_> = audit
+                .issues
+                .iter()
+                .filter(|issue| matches!(issue, WeightIssue::Shape { .. }))
+                .collect();
+            for issue in shapes.iter().take(5) {
+                message.push_str(&format!("\n  - {}", issue));
+            }
+            if shapes.len() > 5 {
+                message.push_str(&format!("\n  - and {} more shape mismatches", shapes.len() - 5));
+            }
+            for hint in &hints {
+                message.push_str(&format!("\nHint: {}", hint));
+            }
+            bail!(message);
+        }
+        if !audit.unexpected.is_empty() {
+            tracing::warn!(
+                "Ignoring {} tensor(s) the model doesn't use: {}",
+                audit.unexpected.len(),
+                summarize_names(&audit.unexpected).join(", ")
+            );
+            for hint in &hints {
+                tracing::warn!("({})", hint);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the weights into the page cache. Returns the number of bytes read.
+    pub fn prefetch(&self) -> Result<u64> {
+        prefetch_files(&[self.weights.as_path()])
+    }
+
+    pub fn load(&self, dtype: DType, device: &Device) -> Result<Model> {
+        let tokenizer = self.load_tokenizer()?;
+        let config = self.load_config()?;
+        check_vocab(&tokenizer, &config)?;
+        let device_map = DeviceMap::single(device.clone(), config.num_hidden_layers);
+        let weights = self.load_weights(&config, dtype, &device_map)?;
+        let attention = self.attention(dtype, &device_map);
+        Ok(Model::new(weights, tokenizer, config, dtype, device.clone()).with_attention(attention))
+    }
+}
+
+impl Model {
+    pub fn new(
+        weights: Weights,
+        tokenizer: Tokenizer,
+        config: Config,
+        dtype: DType,
+        device: Device,
+    ) -> Self {
+        let eos_token_ids = eos_token_ids(&config, &tokenizer);
+        Self {
+            weights,
+            tokenizer,
+            config,
+            eos_token_ids,
+            dtype,
+            device,
+            attention: Attention::Standard,
+        }
+    }
+
+    /// Record the attention implementation the weights were loaded with.
+    pub fn with_attention(mut self, attention: Attention) -> Self {
+        self.attention = attention;
+        self
+    }
+
+    pub fn tokenize(&self, text: &str, add_special_tokens: bool) -> Result<Tokenized> {
+        tokenize(&self.tokenizer, text, add_special_tokens)
+    }
+
+    pub fn detokenize(&self, ids: &[u32], skip_special_tokens: bool) -> Result<String> {
+        detokenize(&self.tokenizer, ids, skip_special_tokens)
+    }
+
+    /// A fresh KV cache for this model.
+    pub fn new_cache(&self, use_kv_cache: bool) -> Result<ModelCache> {
+        Ok(match &self.weights {
+            Weights::Llama(llama) => {
+                ModelCache::Llama(llama.new_cache(use_kv_cache, self.dtype, &self.config)?)
+            }
+            // Stateful models always cache.
+            Weights::Stateful(model) => ModelCache::Stateful(model.clone_box()),
+        })
+    }
+
+    /// Feed `tokens` at position `index_pos` and return the f32 logits over
+    /// the vocabulary for the token that follows them.
+    pub fn forward_logits(
+        &self,
+        tokens: &[u32],
+        index_pos: usize,
+        cache: &mut ModelCache,
+    ) -> Result<Tensor> {
+        self.forward_with_capture(tokens, index_pos, cache, None)
+    }
+
+    /// Same as `forward_logits`, additionally recording the activations
+    /// requested in `capture` (Llama safetensors models only).
+    pub fn forward_with_capture(
+        &self,
+        tokens: &[u32],
+        index_pos: usize,
+        cache: &mut ModelCache,
+        capture: Option<&mut Capture>,
+    ) -> Result<Tensor> {
+        let input = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
+        let logits = match (&self.weights, cache) {
+            (Weights::Llama(llama), ModelCache::Llama(cache)) => {
+                llama.forward_with_capture(&input, index_pos, cache, capture)?
+            }
+            (Weights::Stateful(_), ModelCache::Stateful(model)) => {
+                if capture.is_some() {
+                    bail!("Activations can only be recorded from Llama safetensors models");
+                }
+                model.forward(&input, index_pos)?
+            }
+            _ => bail!("The cache was created for a different model"),
+        };
+        // Batch size 1; some models keep a sequence dimension.
+        Ok(logits.flatten_all()?.to_dtype(DType::F32)?)
+    }
+}
+
+/// Build the model config from config.json, with Llama-2-7B defaults for
+/// missing fields.
+pub fn config_from_json(config_json: &serde_json::Value) -> Config {
+    // Only Llama 3 style scaling is implemented by the model.
+    let rope_scaling = serde_json::from_value::<Llama3RopeConfig>(config_json["rope_scaling"].clone())
+        .ok()
+        .filter(|scaling| matches!(scaling.rope_type, Llama3RopeType::Llama3));
+    Config {
+        hidden_size: config_json["hidden_size"].as_u64().unwrap_or(4096) as usize,
+        intermediate_size: config_json["intermediate_size"].as_u64().unwrap_or(11008) as usize,
+        vocab_size: config_json["vocab_size"].as_u64().unwrap_or(32000) as usize,
+        num_hidden_layers: config_json["num_hidden_layers"].as_u64().unwrap_or(32) as usize,
+        num_attention_heads: config_json["num_attention_heads"].as_u64().unwrap_or(32) as usize,
+        num_key_value_heads: config_json["num_key_value_heads"]
+            .as_u64()
+            // Falcon's names: one key/value head with multi-query attention.
+            .or_else(|| config_json["num_kv_heads"].as_u64())
+            .or_else(|| config_json["multi_query"].as_bool()?.then_some(1))
+            .or_else(|| config_json["num_attention_heads"].as_u64())
+            .unwrap_or(32) as usize,
+        rms_norm_eps: config_json["rms_norm_eps"].as_f64().unwrap_or(1e-5),
+        rope_theta: config_json["rope_theta"].as_f64().unwrap_or(10000.0) as f32,
+        use_flash_attn: false, // Set to false for compatibility
+        bos_token_id: config_json["bos_token_id"].as_u64().map(|id| id as u32),
+        eos_token_id: serde_json::from_value(config_json["eos_token_id"].clone()).ok(),
+        rope_scaling,
+        max_position_embeddings: config_json["max_position_embeddings"]
+            .as_u64()
+            .map_or(DEFAULT_MAX_SEQ_LEN, |n| n as usize),
+        tie_word_embeddings: config_json["tie_word_embeddings"].as_bool().unwrap_or(false),
+    }
+}
+
+/// Build the model config from the metadata of a GGUF file.
+pub fn config_from_gguf(content: &gguf_file::Content) -> Result<Config> {
+    let get = |key: &str| match content.metadata.get(key) {
+        Some(value) => Ok(value),
+        None => bail!("GGUF metadata has no {}", key),
+    };
+    let optional = |key: &str| content.metadata.get(key).and_then(|value| value.to_u32().ok());
+    let num_attention_heads = get("llama.attention.head_count")?.to_u32()? as usize;
+    let vocab_size = match content.tensor_infos.get("token_embd.weight") {
+        Some(info) => info.shape.dims()[0],
+        None => get("tokenizer.ggml.tokens")?.to_vec()?.len(),
+    };
+    Ok(Config {
+        hidden_size: get("llama.embedding_length")?.to_u32()? as usize,
+        intermediate_size: get("llama.feed_forward_length")?.to_u32()? as usize,
+        vocab_size,
+        num_hidden_layers: get("llama.block_count")?.to_u32()? as usize,
+        num_attention_heads,
+        num_key_value_heads: optional("llama.attention.head_count_kv")
+            .map_or(num_attention_heads, |n| n as usize),
+        rms_norm_eps: get("llama.attention.layer_norm_rms_epsilon")?.to_f32()? as f64,
+        rope_theta: content
+            .metadata
+            .get("llama.rope.freq_base")
+            .and_then(|value| value.to_f32().ok())
+            .unwrap_or(10000.0),
+        use_flash_attn: false,
+        bos_token_id: optional("tokenizer.ggml.bos_token_id"),
+        eos_token_id: optional("tokenizer.ggml.eos_token_id").map(LlamaEosToks::Single),
+        rope_scaling: None,
+        max_position_embeddings: optional("llama.context_length")
+            .map_or(DEFAULT_MAX_SEQ_LEN, |n| n as usize),
+        tie_word_embeddings: !content.tensor_infos.contains_key("output.weight"),
+    })
+}
+
+/// Fail with `Sl5Error::TokenizerMismatch` when the tokenizer's base
+/// vocabulary has ids past the model's embeddings. Added tokens are left
+/// out: some checkpoints list special tokens they never embed.
+pub fn check_vocab(tokenizer: &Tokenizer, config: &Config) -> Result<()> {
+    let tokenizer_vocab = tokenizer.get_vocab_size(false);
+    if tokenizer_vocab > config.vocab_size {
+        bail!(Sl5Error::TokenizerMismatch {
+            tokenizer_vocab,
+            model_vocab: config.vocab_size,
+        });
+    }
+    Ok(())
+}
+
+/// End-of-sequence ids from the config (a single id or a list), falling back
+/// to the tokenizer's `</s>`. Gemma's `<end_of_turn>`, which its configs
+/// don't list, is added when the vocabulary has it.
+pub fn eos_token_ids(config: &Config, tokenizer: &Tokenizer) -> Vec<u32> {
+    let mut ids = match &config.eos_token_id {
+        Some(LlamaEosToks::Single(id)) => vec![*id],
+        Some(LlamaEosToks::Multiple(ids)) if !ids.is_empty() => ids.clone(),
+        _ => tokenizer.token_to_id(EOS_TOKEN).into_iter().collect(),
+    };
+    if let Some(id) = tokenizer.token_to_id("<end_of_turn>") {
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    ids
+}
+
+/// Read the given files sequentially so their pages are resident in the OS
+/// page cache before the (random-access) tensor loads touch them.
+pub fn prefetch_files(paths: &[&Path]) -> Result<u64> {
+    let mut buf = vec![0u8; 8 << 20];
+    let mut total = 0u64;
+    for path in paths {
+        let mut file = std::fs::File::open(path)?;
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            total += n as u64;
+        }
+    }
+    Ok(total)
+}
@@ -0,0 +1,117 @@
+// Structured logging and timelines for the `tracing` spans and events the
+// library emits.
+//
+// The library logs through `tracing` rather than printing: a `load` span
+// around loading a model, `prefill` and `decode` spans around each
+// generation step, a `request` span per daemon request or prompt-file line,
+// progress messages and warnings such as ignored tensors. `init` installs a
+// tracing-subscriber `fmt` layer that writes them to stderr as text or JSON
+// lines, each with the chain of spans it happened in, and logs how long
+// every span took when it closes. With a timeline file, tracing-chrome also
+// records every span of debug level or above as a Chrome trace event, for
+// chrome://tracing or https://ui.perfetto.dev.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::level_filters::LevelFilter;
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::time::uptime;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    #[default]
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    fn filter(self) -> LevelFilter {
+        match self {
+            LogLevel::Off => LevelFilter::OFF,
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `  1.234s  INFO request{id=3}:prefill{tokens=12}: message key=value`
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// The timeline writer, flushed and closed by `finish`.
+static TIMELINE: Mutex<Option<FlushGuard>> = Mutex::new(None);
+
+/// Finishes the timeline when dropped.
+pub struct LogGuard(());
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        finish();
+    }
+}
+
+/// Log spans and events up to `level` to stderr in `format`, and record a
+/// Chrome trace of the run's spans in `timeline` if given. `RUST_LOG`
+/// directives (e.g. `candle_inference::loader=debug`) are added to `level`.
+/// Keep the guard until the program ends; call `finish` before
+/// `std::process::exit`.
+pub fn init(level: LogLevel, format: LogFormat, timeline: Option<&Path>) -> Result<LogGuard> {
+    let filter = EnvFilter::builder()
+        .with_default_directive(level.filter().into())
+        .from_env_lossy();
+    let stderr = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_span_events(FmtSpan::CLOSE);
+    let stderr = match format {
+        LogFormat::Text => stderr
+            .with_timer(uptime())
+            .with_ansi(std::io::stderr().is_terminal())
+            .boxed(),
+        LogFormat::Json => stderr.json().with_span_list(true).boxed(),
+    };
+    let (chrome, guard) = match timeline {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            let (layer, guard) = ChromeLayerBuilder::new()
+                .writer(file)
+                .include_args(true)
+                .build();
+            (Some(layer.with_filter(LevelFilter::DEBUG)), Some(guard))
+        }
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(stderr.with_filter(filter))
+        .with(chrome)
+        .try_init()
+        .context("Logging is already initialized")?;
+    *TIMELINE.lock().unwrap() = guard;
+    Ok(LogGuard(()))
+}
+
+/// Write out the rest of the timeline and close it, so it is valid JSON.
+/// Later spans aren't recorded.
+pub fn finish() {
+    drop(TIMELINE.lock().unwrap().take());
+}
@@ -0,0 +1,203 @@
+// LoRA adapters (PEFT format), merged into the base weights as they load.
+//
+// An adapter stores, for each adapted linear layer, two low-rank matrices A
+// (r x in) and B (out x r); the fine-tuned weight is W + alpha / r * B A.
+// `LoraBackend` wraps the base safetensors and adds that product to each
+// adapted tensor when the model asks for it, so the merged model runs at the
+// base model's speed with no separate merge step or merged copy on disk.
+// Several adapters can be merged at once, each with a weight scaling its
+// delta, to interpolate between fine-tunes of the same base model.
+
+use anyhow::{bail, Context, Result};
+use candle_core::safetensors::MmapedSafetensors;
+use candle_core::{DType, Device, Shape, Tensor};
+use candle_nn::var_builder::SimpleBackend;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// An adapter to merge, `PATH_OR_REPO[=WEIGHT]` on the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoraSpec {
+    pub source: String,
+    /// Scales the adapter's delta: 1.0 applies it as trained, 0.5 half-way.
+    pub weight: f64,
+}
+
+impl FromStr for LoraSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // `=` only starts a weight when a number follows it.
+        let (source, weight) = match s.rsplit_once('=') {
+            Some((source, weight)) => match weight.parse::<f64>() {
+                Ok(weight) => (source, weight),
+                Err(_) => (s, 1.0),
+            },
+            None => (s, 1.0),
+        };
+        if source.is_empty() {
+            bail!("Invalid LoRA adapter {:?} (expected PATH_OR_REPO[=WEIGHT])", s);
+        }
+        if !weight.is_finite() {
+            bail!("LoRA adapter weight must be a finite number, got {}", weight);
+        }
+        Ok(Self {
+            source: source.to_string(),
+            weight,
+        })
+    }
+}
+
+/// The two files of a PEFT adapter, and the weight it is merged with.
+#[derive(Debug, Clone)]
+pub struct LoraFiles {
+    pub config: PathBuf,
+    pub weights: PathBuf,
+    pub weight: f64,
+}
+
+pub struct LoraAdapter {
+    /// `alpha`, divided by the rank (or its square root with rsLoRA) per
+    /// weight since ranks may differ between layers.
+    alpha: f64,
+    use_rslora: bool,
+    /// The adapter's share of the merge.
+    weight: f64,
+    /// `(A, B)` by the name of the base weight they adapt, on the CPU.
+    deltas: HashMap<String, (Tensor, Tensor)>,
+    /// Whole tensors that replace base weights (PEFT `modules_to_save`).
+    replacements: HashMap<String, Tensor>,
+}
+
+impl LoraAdapter {
+    pub fn load(files: &LoraFiles) -> Result<Self> {
+        let config: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(&files.config)
+                .with_context(|| format!("Failed to read {}", files.config.display()))?,
+        )?;
+        if config["fan_in_fan_out"].as_bool() == Some(true) {
+            bail!("LoRA adapters with fan_in_fan_out (Conv1D layers) aren't supported");
+        }
+        if config["alpha_pattern"].as_object().is_some_and(|p| !p.is_empty()) {
+            bail!("LoRA adapters with a per-module alpha_pattern aren't supported");
+        }
+        let Some(alpha) = config["lora_alpha"].as_f64() else {
+            bail!("{} has no lora_alpha", files.config.display());
+        };
+
+        let mut a = HashMap::new();
+        let mut b = HashMap::new();
+        let mut replacements = HashMap::new();
+        for (name, tensor) in candle_core::safetensors::load(&files.weights, &Device::Cpu)? {
+            let name = name.strip_prefix("base_model.model.").unwrap_or(&name);
+            if let Some(base) = name.strip_suffix(".lora_A.weight") {
+                a.insert(format!("{base}.weight"), tensor);
+            } else if let Some(base) = name.strip_suffix(".lora_B.weight") {
+                b.insert(format!("{base}.weight"), tensor);
+            } else {
+                replacements.insert(name.to_string(), tensor);
+            }
+        }
+        let mut deltas = HashMap::new();
+        for (name, a) in a {
+            let Some(b) = b.remove(&name) else {
+                bail!("LoRA adapter has lora_A but no lora_B for {}", name);
+            };
+            deltas.insert(name, (a, b));
+        }
+        if let Some(name) = b.keys().next() {
+            bail!("LoRA adapter has lora_B but no lora_A for {}", name);
+        }
+        Ok(Self {
+            alpha,
+            use_rslora: config["use_rslora"].as_bool().unwrap_or(false),
+            weight: files.weight,
+            deltas,
+            replacements,
+        })
+    }
+
+    /// `weight * scale * B A` for an adapted base weight `base` (f32), or
+    /// `weight * (R - W)` for one the adapter replaces with `R`.
+    fn delta(&self, name: &str, base: &Tensor) -> candle_core::Result<Option<Tensor>> {
+        let device = base.device();
+        if let Some(replacement) = self.replacements.get(name) {
+            let replacement = replacement.to_device(device)?.to_dtype(DType::F32)?;
+            return Ok(Some(((replacement - base)? * self.weight)?));
+        }
+        let Some((a, b)) = self.deltas.get(name) else {
+            return Ok(None);
+        };
+        let rank = a.dim(0)? as f64;
+        let scale = self.alpha / if self.use_rslora { rank.sqrt() } else { rank };
+        let a = a.to_device(device)?.to_dtype(DType::F32)?;
+        let b = b.to_device(device)?.to_dtype(DType::F32)?;
+        Ok(Some((b.matmul(&a)? * (self.weight * scale))?))
+    }
+}
+
+/// Base safetensors with LoRA adapters merged in on load.
+pub struct LoraBackend {
+    base: MmapedSafetensors,
+    adapters: Vec<LoraAdapter>,
+}
+
+impl LoraBackend {
+    /// Check that every adapted weight exists in the base model, so an
+    /// adapter for a different model fails here rather than being ignored.
+    pub fn new(base_weights: &Path, adapters: Vec<LoraAdapter>) -> Result<Self> {
+        let base = unsafe { MmapedSafetensors::new(base_weights)? };
+        for adapter in &adapters {
+            for name in adapter.deltas.keys().chain(adapter.replacements.keys()) {
+                if base.get(name).is_err() {
+                    bail!("LoRA adapter weight {} has no matching tensor in the base model", name);
+                }
+            }
+        }
+        Ok(Self { base, adapters })
+    }
+
+    /// `W` plus every adapter's weighted delta, computed in f32.
+    fn merge(&self, name: &str, weight: Tensor, dtype: DType) -> candle_core::Result<Tensor> {
+        let adapted = |adapter: &LoraAdapter| {
+            adapter.deltas.contains_key(name) || adapter.replacements.contains_key(name)
+        };
+        if !self.adapters.iter().any(adapted) {
+            return weight.to_dtype(dtype);
+        }
+        let base = weight.to_dtype(DType::F32)?;
+        let mut merged = base.clone();
+        for adapter in &self.adapters {
+            if let Some(delta) = adapter.delta(name, &base)? {
+                merged = (merged + delta)?;
+            }
+        }
+        merged.to_dtype(dtype)
+    }
+}
+
+impl SimpleBackend for LoraBackend {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        _: candle_nn::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> candle_core::Result<Tensor> {
+        let tensor = self.get_unchecked(name, dtype, dev)?;
+        if tensor.shape() != &s {
+            candle_core::bail!("shape mismatch for {name}: expected {s:?}, got {:?}", tensor.shape());
+        }
+        Ok(tensor)
+    }
+
+    fn get_unchecked(&self, name: &str, dtype: DType, dev: &Device) -> candle_core::Result<Tensor> {
+        self.merge(name, self.base.load(name, dev)?, dtype)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.base.get(name).is_ok()
+    }
+}
@@ -0,0 +1,295 @@
+// Memory pre-flight: will the model fit before its weights load?
+//
+// A model that doesn't fit fails with a CUDA out-of-memory error part way
+// through loading, after its weights have been read for minutes. Its
+// footprint is known up front: the weights (each tensor's size in the load
+// dtype or quantization, or the GGUF file as it is), the KV cache for the
+// positions the run can reach (in its own dtype with --kv-cache-dtype), and the activations of the largest forward
+// pass. `estimate` splits that by device according to the device map,
+// `shortfalls` compares each share against the memory the device has free,
+// and `check` fails with `Sl5Error::OutOfMemory` when one doesn't fit.
+
+use crate::device_map::{format_size, free_cuda_memory, DeviceMap};
+use crate::error::Sl5Error;
+use crate::llama::KvCacheDtype;
+use crate::loader::{ModelFiles, WeightFormat};
+use anyhow::Result;
+use candle_core::safetensors::MmapedSafetensors;
+use candle_core::{DType, Device, DeviceLocation};
+use candle_transformers::models::llama::Config;
+
+/// Memory set aside on every device for the CUDA context, kernels and
+/// allocator slack.
+const RESERVE: u64 = 512 << 20;
+
+/// What one device of the run needs, in bytes.
+#[derive(Debug, Clone)]
+pub struct Footprint {
+    pub device: Device,
+    pub weights: u64,
+    pub kv_cache: u64,
+    pub activations: u64,
+}
+
+impl Footprint {
+    pub fn total(&self) -> u64 {
+        self.weights + self.kv_cache + self.activations + RESERVE
+    }
+}
+
+/// A device that lacks room for its share of the run.
+#[derive(Debug, Clone)]
+pub struct Shortfall {
+    pub footprint: Footprint,
+    pub free: u64,
+}
+
+impl std::fmt::Display for Shortfall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let needed = &self.footprint;
+        write!(
+            f,
+            "{:?} needs about {} (weights {}, KV cache {}, activations {}, {} reserve) but has {} free",
+            needed.device.location(),
+            format_size(needed.total()),
+            format_size(needed.weights),
+            format_size(needed.kv_cache),
+            format_size(needed.activations),
+            format_size(RESERVE),
+            format_size(self.free)
+        )
+    }
+}
+
+impl From<&Shortfall> for Sl5Error {
+    fn from(shortfall: &Shortfall) -> Self {
+        Sl5Error::OutOfMemory {
+            needed: shortfall.footprint.total(),
+            available: shortfall.free,
+        }
+    }
+}
+
+/// The run's footprint on each device of `device_map`, for a sequence of up
+/// to `positions` tokens whose longest forward pass feeds `batch_tokens`.
+/// With `use_kv_cache` false nothing is cached but every pass is
+/// `positions` long.
+pub fn estimate(
+    files: &ModelFiles,
+    config: &Config,
+    dtype: DType,
+    device_map: &DeviceMap,
+    positions: usize,
+    batch_tokens: usize,
+    use_kv_cache: bool,
+) -> Result<Vec<Footprint>> {
+    let stages = device_map.stages();
+    let mut footprints: Vec<Footprint> = stages
+        .iter()
+        .map(|(device, _)| Footprint {
+            device: device.clone(),
+            weights: 0,
+            kv_cache: 0,
+            activations: 0,
+        })
+        .collect();
+    let last = footprints.len() - 1;
+
+    // The stage holding each layer.
+    let stage_of_layer: Vec<usize> = stages
+        .iter()
+        .enumerate()
+        .flat_map(|(stage, (_, layers))| std::iter::repeat_n(stage, *layers))
+        .collect();
+    match files.format {
+        // GGUF weights stay as they are in the file, on one device.
+        WeightFormat::Gguf => footprints[0].weights = std::fs::metadata(&files.weights)?.len(),
+        WeightFormat::Safetensors => {
+            let tensors = unsafe { MmapedSafetensors::new(&files.weights)? };
+            let quantized = files.quantize.map(|q| q.ggml_dtype());
+            for (name, view) in tensors.tensors() {
+                let elements: usize = view.shape().iter().product();
+                let bytes = match quantized {
+                    // GPTQ/AWQ codes become q4_1 blocks (20 bytes per 32
+                    // weights, 8 weights per int32), which include the scales
+                    // and zero points.
+                    _ if name.ends_with(".qweight") => elements * 8 / 32 * 20,
+                    _ if [".qzeros", ".scales", ".g_idx"].iter().any(|s| name.ends_with(s)) => 0,
+                    // Projections and lm_head are quantized as they load.
+                    Some(ggml) if name.ends_with("proj.weight") || name == "lm_head.weight" => {
+                        elements / ggml.block_size() * ggml.type_size()
+                    }
+                    _ => elements * dtype.size_in_bytes(),
+                } as u64;
+                let stage = match layer_index(&name) {
+                    Some(layer) => stage_of_layer.get(layer).copied().unwrap_or(last),
+                    None if name.contains("embed_tokens") => 0,
+                    None => last,
+                };
+                footprints[stage].weights += bytes;
+            }
+        }
+    }
+
+    let head_dim = head_dim(files, config);
+    // Quantized GGUF models keep their cache and activations in f32.
+    let act_size = match files.format {
+        WeightFormat::Gguf => 4,
+        WeightFormat::Safetensors => dtype.size_in_bytes(),
+    } as u64;
+    let per_layer = (2 * config.num_key_value_heads * head_dim * positions) as u64;
+    if use_kv_cache {
+        let per_layer = match files.kv_cache_dtype {
+            None => per_layer * act_size,
+            Some(KvCacheDtype::F16) => per_layer * 2,
+            // A byte per value and a 2-byte scale per 32.
+            Some(KvCacheDtype::Q8_0) => per_layer * 34 / 32,
+        };
+        for &stage in &stage_of_layer {
+            footprints[stage].kv_cache += per_layer;
+        }
+    }
+
+    // The largest pass: attention scores over every key, the MLP's hidden
+    // states and, on the last device, the f32 logits.
+    let tokens = if use_kv_cache {
+        batch_tokens
+    } else {
+        positions
+    } as u64;
+    let scores = config.num_attention_heads as u64 * tokens * positions as u64 * 4;
+    let mlp = 3 * tokens * config.intermediate_size as u64 * act_size;
+    let hidden = 4 * tokens * config.hidden_size as u64 * act_size;
+    // A converted cache is turned back into the model's dtype one layer at a
+    // time.
+    let dequantized = match (use_kv_cache, files.kv_cache_dtype) {
+        (true, Some(_)) => per_layer * act_size,
+        _ => 0,
+    };
+    for footprint in footprints.iter_mut() {
+        footprint.activations = scores + mlp + hidden + dequantized;
+    }
+    footprints[last].activations += config.vocab_size as u64 * 4;
+    Ok(footprints)
+}
+
+/// The devices whose share of `footprints` is larger than their free memory:
+/// what nvidia-smi reports for GPUs and `MemAvailable` for the CPU. Devices
+/// whose free memory can't be read are skipped.
+pub fn shortfalls(footprints: &[Footprint]) -> Vec<Shortfall> {
+    let gpus = if footprints.iter().any(|f| f.device.is_cuda()) {
+        free_cuda_memory().unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    // Stages on the same device share its memory.
+    let mut merged: Vec<Footprint> = Vec::new();
+    for footprint in footprints {
+        match merged
+            .iter_mut()
+            .find(|f| f.device.location() == footprint.device.location())
+        {
+            Some(f) => {
+                f.weights += footprint.weights;
+                f.kv_cache += footprint.kv_cache;
+                f.activations = f.activations.max(footprint.activations);
+            }
+            None => merged.push(footprint.clone()),
+        }
+    }
+    merged
+        .into_iter()
+        .filter_map(|footprint| {
+            let free = match footprint.device.location() {
+                DeviceLocation::Cpu => available_ram()?,
+                location => gpus
+                    .iter()
+                    .find(|(device, _)| device.location() == location)
+                    .map(|(_, free)| *free)?,
+            };
+            (footprint.total() > free).then_some(Shortfall { footprint, free })
+        })
+        .collect()
+}
+
+/// Fail if a device lacks room for its share of `footprints`, with an
+/// `Sl5Error::OutOfMemory` for the device furthest short under a message
+/// listing every such device and what its share is made of.
+pub fn check(footprints: &[Footprint]) -> Result<()> {
+    let shortfalls = shortfalls(footprints);
+    let Some(worst) = shortfalls
+        .iter()
+        .max_by_key(|s| s.footprint.total() - s.free)
+    else {
+        return Ok(());
+    };
+    let mut message = String::from("The model doesn't fit in memory:");
+    for shortfall in &shortfalls {
+        message.push_str(&format!("\n  - {}", shortfall));
+    }
+    Err(anyhow::Error::new(Sl5Error::from(worst)).context(message))
+}
+
+/// `head_dim` from config.json when it has one (Gemma), otherwise the
+/// hidden size split across the heads.
+fn head_dim(files: &ModelFiles, config: &Config) -> usize {
+    files
+        .config
+        .as_ref()
+        .and_then(|path| std::fs::read(path).ok())
+        .and_then(|bytes| serde_json::from_slice::<serde_json::Value>(&bytes).ok())
+        .and_then(|config_json| config_json["head_dim"].as_u64())
+        .map_or(config.hidden_size / config.num_attention_heads, |n| {
+            n as usize
+        })
+}
+
+/// The decoder layer a tensor belongs to, from names like
+/// `model.layers.12.mlp.up_proj.weight`.
+fn layer_index(name: &str) -> Option<usize> {
+    let rest = name.split_once("layers.")?.1;
+    rest.split('.').next()?.parse().ok()
+}
+
+/// `MemAvailable` from /proc/meminfo.
+fn available_ram() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib << 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn footprint(weights: u64) -> Footprint {
+        Footprint {
+            device: Device::Cpu,
+            weights,
+            kv_cache: 1 << 20,
+            activations: 1 << 20,
+        }
+    }
+
+    #[test]
+    fn check_reports_out_of_memory() {
+        let Some(free) = available_ram() else {
+            return;
+        };
+        assert!(check(&[footprint(1 << 20)]).is_ok());
+
+        // Two stages on the CPU share its memory: their weights and KV caches
+        // add up, while only the larger activations count.
+        let huge = footprint(free);
+        let err = check(&[huge.clone(), footprint(1 << 20)]).unwrap_err();
+        assert!(err.to_string().starts_with("The model doesn't fit in memory:\n  - Cpu needs about"));
+        let Some(Sl5Error::OutOfMemory { needed, available }) = err.downcast_ref::<Sl5Error>() else {
+            panic!("expected Sl5Error::OutOfMemory, got {err:?}");
+        };
+        assert_eq!(*needed, huge.total() + (2 << 20));
+        assert!(*available < *needed);
+    }
+}
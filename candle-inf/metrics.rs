@@ -0,0 +1,72 @@
+// Run statistics in the Prometheus text exposition format.
+//
+// A CLI run is too short-lived to be scraped, so the metrics are written to a
+// file instead, for node_exporter's textfile collector or a Pushgateway
+// (`curl --data-binary @metrics.prom`). The file is written to a temporary
+// name and renamed into place so a collector never reads half of it.
+
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    labels: Vec<(String, String)>,
+    text: String,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a label to every metric, e.g. the model id.
+    pub fn with_label(mut self, name: &str, value: &str) -> Self {
+        self.labels.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.push(name, "gauge", help, value);
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: f64) {
+        self.push(name, "counter", help, value);
+    }
+
+    fn push(&mut self, name: &str, kind: &str, help: &str, value: f64) {
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+            .collect();
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels.join(","))
+        };
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+        let _ = writeln!(self.text, "{}{} {}", name, labels, value);
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("prom.tmp");
+        std::fs::write(&tmp, &self.text)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,118 @@
+// PyTorch checkpoints: pytorch_model.bin, alone or sharded with
+// pytorch_model.bin.index.json.
+//
+// Older repos only ship their weights as torch pickles. Everything else here
+// works on a memory-mapped safetensors file (loading, --check-weights, the
+// memory estimate, LoRA merging), so the pickles are converted once, with
+// candle's pickle reader, into a model.safetensors that is cached under
+// `$HF_HOME/converted` (or `--cache-dir`/converted) and reused as long as the
+// checkpoint files don't change.
+
+use crate::hub::cache_dir;
+use anyhow::{bail, Context, Result};
+use candle_core::Tensor;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The index of a sharded checkpoint.
+pub const INDEX: &str = "pytorch_model.bin.index.json";
+
+/// The shard files named in a pytorch_model.bin.index.json, in order of
+/// first use.
+pub fn shard_names(index: &Path) -> Result<Vec<String>> {
+    let text = std::fs::read_to_string(index)
+        .with_context(|| format!("Failed to read {}", index.display()))?;
+    let json: serde_json::Value = serde_json::from_str(&text)
+        .with_context(|| format!("Invalid JSON in {}", index.display()))?;
+    let Some(weight_map) = json["weight_map"].as_object() else {
+        bail!("{} has no weight_map", index.display());
+    };
+    let mut shards: Vec<String> = Vec::new();
+    for file in weight_map.values() {
+        let Some(file) = file.as_str() else {
+            bail!("{} maps a tensor to a non-string file", index.display());
+        };
+        if Path::new(file).is_absolute() || file.contains("..") {
+            bail!(
+                "{} names a shard outside the model: {}",
+                index.display(),
+                file
+            );
+        }
+        if !shards.iter().any(|shard| shard == file) {
+            shards.push(file.to_string());
+        }
+    }
+    if shards.is_empty() {
+        bail!("{} names no shards", index.display());
+    }
+    Ok(shards)
+}
+
+/// The safetensors conversion of the checkpoint `shards`, converting them
+/// first if the cache under `cache_root` (default `$HF_HOME`) has no
+/// conversion of these exact files yet. Converting reads the whole
+/// checkpoint into memory.
+pub fn to_safetensors(shards: &[PathBuf], cache_root: Option<&Path>) -> Result<PathBuf> {
+    let dir = match cache_root {
+        Some(root) => root.join("converted"),
+        None => cache_dir("converted"),
+    }
+    .join(fingerprint(shards)?);
+    let converted = dir.join("model.safetensors");
+    if converted.exists() {
+        tracing::info!("Using converted weights: {}", converted.display());
+        return Ok(converted);
+    }
+
+    let mut tensors: HashMap<String, Tensor> = HashMap::new();
+    for (i, shard) in shards.iter().enumerate() {
+        tracing::info!(
+            "Converting {} to safetensors ({} of {})...",
+            shard.display(),
+            i + 1,
+            shards.len()
+        );
+        let read = candle_core::pickle::read_all(shard)
+            .with_context(|| format!("Failed to read PyTorch checkpoint {}", shard.display()))?;
+        for (name, tensor) in read {
+            if tensors.insert(name.clone(), tensor).is_some() {
+                bail!("Tensor {} appears in more than one shard", name);
+            }
+        }
+    }
+    if tensors.is_empty() {
+        bail!("The PyTorch checkpoint holds no tensors");
+    }
+    std::fs::create_dir_all(&dir)?;
+    let partial = dir.join("model.safetensors.part");
+    candle_core::safetensors::save(&tensors, &partial)
+        .with_context(|| format!("Failed to write {}", partial.display()))?;
+    std::fs::rename(&partial, &converted)?;
+    tracing::info!("Converted weights cached at {}", converted.display());
+    Ok(converted)
+}
+
+/// A directory name for the conversion of `shards`: a hash of their
+/// canonical paths, sizes and modification times.
+fn fingerprint(shards: &[PathBuf]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for shard in shards {
+        let path = shard
+            .canonicalize()
+            .with_context(|| format!("Failed to open {}", shard.display()))?;
+        let metadata = std::fs::metadata(&path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos());
+        hasher.update(path.to_string_lossy().as_bytes());
+        hasher.update(metadata.len().to_le_bytes());
+        hasher.update(modified.to_le_bytes());
+    }
+    Ok(hasher.finalize()[..16]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
@@ -0,0 +1,285 @@
+// Model files from object storage or plain HTTP(S) directories.
+//
+// Production weights often live in S3 or GCS rather than on the Hub. A model
+// spec such as `s3://bucket/models/llama3/`, `gs://bucket/llama3` or
+// `https://example.com/models/llama3/` names a directory holding the usual
+// tokenizer.json, config.json and weight files. Files are streamed to disk,
+// resumed with the same retry policy as Hub downloads and cached under
+// `$HF_HOME/remote/`, so later runs load them without touching the network.
+
+use crate::hub::{agent_builder, cache_dir, download_to, FetchError, RetryPolicy};
+use anyhow::{bail, Result};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Payload hash for requests whose body isn't signed (all of ours are GETs).
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Static AWS credentials for signing S3 requests with SigV4.
+struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    region: String,
+}
+
+enum Auth {
+    /// Public buckets and plain HTTP servers.
+    Anonymous,
+    Bearer(String),
+    SigV4(AwsCredentials),
+}
+
+pub struct RemoteSource {
+    /// URL of the model directory, without a trailing slash.
+    base_url: String,
+    auth: Auth,
+    cache_dir: PathBuf,
+    retry: RetryPolicy,
+    agent: ureq::Agent,
+}
+
+impl RemoteSource {
+    /// Returns `None` when `spec` isn't a URL, i.e. it is a Hub repo id.
+    ///
+    /// S3 credentials come from `AWS_ACCESS_KEY_ID`/`AWS_SECRET_ACCESS_KEY`
+    /// (and `AWS_SESSION_TOKEN`), the region from `AWS_REGION`, and an
+    /// S3-compatible store can be used via `AWS_ENDPOINT_URL`. GCS requests
+    /// send `GOOGLE_OAUTH_ACCESS_TOKEN` as a bearer token when it is set.
+    /// Files are cached under `cache_root/remote` (default: `$HF_HOME/remote`).
+    pub fn parse(
+        spec: &str,
+        retry: RetryPolicy,
+        cache_root: Option<&Path>,
+    ) -> Result<Option<Self>> {
+        let Some((scheme, rest)) = spec.split_once("://") else {
+            return Ok(None);
+        };
+        let rest = rest.trim_end_matches('/');
+        let (host, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if host.is_empty() {
+            bail!("No bucket or host in model spec {}", spec);
+        }
+
+        let (base_url, auth) = match scheme {
+            "http" | "https" => (format!("{scheme}://{rest}"), Auth::Anonymous),
+            "s3" => {
+                let region = env("AWS_REGION")
+                    .or_else(|| env("AWS_DEFAULT_REGION"))
+                    .unwrap_or_else(|| "us-east-1".to_string());
+                let bucket_url = match env("AWS_ENDPOINT_URL_S3").or_else(|| env("AWS_ENDPOINT_URL")) {
+                    Some(endpoint) => format!("{}/{}", endpoint.trim_end_matches('/'), host),
+                    // Dotted bucket names don't match the wildcard certificate.
+                    None if host.contains('.') => {
+                        format!("https://s3.{region}.amazonaws.com/{host}")
+                    }
+                    None => format!("https://{host}.s3.{region}.amazonaws.com"),
+                };
+                let auth = match (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY")) {
+                    (Some(access_key_id), Some(secret_access_key)) => {
+                        Auth::SigV4(AwsCredentials {
+                            access_key_id,
+                            secret_access_key,
+                            session_token: env("AWS_SESSION_TOKEN"),
+                            region,
+                        })
+                    }
+                    _ => Auth::Anonymous,
+                };
+                (join_url(&bucket_url, prefix), auth)
+            }
+            "gs" => {
+                let auth = match env("GOOGLE_OAUTH_ACCESS_TOKEN") {
+                    Some(token) => Auth::Bearer(token),
+                    None => Auth::Anonymous,
+                };
+                let bucket_url = format!("https://storage.googleapis.com/{host}");
+                (join_url(&bucket_url, prefix), auth)
+            }
+            _ => bail!("Unsupported model source {}:// (expected s3, gs, http or https)", scheme),
+        };
+
+        // Cache next to the Hub cache, keyed by where the files came from.
+        let mut cache_dir = match cache_root {
+            Some(root) => root.join("remote"),
+            None => cache_dir("remote"),
+        };
+        cache_dir.push(scheme);
+        for segment in rest.split('/') {
+            if !segment.is_empty() && segment != "." && segment != ".." {
+                cache_dir.push(segment.replace(':', "_"));
+            }
+        }
+
+        Ok(Some(Self {
+            base_url,
+            auth,
+            cache_dir,
+            retry,
+            agent: agent_builder().build(),
+        }))
+    }
+
+    pub fn url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Returns the local path of `filename`, downloading it first if it
+    /// isn't in the cache yet.
+    pub fn get(&self, filename: &str) -> Result<PathBuf> {
+        let path = self.cache_dir.join(filename);
+        if path.exists() {
+            return Ok(path);
+        }
+        std::fs::create_dir_all(&self.cache_dir)?;
+        let partial = self.cache_dir.join(format!("{filename}.part"));
+        let url = join_url(&self.base_url, filename);
+        self.retry.run(filename, || {
+            let size = self.size(&url)?;
+            download_to(self.request("GET", &url)?, &partial, size, filename)
+        })?;
+        std::fs::rename(&partial, &path)?;
+        Ok(path)
+    }
+
+    /// The object size, if the server reports one, so downloads can resume.
+    fn size(&self, url: &str) -> Result<Option<u64>, FetchError> {
+        let response = self.request("HEAD", url)?.call()?;
+        Ok(response
+            .header("content-length")
+            .and_then(|length| length.parse().ok()))
+    }
+
+    fn request(&self, method: &str, url: &str) -> Result<ureq::Request, FetchError> {
+        let request = self.agent.request(method, url);
+        match &self.auth {
+            Auth::Anonymous => Ok(request),
+            Auth::Bearer(token) => Ok(request.set("Authorization", &format!("Bearer {token}"))),
+            Auth::SigV4(credentials) => sign_v4(request, method, credentials),
+        }
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Append a `/`-separated object key to `base`, percent-encoding each segment.
+fn join_url(base: &str, key: &str) -> String {
+    let mut url = base.to_string();
+    for segment in key.split('/').filter(|segment| !segment.is_empty()) {
+        url.push('/');
+        for byte in segment.bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                    url.push(byte as char)
+                }
+                _ => url.push_str(&format!("%{byte:02X}")),
+            }
+        }
+    }
+    url
+}
+
+/// Sign an S3 request with AWS Signature Version 4.
+fn sign_v4(
+    request: ureq::Request,
+    method: &str,
+    credentials: &AwsCredentials,
+) -> Result<ureq::Request, FetchError> {
+    let parsed = request
+        .request_url()
+        .map_err(|err| FetchError::Permanent(err.into()))?;
+    let host = match parsed.port() {
+        Some(port) => format!("{}:{}", parsed.host(), port),
+        None => parsed.host().to_string(),
+    };
+    let (date, timestamp) = utc_timestamp();
+
+    let mut headers = vec![
+        ("host", host),
+        ("x-amz-content-sha256", UNSIGNED_PAYLOAD.to_string()),
+        ("x-amz-date", timestamp.clone()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{name}:{value}\n"))
+        .collect();
+    let canonical_request = format!(
+        "{method}\n{}\n\n{canonical_headers}\n{signed_headers}\n{UNSIGNED_PAYLOAD}",
+        parsed.path()
+    );
+
+    let scope = format!("{date}/{}/s3/aws4_request", credentials.region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let mut key = format!("AWS4{}", credentials.secret_access_key).into_bytes();
+    for part in [date.as_str(), credentials.region.as_str(), "s3", "aws4_request"] {
+        key = hmac_sha256(&key, part.as_bytes());
+    }
+    let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+    let mut request = request.set(
+        "Authorization",
+        &format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            credentials.access_key_id
+        ),
+    );
+    for (name, value) in &headers[1..] {
+        request = request.set(name, value);
+    }
+    Ok(request)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The current UTC date (`YYYYMMDD`) and timestamp (`YYYYMMDDTHHMMSSZ`).
+fn utc_timestamp() -> (String, String) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let date = format!("{year:04}{month:02}{day:02}");
+    let timestamp = format!(
+        "{date}T{:02}{:02}{:02}Z",
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60
+    );
+    (date, timestamp)
+}
+
@@ -0,0 +1,155 @@
+// Truncation strategies beyond top-k and top-p.
+//
+// candle's LogitsProcessor covers greedy, top-k and top-p. Two more ways of
+// cutting off the unreliable tail of the distribution are implemented here,
+// applied to the logits just before they are sampled (and before top-k/top-p,
+// which then act on what is left):
+//
+// - typical sampling (Meister et al., 2022) keeps the tokens whose surprisal
+//   is closest to the entropy of the distribution, until they cover a given
+//   probability mass, so both the tail and overly predictable tokens can go;
+// - top-n-sigma (Tang et al., 2024) keeps the tokens whose logit is within
+//   n standard deviations of the largest one. The logits' spread scales with
+//   the temperature, so the set kept doesn't depend on it.
+
+use crate::generation::log_softmax;
+use anyhow::{bail, Context, Result};
+use candle_core::{DType, Tensor};
+use serde::{Serialize, Serializer};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Truncation {
+    /// Typical sampling covering this probability mass.
+    Typical(f64),
+    /// Top-n-sigma with this many standard deviations.
+    TopNSigma(f64),
+}
+
+impl Truncation {
+    /// Name used in traces and on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Truncation::Typical(_) => "typical",
+            Truncation::TopNSigma(_) => "top_n_sigma",
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            Truncation::Typical(mass) if !(mass > 0. && mass <= 1.) => {
+                bail!("typical mass must be in (0, 1], got {} (1 keeps every token)", mass)
+            }
+            Truncation::TopNSigma(n) if !(n.is_finite() && n > 0.) => {
+                bail!("top-n-sigma n must be positive, got {}", n)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Set the logits of the tokens this strategy removes to `-inf`, for
+    /// sampling at `temperature`. Returns the new logits and how many tokens
+    /// were removed.
+    pub fn apply(&self, logits: &Tensor, temperature: f64) -> Result<(Tensor, usize)> {
+        let mut values: Vec<f32> = logits.to_dtype(DType::F32)?.to_vec1()?;
+        let keep = match *self {
+            Truncation::Typical(mass) => typical(&values, temperature, mass),
+            Truncation::TopNSigma(n) => top_n_sigma(&values, n),
+        };
+        let mut removed = 0;
+        for (value, keep) in values.iter_mut().zip(keep) {
+            if !keep && value.is_finite() {
+                *value = f32::NEG_INFINITY;
+                removed += 1;
+            }
+        }
+        Ok((Tensor::new(values, logits.device())?, removed))
+    }
+}
+
+/// Which tokens typical sampling keeps: by how far their surprisal is from
+/// the entropy, closest first, until their probabilities add up to `mass`.
+fn typical(logits: &[f32], temperature: f64, mass: f64) -> Vec<bool> {
+    let log_probs = log_softmax(logits, temperature);
+    let entropy: f64 = log_probs
+        .iter()
+        .filter(|lp| lp.is_finite())
+        .map(|&lp| -lp.exp() * lp)
+        .sum();
+    let mut order: Vec<usize> = (0..logits.len())
+        .filter(|&i| log_probs[i].is_finite())
+        .collect();
+    order.sort_by(|&a, &b| {
+        let distance = |i: usize| (-log_probs[i] - entropy).abs();
+        distance(a).total_cmp(&distance(b))
+    });
+    let mut keep = vec![false; logits.len()];
+    let mut covered = 0.;
+    for i in order {
+        keep[i] = true;
+        covered += log_probs[i].exp();
+        if covered >= mass {
+            break;
+        }
+    }
+    keep
+}
+
+/// Which tokens top-n-sigma keeps: those with a logit of at least the
+/// largest minus `n` standard deviations of the (finite) logits.
+fn top_n_sigma(logits: &[f32], n: f64) -> Vec<bool> {
+    let finite: Vec<f64> = logits
+        .iter()
+        .filter(|l| l.is_finite())
+        .map(|&l| l as f64)
+        .collect();
+    if finite.is_empty() {
+        return vec![true; logits.len()];
+    }
+    let count = finite.len() as f64;
+    let mean = finite.iter().sum::<f64>() / count;
+    let sigma = (finite.iter().map(|l| (l - mean).powi(2)).sum::<f64>() / count).sqrt();
+    let max = finite.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let threshold = max - n * sigma;
+    logits.iter().map(|&l| l as f64 >= threshold).collect()
+}
+
+impl std::str::FromStr for Truncation {
+    type Err = anyhow::Error;
+
+    /// `typical:MASS` or `top-n-sigma:N`.
+    fn from_str(spec: &str) -> Result<Self> {
+        let Some((name, value)) = spec.split_once(':') else {
+            bail!("Invalid sampling strategy '{}', expected typical:P or top-n-sigma:N", spec);
+        };
+        let value: f64 = value
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid value in sampling strategy '{}'", spec))?;
+        let truncation = match name.trim() {
+            "typical" => Truncation::Typical(value),
+            "top-n-sigma" | "top_n_sigma" => Truncation::TopNSigma(value),
+            name => bail!(
+                "Unknown sampling strategy '{}' (supported: typical, top-n-sigma)",
+                name
+            ),
+        };
+        truncation.validate()?;
+        Ok(truncation)
+    }
+}
+
+impl std::fmt::Display for Truncation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Truncation::Typical(mass) => write!(f, "typical:{}", mass),
+            Truncation::TopNSigma(n) => write!(f, "top-n-sigma:{}", n),
+        }
+    }
+}
+
+/// Serialized as on the command line, e.g. `"typical:0.9"`.
+impl Serialize for Truncation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
@@ -0,0 +1,424 @@
+// Schema-constrained field extraction (sl5 extract).
+//
+// Given a document and a flat JSON schema, the model writes the JSON object
+// itself, one field at a time: the prompt ends with the object so far and
+// the opening of the next field, and every step masks out the tokens that
+// can't continue a valid value of that field's type. The result is valid
+// JSON of the schema's shape by construction; what goes in it is up to the
+// model. For Llama-family models the document and instructions are
+// prefilled once and restored from a session for each field.
+
+use crate::chat::{is_special, ChatTemplate, Message};
+use crate::generation::{GenerationConfig, Generator};
+use crate::loader::{Model, Weights};
+use crate::session::Session;
+use crate::tokenize::tokenize;
+use anyhow::{bail, Context, Result};
+use candle_core::Tensor;
+use serde_json::{Map, Value};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldKind {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    /// One of a list of strings.
+    Enum(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub name: String,
+    pub kind: FieldKind,
+    pub description: Option<String>,
+    pub required: bool,
+}
+
+/// The fields of a JSON schema for an object of scalar properties, in the
+/// order of their names.
+pub fn parse_schema(schema: &Value) -> Result<Vec<Field>> {
+    let Some(properties) = schema["properties"].as_object() else {
+        bail!("The schema must describe an object with \"properties\"");
+    };
+    let required: Vec<&str> = schema["required"]
+        .as_array()
+        .map(|names| names.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let mut fields = Vec::new();
+    for (name, property) in properties {
+        let kind = match (&property["enum"], property["type"].as_str()) {
+            (Value::Array(options), _) => {
+                let options: Option<Vec<String>> = options
+                    .iter()
+                    .map(|option| option.as_str().map(str::to_string))
+                    .collect();
+                match options {
+                    Some(options) if !options.is_empty() => FieldKind::Enum(options),
+                    _ => bail!(
+                        "Field {:?}: only non-empty enums of strings are supported",
+                        name
+                    ),
+                }
+            }
+            (_, Some("string")) => FieldKind::String,
+            (_, Some("integer")) => FieldKind::Integer,
+            (_, Some("number")) => FieldKind::Number,
+            (_, Some("boolean")) => FieldKind::Boolean,
+            (_, kind) => bail!(
+                "Field {:?} has type {:?}; supported are string, integer, number, boolean and \
+                 string enums",
+                name,
+                kind.unwrap_or("(none)")
+            ),
+        };
+        fields.push(Field {
+            name: name.clone(),
+            kind,
+            description: property["description"].as_str().map(str::to_string),
+            required: required.contains(&name.as_str()),
+        });
+    }
+    if fields.is_empty() {
+        bail!("The schema has no properties to extract");
+    }
+    Ok(fields)
+}
+
+pub fn read_schema(path: &Path) -> Result<Vec<Field>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let schema: Value = serde_json::from_str(&text)
+        .with_context(|| format!("{} is not valid JSON", path.display()))?;
+    parse_schema(&schema).with_context(|| format!("Unsupported schema {}", path.display()))
+}
+
+/// How far a value's text gets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Check {
+    Invalid,
+    /// A prefix of a valid value.
+    Partial,
+    /// A complete value: its first `n` bytes, followed only by the
+    /// delimiters that may close it.
+    Done(usize),
+}
+
+/// Check the text generated for a field. Strings and enums are generated
+/// after their opening quote and end with the closing one.
+fn check(kind: &FieldKind, text: &str) -> Check {
+    // What may follow a value in the object: the next field or the end.
+    let rest_ok = |rest: &str| rest.chars().all(|c| matches!(c, ',' | '}' | ' ' | '\n'));
+    match kind {
+        FieldKind::String => {
+            let mut escaped = false;
+            for (at, c) in text.char_indices() {
+                match c {
+                    // serde_json unescapes the value; \u escapes are left out
+                    // to keep the check simple.
+                    c if escaped => match "\"\\/bfnrt".contains(c) {
+                        true => escaped = false,
+                        false => return Check::Invalid,
+                    },
+                    '\\' => escaped = true,
+                    '"' => {
+                        return match rest_ok(&text[at + 1..]) {
+                            true => Check::Done(at),
+                            false => Check::Invalid,
+                        }
+                    }
+                    c if c.is_control() => return Check::Invalid,
+                    _ => {}
+                }
+            }
+            Check::Partial
+        }
+        FieldKind::Enum(options) => {
+            if options.iter().any(|option| option.starts_with(text)) {
+                return Check::Partial;
+            }
+            for option in options {
+                if let Some(rest) = text.strip_prefix(option.as_str()) {
+                    if let Some(rest) = rest.strip_prefix('"') {
+                        if rest_ok(rest) {
+                            return Check::Done(option.len());
+                        }
+                    }
+                }
+            }
+            Check::Invalid
+        }
+        FieldKind::Boolean => {
+            for word in ["true", "false"] {
+                if word.starts_with(text) {
+                    return Check::Partial;
+                }
+                if let Some(rest) = text.strip_prefix(word) {
+                    if !rest.is_empty() && rest_ok(rest) {
+                        return Check::Done(word.len());
+                    }
+                }
+            }
+            Check::Invalid
+        }
+        FieldKind::Integer | FieldKind::Number => {
+            let end = text.find([',', '}', ' ', '\n']).unwrap_or(text.len());
+            let (number, rest) = text.split_at(end);
+            if !number_prefix(number, *kind == FieldKind::Number) {
+                return Check::Invalid;
+            }
+            if rest.is_empty() {
+                Check::Partial
+            } else if rest_ok(rest) && number_complete(number) {
+                Check::Done(end)
+            } else {
+                Check::Invalid
+            }
+        }
+    }
+}
+
+/// Whether `text` can start a JSON integer (or number, with `fraction`).
+fn number_prefix(text: &str, fraction: bool) -> bool {
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    let (int, tail) = digits.split_at(
+        digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(digits.len()),
+    );
+    if int.len() > 1 && int.starts_with('0') {
+        return false;
+    }
+    if tail.is_empty() {
+        return true;
+    }
+    if !fraction || int.is_empty() {
+        return false;
+    }
+    let tail = match tail.strip_prefix('.') {
+        Some(decimals) => decimals.trim_start_matches(|c: char| c.is_ascii_digit()),
+        None => tail,
+    };
+    match tail.strip_prefix(['e', 'E']) {
+        Some(exponent) => {
+            let exponent = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+            exponent.chars().all(|c| c.is_ascii_digit())
+        }
+        None => tail.is_empty(),
+    }
+}
+
+fn number_complete(text: &str) -> bool {
+    serde_json::from_str::<serde_json::Number>(text).is_ok()
+}
+
+/// Runs extractions with one model, reusing its token texts.
+pub struct FieldExtractor<'a> {
+    model: &'a Model,
+    chat_template: Option<ChatTemplate>,
+    /// The text each token adds when it follows other text; `None` for
+    /// special tokens and partial UTF-8 sequences, which are never allowed.
+    pieces: Vec<Option<String>>,
+    /// Maximum number of tokens generated for one field.
+    pub max_field_tokens: usize,
+}
+
+impl<'a> FieldExtractor<'a> {
+    /// `chat_template` (see `ChatTemplate::for_model`) wraps the request in
+    /// a user turn; without one the prompt is plain text.
+    pub fn new(model: &'a Model, chat_template: Option<ChatTemplate>) -> Result<Self> {
+        let tokenizer = &model.tokenizer;
+        // Decoding a token on its own drops SentencePiece's leading space, so
+        // decode it after an anchor and keep the difference.
+        let anchor = tokenize(tokenizer, "a", false)?.ids;
+        let anchor_text = tokenizer
+            .decode(&anchor, false)
+            .map_err(anyhow::Error::msg)?;
+        let vocab_size = tokenizer.get_vocab_size(true);
+        let mut pieces = Vec::with_capacity(vocab_size);
+        for id in 0..vocab_size as u32 {
+            let special = is_special(tokenizer, id);
+            let ids: Vec<u32> = anchor.iter().copied().chain([id]).collect();
+            let piece = match tokenizer.decode(&ids, false) {
+                Ok(text) if !special => text.strip_prefix(&anchor_text).map(str::to_string),
+                _ => None,
+            };
+            pieces.push(piece.filter(|piece| !piece.is_empty() && !piece.contains('\u{FFFD}')));
+        }
+        Ok(Self {
+            model,
+            chat_template,
+            pieces,
+            max_field_tokens: 64,
+        })
+    }
+
+    /// Fill in `fields` from `document`. Optional fields the model doesn't
+    /// finish within `max_field_tokens` are left out.
+    pub fn extract(&self, document: &str, fields: &[Field]) -> Result<Map<String, Value>> {
+        let (head, add_special_tokens) = self.prompt_head(document, fields)?;
+        let tokenize_prompt = |json: &str| -> Result<Vec<u32>> {
+            Ok(tokenize(
+                &self.model.tokenizer,
+                &format!("{}{}", head, json),
+                add_special_tokens,
+            )?
+            .ids)
+        };
+
+        // Prefill the document once and share it between the fields.
+        let session = match &self.model.weights {
+            Weights::Llama(_) => {
+                let config = GenerationConfig {
+                    max_new_tokens: 1,
+                    ..field_config(1)
+                };
+                let mut generator = Generator::new(self.model, config, tokenize_prompt("{")?)?;
+                generator.step()?;
+                Some(generator.session()?)
+            }
+            _ => None,
+        };
+
+        let mut json = String::from("{");
+        let mut values = Map::new();
+        for field in fields {
+            let separator = if values.is_empty() { "" } else { ", " };
+            let opening = match field.kind {
+                FieldKind::String | FieldKind::Enum(_) => "\"",
+                _ => "",
+            };
+            let prefix = format!(
+                "{}{}{}: {}",
+                json,
+                separator,
+                Value::from(field.name.as_str()),
+                opening
+            );
+            let Some(text) =
+                self.generate_value(&tokenize_prompt(&prefix)?, &field.kind, session.as_ref())?
+            else {
+                if field.required {
+                    bail!(
+                        "The model didn't finish the required field {:?} within {} tokens",
+                        field.name,
+                        self.max_field_tokens
+                    );
+                }
+                continue;
+            };
+            let value = match &field.kind {
+                FieldKind::String | FieldKind::Enum(_) => {
+                    serde_json::from_str::<Value>(&format!("\"{}\"", text))?
+                }
+                _ => serde_json::from_str::<Value>(&text)?,
+            };
+            json = format!("{}{}{}", prefix, text, opening);
+            values.insert(field.name.clone(), value);
+        }
+        Ok(values)
+    }
+
+    /// The prompt up to the JSON object: the document and a description of
+    /// the fields, in the model's chat template when it has one.
+    fn prompt_head(&self, document: &str, fields: &[Field]) -> Result<(String, bool)> {
+        let mut request = format!(
+            "{}\n\nExtract the following fields from the text above as a JSON object:\n",
+            document.trim_end()
+        );
+        for field in fields {
+            let kind = match &field.kind {
+                FieldKind::String => "string".to_string(),
+                FieldKind::Integer => "integer".to_string(),
+                FieldKind::Number => "number".to_string(),
+                FieldKind::Boolean => "true or false".to_string(),
+                FieldKind::Enum(options) => format!("one of {}", options.join(", ")),
+            };
+            request.push_str(&format!("- {} ({})", field.name, kind));
+            if let Some(description) = &field.description {
+                request.push_str(&format!(": {}", description));
+            }
+            request.push('\n');
+        }
+        Ok(match &self.chat_template {
+            // The template writes the BOS token itself.
+            Some(template) => (
+                template.render(&[Message::new("user", request)], true)?,
+                false,
+            ),
+            None => (format!("{}\nJSON: ", request), true),
+        })
+    }
+
+    /// Generate one value after `prompt`. Returns its JSON text, or `None`
+    /// if it wasn't finished within `max_field_tokens`.
+    fn generate_value(
+        &self,
+        prompt: &[u32],
+        kind: &FieldKind,
+        session: Option<&Session>,
+    ) -> Result<Option<String>> {
+        let prompt_len = prompt.len();
+        let mut generator = Generator::new(
+            self.model,
+            field_config(self.max_field_tokens),
+            prompt.to_vec(),
+        )?;
+        if let Some(session) = session {
+            generator = generator.with_session(session)?.0;
+        }
+        let pieces = &self.pieces;
+        let mut text = String::new();
+        generator = generator.with_logits_hook(move |tokens: &[u32], logits: Tensor| {
+            let value: String = tokens[prompt_len..]
+                .iter()
+                .filter_map(|&id| pieces.get(id as usize).cloned().flatten())
+                .collect();
+            let mut candidate = String::new();
+            let mask: Vec<f32> = (0..logits.dim(0)?)
+                .map(|id| {
+                    let Some(Some(piece)) = pieces.get(id) else {
+                        return f32::NEG_INFINITY;
+                    };
+                    candidate.clear();
+                    candidate.push_str(&value);
+                    candidate.push_str(piece);
+                    match check(kind, &candidate) {
+                        Check::Invalid => f32::NEG_INFINITY,
+                        _ => 0.,
+                    }
+                })
+                .collect();
+            if mask.iter().all(|m| m.is_infinite()) {
+                bail!("No token can continue the value {:?}", value);
+            }
+            let mask = Tensor::from_vec(mask, logits.dims1()?, logits.device())?;
+            Ok((logits + mask)?)
+        });
+
+        while generator.finish_reason().is_none() {
+            let step = generator.step()?;
+            text.push_str(self.pieces[step.token as usize].as_deref().unwrap_or(""));
+            if let Check::Done(end) = check(kind, &text) {
+                return Ok(Some(text[..end].to_string()));
+            }
+        }
+        // Numbers can end with the token budget.
+        Ok(match kind {
+            FieldKind::Integer | FieldKind::Number if number_complete(&text) => Some(text),
+            _ => None,
+        })
+    }
+}
+
+/// Greedy decoding without a repeat penalty, which would push the model
+/// away from copying the document.
+fn field_config(max_new_tokens: usize) -> GenerationConfig {
+    GenerationConfig {
+        max_new_tokens,
+        temperature: 0.,
+        repeat_penalty: 1.,
+        ..Default::default()
+    }
+}
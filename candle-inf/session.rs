@@ -0,0 +1,132 @@
+// Prompt sessions saved to disk.
+//
+// Prefilling a long system prompt costs the same on every run. A session holds
+// the prompt tokens and the KV cache computed for them; restoring it before
+// generation skips the forward pass over the longest prefix the new prompt
+// shares with the saved one, so only the differing tail is prefilled.
+
+use crate::loader::{Model, ModelCache, Weights};
+use anyhow::{bail, Result};
+use candle_core::{DType, Device, Tensor};
+use std::collections::HashMap;
+use std::path::Path;
+
+pub struct Session {
+    tokens: Vec<u32>,
+    /// Keys and values of each layer, on the CPU.
+    kvs: Vec<(Tensor, Tensor)>,
+}
+
+impl Session {
+    /// Snapshot the first `tokens.len()` positions of `cache`, which must hold
+    /// exactly those tokens.
+    pub fn capture(tokens: &[u32], cache: &ModelCache) -> Result<Self> {
+        let ModelCache::Llama(cache) = cache else {
+            bail!("Sessions are only supported for Llama models loaded from safetensors");
+        };
+        let mut kvs = Vec::new();
+        for kv in cache.kvs()? {
+            let Some((k, v)) = kv else {
+                bail!("Saving a session needs the KV cache of the processed prompt");
+            };
+            if k.dim(2)? != tokens.len() {
+                bail!(
+                    "The KV cache holds {} positions, expected {}",
+                    k.dim(2)?,
+                    tokens.len()
+                );
+            }
+            kvs.push((k.to_device(&Device::Cpu)?, v.to_device(&Device::Cpu)?));
+        }
+        Ok(Self {
+            tokens: tokens.to_vec(),
+            kvs,
+        })
+    }
+
+    /// Tokens the cache was computed for.
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut tensors = HashMap::new();
+        tensors.insert(
+            "tokens".to_string(),
+            Tensor::new(self.tokens.as_slice(), &Device::Cpu)?,
+        );
+        for (layer, (k, v)) in self.kvs.iter().enumerate() {
+            tensors.insert(format!("layers.{layer}.k"), k.clone());
+            tensors.insert(format!("layers.{layer}.v"), v.clone());
+        }
+        candle_core::safetensors::save(&tensors, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut tensors = candle_core::safetensors::load(path, &Device::Cpu)?;
+        let Some(tokens) = tensors.remove("tokens") else {
+            bail!("{} is not a session file", path.display());
+        };
+        let mut kvs = Vec::new();
+        while let (Some(k), Some(v)) = (
+            tensors.remove(&format!("layers.{}.k", kvs.len())),
+            tensors.remove(&format!("layers.{}.v", kvs.len())),
+        ) {
+            kvs.push((k, v));
+        }
+        Ok(Self {
+            tokens: tokens.to_dtype(DType::U32)?.to_vec1()?,
+            kvs,
+        })
+    }
+
+    /// Fill `cache` with the longest prefix of `prompt` this session covers,
+    /// leaving at least the last prompt token to be run so that there are
+    /// logits to sample from. Returns the number of positions restored.
+    pub(crate) fn restore(
+        &self,
+        model: &Model,
+        cache: &mut ModelCache,
+        prompt: &[u32],
+    ) -> Result<usize> {
+        let (Weights::Llama(llama), ModelCache::Llama(cache)) = (&model.weights, cache) else {
+            bail!("Sessions are only supported for Llama models loaded from safetensors");
+        };
+        let config = &model.config;
+        let head_dim = config.hidden_size / config.num_attention_heads;
+        let expected = [1, config.num_key_value_heads, self.tokens.len(), head_dim];
+        if self.kvs.len() != config.num_hidden_layers
+            || self
+                .kvs
+                .iter()
+                .any(|(k, v)| k.dims() != expected || v.dims() != expected)
+        {
+            bail!("The session was saved for a different model");
+        }
+
+        let shared = self
+            .tokens
+            .iter()
+            .zip(prompt)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(prompt.len() - 1);
+        if shared == 0 {
+            return Ok(0);
+        }
+        let mut kvs = Vec::new();
+        for (layer, (k, v)) in self.kvs.iter().enumerate() {
+            let device = llama.layer_device(layer);
+            let restore = |t: &Tensor| -> Result<Tensor> {
+                Ok(t.narrow(2, 0, shared)?
+                    .to_dtype(model.dtype)?
+                    .to_device(device)?
+                    .contiguous()?)
+            };
+            kvs.push(Some((restore(k)?, restore(v)?)));
+        }
+        cache.set_kvs(kvs)?;
+        Ok(shared)
+    }
+}
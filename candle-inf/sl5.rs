@@ -0,0 +1,1395 @@
+// sl5: model tooling built on the candle_inference library
+// Build: cargo build --release --bin sl5 (add --features cuda for GPU)
+// Run: cargo run --release --bin sl5 -- eval quant-diff --base <model_id> --quant <file.gguf> --file sample.txt
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
+use indicatif::{ProgressBar, ProgressStyle};
+
+use candle_core::{DType, Device};
+use candle_inference::bundle::{self, BundledFile, Environment, Manifest};
+use candle_inference::cached::{cached_models, fuzzy_score, CachedModel};
+use candle_inference::chat::{is_special, lint, read_messages, sample_messages, ChatTemplate};
+use candle_inference::check::summarize_names;
+use candle_inference::convert::{convert, GgufType};
+use candle_inference::daemon;
+use candle_inference::dataset;
+use candle_inference::eval::{perplexity, quant_diff};
+use candle_inference::llama::expected_tensors;
+use candle_inference::logging::{self, LogFormat, LogLevel};
+use candle_inference::schema::{read_schema, FieldExtractor};
+use candle_inference::tensors::save_tensors;
+use candle_inference::token_cache::TokenCache;
+use candle_inference::tokenize::{detokenize, tokenize, Tokenized};
+use candle_inference::tts::{write_wav, Tts, TtsConfig, DEFAULT_DESCRIPTION};
+use candle_inference::watermark::Watermark;
+use candle_inference::{ContextOverflow, ModelLoader};
+
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Parser, Debug)]
+#[command(name = "sl5", about = "Model tooling for SL5 inference")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// Log the library's spans and events (model loading, prefill and
+    /// decode steps with their timings, warnings) to stderr up to this level
+    #[arg(long, value_enum, default_value_t = LogLevel::Warn, global = true)]
+    log_level: LogLevel,
+
+    /// `text` log lines, or `json` for one object per line
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    log_format: LogFormat,
+
+    /// Record the spans as a Chrome trace, for chrome://tracing or
+    /// https://ui.perfetto.dev
+    #[arg(long, value_name = "PATH", global = true)]
+    timeline_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Package a base-inf run with its inputs and model hashes, or run one
+    Bundle {
+        #[command(subcommand)]
+        command: BundleCommand,
+    },
+    /// Convert a Llama safetensors checkpoint to a quantized GGUF file
+    Convert(ConvertArgs),
+    /// Keep a model loaded and serve `sl5 run` requests on a Unix socket
+    Daemon(DaemonArgs),
+    /// Check whether a text carries a base-inf --watermark-key watermark
+    DetectWatermark(DetectWatermarkArgs),
+    /// Evaluate model quality
+    Eval {
+        #[command(subcommand)]
+        command: EvalCommand,
+    },
+    /// Write parts of a model's weights to files
+    Export {
+        #[command(subcommand)]
+        command: ExportCommand,
+    },
+    /// Extract the fields of a JSON schema from a document as validated JSON
+    Extract(ExtractArgs),
+    /// Pick a model from the local Hugging Face cache and run it with base-inf
+    Pick(PickArgs),
+    /// Generate from a prompt with the model of a running `sl5 daemon`
+    Run(RunArgs),
+    /// Inspect chat templates
+    Template {
+        #[command(subcommand)]
+        command: TemplateCommand,
+    },
+    /// Show how a model's tokenizer splits text, without loading its weights
+    Tokenize(TokenizeArgs),
+    /// Turn token ids back into text with a model's tokenizer
+    Detokenize(DetokenizeArgs),
+    /// Synthesize speech from text into a WAV file
+    Tts(TtsArgs),
+}
+
+#[derive(Subcommand, Debug)]
+enum BundleCommand {
+    /// Write a bundle for a base-inf command line
+    Create(BundleCreateArgs),
+    /// Check a bundle's model files and run it
+    Run(BundleRunArgs),
+}
+
+#[derive(Subcommand, Debug)]
+enum TemplateCommand {
+    /// Render a model's chat template and show the tokens the model would see
+    Preview(TemplatePreviewArgs),
+}
+
+#[derive(Subcommand, Debug)]
+enum ExportCommand {
+    /// Write the embedding vectors of a list of tokens as .npz or .safetensors
+    Embeddings(ExportEmbeddingsArgs),
+}
+
+#[derive(Subcommand, Debug)]
+enum EvalCommand {
+    /// Measure a model's perplexity on a text file or Hub dataset
+    Ppl(PplArgs),
+    /// Compare a quantized GGUF model against its full-precision original
+    QuantDiff(QuantDiffArgs),
+}
+
+#[derive(Args, Debug)]
+struct PplArgs {
+    /// Hub model ID, local path (with --local) or an s3://, gs:// or
+    /// https:// model directory
+    #[arg(short = 'm', long)]
+    model: String,
+
+    /// Treat --model as a local model directory
+    #[arg(long)]
+    local: bool,
+
+    /// Model revision (branch, tag or commit)
+    #[arg(long)]
+    revision: Option<String>,
+
+    /// Evaluate a GGUF file (a local path or a file in the --model repo)
+    /// instead of the safetensors weights
+    #[arg(long, value_name = "GGUF")]
+    quantized: Option<String>,
+
+    /// Text to evaluate on (.txt, or .parquet/.jsonl/.json rows)
+    #[arg(long, value_name = "PATH", conflicts_with = "dataset", required_unless_present = "dataset")]
+    file: Option<PathBuf>,
+
+    /// Hub dataset to evaluate on, e.g. Salesforce/wikitext
+    #[arg(long, value_name = "REPO", requires = "dataset_file")]
+    dataset: Option<String>,
+
+    /// File in the --dataset repo, e.g.
+    /// wikitext-2-raw-v1/test-00000-of-00001.parquet
+    #[arg(long, value_name = "FILE", requires = "dataset")]
+    dataset_file: Option<String>,
+
+    /// Dataset revision (branch, tag or commit)
+    #[arg(long, requires = "dataset")]
+    dataset_revision: Option<String>,
+
+    /// Column holding the text in parquet and JSON rows
+    #[arg(long, default_value = "text")]
+    column: String,
+
+    /// Evaluate at most this many tokens of the text
+    #[arg(long)]
+    max_tokens: Option<usize>,
+
+    /// Tokens per window
+    #[arg(long, default_value_t = 512)]
+    context: usize,
+
+    /// Tokens the window advances by [default: half the context]
+    #[arg(long)]
+    stride: Option<usize>,
+
+    /// Data type for safetensors weights (f16, bf16, f32)
+    #[arg(long, default_value = "f16")]
+    dtype: String,
+
+    /// Run on CPU instead of GPU
+    #[arg(long)]
+    cpu: bool,
+
+    /// Tokenize the text again instead of reading its cached tokens
+    #[arg(long)]
+    retokenize: bool,
+}
+
+#[derive(Args, Debug)]
+struct QuantDiffArgs {
+    /// Full-precision model: Hub model ID, local path (with --local) or an
+    /// s3://, gs:// or https:// model directory
+    #[arg(long)]
+    base: String,
+
+    /// Quantized GGUF file: a local path or a file in the --base repo
+    #[arg(long, value_name = "GGUF")]
+    quant: String,
+
+    /// Treat --base as a local model directory
+    #[arg(long)]
+    local: bool,
+
+    /// Text to evaluate on
+    #[arg(long, value_name = "PATH")]
+    file: PathBuf,
+
+    /// Evaluate at most this many tokens of the text
+    #[arg(long, default_value_t = 1024)]
+    max_tokens: usize,
+
+    /// Tokens per evaluation window; each window starts from an empty cache
+    #[arg(long, default_value_t = 512)]
+    window: usize,
+
+    /// Data type of the base model (f16, bf16, f32)
+    #[arg(long, default_value = "f16")]
+    dtype: String,
+
+    /// Run on CPU instead of GPU
+    #[arg(long)]
+    cpu: bool,
+
+    /// Tokenize --file again instead of reading its cached tokens
+    #[arg(long)]
+    retokenize: bool,
+}
+
+#[derive(Args, Debug)]
+struct PickArgs {
+    /// Only list models matching this fuzzy filter, e.g. `tiny chat`
+    query: Option<String>,
+
+    /// Arguments for base-inf after the model, e.g. `-- -p "Hello" --cpu`
+    #[arg(last = true)]
+    base_inf_args: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct TemplatePreviewArgs {
+    /// Hub model ID, local path (with --local) or an s3://, gs:// or
+    /// https:// model directory
+    #[arg(short = 'm', long)]
+    model: String,
+
+    /// Treat --model as a local model directory
+    #[arg(long)]
+    local: bool,
+
+    /// Model revision/branch
+    #[arg(long)]
+    revision: Option<String>,
+
+    /// JSON file with the messages to render (default: a short sample
+    /// conversation with every role)
+    #[arg(long, value_name = "PATH")]
+    messages: Option<PathBuf>,
+
+    /// Render this Jinja template instead of the model's own
+    #[arg(long, value_name = "PATH")]
+    template: Option<PathBuf>,
+
+    /// Don't end the prompt with the opening of an assistant turn
+    #[arg(long)]
+    no_generation_prompt: bool,
+}
+
+#[derive(Args, Debug)]
+struct TokenizeArgs {
+    /// Text to tokenize (read from stdin if omitted)
+    text: Option<String>,
+
+    /// Hub model ID, local path (with --local) or an s3://, gs:// or
+    /// https:// model directory
+    #[arg(short = 'm', long)]
+    model: String,
+
+    /// Treat --model as a local model directory
+    #[arg(long)]
+    local: bool,
+
+    /// Model revision/branch
+    #[arg(long)]
+    revision: Option<String>,
+
+    /// Tokenize this file instead of the text argument
+    #[arg(long, value_name = "PATH", conflicts_with = "text")]
+    file: Option<PathBuf>,
+
+    /// Don't add the special tokens (e.g. BOS) base-inf adds to a raw prompt
+    #[arg(long)]
+    no_special_tokens: bool,
+
+    /// Only print the token count
+    #[arg(long, conflicts_with = "json")]
+    count: bool,
+
+    /// Print the ids, pieces and byte offsets as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct DetokenizeArgs {
+    /// Token ids, separated by spaces or commas (read from stdin if omitted)
+    ids: Vec<String>,
+
+    /// Hub model ID, local path (with --local) or an s3://, gs:// or
+    /// https:// model directory
+    #[arg(short = 'm', long)]
+    model: String,
+
+    /// Treat --model as a local model directory
+    #[arg(long)]
+    local: bool,
+
+    /// Model revision/branch
+    #[arg(long)]
+    revision: Option<String>,
+
+    /// Leave special tokens (BOS, EOS, ...) out of the text
+    #[arg(long)]
+    skip_special_tokens: bool,
+}
+
+#[derive(Args, Debug)]
+struct ExportEmbeddingsArgs {
+    /// Hub model ID, local path (with --local) or an s3://, gs:// or
+    /// https:// model directory
+    #[arg(short = 'm', long)]
+    model: String,
+
+    /// Treat --model as a local model directory
+    #[arg(long)]
+    local: bool,
+
+    /// Model revision/branch
+    #[arg(long)]
+    revision: Option<String>,
+
+    /// Tokens to export, one token id, vocabulary entry or piece of text per
+    /// line
+    #[arg(long, value_name = "PATH")]
+    tokens: PathBuf,
+
+    /// File to write (.npz or .safetensors)
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Weight matrix to export token vectors from
+    #[arg(long, value_enum, default_value_t = EmbeddingSource::Input)]
+    source: EmbeddingSource,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum EmbeddingSource {
+    /// Input embedding matrix (model.embed_tokens)
+    Input,
+    /// Output projection rows (lm_head, or the input matrix when tied)
+    LmHead,
+}
+
+#[derive(Args, Debug)]
+struct DetectWatermarkArgs {
+    /// Text file to score
+    file: PathBuf,
+
+    /// Hub model ID, local path (with --local) or an s3://, gs:// or
+    /// https:// model directory of the model that generated the text
+    #[arg(short = 'm', long)]
+    model: String,
+
+    /// Treat --model as a local model directory
+    #[arg(long)]
+    local: bool,
+
+    /// Model revision/branch
+    #[arg(long)]
+    revision: Option<String>,
+
+    /// The --watermark-key the text was generated with
+    #[arg(long)]
+    key: String,
+
+    /// The --watermark-gamma the text was generated with
+    #[arg(long, default_value_t = 0.25)]
+    gamma: f64,
+
+    /// z-score above which the text is reported as watermarked
+    #[arg(long, default_value_t = 4.0)]
+    threshold: f64,
+
+    /// Print the counts and z-score as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct ExtractArgs {
+    /// Model: Hub model ID, local path (with --local) or an s3://, gs:// or
+    /// https:// model directory
+    #[arg(short = 'm', long, default_value = "meta-llama/Llama-3.2-1B-Instruct")]
+    model: String,
+
+    /// Treat --model as a local model directory
+    #[arg(long)]
+    local: bool,
+
+    /// Model revision/branch
+    #[arg(long)]
+    revision: Option<String>,
+
+    /// JSON schema of an object with string, integer, number, boolean or
+    /// string-enum properties
+    #[arg(long)]
+    schema: PathBuf,
+
+    /// Document to extract from (read from stdin if omitted)
+    #[arg(long)]
+    input: Option<PathBuf>,
+
+    /// Maximum number of tokens generated for one field
+    #[arg(long, default_value_t = 64)]
+    max_field_tokens: usize,
+
+    /// Write the JSON here instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Data type (f16, bf16, f32)
+    #[arg(long, default_value = "f16")]
+    dtype: String,
+
+    /// Run on CPU instead of GPU
+    #[arg(long)]
+    cpu: bool,
+}
+
+#[derive(Args, Debug)]
+struct ConvertArgs {
+    /// Model: Hub model ID, local path (with --local) or an s3://, gs:// or
+    /// https:// model directory
+    #[arg(short = 'm', long)]
+    model: String,
+
+    /// Treat --model as a local model directory
+    #[arg(long)]
+    local: bool,
+
+    /// Model revision/branch
+    #[arg(long)]
+    revision: Option<String>,
+
+    /// LoRA adapter to merge into the weights before converting, as
+    /// DIR_OR_REPO or DIR_OR_REPO:WEIGHT (repeatable)
+    #[arg(long, value_name = "ADAPTER")]
+    lora: Vec<String>,
+
+    /// GGUF file to write
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Tensor type of the weight matrices
+    #[arg(long = "type", value_enum, default_value_t = GgufType::Q8_0)]
+    gguf_type: GgufType,
+}
+
+#[derive(Args, Debug)]
+struct DaemonArgs {
+    /// Model: Hub model ID, local path (with --local) or an s3://, gs:// or
+    /// https:// model directory
+    #[arg(short = 'm', long, default_value = "meta-llama/Llama-3.2-1B-Instruct")]
+    model: String,
+
+    /// Treat --model as a local model directory
+    #[arg(long)]
+    local: bool,
+
+    /// Model revision/branch
+    #[arg(long)]
+    revision: Option<String>,
+
+    /// GGUF file in the model repo to load instead of safetensors
+    #[arg(long)]
+    quantized: Option<String>,
+
+    /// Data type (f16, bf16, f32)
+    #[arg(long, default_value = "f16")]
+    dtype: String,
+
+    /// Run on CPU instead of GPU
+    #[arg(long)]
+    cpu: bool,
+
+    /// Socket to listen on (default: $XDG_RUNTIME_DIR/sl5.sock)
+    #[arg(long)]
+    socket: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
+    /// Prompt
+    #[arg(short, long)]
+    prompt: String,
+
+    /// Maximum number of tokens to generate
+    #[arg(short = 'n', long)]
+    max_new_tokens: Option<usize>,
+
+    /// Sampling temperature (0 = greedy)
+    #[arg(long)]
+    temperature: Option<f64>,
+
+    /// Nucleus sampling probability cutoff
+    #[arg(long)]
+    top_p: Option<f64>,
+
+    /// Only sample among the top K tokens
+    #[arg(long)]
+    top_k: Option<usize>,
+
+    /// Random seed
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Penalty for repeating tokens (1.0 = no penalty)
+    #[arg(long)]
+    repeat_penalty: Option<f32>,
+
+    /// Stop once the output contains this text (repeatable)
+    #[arg(long)]
+    stop: Vec<String>,
+
+    /// What happens when the prompt and the tokens to generate don't fit in
+    /// the context window (default: reduce-max-tokens)
+    #[arg(long, value_enum)]
+    context_overflow: Option<ContextOverflow>,
+
+    /// Socket of the daemon (default: $XDG_RUNTIME_DIR/sl5.sock)
+    #[arg(long)]
+    socket: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct BundleCreateArgs {
+    /// Bundle archive to write, e.g. run.zip
+    #[arg(short = 'o', long)]
+    output: PathBuf,
+
+    /// The base-inf arguments, e.g. `-- -m TinyLlama/TinyLlama-1.1B-Chat-v1.0 -p "Hello" --seed 7`
+    #[arg(last = true, required = true)]
+    base_inf_args: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct BundleRunArgs {
+    /// Bundle archive written by `sl5 bundle create`
+    bundle: PathBuf,
+
+    /// Directory to extract the bundle's input files into (default: a
+    /// temporary directory, removed after the run)
+    #[arg(long, value_name = "DIR")]
+    dir: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct TtsArgs {
+    /// Text to speak (read from stdin if omitted)
+    text: Option<String>,
+
+    /// Parler-TTS model: Hub model ID, local path (with --local) or an
+    /// s3://, gs:// or https:// model directory
+    #[arg(short = 'm', long, default_value = "parler-tts/parler-tts-mini-v1")]
+    model: String,
+
+    /// Treat --model as a local model directory
+    #[arg(long)]
+    local: bool,
+
+    /// Model revision/branch
+    #[arg(long)]
+    revision: Option<String>,
+
+    /// WAV file to write
+    #[arg(short, long, default_value = "out.wav")]
+    output: PathBuf,
+
+    /// How the voice should sound, e.g. "A male speaker with a deep voice
+    /// speaks slowly. Very clear audio."
+    #[arg(long)]
+    description: Option<String>,
+
+    /// Sampling temperature (0 = greedy, which tends to repeat itself)
+    #[arg(long, default_value_t = 1.0)]
+    temperature: f64,
+
+    /// Nucleus sampling probability cutoff
+    #[arg(long)]
+    top_p: Option<f64>,
+
+    /// Random seed
+    #[arg(long, default_value_t = 299792458)]
+    seed: u64,
+
+    /// Maximum number of audio frames to generate (86 per second of audio
+    /// for parler-tts-mini)
+    #[arg(long, default_value_t = 512)]
+    max_steps: usize,
+
+    /// Data type (f16, bf16, f32)
+    #[arg(long, default_value = "f32")]
+    dtype: String,
+
+    /// Run on CPU instead of GPU
+    #[arg(long)]
+    cpu: bool,
+}
+
+fn parse_dtype(dtype: &str) -> Result<DType> {
+    match dtype {
+        "f16" => Ok(DType::F16),
+        "bf16" => Ok(DType::BF16),
+        "f32" => Ok(DType::F32),
+        dtype => bail!("Unsupported dtype: {}", dtype),
+    }
+}
+
+fn device(cpu: bool) -> Result<Device> {
+    Ok(if cpu {
+        Device::Cpu
+    } else {
+        Device::cuda_if_available(0)?
+    })
+}
+
+fn run_ppl(args: &PplArgs) -> Result<()> {
+    let device = device(args.cpu)?;
+    let stride = args.stride.unwrap_or((args.context / 2).max(1));
+    if args.context < 2 || stride == 0 || stride > args.context {
+        bail!("--context must be at least 2 and --stride between 1 and --context");
+    }
+
+    let (path, source) = match (&args.file, &args.dataset, &args.dataset_file) {
+        (Some(file), _, _) => (file.clone(), file.display().to_string()),
+        (None, Some(dataset), Some(file)) => (
+            dataset::download(dataset, file, args.dataset_revision.as_deref())?,
+            format!("{}/{}", dataset, file),
+        ),
+        _ => bail!("Pass --file or --dataset with --dataset-file"),
+    };
+    let text = dataset::read_text(&path, &args.column)?;
+
+    println!("Loading model...");
+    let loader = ModelLoader::new(args.model.clone())
+        .local(args.local)
+        .revision(args.revision.clone())
+        .quantized(args.quantized.clone());
+    let files = loader.files()?;
+    let model = files.load(parse_dtype(&args.dtype)?, &device)?;
+    if args.context > model.config.max_position_embeddings {
+        tracing::warn!(
+            "--context {} is longer than the model's context window ({})",
+            args.context, model.config.max_position_embeddings
+        );
+    }
+
+    let (mut tokens, cached) = TokenCache::new(&files.tokenizer)?
+        .refresh(args.retokenize)
+        .encode(&model.tokenizer, &text)?;
+    if cached {
+        println!("Loaded {} cached tokens for {}", tokens.len(), source);
+    }
+    if let Some(max_tokens) = args.max_tokens {
+        tokens.truncate(max_tokens);
+    }
+    println!(
+        "Evaluating {} tokens of {} (context {}, stride {})...\n",
+        tokens.len(),
+        source,
+        args.context,
+        stride
+    );
+
+    let bar = ProgressBar::new(tokens.len().saturating_sub(1) as u64).with_style(
+        ProgressStyle::with_template("{bar:40} {pos}/{len} tokens, {msg} [{elapsed} < {eta}]")?,
+    );
+    let start = std::time::Instant::now();
+    let result = perplexity(&model, &tokens, args.context, stride, |scored, ppl| {
+        bar.set_position(scored as u64);
+        bar.set_message(format!("perplexity {:.4}", ppl));
+    })?;
+    bar.finish_and_clear();
+    let elapsed = start.elapsed();
+
+    println!("=== Perplexity ===");
+    println!("Model: {}", args.quantized.as_deref().unwrap_or(&args.model));
+    println!("Tokens scored: {} in {} windows", result.tokens, result.windows);
+    println!("Mean NLL: {:.4} nats/token", result.mean_nll);
+    println!("Perplexity: {:.4}", result.perplexity);
+    println!(
+        "Time: {:.2?} ({:.1} tokens/s)",
+        elapsed,
+        result.tokens as f64 / elapsed.as_secs_f64()
+    );
+    Ok(())
+}
+
+fn run_quant_diff(args: &QuantDiffArgs) -> Result<()> {
+    let device = device(args.cpu)?;
+    let dtype = parse_dtype(&args.dtype)?;
+
+    println!("Loading base model...");
+    let base_files = ModelLoader::new(args.base.clone())
+        .local(args.local)
+        .files()?;
+    let base = base_files.load(dtype, &device)?;
+    println!("Loading quantized model...");
+    let quant = ModelLoader::new(args.base.clone())
+        .local(args.local)
+        .quantized(Some(args.quant.clone()))
+        .load(DType::F32, &device)?;
+
+    let text = std::fs::read_to_string(&args.file)?;
+    let (mut tokens, cached) = TokenCache::new(&base_files.tokenizer)?
+        .refresh(args.retokenize)
+        .encode(&base.tokenizer, &text)?;
+    if cached {
+        println!("Loaded {} cached tokens for {}", tokens.len(), args.file.display());
+    }
+    tokens.truncate(args.max_tokens);
+    println!("Evaluating {} tokens...\n", tokens.len());
+
+    let start = std::time::Instant::now();
+    let diff = quant_diff(&base, &quant, &tokens, args.window)?;
+    let delta = diff.quant_perplexity - diff.base_perplexity;
+
+    println!("=== Quantization Diff ===");
+    println!("Tokens scored: {}", diff.tokens);
+    println!("Base perplexity: {:.4}", diff.base_perplexity);
+    println!(
+        "Quantized perplexity: {:.4} ({:+.4}, {:+.2}%)",
+        diff.quant_perplexity,
+        delta,
+        100. * delta / diff.base_perplexity
+    );
+    println!(
+        "KL divergence: mean {:.5}, p99 {:.5}, max {:.5}",
+        diff.mean_kl, diff.p99_kl, diff.max_kl
+    );
+    println!("Top-1 agreement: {:.2}%", 100. * diff.top1_agreement);
+    println!("Time: {:.2?}", start.elapsed());
+    Ok(())
+}
+
+fn run_pick(args: &PickArgs) -> Result<()> {
+    let models = cached_models()?;
+    if models.is_empty() {
+        bail!("No models in the Hugging Face cache yet; run base-inf -m <model_id> to download one");
+    }
+    let mut query = args.query.clone().unwrap_or_default();
+    loop {
+        let mut matches: Vec<(usize, &CachedModel)> = models
+            .iter()
+            .filter_map(|model| Some((fuzzy_score(&query, &model.id)?, model)))
+            .collect();
+        matches.sort_by_key(|&(score, _)| score);
+
+        println!("\n{:>3}  {:<48} {:>9}  {:<20} {:>8}", "#", "MODEL", "SIZE", "WEIGHTS", "CONTEXT");
+        for (i, (_, model)) in matches.iter().enumerate() {
+            println!(
+                "{:>3}  {:<48} {:>5.2} GiB  {:<20} {:>8}",
+                i + 1,
+                model.id,
+                model.size as f64 / (1u64 << 30) as f64,
+                model.weights(),
+                model.context.map_or("?".to_string(), |n| n.to_string())
+            );
+        }
+        if matches.is_empty() {
+            println!("     (no model matches '{}')", query);
+        }
+        print!("Number to run, text to filter, or Enter to quit: ");
+        std::io::stdout().flush()?;
+
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input)?;
+        let input = input.trim();
+        if input.is_empty() {
+            return Ok(());
+        }
+        match input.parse::<usize>() {
+            Ok(n) if (1..=matches.len()).contains(&n) => {
+                let model = matches[n - 1].1;
+                let base_inf = base_inf_path()?;
+                println!("Running {} -m {} {}\n", base_inf.display(), model.id, args.base_inf_args.join(" "));
+                let status = std::process::Command::new(&base_inf)
+                    .arg("-m")
+                    .arg(&model.id)
+                    .args(&args.base_inf_args)
+                    .status()
+                    .with_context(|| format!("Failed to start {}", base_inf.display()))?;
+                logging::finish();
+                std::process::exit(status.code().unwrap_or(1));
+            }
+            _ => query = input.to_string(),
+        }
+    }
+}
+
+/// The base-inf binary next to this one.
+fn base_inf_path() -> Result<PathBuf> {
+    Ok(std::env::current_exe()?.with_file_name(format!("base-inf{}", std::env::consts::EXE_SUFFIX)))
+}
+
+/// `args` without --hf-token, and the token it gave.
+fn strip_hf_token(args: &[String]) -> (Vec<String>, Option<String>) {
+    let (mut kept, mut token) = (Vec::new(), None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--hf-token" {
+            token = args.next().cloned();
+        } else if let Some(value) = arg.strip_prefix("--hf-token=") {
+            token = Some(value.to_string());
+        } else {
+            kept.push(arg.clone());
+        }
+    }
+    (kept, token)
+}
+
+fn run_bundle_create(args: &BundleCreateArgs) -> Result<()> {
+    let base_inf = base_inf_path()?;
+    let output = std::process::Command::new(&base_inf)
+        .args(&args.base_inf_args)
+        .arg("--dump-config")
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .with_context(|| format!("Failed to start {}", base_inf.display()))?;
+    if !output.status.success() {
+        bail!("base-inf rejected the arguments");
+    }
+    let config: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .context("Failed to read base-inf's --dump-config output")?;
+    // The token is a credential; `bundle run` uses the runner's own.
+    let (base_inf_args, hf_token) = strip_hf_token(&args.base_inf_args);
+
+    println!("Hashing the model files...");
+    let loader = bundle::model_loader(&config)?.hf_token(hf_token);
+    let (model, template) = bundle::model_hashes(&loader)?;
+    let (mut inputs, mut sources) = (Vec::new(), Vec::new());
+    for option in bundle::INPUT_OPTIONS {
+        let Some(original) = config[option].as_str() else {
+            continue;
+        };
+        let path = PathBuf::from(original);
+        let name = path
+            .file_name()
+            .map_or("input".to_string(), |name| name.to_string_lossy().into_owned());
+        let entry = format!("inputs/{option}/{name}");
+        inputs.push(BundledFile {
+            option: option.to_string(),
+            original: original.to_string(),
+            entry: entry.clone(),
+            sha256: bundle::sha256_file(&path)?,
+        });
+        sources.push((entry, path));
+    }
+    let manifest = Manifest {
+        created: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+        args: base_inf_args,
+        seed: config["seed"].as_u64(),
+        config,
+        model,
+        inputs,
+        environment: Environment::snapshot(),
+    };
+    bundle::write(&args.output, &manifest, &sources, template.as_ref())?;
+
+    for file in &manifest.model {
+        println!("  {:<16} {}  {}", file.name, &file.sha256[..16], file.path);
+    }
+    for input in &manifest.inputs {
+        println!("  {:<16} {}  {}", input.option, &input.sha256[..16], input.original);
+    }
+    println!("Wrote {}", args.output.display());
+    Ok(())
+}
+
+fn run_bundle_run(args: &BundleRunArgs) -> Result<()> {
+    let dir = match &args.dir {
+        Some(dir) => dir.clone(),
+        None => std::env::temp_dir().join(format!("sl5-bundle-{}", std::process::id())),
+    };
+    std::fs::create_dir_all(&dir)?;
+    let prepared = prepare_bundle_run(&args.bundle, &dir);
+    let status = prepared.and_then(|base_inf_args| {
+        let base_inf = base_inf_path()?;
+        println!("Running {} {}\n", base_inf.display(), base_inf_args.join(" "));
+        std::process::Command::new(&base_inf)
+            .args(&base_inf_args)
+            .status()
+            .with_context(|| format!("Failed to start {}", base_inf.display()))
+    });
+    if args.dir.is_none() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    logging::finish();
+    std::process::exit(status?.code().unwrap_or(1));
+}
+
+/// Extract the bundle into `dir` and check the model files, returning the
+/// base-inf arguments to run it with.
+fn prepare_bundle_run(path: &Path, dir: &Path) -> Result<Vec<String>> {
+    let manifest = bundle::extract(path, dir)?;
+
+    println!("Checking the model files against the bundle...");
+    let (current, _) = bundle::model_hashes(&bundle::model_loader(&manifest.config)?)?;
+    let differences = bundle::compare(&manifest.model, &current);
+    if !differences.is_empty() {
+        bail!(
+            "The model files differ from the ones the bundle was created with; refusing to run:\n  {}",
+            differences.join("\n  ")
+        );
+    }
+    // Outputs can still differ numerically on other hardware or builds.
+    let recorded = &manifest.environment;
+    let environment = Environment::snapshot();
+    let describe = |e: &Environment| {
+        format!("{} {} {:?} {:?}", e.os, e.arch, e.features, e.gpus)
+    };
+    if recorded.version != environment.version || describe(recorded) != describe(&environment) {
+        println!(
+            "Note: the bundle was created with version {} on {}; this is version {} on {}",
+            recorded.version,
+            describe(recorded),
+            environment.version,
+            describe(&environment)
+        );
+    }
+    Ok(bundle::rewrite_args(&manifest.args, &manifest.inputs, dir))
+}
+
+fn run_template_preview(args: &TemplatePreviewArgs) -> Result<()> {
+    let loader = ModelLoader::new(args.model.clone())
+        .local(args.local)
+        .revision(args.revision.clone());
+    let tokenizer = tokenizers::Tokenizer::from_file(loader.file("tokenizer.json")?)
+        .map_err(anyhow::Error::msg)?;
+    let model_template = ChatTemplate::for_model(&loader)?;
+    let template = match (&args.template, model_template) {
+        (Some(path), model_template) => {
+            let (bos_token, eos_token) = model_template
+                .map(|t| (t.bos_token, t.eos_token))
+                .unwrap_or_default();
+            ChatTemplate {
+                source: std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?,
+                bos_token,
+                eos_token,
+            }
+        }
+        (None, Some(template)) => template,
+        (None, None) => bail!("{} has no chat template; pass one with --template", args.model),
+    };
+    let messages = match &args.messages {
+        Some(path) => read_messages(path)?,
+        None => sample_messages(),
+    };
+
+    let rendered = template.render(&messages, !args.no_generation_prompt)?;
+    // Templates write the BOS token themselves, so (like transformers) the
+    // rendered text is encoded without adding special tokens.
+    let tokens = tokenize(&tokenizer, &rendered, false)?;
+    println!("\n=== Rendered prompt ({} messages) ===", messages.len());
+    println!("{}", rendered);
+
+    println!("\n=== Tokens ({}) ===", tokens.ids.len());
+    print_tokens(&tokenizer, &rendered, &tokens);
+
+    let warnings = lint(&template, &messages, &rendered, &tokens, &tokenizer)?;
+    println!("\n=== Lint ===");
+    if warnings.is_empty() {
+        println!("No problems found");
+    }
+    for warning in &warnings {
+        println!("warning: {}", warning);
+    }
+    Ok(())
+}
+
+/// One line per token: its index, id and the text it covers, with special
+/// tokens highlighted.
+fn print_tokens(tokenizer: &tokenizers::Tokenizer, text: &str, tokens: &Tokenized) {
+    let color = std::io::stdout().is_terminal();
+    for (index, (&id, token)) in tokens.ids.iter().zip(&tokens.tokens).enumerate() {
+        let (start, end) = tokens.offsets[index];
+        let piece = text.get(start..end).filter(|piece| !piece.is_empty()).unwrap_or(token);
+        if is_special(tokenizer, id) {
+            let special = format!("{:?}  special", piece);
+            if color {
+                println!("{:>5} {:>7}  \x1b[1;35m{}\x1b[0m", index, id, special);
+            } else {
+                println!("{:>5} {:>7}  {}", index, id, special);
+            }
+        } else {
+            println!("{:>5} {:>7}  {:?}", index, id, piece);
+        }
+    }
+}
+
+/// The tokenizer of the model `--model` names; only tokenizer.json is fetched.
+fn load_tokenizer(
+    model: &str,
+    local: bool,
+    revision: Option<String>,
+) -> Result<(ModelLoader, tokenizers::Tokenizer)> {
+    let loader = ModelLoader::new(model.to_string()).local(local).revision(revision);
+    let tokenizer = tokenizers::Tokenizer::from_file(loader.file("tokenizer.json")?)
+        .map_err(anyhow::Error::msg)?;
+    Ok((loader, tokenizer))
+}
+
+/// The model's context length from its config.json, if it has one.
+fn context_length(loader: &ModelLoader) -> Option<u64> {
+    let config = std::fs::read_to_string(loader.file("config.json").ok()?).ok()?;
+    let config: serde_json::Value = serde_json::from_str(&config).ok()?;
+    config["max_position_embeddings"].as_u64()
+}
+
+fn run_tokenize(args: &TokenizeArgs) -> Result<()> {
+    let text = match (&args.text, &args.file) {
+        (Some(text), _) => text.clone(),
+        (None, Some(path)) => std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?,
+        (None, None) => std::io::read_to_string(std::io::stdin())?,
+    };
+    let (loader, tokenizer) = load_tokenizer(&args.model, args.local, args.revision.clone())?;
+    let tokens = tokenize(&tokenizer, &text, !args.no_special_tokens)?;
+    if args.count {
+        println!("{}", tokens.ids.len());
+        return Ok(());
+    }
+    if args.json {
+        println!("{}", serde_json::to_string(&tokens)?);
+        return Ok(());
+    }
+    print_tokens(&tokenizer, &text, &tokens);
+    println!();
+    println!("Tokens: {}", tokens.ids.len());
+    println!("Characters: {} ({} bytes)", text.chars().count(), text.len());
+    if let Some(context) = context_length(&loader) {
+        println!(
+            "Context: {} of {} ({} left)",
+            tokens.ids.len(),
+            context,
+            context.saturating_sub(tokens.ids.len() as u64)
+        );
+    }
+    Ok(())
+}
+
+fn run_detokenize(args: &DetokenizeArgs) -> Result<()> {
+    let input = if args.ids.is_empty() {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        args.ids.join(" ")
+    };
+    let ids = input
+        .split(|c: char| c.is_whitespace() || c == ',' || c == '[' || c == ']')
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse::<u32>().with_context(|| format!("Invalid token id {:?}", id)))
+        .collect::<Result<Vec<_>>>()?;
+    let (_, tokenizer) = load_tokenizer(&args.model, args.local, args.revision.clone())?;
+    let vocab_size = tokenizer.get_vocab_size(true);
+    if let Some(id) = ids.iter().find(|&&id| id as usize >= vocab_size) {
+        bail!("Token id {} is outside the vocabulary ({} tokens)", id, vocab_size);
+    }
+    println!("{}", detokenize(&tokenizer, &ids, args.skip_special_tokens)?);
+    Ok(())
+}
+
+fn run_detect_watermark(args: &DetectWatermarkArgs) -> Result<()> {
+    if args.gamma <= 0. || args.gamma >= 1. {
+        bail!("--gamma must be between 0 and 1");
+    }
+    let text = std::fs::read_to_string(&args.file)
+        .with_context(|| format!("Failed to read {}", args.file.display()))?;
+    // Only the tokenizer is needed to score text, not the weights. The bias
+    // doesn't matter for detection.
+    let (_, tokenizer) = load_tokenizer(&args.model, args.local, args.revision.clone())?;
+    let tokens = tokenize(&tokenizer, &text, false)?;
+    let detection = Watermark::new(&args.key, args.gamma, 0.).detect(&tokens.ids);
+    let expected_green = args.gamma * detection.scored as f64;
+    let watermarked = detection.z_score > args.threshold;
+    if args.json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "scored": detection.scored,
+                "green": detection.green,
+                "expected_green": expected_green,
+                "z_score": detection.z_score,
+                "watermarked": watermarked,
+            })
+        );
+        return Ok(());
+    }
+    println!("Tokens scored: {}", detection.scored);
+    println!(
+        "Green tokens: {} (expected {:.1} by chance)",
+        detection.green, expected_green
+    );
+    println!("z-score: {:.2}", detection.z_score);
+    println!("Watermarked: {}", if watermarked { "yes" } else { "no" });
+    Ok(())
+}
+
+/// Resolve each line of `path` to token ids: a numeric id, an exact vocabulary
+/// entry (e.g. `▁Paris`), or otherwise the tokens the text encodes to.
+fn read_token_list(tokenizer: &tokenizers::Tokenizer, path: &Path) -> Result<Vec<u32>> {
+    let mut ids = Vec::new();
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    for line in text.lines() {
+        if line.is_empty() {
+            continue;
+        }
+        if let Ok(id) = line.parse::<u32>() {
+            ids.push(id);
+        } else if let Some(id) = tokenizer.token_to_id(line) {
+            ids.push(id);
+        } else {
+            ids.extend(tokenize(tokenizer, line, false)?.ids);
+        }
+    }
+    Ok(ids)
+}
+
+fn run_export_embeddings(args: &ExportEmbeddingsArgs) -> Result<()> {
+    let files = ModelLoader::new(args.model.clone())
+        .local(args.local)
+        .revision(args.revision.clone())
+        .files()?;
+    let config = files.load_config()?;
+    let ids = read_token_list(&files.load_tokenizer()?, &args.tokens)?;
+    if let Some(id) = ids.iter().find(|&&id| id as usize >= config.vocab_size) {
+        bail!("Token id {} is outside the vocabulary ({} entries)", id, config.vocab_size);
+    }
+    // Only the one matrix is read, in f32 so the vectors are exact.
+    let vb = files.var_builder(DType::F32, &Device::Cpu)?;
+    let name = match args.source {
+        EmbeddingSource::LmHead if vb.contains_tensor("lm_head.weight") => "lm_head.weight",
+        // Models with tied embeddings have no separate lm_head.
+        _ => "model.embed_tokens.weight",
+    };
+    let weights = vb.get((config.vocab_size, config.hidden_size), name)?;
+    let index = candle_core::Tensor::new(ids.as_slice(), &Device::Cpu)?;
+    save_tensors(
+        vec![
+            ("embeddings".to_string(), weights.index_select(&index, 0)?),
+            ("tokens".to_string(), index),
+        ],
+        &args.output,
+    )?;
+    println!(
+        "Exported {} vectors from {} to {}",
+        ids.len(),
+        name,
+        args.output.display()
+    );
+    Ok(())
+}
+
+fn run_extract(args: &ExtractArgs) -> Result<()> {
+    if args.max_field_tokens == 0 {
+        bail!("--max-field-tokens must be at least 1");
+    }
+    let fields = read_schema(&args.schema)?;
+    let document = match &args.input {
+        Some(path) => std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?,
+        None => std::io::read_to_string(std::io::stdin())?,
+    };
+    let device = device(args.cpu)?;
+    let loader = ModelLoader::new(args.model.clone())
+        .local(args.local)
+        .revision(args.revision.clone());
+    tracing::info!("Loading model");
+    let model = loader.load(parse_dtype(&args.dtype)?, &device)?;
+    let mut extractor = FieldExtractor::new(&model, ChatTemplate::for_model(&loader)?)?;
+    extractor.max_field_tokens = args.max_field_tokens;
+
+    tracing::info!("Extracting {} fields", fields.len());
+    let start = std::time::Instant::now();
+    let values = extractor.extract(&document, &fields)?;
+    tracing::info!("Extracted the fields in {:.2?}", start.elapsed());
+
+    let json = serde_json::to_string_pretty(&values)?;
+    match &args.output {
+        Some(path) => std::fs::write(path, json + "\n")
+            .with_context(|| format!("Failed to write {}", path.display()))?,
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+fn run_convert(args: &ConvertArgs) -> Result<()> {
+    let files = ModelLoader::new(args.model.clone())
+        .local(args.local)
+        .revision(args.revision.clone())
+        .lora(args.lora.clone())
+        .files()?;
+    let tensors = expected_tensors(&files.load_config()?).len();
+    let bar = ProgressBar::new(tensors as u64).with_style(ProgressStyle::with_template(
+        "{bar:40} {pos}/{len} tensors, {msg} [{elapsed} < {eta}]",
+    )?);
+    let name = args.model.trim_end_matches('/').rsplit('/').next().unwrap_or(&args.model);
+    let conversion = convert(&files, name, &args.output, args.gguf_type, |tensor| {
+        bar.set_message(tensor.to_string());
+        bar.inc(1);
+    })?;
+    bar.finish_and_clear();
+    println!(
+        "Wrote {} tensors ({}) to {} ({:.1} MiB)",
+        conversion.tensors,
+        args.gguf_type.name(),
+        args.output.display(),
+        conversion.size as f64 / (1 << 20) as f64
+    );
+    if !conversion.f16_fallback.is_empty() {
+        println!(
+            "Stored as f16, their rows don't divide into {} blocks: {}",
+            args.gguf_type.name(),
+            summarize_names(&conversion.f16_fallback).join(", ")
+        );
+    }
+    if conversion.dropped_rope_scaling {
+        tracing::warn!(
+            "The config's rope_scaling isn't carried over; the GGUF model runs \
+             with its original context length"
+        );
+    }
+    println!(
+        "Run it with: base-inf -m {}{} --quantized {}",
+        args.model,
+        if args.local { " --local" } else { "" },
+        args.output.display()
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+fn run_daemon(args: &DaemonArgs) -> Result<()> {
+    let socket = args.socket.clone().unwrap_or_else(daemon::default_socket);
+    // Bound first so that a second daemon fails before loading its model.
+    let listener = daemon::bind(&socket)?;
+    let device = device(args.cpu)?;
+    let loader = ModelLoader::new(args.model.clone())
+        .local(args.local)
+        .revision(args.revision.clone())
+        .quantized(args.quantized.clone());
+    tracing::info!("Loading model");
+    let model = loader.load(parse_dtype(&args.dtype)?, &device)?;
+    tracing::info!("Listening on {}", socket.display());
+    daemon::serve(&model, &listener)
+}
+
+#[cfg(not(unix))]
+fn run_daemon(_args: &DaemonArgs) -> Result<()> {
+    bail!("sl5 daemon needs Unix sockets, which this platform doesn't have")
+}
+
+#[cfg(unix)]
+fn run_client(args: &RunArgs) -> Result<()> {
+    let socket = args.socket.clone().unwrap_or_else(daemon::default_socket);
+    let request = daemon::Request {
+        prompt: args.prompt.clone(),
+        max_new_tokens: args.max_new_tokens,
+        temperature: args.temperature,
+        top_p: args.top_p,
+        top_k: args.top_k,
+        seed: args.seed,
+        repeat_penalty: args.repeat_penalty,
+        stop: args.stop.clone(),
+        context_overflow: args.context_overflow,
+    };
+    request.generation_config().validate()?;
+    let mut stdout = std::io::stdout();
+    daemon::request(&socket, &request, |event| {
+        match event {
+            daemon::Event::Token { text } => {
+                write!(stdout, "{}", text)?;
+                stdout.flush()?;
+            }
+            daemon::Event::Done {
+                finish_reason,
+                prompt_tokens,
+                generated_tokens,
+                time_to_first_token_ms,
+                generation_ms,
+            } => {
+                println!();
+                tracing::info!(
+                    "Prompt: {} tokens, generated: {} tokens ({}), time to first token: {:.0}ms, {:.2} tokens/s",
+                    prompt_tokens,
+                    generated_tokens,
+                    finish_reason,
+                    time_to_first_token_ms,
+                    *generated_tokens as f64 / (generation_ms / 1000.)
+                );
+            }
+            daemon::Event::Error { .. } => {}
+        }
+        Ok(())
+    })
+}
+
+#[cfg(not(unix))]
+fn run_client(_args: &RunArgs) -> Result<()> {
+    bail!("sl5 run needs Unix sockets, which this platform doesn't have")
+}
+
+fn run_tts(args: &TtsArgs) -> Result<()> {
+    if !args.temperature.is_finite() || args.temperature < 0. {
+        bail!("--temperature must be 0 or positive, got {}", args.temperature);
+    }
+    let text = match &args.text {
+        Some(text) => text.clone(),
+        None => std::io::read_to_string(std::io::stdin())?,
+    };
+    let config = TtsConfig {
+        description: args.description.clone().unwrap_or(DEFAULT_DESCRIPTION.to_string()),
+        temperature: args.temperature,
+        top_p: args.top_p,
+        seed: args.seed,
+        max_steps: args.max_steps,
+    };
+    let device = device(args.cpu)?;
+    let loader = ModelLoader::new(args.model.clone())
+        .local(args.local)
+        .revision(args.revision.clone());
+    let mut tts = Tts::load(&loader, parse_dtype(&args.dtype)?, &device)?;
+
+    println!("Synthesizing...");
+    let start = std::time::Instant::now();
+    let samples = tts.synthesize(&text, &config)?;
+    let elapsed = start.elapsed();
+    write_wav(&args.output, &samples, tts.sample_rate)?;
+
+    let seconds = samples.len() as f64 / tts.sample_rate as f64;
+    println!("Wrote {:.2}s of audio to {}", seconds, args.output.display());
+    println!("Time: {:.2?} ({:.2}x real time)", elapsed, seconds / elapsed.as_secs_f64());
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let _log_guard = logging::init(cli.log_level, cli.log_format, cli.timeline_file.as_deref())?;
+    match &cli.command {
+        Command::Bundle {
+            command: BundleCommand::Create(args),
+        } => run_bundle_create(args),
+        Command::Bundle {
+            command: BundleCommand::Run(args),
+        } => run_bundle_run(args),
+        Command::Convert(args) => run_convert(args),
+        Command::Daemon(args) => run_daemon(args),
+        Command::DetectWatermark(args) => run_detect_watermark(args),
+        Command::Eval {
+            command: EvalCommand::Ppl(args),
+        } => run_ppl(args),
+        Command::Eval {
+            command: EvalCommand::QuantDiff(args),
+        } => run_quant_diff(args),
+        Command::Export {
+            command: ExportCommand::Embeddings(args),
+        } => run_export_embeddings(args),
+        Command::Extract(args) => run_extract(args),
+        Command::Pick(args) => run_pick(args),
+        Command::Run(args) => run_client(args),
+        Command::Template {
+            command: TemplateCommand::Preview(args),
+        } => run_template_preview(args),
+        Command::Tokenize(args) => run_tokenize(args),
+        Command::Detokenize(args) => run_detokenize(args),
+        Command::Tts(args) => run_tts(args),
+    }
+}
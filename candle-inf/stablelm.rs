@@ -0,0 +1,442 @@
+// StableLM model (StableLM-3B-4E1T, StableLM 2 1.6B and 12B, Zephyr).
+//
+// Adapted from candle_transformers::models::stable_lm, whose model isn't
+// `Clone`: stateful models give every sequence its own clone of the model
+// and its KV cache (see arch.rs). This copy also covers StableLM 2 12B, which
+// runs attention and the MLP in parallel on the same normalized input
+// (`use_parallel_residual`) and normalizes queries and keys per head
+// (`qk_layernorm`), and builds the rotary tables in f32 so positions stay
+// exact in bf16.
+//
+// StableLM uses LayerNorm with biases instead of RMSNorm, rotates only the
+// first `partial_rotary_factor` of each head and, in StableLM 2, has biases
+// on the query/key/value projections (`use_qkv_bias`).
+
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::{
+    embedding, layer_norm, linear_b, linear_no_bias, Activation, Embedding, LayerNorm, Linear,
+    VarBuilder,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// The config.json fields StableLM uses, with the names of the older
+/// `stablelm_epoch` configs as aliases.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub intermediate_size: usize,
+    pub hidden_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    #[serde(default)]
+    pub num_key_value_heads: Option<usize>,
+    #[serde(default = "default_hidden_act")]
+    pub hidden_act: Activation,
+    #[serde(default = "default_partial_rotary_factor", alias = "rope_pct")]
+    pub partial_rotary_factor: f64,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f64,
+    #[serde(default = "default_max_position_embeddings")]
+    pub max_position_embeddings: usize,
+    #[serde(default = "default_layer_norm_eps", alias = "norm_eps")]
+    pub layer_norm_eps: f64,
+    #[serde(default)]
+    pub use_qkv_bias: bool,
+    #[serde(default)]
+    pub qk_layernorm: bool,
+    #[serde(default)]
+    pub use_parallel_residual: bool,
+    #[serde(default)]
+    pub tie_word_embeddings: bool,
+    #[serde(default)]
+    pub use_flash_attn: bool,
+}
+
+fn default_hidden_act() -> Activation {
+    Activation::Silu
+}
+
+fn default_partial_rotary_factor() -> f64 {
+    0.25
+}
+
+fn default_rope_theta() -> f64 {
+    10_000.
+}
+
+fn default_max_position_embeddings() -> usize {
+    4096
+}
+
+fn default_layer_norm_eps() -> f64 {
+    1e-5
+}
+
+impl Config {
+    fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+
+    fn num_kv_heads(&self) -> usize {
+        self.num_key_value_heads.unwrap_or(self.num_attention_heads)
+    }
+
+    fn rotary_ndims(&self) -> usize {
+        (self.head_dim() as f64 * self.partial_rotary_factor) as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+fn rotate_half(xs: &Tensor) -> Result<Tensor> {
+    let xs = xs.chunk(2, D::Minus1)?;
+    Tensor::cat(&[&xs[1].neg()?, &xs[0]], D::Minus1)
+}
+
+impl RotaryEmbedding {
+    fn new(dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
+        let dim = cfg.rotary_ndims();
+        let max_seq_len = cfg.max_position_embeddings;
+        let inv_freq: Vec<_> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / cfg.rope_theta.powf(i as f64 / dim as f64) as f32)
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((max_seq_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        let freqs = Tensor::cat(&[&freqs, &freqs], D::Minus1)?;
+        Ok(Self {
+            sin: freqs.sin()?.to_dtype(dtype)?,
+            cos: freqs.cos()?.to_dtype(dtype)?,
+        })
+    }
+
+    fn apply(&self, q: &Tensor, k: &Tensor, seqlen_offset: usize) -> Result<(Tensor, Tensor)> {
+        let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
+        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
+        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
+        let cos = cos.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
+        let sin = sin.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
+        let q_embed = (q.broadcast_mul(&cos)? + rotate_half(q)?.broadcast_mul(&sin))?;
+        let k_embed = (k.broadcast_mul(&cos)? + rotate_half(k)?.broadcast_mul(&sin))?;
+        Ok((q_embed, k_embed))
+    }
+}
+
+/// LayerNorm without bias over each head's dimensions, with its own weights
+/// for every head (StableLM 2 12B's `qk_layernorm`).
+#[derive(Debug, Clone)]
+struct HeadNorm {
+    /// (1, heads, 1, head_dim)
+    weight: Tensor,
+    eps: f64,
+}
+
+impl HeadNorm {
+    fn new(heads: usize, head_dim: usize, eps: f64, vb: VarBuilder) -> Result<Self> {
+        let weights = (0..heads)
+            .map(|i| vb.pp("norms").pp(i).get(head_dim, "weight"))
+            .collect::<Result<Vec<_>>>()?;
+        let weight = Tensor::stack(&weights, 0)?.reshape((1, heads, 1, head_dim))?;
+        Ok(Self { weight, eps })
+    }
+
+    /// `xs` is (batch, heads, seq_len, head_dim).
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let dtype = xs.dtype();
+        let xs = xs.to_dtype(DType::F32)?;
+        let xs = xs.broadcast_sub(&xs.mean_keepdim(D::Minus1)?)?;
+        let var = xs.sqr()?.mean_keepdim(D::Minus1)?;
+        let xs = xs.broadcast_div(&(var + self.eps)?.sqrt()?)?;
+        xs.to_dtype(dtype)?.broadcast_mul(&self.weight)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    act_fn: Activation,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let (hidden, intermediate) = (cfg.hidden_size, cfg.intermediate_size);
+        Ok(Self {
+            gate_proj: linear_no_bias(hidden, intermediate, vb.pp("gate_proj"))?,
+            up_proj: linear_no_bias(hidden, intermediate, vb.pp("up_proj"))?,
+            down_proj: linear_no_bias(intermediate, hidden, vb.pp("down_proj"))?,
+            act_fn: cfg.hidden_act,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let lhs = xs.apply(&self.gate_proj)?.apply(&self.act_fn)?;
+        let rhs = xs.apply(&self.up_proj)?;
+        (lhs * rhs)?.apply(&self.down_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    q_norm: Option<HeadNorm>,
+    k_norm: Option<HeadNorm>,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    hidden_size: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    kv_cache: Option<(Tensor, Tensor)>,
+    rotary_ndims: usize,
+    use_flash_attn: bool,
+}
+
+impl Attention {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let hidden = cfg.hidden_size;
+        let head_dim = cfg.head_dim();
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_kv_heads();
+        let bias = cfg.use_qkv_bias;
+        let (q_norm, k_norm) = if cfg.qk_layernorm {
+            let eps = cfg.layer_norm_eps;
+            (
+                Some(HeadNorm::new(
+                    num_heads,
+                    head_dim,
+                    eps,
+                    vb.pp("q_layernorm"),
+                )?),
+                Some(HeadNorm::new(
+                    num_kv_heads,
+                    head_dim,
+                    eps,
+                    vb.pp("k_layernorm"),
+                )?),
+            )
+        } else {
+            (None, None)
+        };
+        Ok(Self {
+            q_proj: linear_b(hidden, num_heads * head_dim, bias, vb.pp("q_proj"))?,
+            k_proj: linear_b(hidden, num_kv_heads * head_dim, bias, vb.pp("k_proj"))?,
+            v_proj: linear_b(hidden, num_kv_heads * head_dim, bias, vb.pp("v_proj"))?,
+            o_proj: linear_no_bias(num_heads * head_dim, hidden, vb.pp("o_proj"))?,
+            q_norm,
+            k_norm,
+            num_heads,
+            num_kv_heads,
+            head_dim,
+            hidden_size: hidden,
+            rotary_emb,
+            kv_cache: None,
+            rotary_ndims: cfg.rotary_ndims(),
+            use_flash_attn: cfg.use_flash_attn,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+        let heads = |xs: Tensor, n: usize| -> Result<Tensor> {
+            xs.reshape((b_sz, q_len, n, self.head_dim))?.transpose(1, 2)
+        };
+        let mut q = heads(self.q_proj.forward(xs)?, self.num_heads)?;
+        let mut k = heads(self.k_proj.forward(xs)?, self.num_kv_heads)?;
+        let v = heads(self.v_proj.forward(xs)?, self.num_kv_heads)?;
+        if let (Some(q_norm), Some(k_norm)) = (&self.q_norm, &self.k_norm) {
+            q = q_norm.forward(&q)?;
+            k = k_norm.forward(&k)?;
+        }
+
+        // Only the first `rotary_ndims` of each head are rotated.
+        let (rot, pass) = (self.rotary_ndims, self.head_dim - self.rotary_ndims);
+        let (q_rot, k_rot) = self.rotary_emb.apply(
+            &q.narrow(D::Minus1, 0, rot)?,
+            &k.narrow(D::Minus1, 0, rot)?,
+            seqlen_offset,
+        )?;
+        let q = Tensor::cat(&[q_rot, q.narrow(D::Minus1, rot, pass)?], D::Minus1)?.contiguous()?;
+        let k = Tensor::cat(&[k_rot, k.narrow(D::Minus1, rot, pass)?], D::Minus1)?.contiguous()?;
+
+        let (k, v) = match &self.kv_cache {
+            None => (k, v),
+            Some((prev_k, prev_v)) => (
+                Tensor::cat(&[prev_k, &k], 2)?,
+                Tensor::cat(&[prev_v, &v], 2)?,
+            ),
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+
+        let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
+        let y = if self.use_flash_attn {
+            // flash-attn takes (batch, seq_len, heads, head_dim) and shares
+            // key/value heads between query heads itself.
+            crate::llama::flash_attn(
+                &q.transpose(1, 2)?,
+                &k.transpose(1, 2)?,
+                &v.transpose(1, 2)?,
+                softmax_scale,
+                None,
+                q_len > 1,
+            )?
+            .transpose(1, 2)?
+        } else {
+            let groups = self.num_heads / self.num_kv_heads;
+            let k = candle_transformers::utils::repeat_kv(k, groups)?.contiguous()?;
+            let v = candle_transformers::utils::repeat_kv(v, groups)?.contiguous()?;
+            let in_dtype = q.dtype();
+            let att = (q
+                .to_dtype(DType::F32)?
+                .matmul(&k.to_dtype(DType::F32)?.t()?)?
+                * softmax_scale as f64)?;
+            let att = match attention_mask {
+                None => att,
+                Some(mask) => att.broadcast_add(mask)?,
+            };
+            candle_nn::ops::softmax_last_dim(&att)?
+                .to_dtype(in_dtype)?
+                .matmul(&v)?
+        };
+        y.transpose(1, 2)?
+            .reshape((b_sz, q_len, self.hidden_size))?
+            .apply(&self.o_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: Mlp,
+    input_layernorm: LayerNorm,
+    /// `None` with a parallel residual, where the MLP reads the input norm.
+    post_attention_layernorm: Option<LayerNorm>,
+}
+
+impl DecoderLayer {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let (hidden, eps) = (cfg.hidden_size, cfg.layer_norm_eps);
+        let post_attention_layernorm = if cfg.use_parallel_residual {
+            None
+        } else {
+            Some(layer_norm(hidden, eps, vb.pp("post_attention_layernorm"))?)
+        };
+        Ok(Self {
+            self_attn: Attention::new(rotary_emb, cfg, vb.pp("self_attn"))?,
+            mlp: Mlp::new(cfg, vb.pp("mlp"))?,
+            input_layernorm: layer_norm(hidden, eps, vb.pp("input_layernorm"))?,
+            post_attention_layernorm,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let normed = self.input_layernorm.forward(xs)?;
+        let attn = self
+            .self_attn
+            .forward(&normed, attention_mask, seqlen_offset)?;
+        match &self.post_attention_layernorm {
+            // x + attn(ln(x)) + mlp(ln(x))
+            None => (xs + attn)? + self.mlp.forward(&normed)?,
+            Some(norm) => {
+                let xs = (xs + attn)?;
+                let mlp = xs.apply(norm)?.apply(&self.mlp)?;
+                xs + mlp
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Model {
+    embed_tokens: Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: LayerNorm,
+    lm_head: Linear,
+    device: Device,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb_m = vb.pp("model");
+        let embed_tokens = embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let rotary_emb = Arc::new(RotaryEmbedding::new(vb.dtype(), cfg, vb_m.device())?);
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| DecoderLayer::new(rotary_emb.clone(), cfg, vb_m.pp("layers").pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        let norm = layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb_m.pp("norm"))?;
+        let lm_head = if cfg.tie_word_embeddings {
+            Linear::new(embed_tokens.embeddings().clone(), None)
+        } else {
+            linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?
+        };
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            device: vb.device().clone(),
+        })
+    }
+
+    /// The causal mask for `tgt_len` new positions after `seqlen_offset`
+    /// cached ones.
+    fn mask(&self, tgt_len: usize, seqlen_offset: usize) -> Result<Tensor> {
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| {
+                (0..tgt_len + seqlen_offset).map(move |j| {
+                    if j > i + seqlen_offset {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect();
+        Tensor::from_slice(
+            &mask,
+            (1, 1, tgt_len, tgt_len + seqlen_offset),
+            &self.device,
+        )
+    }
+
+    /// Logits for the last position of `input_ids`.
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (_b_size, seq_len) = input_ids.dims2()?;
+        let mask = if seq_len <= 1 {
+            None
+        } else {
+            Some(self.mask(seq_len, seqlen_offset)?)
+        };
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, mask.as_ref(), seqlen_offset)?
+        }
+        xs.narrow(1, seq_len - 1, 1)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)
+    }
+}
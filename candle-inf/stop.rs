@@ -0,0 +1,127 @@
+// Stop-sequence matching over streamed text.
+//
+// Generated text arrives a token at a time, and a stop sequence can span
+// several tokens. Text that could still turn out to be the start of a stop
+// sequence is held back until the next token either completes the match
+// (the held text is dropped) or rules it out (the held text is released), so
+// a front-end never prints part of a stop string.
+
+pub struct StopMatcher {
+    stops: Vec<String>,
+    pending: String,
+}
+
+impl StopMatcher {
+    pub fn new(stops: Vec<String>) -> Self {
+        Self {
+            stops: stops.into_iter().filter(|stop| !stop.is_empty()).collect(),
+            pending: String::new(),
+        }
+    }
+
+    /// Feed newly generated text. Returns the text that is now safe to show,
+    /// plus the stop sequence if one was completed; output ends right before
+    /// it and nothing should be generated after.
+    pub fn push(&mut self, text: &str) -> (String, Option<&str>) {
+        self.pending.push_str(text);
+
+        let earliest = self
+            .stops
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()).map(|at| (at, stop)))
+            .min_by_key(|&(at, _)| at);
+        if let Some((at, stop)) = earliest {
+            let mut visible = std::mem::take(&mut self.pending);
+            visible.truncate(at);
+            return (visible, Some(stop.as_str()));
+        }
+
+        let held = self
+            .stops
+            .iter()
+            .map(|stop| partial_match_len(&self.pending, stop))
+            .max()
+            .unwrap_or(0);
+        let rest = self.pending.split_off(self.pending.len() - held);
+        (std::mem::replace(&mut self.pending, rest), None)
+    }
+
+    /// Release any held-back text once generation has ended for another
+    /// reason.
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Length of the longest suffix of `text` that is a proper prefix of `stop`.
+fn partial_match_len(text: &str, stop: &str) -> usize {
+    (1..stop.len().min(text.len() + 1))
+        .rev()
+        .find(|&len| {
+            stop.is_char_boundary(len)
+                && text.is_char_boundary(text.len() - len)
+                && text.ends_with(&stop[..len])
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(stops: &[&str]) -> StopMatcher {
+        StopMatcher::new(stops.iter().map(|stop| stop.to_string()).collect())
+    }
+
+    #[test]
+    fn matches_a_stop_split_across_tokens() {
+        let mut stop = matcher(&["###"]);
+        assert_eq!(stop.push("Hello #"), ("Hello ".to_string(), None));
+        assert_eq!(stop.push("#"), (String::new(), None));
+        assert_eq!(stop.push("# more"), (String::new(), Some("###")));
+    }
+
+    #[test]
+    fn releases_held_text_when_the_match_breaks() {
+        let mut stop = matcher(&["###"]);
+        assert_eq!(stop.push("a##"), ("a".to_string(), None));
+        assert_eq!(stop.push("b"), ("##b".to_string(), None));
+    }
+
+    #[test]
+    fn keeps_holding_a_shorter_partial_match() {
+        // "abab" rules out the first "ab" but the second may still start
+        // "abc".
+        let mut stop = matcher(&["abc"]);
+        assert_eq!(stop.push("ab"), (String::new(), None));
+        assert_eq!(stop.push("ab"), ("ab".to_string(), None));
+        assert_eq!(stop.push("c"), (String::new(), Some("abc")));
+    }
+
+    #[test]
+    fn flush_releases_held_text() {
+        let mut stop = matcher(&["</s>"]);
+        assert_eq!(stop.push("the end</"), ("the end".to_string(), None));
+        assert_eq!(stop.flush(), "</");
+        assert_eq!(stop.flush(), "");
+    }
+
+    #[test]
+    fn earliest_stop_wins() {
+        let mut stop = matcher(&["world", "lo"]);
+        assert_eq!(stop.push("hello world"), ("hel".to_string(), Some("lo")));
+    }
+
+    #[test]
+    fn holds_whole_characters() {
+        let mut stop = matcher(&["é!"]);
+        assert_eq!(stop.push("café"), ("caf".to_string(), None));
+        assert_eq!(stop.push("?"), ("é?".to_string(), None));
+    }
+
+    #[test]
+    fn ignores_empty_stops() {
+        let mut stop = matcher(&[""]);
+        assert_eq!(stop.push("text"), ("text".to_string(), None));
+    }
+}
@@ -0,0 +1,54 @@
+// Writing named tensors (hidden states, attention maps, embeddings) to disk.
+
+use anyhow::{bail, Result};
+use candle_core::{DType, Tensor};
+use std::io::Write;
+use std::path::Path;
+
+/// Like `Tensor::write_npz` but deflate-compressed, which matters for the
+/// mostly-near-zero attention maps.
+pub fn write_npz(tensors: &[(String, Tensor)], path: &Path) -> Result<()> {
+    let mut zip = zip::ZipWriter::new(std::fs::File::create(path)?);
+    let options = zip::write::FileOptions::<()>::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+    for (name, tensor) in tensors {
+        let values = tensor.flatten_all()?;
+        let (descr, data): (&str, Vec<u8>) = match tensor.dtype() {
+            DType::F32 => (
+                "<f4",
+                values.to_vec1::<f32>()?.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ),
+            DType::U32 => (
+                "<u4",
+                values.to_vec1::<u32>()?.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ),
+            dtype => bail!("Unsupported dtype {:?} for npz output", dtype),
+        };
+        let shape: String = tensor.dims().iter().map(|d| format!("{d},")).collect();
+        let mut header =
+            format!("{{'descr': '{descr}', 'fortran_order': False, 'shape': ({shape}), }}");
+        // Magic + version + header length + header must be a multiple of 64 bytes.
+        let unpadded = 10 + header.len() + 1;
+        header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+        header.push('\n');
+        zip.start_file(format!("{name}.npy"), options)?;
+        zip.write_all(b"\x93NUMPY\x01\x00")?;
+        zip.write_all(&(header.len() as u16).to_le_bytes())?;
+        zip.write_all(header.as_bytes())?;
+        zip.write_all(&data)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Write named tensors as .npz or .safetensors depending on the file extension.
+pub fn save_tensors(tensors: Vec<(String, Tensor)>, path: &Path) -> Result<()> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("npz") => write_npz(&tensors, path)?,
+        Some("safetensors") => {
+            candle_core::safetensors::save(&tensors.into_iter().collect(), path)?
+        }
+        _ => bail!("Unsupported output format for {} (use .npz or .safetensors)", path.display()),
+    }
+    Ok(())
+}
@@ -0,0 +1,71 @@
+// On-disk cache of tokenized text.
+//
+// Tokenizing a multi-GB evaluation corpus can take longer than the evaluation
+// itself, and the result only changes when the text or the tokenizer does.
+// Token ids are stored under `$HF_HOME/tokens/<tokenizer hash>/<text hash>`
+// as little-endian u32s, so a repeated run reads them back instead.
+
+use crate::tokenize::encode_prompt;
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokenizers::Tokenizer;
+
+/// Bytes per parallel tokenization chunk on a cache miss.
+const CHUNK_SIZE: usize = 16384;
+
+pub struct TokenCache {
+    /// Directory for this tokenizer's entries.
+    dir: PathBuf,
+    refresh: bool,
+}
+
+impl TokenCache {
+    /// A cache for the tokenizer loaded from `tokenizer_path` (its
+    /// tokenizer.json), in the default cache directory.
+    pub fn new(tokenizer_path: &Path) -> Result<Self> {
+        let tokenizer_hash = hex(&Sha256::digest(std::fs::read(tokenizer_path)?));
+        Ok(Self {
+            dir: crate::hub::cache_dir("tokens").join(&tokenizer_hash[..16]),
+            refresh: false,
+        })
+    }
+
+    /// Re-tokenize and overwrite entries instead of reading them.
+    pub fn refresh(mut self, refresh: bool) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    /// The token ids of `text` (with the tokenizer's special tokens), and
+    /// whether they came from the cache.
+    pub fn encode(&self, tokenizer: &Tokenizer, text: &str) -> Result<(Vec<u32>, bool)> {
+        let path = self.dir.join(hex(&Sha256::digest(text.as_bytes())));
+        if !self.refresh {
+            if let Ok(bytes) = std::fs::read(&path) {
+                if bytes.len() % 4 != 0 {
+                    bail!("Corrupt token cache entry {} (use --retokenize)", path.display());
+                }
+                let ids = bytes
+                    .chunks_exact(4)
+                    .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect();
+                return Ok((ids, true));
+            }
+        }
+
+        let (ids, _) = encode_prompt(tokenizer, text, CHUNK_SIZE)?;
+        let bytes: Vec<u8> = ids.iter().flat_map(|id| id.to_le_bytes()).collect();
+        // Write to a temporary name first so an interrupted run can't leave a
+        // truncated entry behind.
+        std::fs::create_dir_all(&self.dir)?;
+        let partial = path.with_extension("part");
+        std::fs::write(&partial, bytes)?;
+        std::fs::rename(&partial, &path)?;
+        Ok((ids, false))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
@@ -0,0 +1,203 @@
+// Tokenization helpers.
+//
+// `tokenize`/`detokenize` let callers count tokens and build prompts against
+// the model's own tokenizer. Very long prompts are split at line boundaries
+// and encoded in parallel with the tokenizer's batch encoder, then merged back
+// so the ids match a single pass. `DecodeStream` does the reverse for
+// generated tokens, one at a time.
+
+use anyhow::Result;
+use serde::Serialize;
+use tokenizers::{Encoding, Tokenizer};
+
+/// Text split into the model's tokens.
+#[derive(Debug, Clone, Serialize)]
+pub struct Tokenized {
+    pub ids: Vec<u32>,
+    /// Vocabulary entry of each token.
+    pub tokens: Vec<String>,
+    /// Byte range of the input each token came from.
+    pub offsets: Vec<(usize, usize)>,
+}
+
+/// Tokenize `text`, optionally with the special tokens (e.g. BOS) the model
+/// expects around a prompt.
+pub fn tokenize(tokenizer: &Tokenizer, text: &str, add_special_tokens: bool) -> Result<Tokenized> {
+    let encoding = tokenizer
+        .encode(text, add_special_tokens)
+        .map_err(|e| anyhow::anyhow!("Failed to encode text: {}", e))?;
+    Ok(Tokenized {
+        ids: encoding.get_ids().to_vec(),
+        tokens: encoding.get_tokens().to_vec(),
+        offsets: encoding.get_offsets().to_vec(),
+    })
+}
+
+/// Turn token ids back into text.
+pub fn detokenize(tokenizer: &Tokenizer, ids: &[u32], skip_special_tokens: bool) -> Result<String> {
+    tokenizer
+        .decode(ids, skip_special_tokens)
+        .map_err(|e| anyhow::anyhow!("Failed to decode tokens: {}", e))
+}
+
+/// Turns generated tokens into text as they arrive. Decoding each token on its
+/// own drops the word-boundary space of SentencePiece tokenizers and garbles
+/// characters whose bytes span several tokens, so instead the recent tokens are
+/// decoded together and only what the newest ones add is returned, once it no
+/// longer ends in an incomplete character.
+pub struct DecodeStream {
+    ids: Vec<u32>,
+    /// Tokens decoded as context before the unread ones.
+    prefix_offset: usize,
+    /// Tokens before this have been returned as text.
+    read_offset: usize,
+}
+
+impl DecodeStream {
+    /// Start after `context`, the tokens that precede the stream (typically
+    /// the prompt); the last few are used so the first piece gets its leading
+    /// space right.
+    pub fn new(context: &[u32]) -> Self {
+        let context = &context[context.len().saturating_sub(4)..];
+        Self {
+            ids: context.to_vec(),
+            prefix_offset: 0,
+            read_offset: context.len(),
+        }
+    }
+
+    /// Add a token and return the text that is now complete (often empty
+    /// while a multi-byte character is still being assembled).
+    pub fn step(&mut self, tokenizer: &Tokenizer, id: u32) -> Result<String> {
+        self.ids.push(id);
+        let text = self.pending_text(tokenizer)?;
+        if text.is_empty() || text.ends_with('\u{FFFD}') {
+            return Ok(String::new());
+        }
+        self.ids.drain(..self.read_offset);
+        self.prefix_offset = 0;
+        self.read_offset = self.ids.len();
+        Ok(text)
+    }
+
+    /// Text of any tokens still held back, with incomplete characters
+    /// replaced by U+FFFD.
+    pub fn flush(&mut self, tokenizer: &Tokenizer) -> Result<String> {
+        let text = self.pending_text(tokenizer)?;
+        self.prefix_offset = self.ids.len();
+        self.read_offset = self.ids.len();
+        Ok(text)
+    }
+
+    /// What the unread tokens add to the decoded context.
+    fn pending_text(&self, tokenizer: &Tokenizer) -> Result<String> {
+        let prefix = detokenize(tokenizer, &self.ids[self.prefix_offset..self.read_offset], true)?;
+        let text = detokenize(tokenizer, &self.ids[self.prefix_offset..], true)?;
+        Ok(match text.strip_prefix(prefix.as_str()) {
+            Some(new) => new.to_string(),
+            // Decoding together changed the context's text (e.g. it ended in
+            // a partial character); everything past its length is new.
+            None => text.get(prefix.len()..).unwrap_or_default().to_string(),
+        })
+    }
+}
+
+/// A slice of the prompt to be tokenized independently. `context_start..start`
+/// is the preceding line, encoded along with the chunk so that tokenizers which
+/// add a word-boundary prefix see the same context as in a single pass.
+struct PromptChunk {
+    context_start: usize,
+    start: usize,
+    end: usize,
+}
+
+fn split_prompt(prompt: &str, chunk_size: usize) -> Vec<PromptChunk> {
+    let bytes = prompt.as_bytes();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < bytes.len() {
+        // Only cut right after a newline that is followed by non-whitespace, so
+        // no token can straddle the boundary.
+        let end = (start + chunk_size.max(1)..bytes.len())
+            .find(|&p| bytes[p - 1] == b'\n' && !bytes[p].is_ascii_whitespace())
+            .unwrap_or(bytes.len());
+        let context_start = if start == 0 {
+            0
+        } else {
+            prompt[..start - 1].rfind('\n').map_or(0, |i| i + 1)
+        };
+        chunks.push(PromptChunk {
+            context_start,
+            start,
+            end,
+        });
+        start = end;
+    }
+    chunks
+}
+
+/// Drop the first `from` tokens of an encoding and shift its offsets by `shift`.
+fn slice_encoding(encoding: &Encoding, from: usize, shift: usize) -> Encoding {
+    Encoding::new(
+        encoding.get_ids()[from..].to_vec(),
+        encoding.get_type_ids()[from..].to_vec(),
+        encoding.get_tokens()[from..].to_vec(),
+        encoding.get_word_ids()[from..].to_vec(),
+        encoding.get_offsets()[from..]
+            .iter()
+            .map(|&(s, e)| (s + shift, e + shift))
+            .collect(),
+        encoding.get_special_tokens_mask()[from..].to_vec(),
+        encoding.get_attention_mask()[from..].to_vec(),
+        vec![],
+        Default::default(),
+    )
+}
+
+/// Tokenize the prompt, using the tokenizer's parallel batch encoder for long
+/// inputs. Returns the token ids and the number of chunks used.
+pub fn encode_prompt(
+    tokenizer: &Tokenizer,
+    prompt: &str,
+    chunk_size: usize,
+) -> Result<(Vec<u32>, usize)> {
+    let chunks = if chunk_size == 0 {
+        Vec::new()
+    } else {
+        split_prompt(prompt, chunk_size)
+    };
+    if chunks.len() <= 1 {
+        let encoding = tokenizer
+            .encode(prompt, true)
+            .map_err(|e| anyhow::anyhow!("Failed to encode prompt: {}", e))?;
+        return Ok((encoding.get_ids().to_vec(), 1));
+    }
+
+    // Padding/truncation apply to the whole prompt, not to each chunk.
+    let mut batch_tokenizer = tokenizer.clone();
+    batch_tokenizer.with_padding(None);
+    batch_tokenizer
+        .with_truncation(None)
+        .map_err(|e| anyhow::anyhow!("Failed to configure tokenizer: {}", e))?;
+    let inputs: Vec<&str> = chunks
+        .iter()
+        .map(|c| &prompt[c.context_start..c.end])
+        .collect();
+    let encodings = batch_tokenizer
+        .encode_batch(inputs, false)
+        .map_err(|e| anyhow::anyhow!("Failed to encode prompt: {}", e))?;
+
+    let parts = chunks.iter().zip(&encodings).map(|(chunk, encoding)| {
+        let context_len = chunk.start - chunk.context_start;
+        let keep_from = encoding
+            .get_offsets()
+            .iter()
+            .position(|&(start, _)| start >= context_len)
+            .unwrap_or(encoding.len());
+        slice_encoding(encoding, keep_from, chunk.context_start)
+    });
+    let encoding = tokenizer
+        .post_process(Encoding::merge(parts, false), None, true)
+        .map_err(|e| anyhow::anyhow!("Failed to encode prompt: {}", e))?;
+    Ok((encoding.get_ids().to_vec(), chunks.len()))
+}
@@ -0,0 +1,144 @@
+// Text-to-speech with Parler-TTS.
+//
+// Parler-TTS conditions its speech on two texts: the words to say, and a
+// plain-language description of the voice ("A female speaker with a calm,
+// low-pitched voice..."). A T5 encoder reads the description, a decoder
+// generates audio codes for several codebooks at once (each codebook one step
+// behind the previous, as in MusicGen) and the DAC codec turns the codes into
+// a waveform. The model repo is fetched with the same `ModelLoader` as the
+// language models.
+
+use crate::loader::ModelLoader;
+use anyhow::{bail, Context, Result};
+use candle_core::{DType, Device, IndexOp, Tensor};
+use candle_transformers::generation::LogitsProcessor;
+use candle_transformers::models::parler_tts;
+use std::io::Write;
+use std::path::Path;
+use tokenizers::Tokenizer;
+
+/// Used when no voice description is given.
+pub const DEFAULT_DESCRIPTION: &str = "A female speaker delivers a slightly expressive and \
+    animated speech with a moderate speed and pitch. The recording is of very high quality, \
+    with the speaker's voice sounding clear and very close up.";
+
+/// Sampling settings for `Tts::synthesize`.
+#[derive(Debug, Clone)]
+pub struct TtsConfig {
+    /// How the voice should sound.
+    pub description: String,
+    /// Sampling temperature; 0 samples greedily, which tends to loop.
+    pub temperature: f64,
+    pub top_p: Option<f64>,
+    pub seed: u64,
+    /// Maximum number of audio frames to generate.
+    pub max_steps: usize,
+}
+
+impl Default for TtsConfig {
+    fn default() -> Self {
+        Self {
+            description: DEFAULT_DESCRIPTION.to_string(),
+            temperature: 1.0,
+            top_p: None,
+            seed: 299792458,
+            max_steps: 512,
+        }
+    }
+}
+
+pub struct Tts {
+    model: parler_tts::Model,
+    tokenizer: Tokenizer,
+    device: Device,
+    /// Samples per second of the generated audio.
+    pub sample_rate: u32,
+    /// Audio frames per second, i.e. per generation step.
+    pub frame_rate: u32,
+}
+
+impl Tts {
+    /// Load a Parler-TTS model (config.json, model.safetensors and
+    /// tokenizer.json).
+    pub fn load(loader: &ModelLoader, dtype: DType, device: &Device) -> Result<Self> {
+        let files = loader.files()?;
+        let Some(config_path) = &files.config else {
+            bail!("Parler-TTS needs safetensors weights and a config.json, not GGUF");
+        };
+        let config: parler_tts::Config = serde_json::from_slice(&std::fs::read(config_path)?)
+            .with_context(|| format!("{} is not a Parler-TTS config", config_path.display()))?;
+        let vb = files.var_builder(dtype, device)?;
+        let model = parler_tts::Model::new(&config, vb)?;
+        Ok(Self {
+            model,
+            tokenizer: files.load_tokenizer()?,
+            device: device.clone(),
+            sample_rate: config.audio_encoder.sampling_rate,
+            frame_rate: config.audio_encoder.frame_rate,
+        })
+    }
+
+    /// Speak `text` in the voice `config.description` describes. Returns
+    /// mono samples in [-1, 1] at `sample_rate`.
+    pub fn synthesize(&mut self, text: &str, config: &TtsConfig) -> Result<Vec<f32>> {
+        if text.trim().is_empty() {
+            bail!("Nothing to say: the text is empty");
+        }
+        let prompt = self.encode(text)?;
+        let description = self.encode(&config.description)?;
+        let sampler = LogitsProcessor::new(config.seed, Some(config.temperature), config.top_p);
+        let codes = self
+            .model
+            .generate(&prompt, &description, sampler, config.max_steps)?;
+        if codes.dim(1)? == 0 {
+            bail!("The model generated no audio");
+        }
+        let codes = codes
+            .to_dtype(DType::I64)?
+            .unsqueeze(0)?
+            .to_device(&self.device)?;
+        let audio = self.model.audio_encoder.decode_codes(&codes)?;
+        let mut samples: Vec<f32> = audio.i((0, 0))?.to_dtype(DType::F32)?.to_vec1()?;
+        // The codec can overshoot; scale down rather than clip.
+        let peak = samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+        if peak > 1. {
+            samples.iter_mut().for_each(|s| *s /= peak);
+        }
+        Ok(samples)
+    }
+
+    fn encode(&self, text: &str) -> Result<Tensor> {
+        let ids = self
+            .tokenizer
+            .encode(text, true)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec();
+        Ok(Tensor::new(ids, &self.device)?.unsqueeze(0)?)
+    }
+}
+
+/// Write mono samples in [-1, 1] as a 16-bit PCM WAV file.
+pub fn write_wav(path: &Path, samples: &[f32], sample_rate: u32) -> Result<()> {
+    let data_len = (samples.len() * 2) as u32;
+    let mut wav = Vec::with_capacity(44 + samples.len() * 2);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * 2).to_le_bytes()); // bytes per second
+    wav.extend_from_slice(&2u16.to_le_bytes()); // bytes per frame
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        let sample = (sample.clamp(-1., 1.) * i16::MAX as f32) as i16;
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    std::fs::File::create(path)
+        .and_then(|mut file| file.write_all(&wav))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
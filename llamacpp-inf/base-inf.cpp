@@ -3,30 +3,161 @@
 // Or use CMake from llama.cpp directory
 
 #include "llama.h"
+#include <algorithm>
+#include <cmath>
+#include <csignal>
+#include <cstdarg>
 #include <cstdio>
 #include <cstring>
+#include <numeric>
 #include <string>
+#include <utility>
 #include <vector>
 
 static void print_usage(int, char ** argv) {
     printf("\n=== Basic LLM Inference with llama.cpp ===\n");
     printf("\nUsage:\n");
-    printf("    %s -m <model.gguf> [-n tokens] [-ngl gpu_layers] [prompt]\n\n", argv[0]);
+    printf("    %s -m <model.gguf> [-n tokens] [-ngl gpu_layers] [-logprobs N] [-output text|json] [-stream-format text|ndjson]\n", argv[0]);
+    printf("        [-repeat-penalty X] [-repeat-last-n N] [-repeat-scope prompt|generated|all]\n");
+    printf("        [-dry-multiplier X] [-dry-base X] [-dry-allowed-length N] [-system text] [-message role:content ...]\n");
+    printf("        [-context-overflow error|truncate-left|truncate-middle|reduce-max-tokens] [prompt]\n\n");
     printf("Options:\n");
     printf("    -m <path>      Path to GGUF model file (required)\n");
     printf("    -n <number>    Number of tokens to generate (default: 128)\n");
     printf("    -ngl <number>  Number of GPU layers to offload (default: 99)\n");
+    printf("    -logprobs <N>  Print each generated token's log-probability and the N most\n");
+    printf("                   likely alternatives as JSON after the output\n");
+    printf("    -output <fmt>  text (default), or json: print only one JSON object with the\n");
+    printf("                   completion, token counts, timings and sampling settings\n");
+    printf("    -stream-format <fmt>  text (default), or ndjson: one {\"token\", \"id\", \"logprob\"}\n");
+    printf("                   JSON line per generated token, then the -output json object\n");
+    printf("    -repeat-penalty <x>  Penalty for repeating tokens (default: 1.1; 1.0 = off)\n");
+    printf("    -repeat-last-n <n>  Tokens the repeat penalty looks back through (default: 128)\n");
+    printf("    -repeat-scope <scope>  What -repeat-last-n counts back through: prompt,\n");
+    printf("                   generated or all (default: all, prompt and output together)\n");
+    printf("    -dry-multiplier <x>  DRY penalty on tokens that would continue a sequence\n");
+    printf("                   already in the context (default: 0 = off; 0.8 is common)\n");
+    printf("    -dry-base <x>  How fast the DRY penalty grows with the repetition (default: 1.75)\n");
+    printf("    -dry-allowed-length <n>  Repetitions up to this long aren't penalized (default: 2)\n");
+    printf("    -system <text> System message; the prompt becomes the user's turn and both are\n");
+    printf("                   rendered with the model's chat template\n");
+    printf("    -message <role:content>  A conversation turn, e.g. user:Hello (repeatable, in\n");
+    printf("                   order), rendered with the model's chat template instead of the prompt\n");
+    printf("    -context-overflow <policy>  When the prompt and -n tokens don't fit in the model's\n");
+    printf("                   context window: error, truncate-left, truncate-middle or\n");
+    printf("                   reduce-max-tokens (default)\n");
     printf("    [prompt]       Text prompt (default: 'Hello, my name is')\n\n");
     printf("Example:\n");
     printf("    %s -m ./models/llama-2-7b.Q4_K_M.gguf -n 50 \"Tell me a story\"\n\n", argv[0]);
 }
 
+// Set by -output json and -stream-format ndjson, which keep stdout for JSON.
+static bool json_output = false;
+
+// printf for the human-readable banners, output and statistics.
+static void say(const char * fmt, ...) {
+    if (json_output) {
+        return;
+    }
+    va_list args;
+    va_start(args, fmt);
+    vprintf(fmt, args);
+    va_end(args);
+}
+
+// Set by Ctrl-C once generation has started; the loop stops at the next token.
+static volatile std::sig_atomic_t interrupted = 0;
+
+static void on_interrupt(int) {
+    interrupted = 1;
+    // A second Ctrl-C ends the process right away.
+    std::signal(SIGINT, SIG_DFL);
+}
+
+// A generated token's log-probability and the most likely alternatives.
+struct token_logprobs {
+    llama_token id;
+    float logprob;
+    std::vector<std::pair<llama_token, float>> top;
+};
+
+static std::string token_to_string(const llama_vocab * vocab, llama_token id) {
+    char buf[128];
+    int n = llama_token_to_piece(vocab, id, buf, sizeof(buf), 0, true);
+    return n < 0 ? std::string() : std::string(buf, n);
+}
+
+static std::string json_string(const std::string & s) {
+    std::string out = "\"";
+    for (unsigned char c : s) {
+        if (c == '"' || c == '\\') {
+            out += '\\';
+            out += c;
+        } else if (c < 0x20) {
+            char buf[8];
+            snprintf(buf, sizeof(buf), "\\u%04x", c);
+            out += buf;
+        } else {
+            out += c;
+        }
+    }
+    return out + "\"";
+}
+
+static token_logprobs get_logprobs(const float * logits, int n_vocab, llama_token id, int top_n) {
+    const float max_logit = *std::max_element(logits, logits + n_vocab);
+    double sum = 0.0;
+    for (int i = 0; i < n_vocab; i++) {
+        sum += std::exp(logits[i] - max_logit);
+    }
+    const float log_z = max_logit + (float) std::log(sum);
+
+    token_logprobs result = { id, logits[id] - log_z, {} };
+    top_n = std::min(top_n, n_vocab);
+    std::vector<llama_token> order(n_vocab);
+    std::iota(order.begin(), order.end(), 0);
+    std::partial_sort(order.begin(), order.begin() + top_n, order.end(),
+                      [&](llama_token a, llama_token b) { return logits[a] > logits[b]; });
+    for (int i = 0; i < top_n; i++) {
+        result.top.emplace_back(order[i], logits[order[i]] - log_z);
+    }
+    return result;
+}
+
+static std::string logprobs_json(const llama_vocab * vocab, const std::vector<token_logprobs> & tokens) {
+    auto entry = [&](llama_token id, float logprob) {
+        return "\"token\":" + std::to_string(id) + ",\"text\":" + json_string(token_to_string(vocab, id)) +
+               ",\"logprob\":" + std::to_string(logprob);
+    };
+    std::string out = "[";
+    for (size_t i = 0; i < tokens.size(); i++) {
+        out += (i > 0 ? ",{" : "{") + entry(tokens[i].id, tokens[i].logprob) + ",\"top_logprobs\":[";
+        for (size_t j = 0; j < tokens[i].top.size(); j++) {
+            out += (j > 0 ? ",{" : "{") + entry(tokens[i].top[j].first, tokens[i].top[j].second) + "}";
+        }
+        out += "]}";
+    }
+    return out + "]";
+}
+
 int main(int argc, char ** argv) {
     // Default parameters
     std::string model_path;
     std::string prompt = "Hello, my name is";
     int ngl = 99;  // GPU layers to offload
     int n_predict = 128;  // Number of tokens to generate
+    int n_logprobs = -1;  // Alternatives to report with each token's logprob (-1 = off)
+    bool stream_ndjson = false;  // One JSON line per generated token
+    float repeat_penalty = 1.1f;  // The same defaults as the candle script
+    int repeat_last_n = 128;
+    std::string repeat_scope = "all";  // Tokens -repeat-last-n counts back through
+    float dry_multiplier = 0.0f;  // DRY repetition penalty (0 = off)
+    float dry_base = 1.75f;
+    int dry_allowed_length = 2;
+    std::string system_prompt;  // -system, rendered with the chat template
+    std::vector<std::pair<std::string, std::string>> messages;  // -message turns (role, content)
+    std::string context_overflow = "reduce-max-tokens";  // Prompts too long for the context window
+    bool prompt_given = false;
 
     // Parse command line arguments
     {
@@ -65,8 +196,135 @@ int main(int argc, char ** argv) {
                     print_usage(argc, argv);
                     return 1;
                 }
+            } else if (strcmp(argv[i], "-logprobs") == 0) {
+                if (i + 1 < argc) {
+                    try {
+                        n_logprobs = std::stoi(argv[++i]);
+                    } catch (...) {
+                        n_logprobs = -1;
+                    }
+                    if (n_logprobs < 0) {
+                        fprintf(stderr, "Error: Invalid number for -logprobs\n");
+                        print_usage(argc, argv);
+                        return 1;
+                    }
+                } else {
+                    print_usage(argc, argv);
+                    return 1;
+                }
+            } else if (strcmp(argv[i], "-repeat-penalty") == 0) {
+                if (i + 1 < argc) {
+                    try {
+                        repeat_penalty = std::stof(argv[++i]);
+                    } catch (...) {
+                        fprintf(stderr, "Error: Invalid number for -repeat-penalty\n");
+                        print_usage(argc, argv);
+                        return 1;
+                    }
+                } else {
+                    print_usage(argc, argv);
+                    return 1;
+                }
+            } else if (strcmp(argv[i], "-repeat-last-n") == 0) {
+                if (i + 1 < argc) {
+                    try {
+                        repeat_last_n = std::stoi(argv[++i]);
+                    } catch (...) {
+                        repeat_last_n = -1;
+                    }
+                    if (repeat_last_n < 0) {
+                        fprintf(stderr, "Error: Invalid number for -repeat-last-n\n");
+                        print_usage(argc, argv);
+                        return 1;
+                    }
+                } else {
+                    print_usage(argc, argv);
+                    return 1;
+                }
+            } else if (strcmp(argv[i], "-repeat-scope") == 0) {
+                const char * scopes[] = { "prompt", "generated", "all" };
+                const bool valid = i + 1 < argc && std::any_of(std::begin(scopes), std::end(scopes),
+                                                               [&](const char * s) { return strcmp(argv[i + 1], s) == 0; });
+                if (!valid) {
+                    fprintf(stderr, "Error: -repeat-scope must be prompt, generated or all\n");
+                    print_usage(argc, argv);
+                    return 1;
+                }
+                repeat_scope = argv[++i];
+            } else if (strcmp(argv[i], "-dry-multiplier") == 0 || strcmp(argv[i], "-dry-base") == 0) {
+                float & value = strcmp(argv[i], "-dry-base") == 0 ? dry_base : dry_multiplier;
+                const char * flag = argv[i];
+                if (i + 1 < argc) {
+                    try {
+                        value = std::stof(argv[++i]);
+                    } catch (...) {
+                        fprintf(stderr, "Error: Invalid number for %s\n", flag);
+                        print_usage(argc, argv);
+                        return 1;
+                    }
+                } else {
+                    print_usage(argc, argv);
+                    return 1;
+                }
+            } else if (strcmp(argv[i], "-dry-allowed-length") == 0) {
+                if (i + 1 < argc) {
+                    try {
+                        dry_allowed_length = std::stoi(argv[++i]);
+                    } catch (...) {
+                        fprintf(stderr, "Error: Invalid number for -dry-allowed-length\n");
+                        print_usage(argc, argv);
+                        return 1;
+                    }
+                } else {
+                    print_usage(argc, argv);
+                    return 1;
+                }
+            } else if (strcmp(argv[i], "-output") == 0) {
+                if (i + 1 < argc && (strcmp(argv[i + 1], "text") == 0 || strcmp(argv[i + 1], "json") == 0)) {
+                    json_output = strcmp(argv[++i], "json") == 0;
+                } else {
+                    fprintf(stderr, "Error: -output must be text or json\n");
+                    print_usage(argc, argv);
+                    return 1;
+                }
+            } else if (strcmp(argv[i], "-stream-format") == 0) {
+                if (i + 1 < argc && (strcmp(argv[i + 1], "text") == 0 || strcmp(argv[i + 1], "ndjson") == 0)) {
+                    stream_ndjson = strcmp(argv[++i], "ndjson") == 0;
+                } else {
+                    fprintf(stderr, "Error: -stream-format must be text or ndjson\n");
+                    print_usage(argc, argv);
+                    return 1;
+                }
+            } else if (strcmp(argv[i], "-system") == 0) {
+                if (i + 1 < argc) {
+                    system_prompt = argv[++i];
+                } else {
+                    fprintf(stderr, "Error: -system requires a value\n");
+                    print_usage(argc, argv);
+                    return 1;
+                }
+            } else if (strcmp(argv[i], "-message") == 0) {
+                const char * colon = i + 1 < argc ? strchr(argv[i + 1], ':') : NULL;
+                if (colon == NULL || colon == argv[i + 1]) {
+                    fprintf(stderr, "Error: -message must be role:content, e.g. user:Hello\n");
+                    print_usage(argc, argv);
+                    return 1;
+                }
+                i++;
+                messages.emplace_back(std::string(argv[i], colon - argv[i]), std::string(colon + 1));
+            } else if (strcmp(argv[i], "-context-overflow") == 0) {
+                const char * policies[] = { "error", "truncate-left", "truncate-middle", "reduce-max-tokens" };
+                const bool valid = i + 1 < argc && std::any_of(std::begin(policies), std::end(policies),
+                                                               [&](const char * p) { return strcmp(argv[i + 1], p) == 0; });
+                if (!valid) {
+                    fprintf(stderr, "Error: -context-overflow must be error, truncate-left, truncate-middle or reduce-max-tokens\n");
+                    print_usage(argc, argv);
+                    return 1;
+                }
+                context_overflow = argv[++i];
             } else {
                 // Prompt starts here
+                prompt_given = true;
                 prompt = argv[i++];
                 for (; i < argc; i++) {
                     prompt += " ";
@@ -76,6 +334,38 @@ int main(int argc, char ** argv) {
             }
         }
         
+        json_output = json_output || stream_ndjson;
+
+        if (prompt_given && !messages.empty()) {
+            fprintf(stderr, "Error: give either -message turns or a prompt, not both\n");
+            return 1;
+        }
+        // With only -system, the prompt is the user's turn.
+        if (messages.empty() && !system_prompt.empty()) {
+            messages.emplace_back("user", prompt);
+        }
+        if (!system_prompt.empty()) {
+            messages.insert(messages.begin(), { "system", system_prompt });
+        }
+
+        if (!(repeat_penalty > 0.0f) || std::isinf(repeat_penalty)) {
+            fprintf(stderr, "Error: -repeat-penalty must be positive (1.0 disables it)\n");
+            return 1;
+        }
+        if (repeat_penalty != 1.0f && repeat_last_n == 0) {
+            fprintf(stderr, "Error: -repeat-penalty %g has no effect with -repeat-last-n 0\n", repeat_penalty);
+            return 1;
+        }
+
+        if (!(dry_multiplier >= 0.0f)) {
+            fprintf(stderr, "Error: -dry-multiplier must be 0 (off) or positive\n");
+            return 1;
+        }
+        if (dry_multiplier > 0.0f && (!(dry_base > 1.0f) || dry_allowed_length < 1)) {
+            fprintf(stderr, "Error: -dry-base must be greater than 1 and -dry-allowed-length at least 1\n");
+            return 1;
+        }
+
         if (model_path.empty()) {
             fprintf(stderr, "Error: Model path is required!\n");
             print_usage(argc, argv);
@@ -83,11 +373,17 @@ int main(int argc, char ** argv) {
         }
     }
 
-    printf("\n=== LLM Inference Starting ===\n");
-    printf("Model: %s\n", model_path.c_str());
-    printf("Prompt: \"%s\"\n", prompt.c_str());
-    printf("Tokens to generate: %d\n", n_predict);
-    printf("GPU layers: %d\n\n", ngl);
+    const auto t_load_start = ggml_time_us();
+
+    say("\n=== LLM Inference Starting ===\n");
+    say("Model: %s\n", model_path.c_str());
+    if (messages.empty()) {
+        say("Prompt: \"%s\"\n", prompt.c_str());
+    } else {
+        say("Conversation: %zu messages\n", messages.size());
+    }
+    say("Tokens to generate: %d\n", n_predict);
+    say("GPU layers: %d\n\n", ngl);
 
     // Load dynamic backends
     ggml_backend_load_all();
@@ -96,21 +392,48 @@ int main(int argc, char ** argv) {
     llama_model_params model_params = llama_model_default_params();
     model_params.n_gpu_layers = ngl;
 
-    printf("Loading model...\n");
+    say("Loading model...\n");
     llama_model * model = llama_model_load_from_file(model_path.c_str(), model_params);
 
     if (model == NULL) {
         fprintf(stderr, "Error: Unable to load model from %s\n", model_path.c_str());
         return 1;
     }
-    printf("Model loaded successfully!\n\n");
+    say("Model loaded successfully!\n\n");
 
     // Get vocabulary
     const llama_vocab * vocab = llama_model_get_vocab(model);
 
+    // Render the conversation with the model's chat template
+    if (!messages.empty()) {
+        const char * tmpl = llama_model_chat_template(model, NULL);
+        if (tmpl == NULL) {
+            fprintf(stderr, "Error: %s has no chat template for -system/-message\n", model_path.c_str());
+            llama_model_free(model);
+            return 1;
+        }
+        std::vector<llama_chat_message> chat;
+        for (const auto & message : messages) {
+            chat.push_back({ message.first.c_str(), message.second.c_str() });
+        }
+        std::vector<char> buf(4096);
+        int n = llama_chat_apply_template(tmpl, chat.data(), chat.size(), true, buf.data(), buf.size());
+        if (n > (int) buf.size()) {
+            buf.resize(n);
+            n = llama_chat_apply_template(tmpl, chat.data(), chat.size(), true, buf.data(), buf.size());
+        }
+        if (n < 0) {
+            fprintf(stderr, "Error: llama.cpp doesn't support this model's chat template\n");
+            llama_model_free(model);
+            return 1;
+        }
+        prompt.assign(buf.data(), n);
+        say("Rendered the conversation with the model's chat template\n\n");
+    }
+
     // Tokenize the prompt
-    printf("Tokenizing prompt...\n");
-    const int n_prompt = -llama_tokenize(vocab, prompt.c_str(), prompt.size(), NULL, 0, true, true);
+    say("Tokenizing prompt...\n");
+    int n_prompt = -llama_tokenize(vocab, prompt.c_str(), prompt.size(), NULL, 0, true, true);
     
     std::vector<llama_token> prompt_tokens(n_prompt);
     if (llama_tokenize(vocab, prompt.c_str(), prompt.size(), prompt_tokens.data(), prompt_tokens.size(), true, true) < 0) {
@@ -118,7 +441,65 @@ int main(int argc, char ** argv) {
         llama_model_free(model);
         return 1;
     }
-    printf("Tokenized into %d tokens\n\n", n_prompt);
+    say("Tokenized into %d tokens\n\n", n_prompt);
+
+    // Make the prompt and the tokens to generate fit in the context window
+    // the model was trained with, the same way as the candle script's
+    // --context-overflow.
+    const int n_ctx_train = llama_model_n_ctx_train(model);
+    const int max_new_tokens = n_predict;
+    const int room = std::max(n_ctx_train - n_predict, 0);
+    const int excess = std::max(n_prompt - room, 0);
+    bool max_tokens_reduced = false;
+    if (repeat_last_n > n_ctx_train) {
+        fprintf(stderr, "Error: -repeat-last-n %d is larger than the model's %d-token context window\n",
+                repeat_last_n, n_ctx_train);
+        llama_model_free(model);
+        return 1;
+    }
+    if (room == 0 && context_overflow.compare(0, 8, "truncate") == 0) {
+        fprintf(stderr, "Error: -n %d leaves no room for the prompt in the model's %d-token context window\n",
+                n_predict, n_ctx_train);
+        llama_model_free(model);
+        return 1;
+    }
+    if (excess > 0) {
+        if (context_overflow == "error") {
+            fprintf(stderr,
+                    "Error: The prompt has %d tokens, but only %d fit in the model's %d-token context window next to "
+                    "the %d to generate (-context-overflow truncate-left or truncate-middle shortens the prompt, "
+                    "reduce-max-tokens generates fewer)\n",
+                    n_prompt, room, n_ctx_train, n_predict);
+            llama_model_free(model);
+            return 1;
+        } else if (context_overflow == "reduce-max-tokens") {
+            if (n_prompt > n_ctx_train) {
+                fprintf(stderr, "Error: The prompt has %d tokens, more than the model's %d-token context window\n",
+                        n_prompt, n_ctx_train);
+                llama_model_free(model);
+                return 1;
+            }
+            n_predict = n_ctx_train - n_prompt;
+            max_tokens_reduced = true;
+            say("Generating at most %d tokens to stay in the %d-token context window\n\n", n_predict, n_ctx_train);
+        } else {
+            int head = room / 2;
+            if (context_overflow == "truncate-left") {
+                // The BOS token (and any other leading special tokens) stays:
+                // models behave erratically without it.
+                int specials = 0;
+                while (specials < n_prompt && (llama_vocab_is_control(vocab, prompt_tokens[specials]) ||
+                                               prompt_tokens[specials] == llama_vocab_bos(vocab))) {
+                    specials++;
+                }
+                head = std::min(specials, head);
+            }
+            prompt_tokens.erase(prompt_tokens.begin() + head, prompt_tokens.begin() + head + excess);
+            n_prompt = prompt_tokens.size();
+            say("Dropped %d prompt tokens to fit the context window (%s), %d left\n\n", excess,
+                context_overflow.c_str(), n_prompt);
+        }
+    }
 
     // Initialize the context
     llama_context_params ctx_params = llama_context_default_params();
@@ -126,7 +507,7 @@ int main(int argc, char ** argv) {
     ctx_params.n_batch = n_prompt;
     ctx_params.no_perf = false;
 
-    printf("Creating context...\n");
+    say("Creating context...\n");
     llama_context * ctx = llama_init_from_model(model, ctx_params);
 
     if (ctx == NULL) {
@@ -134,28 +515,52 @@ int main(int argc, char ** argv) {
         llama_model_free(model);
         return 1;
     }
-    printf("Context created successfully!\n\n");
+    say("Context created successfully!\n\n");
 
     // Initialize the sampler (greedy decoding for simplicity)
     auto sparams = llama_sampler_chain_default_params();
     sparams.no_perf = false;
     llama_sampler * smpl = llama_sampler_chain_init(sparams);
+    if (dry_multiplier > 0.0f) {
+        // Matches don't extend across these, the same defaults as the candle
+        // script's --dry-sequence-breaker.
+        const char * breakers[] = { "\n", ":", "\"", "*" };
+        llama_sampler_chain_add(smpl, llama_sampler_init_dry(vocab, llama_model_n_ctx_train(model), dry_multiplier,
+                                                             dry_base, dry_allowed_length, -1, breakers, 4));
+        // DRY looks for repetitions of the prompt too.
+        for (auto id : prompt_tokens) {
+            llama_sampler_accept(smpl, id);
+        }
+    }
     llama_sampler_chain_add(smpl, llama_sampler_init_greedy());
 
+    // The repeat penalty runs before the chain, as in the candle script, and
+    // outside it: with -repeat-scope prompt it must not see the output.
+    llama_sampler * penalties = NULL;
+    if (repeat_penalty != 1.0f) {
+        penalties = llama_sampler_init_penalties(repeat_last_n, repeat_penalty, 0.0f, 0.0f);
+        if (repeat_scope != "generated") {
+            for (auto id : prompt_tokens) {
+                llama_sampler_accept(penalties, id);
+            }
+        }
+    }
+
     // Print the prompt
-    printf("=== Output ===\n");
+    say("=== Output ===\n");
     for (auto id : prompt_tokens) {
         char buf[128];
         int n = llama_token_to_piece(vocab, id, buf, sizeof(buf), 0, true);
         if (n < 0) {
             fprintf(stderr, "Error: Failed to convert token to piece\n");
             llama_sampler_free(smpl);
+            llama_sampler_free(penalties);
             llama_free(ctx);
             llama_model_free(model);
             return 1;
         }
         std::string s(buf, n);
-        printf("%s", s.c_str());
+        say("%s", s.c_str());
     }
     fflush(stdout);
 
@@ -167,6 +572,7 @@ int main(int argc, char ** argv) {
         if (llama_encode(ctx, batch)) {
             fprintf(stderr, "Error: Failed to encode\n");
             llama_sampler_free(smpl);
+            llama_sampler_free(penalties);
             llama_free(ctx);
             llama_model_free(model);
             return 1;
@@ -184,12 +590,19 @@ int main(int argc, char ** argv) {
     const auto t_main_start = ggml_time_us();
     int n_decode = 0;
     llama_token new_token_id;
+    const int n_vocab = llama_vocab_n_tokens(vocab);
+    std::vector<llama_token_data> candidates(n_vocab);
+    std::vector<token_logprobs> logprobs;
+    std::string completion;
+    const char * finish_reason = "length";
+    std::signal(SIGINT, on_interrupt);
 
     for (int n_pos = 0; n_pos + batch.n_tokens < n_prompt + n_predict; ) {
         // Evaluate the current batch
         if (llama_decode(ctx, batch)) {
             fprintf(stderr, "\nError: Failed to decode\n");
             llama_sampler_free(smpl);
+            llama_sampler_free(penalties);
             llama_free(ctx);
             llama_model_free(model);
             return 1;
@@ -197,12 +610,37 @@ int main(int argc, char ** argv) {
 
         n_pos += batch.n_tokens;
 
-        // Sample the next token
-        new_token_id = llama_sampler_sample(smpl, ctx, -1);
+        // Sample the next token: llama_sampler_sample with the repeat
+        // penalty in front of the chain
+        const float * logits = llama_get_logits_ith(ctx, -1);
+        for (llama_token id = 0; id < n_vocab; id++) {
+            candidates[id] = { id, logits[id], 0.0f };
+        }
+        llama_token_data_array cur_p = { candidates.data(), candidates.size(), -1, false };
+        if (penalties != NULL) {
+            llama_sampler_apply(penalties, &cur_p);
+        }
+        llama_sampler_apply(smpl, &cur_p);
+        new_token_id = cur_p.data[cur_p.selected].id;
+        llama_sampler_accept(smpl, new_token_id);
+        if (penalties != NULL && repeat_scope != "prompt") {
+            llama_sampler_accept(penalties, new_token_id);
+        }
+        if (n_logprobs >= 0 || stream_ndjson) {
+            logprobs.push_back(get_logprobs(logits, n_vocab, new_token_id, std::max(n_logprobs, 0)));
+        }
+        if (stream_ndjson) {
+            // The end-of-generation token adds no text to the completion.
+            const std::string text = llama_vocab_is_eog(vocab, new_token_id) ? "" : token_to_string(vocab, new_token_id);
+            printf("{\"token\":%s,\"id\":%d,\"logprob\":%f}\n", json_string(text).c_str(), new_token_id,
+                   logprobs.back().logprob);
+            fflush(stdout);
+        }
 
         // Check for end of generation
         if (llama_vocab_is_eog(vocab, new_token_id)) {
-            printf("\n[End of generation]\n");
+            finish_reason = "eos";
+            say("\n[End of generation]\n");
             break;
         }
 
@@ -212,38 +650,99 @@ int main(int argc, char ** argv) {
         if (n < 0) {
             fprintf(stderr, "\nError: Failed to convert token to piece\n");
             llama_sampler_free(smpl);
+            llama_sampler_free(penalties);
             llama_free(ctx);
             llama_model_free(model);
             return 1;
         }
         std::string s(buf, n);
-        printf("%s", s.c_str());
+        say("%s", s.c_str());
         fflush(stdout);
+        completion += s;
 
         // Prepare next batch with the sampled token
         batch = llama_batch_get_one(&new_token_id, 1);
 
         n_decode += 1;
+
+        if (interrupted) {
+            finish_reason = "cancelled";
+            say("\n[Generation interrupted]\n");
+            break;
+        }
+    }
+
+    // -context-overflow reduce-max-tokens cut the generation short.
+    if (max_tokens_reduced && strcmp(finish_reason, "length") == 0) {
+        finish_reason = "context_full";
     }
 
-    printf("\n\n");
+    say("\n\n");
 
     const auto t_main_end = ggml_time_us();
 
-    // Print performance statistics
-    printf("=== Statistics ===\n");
-    printf("Tokens generated: %d\n", n_decode);
-    printf("Time: %.2f s\n", (t_main_end - t_main_start) / 1000000.0f);
-    printf("Speed: %.2f tokens/s\n\n", n_decode / ((t_main_end - t_main_start) / 1000000.0f));
+    if (n_logprobs >= 0) {
+        say("=== Logprobs ===\n");
+        say("%s\n", logprobs_json(vocab, logprobs).c_str());
+        say("\n");
+    }
 
-    llama_perf_sampler_print(smpl);
-    llama_perf_context_print(ctx);
+    // Print performance statistics
+    double mean_logprob = 0.0;
+    for (const auto & token : logprobs) {
+        mean_logprob += token.logprob / logprobs.size();
+    }
+    const double seconds = (t_main_end - t_main_start) / 1000000.0;
+    say("=== Statistics ===\n");
+    say("Tokens generated: %d\n", n_decode);
+    if (!logprobs.empty()) {
+        say("Output perplexity: %.4f (mean logprob %.4f)\n", std::exp(-mean_logprob), mean_logprob);
+    }
+    say("Time: %.2f s\n", seconds);
+    say("Speed: %.2f tokens/s\n\n", n_decode / seconds);
+
+    if (json_output) {
+        std::string json = "{\"model\":" + json_string(model_path) +
+                           ",\"completion\":" + json_string(completion) +
+                           ",\"prompt_tokens\":" + std::to_string(n_prompt) +
+                           (excess > 0 && !max_tokens_reduced
+                                ? ",\"truncated_prompt_tokens\":" + std::to_string(excess) : std::string()) +
+                           ",\"generated_tokens\":" + std::to_string(n_decode) +
+                           ",\"finish_reason\":\"" + finish_reason + "\"" +
+                           ",\"load_ms\":" + std::to_string((t_main_start - t_load_start) / 1000.0) +
+                           ",\"generation_ms\":" + std::to_string(seconds * 1000.0) +
+                           ",\"tokens_per_sec\":" + std::to_string(n_decode / seconds);
+        if (n_logprobs >= 0) {
+            json += ",\"logprobs\":" + logprobs_json(vocab, logprobs);
+        }
+        if (!logprobs.empty()) {
+            json += ",\"perplexity\":" + std::to_string(std::exp(-mean_logprob));
+        }
+        json += ",\"sampling\":{\"sampler\":\"greedy\",\"max_new_tokens\":" + std::to_string(max_new_tokens) +
+                ",\"gpu_layers\":" + std::to_string(ngl) +
+                ",\"repeat_penalty\":" + std::to_string(repeat_penalty) +
+                ",\"repeat_last_n\":" + std::to_string(repeat_last_n) +
+                ",\"repeat_scope\":\"" + repeat_scope + "\"" +
+                ",\"dry_multiplier\":" + std::to_string(dry_multiplier) +
+                ",\"dry_base\":" + std::to_string(dry_base) +
+                ",\"dry_allowed_length\":" + std::to_string(dry_allowed_length) +
+                ",\"context_overflow\":\"" + context_overflow + "\"}}";
+        printf("%s\n", json.c_str());
+    } else {
+        llama_perf_sampler_print(smpl);
+        llama_perf_context_print(ctx);
+    }
 
     // Cleanup
     llama_sampler_free(smpl);
+    llama_sampler_free(penalties);
     llama_free(ctx);
     llama_model_free(model);
 
-    printf("\n=== Inference Complete ===\n");
+    if (strcmp(finish_reason, "cancelled") == 0) {
+        say("\n=== Inference Interrupted ===\n");
+        return 130;  // as a shell reports SIGINT
+    }
+    say("\n=== Inference Complete ===\n");
     return 0;
 }